@@ -0,0 +1,27 @@
+//! Throughput of the UDP socket, sending packets one at a time against sending them in batches
+//! from the packet pool.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use freenet::dev_tool::socket_bench;
+
+const PACKETS: usize = 10_000;
+
+fn udp_socket(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("udp_socket");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.bench_function("unbatched", |b| {
+        b.to_async(&rt)
+            .iter(|| async { socket_bench::unbatched(PACKETS).await.unwrap() })
+    });
+    group.bench_function("batched", |b| {
+        b.to_async(&rt)
+            .iter(|| async { socket_bench::batched(PACKETS).await.unwrap() })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, udp_socket);
+criterion_main!(benches);
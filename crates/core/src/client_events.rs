@@ -11,6 +11,8 @@ use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender;
 
+use crate::node::NodeHandle;
+
 pub(crate) mod combinator;
 #[cfg(feature = "websocket")]
 pub(crate) mod websocket;
@@ -150,6 +152,9 @@ pub trait ClientEventsProxy {
         id: ClientId,
         response: Result<HostResponse, ClientError>,
     ) -> BoxFuture<Result<(), ClientError>>;
+
+    /// Called once the node this proxy is serving has been built.
+    fn attach_node(&mut self, _node: &NodeHandle) {}
 }
 
 pub(crate) mod test {
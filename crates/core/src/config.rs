@@ -2,7 +2,7 @@ use std::{
     fs::{self, File},
     future::Future,
     io::{Read, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     sync::{atomic::AtomicBool, Arc},
     time::Duration,
@@ -14,7 +14,9 @@ use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
 
-use crate::{dev_tool::PeerId, local_node::OperationMode, transport::TransportKeypair};
+use crate::{
+    dev_tool::PeerId, local_node::OperationMode, ring::SeedingWeights, transport::TransportKeypair,
+};
 
 mod secret;
 pub use secret::*;
@@ -73,10 +75,21 @@ impl Default for ConfigArgs {
         Self {
             mode: Some(OperationMode::Network),
             network_listener: NetworkArgs {
-                address: Some(default_address()),
+                address: Some(default_network_address()),
                 network_port: Some(default_network_port()),
                 public_address: None,
                 public_port: None,
+                alt_public_address: None,
+                packet_capture: None,
+                idle_timeout: None,
+                max_connections_per_ipv4_subnet: None,
+                max_connections_per_ipv6_subnet: None,
+                intro_cookie_threshold: None,
+                intro_pow_threshold: None,
+                seeding_distance_weight: None,
+                seeding_frequency_weight: None,
+                seeding_recency_weight: None,
+                seeding_size_weight: None,
                 is_gateway: false,
             },
             ws_api: WebsocketApiArgs {
@@ -166,6 +179,13 @@ impl ConfigArgs {
 
     /// Parse the command line arguments and return the configuration.
     pub fn build(mut self) -> anyhow::Result<Config> {
+        if self.network_listener.idle_timeout == Some(0) {
+            return Err(anyhow::Error::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Idle timeout must be at least one second",
+            )));
+        }
+
         let cfg = if let Some(path) = self.config_paths.config_dir.as_ref() {
             if !path.exists() {
                 return Err(anyhow::Error::new(std::io::Error::new(
@@ -258,7 +278,7 @@ impl ConfigArgs {
             network_api: NetworkApiConfig {
                 address: self.network_listener.address.unwrap_or_else(|| match mode {
                     OperationMode::Local => default_local_address(),
-                    OperationMode::Network => default_address(),
+                    OperationMode::Network => default_network_address(),
                 }),
                 port: self
                     .network_listener
@@ -266,6 +286,21 @@ impl ConfigArgs {
                     .unwrap_or(default_network_port()),
                 public_address: self.network_listener.public_address,
                 public_port: self.network_listener.public_port,
+                alt_public_address: self.network_listener.alt_public_address,
+                packet_capture: self.network_listener.packet_capture.clone(),
+                idle_timeout: self.network_listener.idle_timeout,
+                max_connections_per_ipv4_subnet: self
+                    .network_listener
+                    .max_connections_per_ipv4_subnet,
+                max_connections_per_ipv6_subnet: self
+                    .network_listener
+                    .max_connections_per_ipv6_subnet,
+                intro_cookie_threshold: self.network_listener.intro_cookie_threshold,
+                intro_pow_threshold: self.network_listener.intro_pow_threshold,
+                seeding_distance_weight: self.network_listener.seeding_distance_weight,
+                seeding_frequency_weight: self.network_listener.seeding_frequency_weight,
+                seeding_recency_weight: self.network_listener.seeding_recency_weight,
+                seeding_size_weight: self.network_listener.seeding_size_weight,
             },
             ws_api: WebsocketApiConfig {
                 address: self.ws_api.address.unwrap_or_else(|| match mode {
@@ -374,9 +409,10 @@ impl Config {
     }
 }
 
-#[derive(clap::Parser, Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[derive(clap::Parser, Debug, Default, Clone, Serialize, Deserialize)]
 pub struct NetworkArgs {
-    /// Address to bind to for the network event listener, default is 0.0.0.0
+    /// Address to bind to for the network event listener, default is [::], which accepts both
+    /// IPv4 and IPv6 on dual-stack systems
     #[arg(long = "network-address", env = "NETWORK_ADDRESS")]
     #[serde(rename = "network-address", skip_serializing_if = "Option::is_none")]
     pub address: Option<IpAddr>,
@@ -402,16 +438,111 @@ pub struct NetworkArgs {
     )]
     pub public_port: Option<u16>,
 
+    /// Public address in the other address family than the public network address, for
+    /// dual-stack nodes. Advertised to peers so they can connect over either family.
+    #[arg(
+        long = "alt-public-network-address",
+        env = "ALT_PUBLIC_NETWORK_ADDRESS"
+    )]
+    #[serde(
+        rename = "alt-public-network-address",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub alt_public_address: Option<IpAddr>,
+
+    /// Capture every packet sent and received to this file, for debugging. The session keys
+    /// are written to `<file>.keylog` so the capture can be decoded with `fdev decode-capture`.
+    #[arg(long, env = "PACKET_CAPTURE")]
+    #[serde(rename = "packet-capture", skip_serializing_if = "Option::is_none")]
+    pub packet_capture: Option<PathBuf>,
+
+    /// Seconds without receiving anything from a peer after which the connection to it is
+    /// closed, at least 1. Idle connections are pinged well before that.
+    #[arg(long, env = "IDLE_TIMEOUT")]
+    #[serde(rename = "idle-timeout", skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+
+    /// Max number of connections to peers in the same IPv4 /24 subnet, so a single
+    /// operator can't surround this peer with nodes it controls.
+    #[arg(long, env = "MAX_CONNECTIONS_PER_IPV4_SUBNET")]
+    #[serde(
+        rename = "max-connections-per-ipv4-subnet",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_connections_per_ipv4_subnet: Option<usize>,
+
+    /// Max number of connections to peers in the same IPv6 /48 subnet.
+    #[arg(long, env = "MAX_CONNECTIONS_PER_IPV6_SUBNET")]
+    #[serde(
+        rename = "max-connections-per-ipv6-subnet",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_connections_per_ipv6_subnet: Option<usize>,
+
+    /// Intro packets per second above which a gateway asks connecting peers to echo a cookie
+    /// first, default is 100.
+    #[arg(long, env = "INTRO_COOKIE_THRESHOLD")]
+    #[serde(
+        rename = "intro-cookie-threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub intro_cookie_threshold: Option<u32>,
+
+    /// Intro packets per second above which a gateway also asks connecting peers to solve a
+    /// proof of work, default is 1000.
+    #[arg(long, env = "INTRO_POW_THRESHOLD")]
+    #[serde(
+        rename = "intro-pow-threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub intro_pow_threshold: Option<u32>,
+
+    /// Weight of the closeness of a contract to this peer when deciding which contracts to
+    /// seed, default is 1.
+    #[arg(long, env = "SEEDING_DISTANCE_WEIGHT")]
+    #[serde(
+        rename = "seeding-distance-weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_distance_weight: Option<f64>,
+
+    /// Weight of how often a contract is requested when deciding which contracts to seed,
+    /// default is 1.
+    #[arg(long, env = "SEEDING_FREQUENCY_WEIGHT")]
+    #[serde(
+        rename = "seeding-frequency-weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_frequency_weight: Option<f64>,
+
+    /// Weight of how recently a contract was requested when deciding which contracts to seed,
+    /// default is 0.25.
+    #[arg(long, env = "SEEDING_RECENCY_WEIGHT")]
+    #[serde(
+        rename = "seeding-recency-weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_recency_weight: Option<f64>,
+
+    /// Weight of the penalty for the state size of a contract when deciding which contracts
+    /// to seed, default is 0.25.
+    #[arg(long, env = "SEEDING_SIZE_WEIGHT")]
+    #[serde(
+        rename = "seeding-size-weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_size_weight: Option<f64>,
+
     /// Whether the node is a gateway or not.
     /// If the node is a gateway, it will be able to accept connections from other nodes.
     #[arg(long)]
     pub is_gateway: bool,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkApiConfig {
     /// Address to bind to
-    #[serde(default = "default_address", rename = "network-address")]
+    #[serde(default = "default_network_address", rename = "network-address")]
     pub address: IpAddr,
 
     /// Port to expose api on
@@ -426,6 +557,99 @@ pub struct NetworkApiConfig {
 
     #[serde(rename = "public_port", skip_serializing_if = "Option::is_none")]
     pub public_port: Option<u16>,
+
+    #[serde(
+        rename = "alt_public_network_address",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub alt_public_address: Option<IpAddr>,
+
+    #[serde(rename = "packet_capture", skip_serializing_if = "Option::is_none")]
+    pub packet_capture: Option<PathBuf>,
+
+    /// Seconds without receiving anything from a peer after which the connection is closed.
+    #[serde(rename = "idle_timeout", skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+
+    /// Max number of connections to peers in the same IPv4 /24 subnet.
+    #[serde(
+        rename = "max_connections_per_ipv4_subnet",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_connections_per_ipv4_subnet: Option<usize>,
+
+    /// Max number of connections to peers in the same IPv6 /48 subnet.
+    #[serde(
+        rename = "max_connections_per_ipv6_subnet",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_connections_per_ipv6_subnet: Option<usize>,
+
+    /// Intro packets per second above which connecting peers have to echo a cookie.
+    #[serde(
+        rename = "intro_cookie_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub intro_cookie_threshold: Option<u32>,
+
+    /// Intro packets per second above which connecting peers also have to solve a proof of work.
+    #[serde(
+        rename = "intro_pow_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub intro_pow_threshold: Option<u32>,
+
+    /// Weight of the closeness of a contract to this peer when deciding which contracts to seed.
+    #[serde(
+        rename = "seeding_distance_weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_distance_weight: Option<f64>,
+
+    /// Weight of how often a contract is requested when deciding which contracts to seed.
+    #[serde(
+        rename = "seeding_frequency_weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_frequency_weight: Option<f64>,
+
+    /// Weight of how recently a contract was requested when deciding which contracts to seed.
+    #[serde(
+        rename = "seeding_recency_weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_recency_weight: Option<f64>,
+
+    /// Weight of the penalty for the state size of a contract when deciding which contracts
+    /// to seed.
+    #[serde(
+        rename = "seeding_size_weight",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seeding_size_weight: Option<f64>,
+}
+
+impl NetworkApiConfig {
+    /// Weights of the seeding policy if any of them is configured, the rest keep their
+    /// default value.
+    pub(crate) fn seeding_weights(&self) -> Option<SeedingWeights> {
+        let configured = [
+            self.seeding_distance_weight,
+            self.seeding_frequency_weight,
+            self.seeding_recency_weight,
+            self.seeding_size_weight,
+        ];
+        if configured.iter().all(Option::is_none) {
+            return None;
+        }
+        let default = SeedingWeights::default();
+        Some(SeedingWeights {
+            distance: self.seeding_distance_weight.unwrap_or(default.distance),
+            frequency: self.seeding_frequency_weight.unwrap_or(default.frequency),
+            recency: self.seeding_recency_weight.unwrap_or(default.recency),
+            size: self.seeding_size_weight.unwrap_or(default.size),
+        })
+    }
 }
 
 #[inline]
@@ -481,6 +705,12 @@ const fn default_address() -> IpAddr {
     IpAddr::V4(Ipv4Addr::UNSPECIFIED)
 }
 
+/// Binds both families on dual-stack systems, IPv4 peers show up as IPv4-mapped addresses.
+#[inline]
+const fn default_network_address() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
 #[inline]
 const fn default_local_address() -> IpAddr {
     IpAddr::V4(Ipv4Addr::LOCALHOST)
@@ -692,6 +922,20 @@ impl ConfigPaths {
         }
     }
 
+    pub fn router_history(&self, mode: OperationMode) -> PathBuf {
+        match mode {
+            OperationMode::Local => self.data_dir.join("_ROUTER_HISTORY_LOCAL"),
+            OperationMode::Network => self.data_dir.join("_ROUTER_HISTORY"),
+        }
+    }
+
+    pub fn ban_list(&self, mode: OperationMode) -> PathBuf {
+        match mode {
+            OperationMode::Local => self.data_dir.join("_BAN_LIST_LOCAL"),
+            OperationMode::Network => self.data_dir.join("_BAN_LIST"),
+        }
+    }
+
     pub fn with_event_log(mut self, event_log: PathBuf) -> Self {
         self.event_log = event_log;
         self
@@ -767,6 +1011,14 @@ impl Config {
         self.config_paths.event_log(self.mode)
     }
 
+    pub fn router_history(&self) -> PathBuf {
+        self.config_paths.router_history(self.mode)
+    }
+
+    pub fn ban_list(&self) -> PathBuf {
+        self.config_paths.ban_list(self.mode)
+    }
+
     pub fn config_dir(&self) -> PathBuf {
         self.config_paths.config_dir()
     }
@@ -859,6 +1111,13 @@ mod tests {
         let _: Config = toml::from_str(&serialized).unwrap();
     }
 
+    #[test]
+    fn zero_idle_timeout_rejected() {
+        let mut args = ConfigArgs::default();
+        args.network_listener.idle_timeout = Some(0);
+        assert!(args.build().is_err());
+    }
+
     #[test]
     fn test_gateways() {
         let gateways = Gateways {
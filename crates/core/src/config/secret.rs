@@ -4,7 +4,6 @@ use aes_gcm::KeyInit;
 use blake3::traits::digest::generic_array::GenericArray;
 use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use freenet_stdlib::client_api::DelegateRequest;
-use rsa::pkcs8::DecodePrivateKey;
 
 use super::*;
 
@@ -46,7 +45,7 @@ impl ConfigArgs {
 
 #[derive(Debug, Default, Clone, clap::Parser, serde::Serialize, serde::Deserialize)]
 pub struct SecretArgs {
-    /// Path to the RSA private key for the transport layer.
+    /// Path to the private key for the transport layer, either RSA or Ed25519.
     #[clap(long, value_parser, default_value=None, env = "TRANSPORT_KEYPAIR")]
     pub transport_keypair: Option<PathBuf>,
 
@@ -219,14 +218,12 @@ fn read_transport_keypair(path_to_key: impl AsRef<Path>) -> std::io::Result<Tran
         )
     })?;
 
-    let pk = rsa::RsaPrivateKey::from_pkcs8_pem(&buf).map_err(|e| {
+    TransportKeypair::from_pkcs8_pem(&buf).map_err(|e| {
         std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             format!("Failed to read key file {}: {e}", path_to_key.display()),
         )
-    })?;
-
-    Ok::<_, std::io::Error>(TransportKeypair::from_private_key(pk))
+    })
 }
 
 #[cfg(test)]
@@ -16,7 +16,8 @@ pub(crate) use executor::{
 pub(crate) use handler::{
     client_responses_channel, contract_handler_channel, in_memory::MemoryContractHandler,
     ClientResponsesReceiver, ClientResponsesSender, ContractHandler, ContractHandlerChannel,
-    ContractHandlerEvent, NetworkContractHandler, SenderHalve, StoreResponse, WaitingResolution,
+    ContractHandlerEvent, ExecutionUsage, NetworkContractHandler, SenderHalve, StoreResponse,
+    WaitingResolution,
 };
 
 pub use executor::{Executor, ExecutorError, OperationMode};
@@ -36,12 +37,13 @@ where
                 key,
                 fetch_contract,
             } => {
-                match contract_handler
+                let get_result = contract_handler
                     .executor()
                     .fetch_contract(key, fetch_contract)
                     .instrument(tracing::info_span!("fetch_contract", %key, %fetch_contract))
-                    .await
-                {
+                    .await;
+                record_execution_usage(&mut contract_handler);
+                match get_result {
                     Ok((state, contract)) => {
                         tracing::debug!(with_contract = %fetch_contract, has_contract = %contract.is_some(), "Fetched contract {key}");
                         contract_handler
@@ -92,6 +94,7 @@ where
                     .upsert_contract_state(key, Either::Left(state), related_contracts, contract)
                     .instrument(tracing::info_span!("upsert_contract_state", %key))
                     .await;
+                record_execution_usage(&mut contract_handler);
                 contract_handler
                     .channel()
                     .send_to_sender(
@@ -108,19 +111,15 @@ where
             }
             ContractHandlerEvent::UpdateQuery {
                 key,
-                state,
+                update,
                 related_contracts,
             } => {
                 let update_result = contract_handler
                     .executor()
-                    .upsert_contract_state(
-                        key,
-                        Either::Left(state.clone()),
-                        related_contracts,
-                        None,
-                    )
+                    .upsert_contract_state(key, update, related_contracts, None)
                     .instrument(tracing::info_span!("upsert_contract_state", %key))
                     .await;
+                record_execution_usage(&mut contract_handler);
 
                 contract_handler
                     .channel()
@@ -136,11 +135,71 @@ where
                         error
                     })?;
             }
+            ContractHandlerEvent::GetSummaryQuery { key, state } => {
+                let summary = contract_handler
+                    .executor()
+                    .summarize_state(key, state)
+                    .instrument(tracing::info_span!("summarize_state", %key))
+                    .await;
+                record_execution_usage(&mut contract_handler);
+                contract_handler
+                    .channel()
+                    .send_to_sender(id, ContractHandlerEvent::GetSummaryResponse { summary })
+                    .await
+                    .map_err(|error| {
+                        tracing::debug!(%error, "shutting down contract handler");
+                        error
+                    })?;
+            }
+            ContractHandlerEvent::GetDeltaQuery {
+                key,
+                state,
+                summary,
+            } => {
+                let delta = contract_handler
+                    .executor()
+                    .get_state_delta(key, state, summary)
+                    .instrument(tracing::info_span!("get_state_delta", %key))
+                    .await;
+                record_execution_usage(&mut contract_handler);
+                contract_handler
+                    .channel()
+                    .send_to_sender(id, ContractHandlerEvent::GetDeltaResponse { delta })
+                    .await
+                    .map_err(|error| {
+                        tracing::debug!(%error, "shutting down contract handler");
+                        error
+                    })?;
+            }
+            ContractHandlerEvent::DelegateQuery {
+                request,
+                attested_contract,
+            } => {
+                let response = contract_handler
+                    .executor()
+                    .execute_delegate_request(request, attested_contract.as_ref());
+                record_execution_usage(&mut contract_handler);
+                contract_handler
+                    .channel()
+                    .send_to_sender(id, ContractHandlerEvent::DelegateResponse { response })
+                    .await
+                    .map_err(|error| {
+                        tracing::debug!(%error, "shutting down contract handler");
+                        error
+                    })?;
+            }
             _ => unreachable!(),
         }
     }
 }
 
+fn record_execution_usage<CH: ContractHandler>(contract_handler: &mut CH) {
+    let (cpu_time, memory_usage) = contract_handler.executor().take_execution_usage();
+    contract_handler
+        .channel()
+        .record_execution_usage(cpu_time, memory_usage);
+}
+
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum ContractError {
     #[error("handler channel dropped")]
@@ -20,7 +20,7 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self};
 
 use crate::config::Config;
-use crate::message::Transaction;
+use crate::message::{Transaction, TransactionType};
 use crate::node::OpManager;
 #[cfg(any(
     not(feature = "local-mode"),
@@ -44,7 +44,11 @@ pub(super) mod mock_runtime;
 pub(super) mod runtime;
 
 #[derive(Debug)]
-pub struct ExecutorError(Either<Box<RequestError>, anyhow::Error>);
+pub struct ExecutorError {
+    inner: Either<Box<RequestError>, anyhow::Error>,
+    /// The state or delta failed the contract validation.
+    invalid_state: bool,
+}
 
 enum InnerOpError {
     Upsert(ContractKey),
@@ -55,16 +59,24 @@ impl std::error::Error for ExecutorError {}
 
 impl ExecutorError {
     pub fn other(error: impl Into<anyhow::Error>) -> Self {
-        Self(Either::Right(error.into()))
+        Self::from(Either::Right(error.into()))
     }
 
     /// Call this when an unreachable path is reached but need to avoid panics.
     fn internal_error() -> Self {
-        ExecutorError(Either::Right(anyhow::anyhow!("internal error")))
+        Self::other(anyhow::anyhow!("internal error"))
     }
 
     fn request(error: impl Into<RequestError>) -> Self {
-        Self(Either::Left(Box::new(error.into())))
+        Self::from(Either::Left(Box::new(error.into())))
+    }
+
+    /// A state or delta provided by the requester failed the contract validation.
+    fn invalid_state(error: impl Into<RequestError>) -> Self {
+        Self {
+            invalid_state: true,
+            ..Self::request(error)
+        }
     }
 
     fn execution(
@@ -127,11 +139,16 @@ impl ExecutorError {
     }
 
     pub fn is_request(&self) -> bool {
-        matches!(self.0, Either::Left(_))
+        matches!(self.inner, Either::Left(_))
+    }
+
+    /// Whether the requester provided a state or delta which failed the contract validation.
+    pub fn is_invalid_state(&self) -> bool {
+        self.invalid_state
     }
 
     pub fn unwrap_request(self) -> RequestError {
-        match self.0 {
+        match self.inner {
             Either::Left(err) => *err,
             Either::Right(_) => panic!(),
         }
@@ -140,13 +157,22 @@ impl ExecutorError {
 
 impl From<RequestError> for ExecutorError {
     fn from(value: RequestError) -> Self {
-        Self(Either::Left(Box::new(value)))
+        Self::from(Either::Left(Box::new(value)))
+    }
+}
+
+impl From<Either<Box<RequestError>, anyhow::Error>> for ExecutorError {
+    fn from(inner: Either<Box<RequestError>, anyhow::Error>) -> Self {
+        Self {
+            inner,
+            invalid_state: false,
+        }
     }
 }
 
 impl Display for ExecutorError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.0 {
+        match &self.inner {
             Either::Left(l) => write!(f, "{}", &**l),
             Either::Right(r) => write!(f, "{}", &**r),
         }
@@ -155,7 +181,7 @@ impl Display for ExecutorError {
 
 impl From<Box<RequestError>> for ExecutorError {
     fn from(value: Box<RequestError>) -> Self {
-        Self(Either::Left(value))
+        Self::from(Either::Left(value))
     }
 }
 
@@ -390,7 +416,7 @@ impl ComposeNetworkMessage<operations::put::PutOp> for PutContract {
             contract,
             related_contracts,
             state,
-            op_manager.ring.max_hops_to_live,
+            op_manager.ring.hops_to_live(TransactionType::Put),
         )
     }
 
@@ -434,6 +460,33 @@ pub(crate) trait ContractExecutor: Send + 'static {
         related_contracts: RelatedContracts<'static>,
         code: Option<ContractContainer>,
     ) -> impl Future<Output = Result<WrappedState, ExecutorError>> + Send;
+
+    /// Summary of the state, other peers holding the contract compute deltas from it.
+    fn summarize_state(
+        &mut self,
+        key: ContractKey,
+        state: WrappedState,
+    ) -> impl Future<Output = Result<StateSummary<'static>, ExecutorError>> + Send;
+
+    /// Delta from the state summarized to the given state.
+    fn get_state_delta(
+        &mut self,
+        key: ContractKey,
+        state: WrappedState,
+        summary: StateSummary<'static>,
+    ) -> impl Future<Output = Result<StateDelta<'static>, ExecutorError>> + Send;
+
+    /// Executes a delegate request made by a local client.
+    fn execute_delegate_request(
+        &mut self,
+        req: DelegateRequest<'static>,
+        attested_contract: Option<&ContractInstanceId>,
+    ) -> Response;
+
+    /// CPU time spent and bytes of memory used by the executions performed since the last call.
+    fn take_execution_usage(&mut self) -> (Duration, usize) {
+        (Duration::ZERO, 0)
+    }
 }
 
 /// A WASM executor which will run any contracts, delegates, etc. registered.
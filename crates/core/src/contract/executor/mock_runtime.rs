@@ -105,9 +105,72 @@ impl ContractExecutor for Executor<MockRuntime> {
                     .map_err(ExecutorError::other)?;
                 Ok(incoming_state)
             }
+            (Either::Right(delta), None) => {
+                let current_state = self
+                    .state_store
+                    .get(&key)
+                    .await
+                    .map_err(ExecutorError::other)?;
+                let current_summary = blake3::hash(current_state.as_ref());
+                let Some(appended) = delta.strip_prefix(current_summary.as_bytes().as_slice())
+                else {
+                    return Err(ExecutorError::other(anyhow::anyhow!(
+                        "delta doesn't apply to the state of contract {key}"
+                    )));
+                };
+                let mut new_state = current_state.as_ref().to_vec();
+                new_state.extend_from_slice(appended);
+                let new_state = WrappedState::new(new_state);
+                self.state_store
+                    .update(&key, new_state.clone())
+                    .await
+                    .map_err(ExecutorError::other)?;
+                Ok(new_state)
+            }
             (update, contract) => unreachable!("{update:?}, {contract:?}"),
         }
     }
+
+    async fn summarize_state(
+        &mut self,
+        _key: ContractKey,
+        state: WrappedState,
+    ) -> Result<StateSummary<'static>, ExecutorError> {
+        let hash = blake3::hash(state.as_ref());
+        Ok(StateSummary::from(hash.as_bytes().to_vec()))
+    }
+
+    /// Without contract code to tell what changed, states are treated as append-only: the delta
+    /// is the summary of the state it applies to followed by the bytes appended to it.
+    async fn get_state_delta(
+        &mut self,
+        key: ContractKey,
+        state: WrappedState,
+        summary: StateSummary<'static>,
+    ) -> Result<StateDelta<'static>, ExecutorError> {
+        let state = state.as_ref();
+        let Some(summarized_len) = (0..=state.len())
+            .rev()
+            .find(|&len| blake3::hash(&state[..len]).as_bytes().as_slice() == &*summary)
+        else {
+            return Err(ExecutorError::other(anyhow::anyhow!(
+                "the summarized state is not part of the state of contract {key}"
+            )));
+        };
+        let mut delta = summary.into_bytes();
+        delta.extend_from_slice(&state[summarized_len..]);
+        Ok(StateDelta::from(delta))
+    }
+
+    fn execute_delegate_request(
+        &mut self,
+        _req: DelegateRequest<'static>,
+        _attested_contract: Option<&ContractInstanceId>,
+    ) -> Response {
+        Err(ExecutorError::other(anyhow::anyhow!(
+            "delegates are not supported by the mock runtime"
+        )))
+    }
 }
 
 #[cfg(test)]
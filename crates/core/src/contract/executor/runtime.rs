@@ -75,7 +75,9 @@ impl ContractExecutor for Executor<Runtime> {
                 match result {
                     ValidateResult::Valid => {}
                     ValidateResult::Invalid => {
-                        return Err(ExecutorError::request(StdContractError::invalid_put(key)));
+                        return Err(ExecutorError::invalid_state(StdContractError::invalid_put(
+                            key,
+                        )));
                     }
                     ValidateResult::RequestRelated(mut related) => {
                         if let Some(key) = related.pop() {
@@ -101,9 +103,9 @@ impl ContractExecutor for Executor<Runtime> {
                         ExecutorError::other(err)
                     })?;
                 if !valid {
-                    return Err(ExecutorError::request(StdContractError::invalid_update(
-                        key,
-                    )));
+                    return Err(ExecutorError::invalid_state(
+                        StdContractError::invalid_update(key),
+                    ));
                 }
                 // todo: forward delta like we are doing with puts
                 vec![UpdateData::Delta(delta)]
@@ -147,6 +149,41 @@ impl ContractExecutor for Executor<Runtime> {
         };
         Ok(updated_state)
     }
+
+    async fn summarize_state(
+        &mut self,
+        key: ContractKey,
+        state: WrappedState,
+    ) -> Result<StateSummary<'static>, ExecutorError> {
+        let params = self.contract_params(&key).await?;
+        self.runtime
+            .summarize_state(&key, &params, &state)
+            .map_err(|err| ExecutorError::execution(err, Some(InnerOpError::Upsert(key))))
+    }
+
+    async fn get_state_delta(
+        &mut self,
+        key: ContractKey,
+        state: WrappedState,
+        summary: StateSummary<'static>,
+    ) -> Result<StateDelta<'static>, ExecutorError> {
+        let params = self.contract_params(&key).await?;
+        self.runtime
+            .get_state_delta(&key, &params, &state, &summary)
+            .map_err(|err| ExecutorError::execution(err, Some(InnerOpError::Upsert(key))))
+    }
+
+    fn execute_delegate_request(
+        &mut self,
+        req: DelegateRequest<'static>,
+        attested_contract: Option<&ContractInstanceId>,
+    ) -> Response {
+        self.delegate_request(req, attested_contract)
+    }
+
+    fn take_execution_usage(&mut self) -> (Duration, usize) {
+        self.runtime.take_execution_usage()
+    }
 }
 
 impl Executor<Runtime> {
@@ -224,7 +261,7 @@ impl Executor<Runtime> {
             )
             .await
         {
-            match err.0 {
+            match err.inner {
                 Either::Left(err) => tracing::error!("req error: {err}"),
                 Either::Right(err) => tracing::error!("other error: {err}"),
             }
@@ -911,6 +948,22 @@ impl Executor<Runtime> {
         };
         Ok(Some(contract))
     }
+
+    async fn contract_params(
+        &self,
+        key: &ContractKey,
+    ) -> Result<Parameters<'static>, ExecutorError> {
+        self.state_store
+            .get_params(key)
+            .await
+            .map_err(ExecutorError::other)?
+            .ok_or_else(|| {
+                ExecutorError::request(StdContractError::Update {
+                    key: *key,
+                    cause: "missing contract parameters".into(),
+                })
+            })
+    }
 }
 
 #[cfg(any(
@@ -5,6 +5,8 @@ use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
 use std::sync::Arc;
 use std::time::Duration;
 
+use either::Either;
+use freenet_stdlib::client_api::{DelegateRequest, HostResponse};
 use freenet_stdlib::prelude::*;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
@@ -150,7 +152,9 @@ pub(crate) struct ContractHandlerChannel<End: sealed::ChannelHalve> {
 
 pub(crate) struct ContractHandlerHalve {
     event_receiver: mpsc::UnboundedReceiver<InternalCHEvent>,
-    waiting_response: BTreeMap<u64, tokio::sync::oneshot::Sender<(EventId, ContractHandlerEvent)>>,
+    waiting_response: BTreeMap<u64, ResponseSender>,
+    /// Resources used by the executions performed for the event being handled.
+    usage: ExecutionUsage,
 }
 
 pub(crate) struct SenderHalve {
@@ -190,6 +194,7 @@ pub(crate) fn contract_handler_channel() -> (
             end: ContractHandlerHalve {
                 event_receiver,
                 waiting_response: BTreeMap::new(),
+                usage: ExecutionUsage::default(),
             },
         },
         ContractHandlerChannel {
@@ -225,6 +230,14 @@ impl ContractHandlerChannel<SenderHalve> {
         &self,
         ev: ContractHandlerEvent,
     ) -> Result<ContractHandlerEvent, ContractError> {
+        self.send_to_handler_metered(ev).await.map(|(res, _)| res)
+    }
+
+    /// Like [`Self::send_to_handler`], but also returns the resources used to handle the event.
+    pub async fn send_to_handler_metered(
+        &self,
+        ev: ContractHandlerEvent,
+    ) -> Result<(ContractHandlerEvent, ExecutionUsage), ContractError> {
         let id = EV_ID.fetch_add(1, SeqCst);
         let (result, result_receiver) = tokio::sync::oneshot::channel();
         self.end
@@ -232,7 +245,7 @@ impl ContractHandlerChannel<SenderHalve> {
             .send(InternalCHEvent { ev, id, result })
             .map_err(|err| ContractError::ChannelDropped(Box::new(err.0.ev)))?;
         match tokio::time::timeout(Self::CH_EV_RESPONSE_TIME_OUT, result_receiver).await {
-            Ok(Ok((_, res))) => Ok(res),
+            Ok(Ok((_, res, usage))) => Ok((res, usage)),
             Ok(Err(_)) | Err(_) => Err(ContractError::NoEvHandlerResponse),
         }
     }
@@ -256,9 +269,19 @@ impl ContractHandlerChannel<ContractHandlerHalve> {
         id: EventId,
         ev: ContractHandlerEvent,
     ) -> Result<(), ContractError> {
+        let mut usage = std::mem::take(&mut self.end.usage);
         if let Some(response) = self.end.waiting_response.remove(&id.id) {
+            usage.storage_bytes = match &ev {
+                ContractHandlerEvent::PutResponse {
+                    new_value: Ok(state),
+                }
+                | ContractHandlerEvent::UpdateResponse {
+                    new_value: Ok(state),
+                } => state.size(),
+                _ => 0,
+            };
             response
-                .send((id, ev))
+                .send((id, ev, usage))
                 .map_err(|_| ContractError::NoEvHandlerResponse)
         } else {
             Err(ContractError::NoEvHandlerResponse)
@@ -274,6 +297,23 @@ impl ContractHandlerChannel<ContractHandlerHalve> {
         }
         Err(ContractError::NoEvHandlerResponse)
     }
+
+    /// Records the resources used by the executions performed for the event being handled.
+    pub fn record_execution_usage(&mut self, cpu_time: Duration, memory_bytes: usize) {
+        self.end.usage.cpu_time += cpu_time;
+        self.end.usage.memory_bytes = self.end.usage.memory_bytes.max(memory_bytes);
+    }
+}
+
+/// Resources used by the contract handler to handle an event.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ExecutionUsage {
+    /// CPU time spent by the executions.
+    pub cpu_time: Duration,
+    /// Bytes of state written to storage.
+    pub storage_bytes: usize,
+    /// Peak bytes of WASM memory added by the executions.
+    pub memory_bytes: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -282,11 +322,13 @@ pub(crate) struct StoreResponse {
     pub contract: Option<ContractContainer>,
 }
 
+type ResponseSender = tokio::sync::oneshot::Sender<(EventId, ContractHandlerEvent, ExecutionUsage)>;
+
 struct InternalCHEvent {
     ev: ContractHandlerEvent,
     id: u64,
     // client_id: Option<ClientId>,
-    result: tokio::sync::oneshot::Sender<(EventId, ContractHandlerEvent)>,
+    result: ResponseSender,
 }
 
 #[derive(Debug)]
@@ -312,16 +354,44 @@ pub(crate) enum ContractHandlerEvent {
         key: ContractKey,
         response: Result<StoreResponse, ExecutorError>,
     },
-    /// Updates a supposedly existing contract in this node
+    /// Updates a supposedly existing contract in this node, with either a new state or a delta
     UpdateQuery {
         key: ContractKey,
-        state: WrappedState,
+        update: Either<WrappedState, StateDelta<'static>>,
         related_contracts: RelatedContracts<'static>,
     },
     /// The response to an update query
     UpdateResponse {
         new_value: Result<WrappedState, ExecutorError>,
     },
+    /// Summarizes a state of the contract, so other peers can send deltas from it
+    GetSummaryQuery {
+        key: ContractKey,
+        state: WrappedState,
+    },
+    /// The response to a get summary query
+    GetSummaryResponse {
+        summary: Result<StateSummary<'static>, ExecutorError>,
+    },
+    /// Computes the delta from the state summarized to the given state of the contract
+    GetDeltaQuery {
+        key: ContractKey,
+        state: WrappedState,
+        summary: StateSummary<'static>,
+    },
+    /// The response to a get delta query
+    GetDeltaResponse {
+        delta: Result<StateDelta<'static>, ExecutorError>,
+    },
+    /// Executes a delegate request made by a local client
+    DelegateQuery {
+        request: DelegateRequest<'static>,
+        attested_contract: Option<ContractInstanceId>,
+    },
+    /// The response to a delegate query
+    DelegateResponse {
+        response: Result<HostResponse, ExecutorError>,
+    },
 }
 
 impl std::fmt::Display for ContractHandlerEvent {
@@ -374,6 +444,25 @@ impl std::fmt::Display for ContractHandlerEvent {
                     write!(f, "update query failed {{ {e} }}",)
                 }
             },
+            ContractHandlerEvent::GetSummaryQuery { key, .. } => {
+                write!(f, "get summary query {{ {key} }}")
+            }
+            ContractHandlerEvent::GetSummaryResponse { summary } => match summary {
+                Ok(_) => write!(f, "get summary query response"),
+                Err(e) => write!(f, "get summary query failed {{ {e} }}"),
+            },
+            ContractHandlerEvent::GetDeltaQuery { key, .. } => {
+                write!(f, "get delta query {{ {key} }}")
+            }
+            ContractHandlerEvent::GetDeltaResponse { delta } => match delta {
+                Ok(_) => write!(f, "get delta query response"),
+                Err(e) => write!(f, "get delta query failed {{ {e} }}"),
+            },
+            ContractHandlerEvent::DelegateQuery { .. } => write!(f, "delegate query"),
+            ContractHandlerEvent::DelegateResponse { response } => match response {
+                Ok(_) => write!(f, "delegate query response"),
+                Err(e) => write!(f, "delegate query failed {{ {e} }}"),
+            },
         }
     }
 }
@@ -396,7 +485,7 @@ pub mod test {
 
         let h = GlobalExecutor::spawn(async move {
             send_halve
-                .send_to_handler(ContractHandlerEvent::PutQuery {
+                .send_to_handler_metered(ContractHandlerEvent::PutQuery {
                     key: contract.key(),
                     state: vec![6, 7, 8].into(),
                     related_contracts: RelatedContracts::default(),
@@ -423,11 +512,12 @@ pub mod test {
             ),
         )
         .await??;
-        let ContractHandlerEvent::PutResponse { new_value } = h.await?? else {
+        let (ContractHandlerEvent::PutResponse { new_value }, usage) = h.await?? else {
             anyhow::bail!("invalid event!");
         };
         let new_value = new_value.map_err(|e| anyhow::anyhow!(e))?;
         assert_eq!(new_value.as_ref(), &[0, 7]);
+        assert_eq!(usage.storage_bytes, 2);
 
         Ok(())
     }
@@ -50,10 +50,14 @@ pub mod dev_tool {
     pub use message::Transaction;
     pub use node::{
         testing_impl::{EventChain, NetworkPeer, NodeLabel, PeerMessage, PeerStatus, SimNetwork},
-        InitPeerNode, NodeConfig, PeerId,
+        InitPeerNode, NodeConfig, NodeHandle, PeerId,
+    };
+    pub use ring::{Location, RingHealth, SeedingWeights};
+    pub use router::{RoutingCandidate, RoutingExplanation, RoutingPrediction};
+    pub use transport::{
+        read_capture, socket_bench, CaptureDecoder, CapturedPacket, Direction, TimelineEntry,
+        TransportKeypair,
     };
-    pub use ring::Location;
-    pub use transport::TransportKeypair;
     pub use wasm_runtime::{ContractStore, DelegateStore, Runtime, SecretsStore, StateStore};
 }
 
@@ -3,6 +3,7 @@
 use std::{
     borrow::{Borrow, Cow},
     fmt::Display,
+    net::SocketAddr,
     time::{Duration, SystemTime},
 };
 
@@ -13,9 +14,11 @@ use ulid::Ulid;
 use crate::{
     node::PeerId,
     operations::{
-        connect::ConnectMsg, get::GetMsg, put::PutMsg, subscribe::SubscribeMsg, update::UpdateMsg,
+        connect::ConnectMsg, get::GetMsg, put::PutMsg, subscribe::SubscribeMsg, swap::SwapMsg,
+        update::UpdateMsg,
     },
     ring::{Location, PeerKeyLocation},
+    transport::MessagePriority,
 };
 pub(crate) use sealed_msg_type::{TransactionType, TransactionTypeId};
 
@@ -53,6 +56,7 @@ impl Transaction {
             2 => TransactionType::Get,
             3 => TransactionType::Subscribe,
             4 => TransactionType::Update,
+            5 => TransactionType::Swap,
             _ => unsafe { std::hint::unreachable_unchecked() },
         }
     }
@@ -180,6 +184,7 @@ mod sealed_msg_type {
         Get = 2,
         Subscribe = 3,
         Update = 4,
+        Swap = 5,
     }
 
     impl TransactionType {
@@ -190,6 +195,7 @@ mod sealed_msg_type {
                 TransactionType::Get => "get",
                 TransactionType::Subscribe => "subscribe",
                 TransactionType::Update => "update",
+                TransactionType::Swap => "swap",
             }
         }
     }
@@ -223,7 +229,8 @@ mod sealed_msg_type {
         Put -> PutMsg,
         Get -> GetMsg,
         Subscribe -> SubscribeMsg,
-        Update -> UpdateMsg
+        Update -> UpdateMsg,
+        Swap -> SwapMsg
     });
 }
 
@@ -253,6 +260,7 @@ pub(crate) enum NetMessageV1 {
     },
     Update(UpdateMsg),
     Aborted(Transaction),
+    Swap(SwapMsg),
 }
 
 trait Versioned {
@@ -277,6 +285,26 @@ impl Versioned for NetMessageV1 {
             NetMessageV1::Unsubscribed { .. } => semver::Version::new(1, 0, 0),
             NetMessageV1::Update(_) => semver::Version::new(1, 0, 0),
             NetMessageV1::Aborted(_) => semver::Version::new(1, 0, 0),
+            NetMessageV1::Swap(_) => semver::Version::new(1, 0, 0),
+        }
+    }
+}
+
+impl NetMessage {
+    /// Priority of the message when it is large enough to be streamed, operations which may
+    /// carry contract states go after the rest so they don't delay other operations.
+    pub(crate) fn priority(&self) -> MessagePriority {
+        match self {
+            NetMessage::V1(msg) => match msg {
+                NetMessageV1::Put(_) | NetMessageV1::Get(_) | NetMessageV1::Update(_) => {
+                    MessagePriority::Transfer
+                }
+                NetMessageV1::Connect(_)
+                | NetMessageV1::Subscribe(_)
+                | NetMessageV1::Unsubscribed { .. }
+                | NetMessageV1::Aborted(_)
+                | NetMessageV1::Swap(_) => MessagePriority::Control,
+            },
         }
     }
 }
@@ -303,6 +331,8 @@ pub(crate) enum NodeEvent {
     // Try connecting to the given peer.
     ConnectPeer {
         peer: PeerId,
+        /// Address of the peer in the other address family, tried as well if set.
+        alt_addr: Option<SocketAddr>,
         callback: tokio::sync::mpsc::Sender<Result<(), ()>>,
     },
     Disconnect {
@@ -357,6 +387,7 @@ impl MessageStats for NetMessageV1 {
             NetMessageV1::Get(op) => op.id(),
             NetMessageV1::Subscribe(op) => op.id(),
             NetMessageV1::Update(op) => op.id(),
+            NetMessageV1::Swap(op) => op.id(),
             NetMessageV1::Aborted(tx) => tx,
             NetMessageV1::Unsubscribed { transaction, .. } => transaction,
         }
@@ -369,6 +400,7 @@ impl MessageStats for NetMessageV1 {
             NetMessageV1::Get(op) => op.target().as_ref().map(|b| b.borrow().clone()),
             NetMessageV1::Subscribe(op) => op.target().as_ref().map(|b| b.borrow().clone()),
             NetMessageV1::Update(op) => op.target().as_ref().map(|b| b.borrow().clone()),
+            NetMessageV1::Swap(op) => op.target().as_ref().map(|b| b.borrow().clone()),
             NetMessageV1::Aborted(_) => None,
             NetMessageV1::Unsubscribed { .. } => None,
         }
@@ -381,6 +413,7 @@ impl MessageStats for NetMessageV1 {
             NetMessageV1::Get(op) => op.requested_location(),
             NetMessageV1::Subscribe(op) => op.requested_location(),
             NetMessageV1::Update(op) => op.requested_location(),
+            NetMessageV1::Swap(op) => op.requested_location(),
             NetMessageV1::Aborted(_) => None,
             NetMessageV1::Unsubscribed { .. } => None,
         }
@@ -398,6 +431,7 @@ impl Display for NetMessage {
                 Get(msg) => msg.fmt(f)?,
                 Subscribe(msg) => msg.fmt(f)?,
                 Update(msg) => msg.fmt(f)?,
+                Swap(msg) => msg.fmt(f)?,
                 Aborted(msg) => msg.fmt(f)?,
                 Unsubscribed { key, from, .. } => {
                     write!(f, "Unsubscribed {{  key: {}, from: {} }}", key, from)?;
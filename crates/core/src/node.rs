@@ -14,6 +14,7 @@ use std::{
     fs::File,
     io::Read,
     net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
@@ -21,11 +22,10 @@ use std::{
 use anyhow::Context;
 use either::Either;
 use freenet_stdlib::{
-    client_api::{ClientRequest, ContractRequest, ErrorKind},
+    client_api::{ClientError, ClientRequest, ContractRequest, ErrorKind},
     prelude::{ContractKey, RelatedContracts, WrappedState},
 };
 
-use rsa::pkcs8::DecodePublicKey;
 use serde::{Deserialize, Serialize};
 use tracing::Instrument;
 
@@ -41,9 +41,9 @@ use crate::{
     message::{NetMessage, NodeEvent, Transaction, TransactionType},
     operations::{
         connect::{self, ConnectOp},
-        get, put, subscribe, update, OpEnum, OpError, OpOutcome,
+        get, put, subscribe, swap, update, OpEnum, OpError, OpOutcome,
     },
-    ring::{Location, PeerKeyLocation},
+    ring::{Location, PeerKeyLocation, SeedingWeights},
     router::{RouteEvent, RouteOutcome},
     tracing::{EventRegister, NetEventLog, NetEventRegister},
 };
@@ -53,6 +53,8 @@ use crate::{
 };
 
 use crate::operations::handle_op_request;
+#[cfg(test)]
+pub(crate) use network_bridge::{event_loop_notification_channel, EventLoopNotificationsReceiver};
 pub(crate) use network_bridge::{ConnectionError, EventLoopNotificationsSender, NetworkBridge};
 
 use crate::topology::rate::Rate;
@@ -77,6 +79,13 @@ impl Node {
     }
 }
 
+/// Handle to the internals of a running node, given to the client proxies so they can
+/// serve diagnostics about the node.
+#[derive(Clone)]
+pub struct NodeHandle {
+    pub(crate) op_manager: Arc<OpManager>,
+}
+
 /// When instancing a node you can either join an existing network or bootstrap a new network with a listener
 /// which will act as the initial provider. This initial peer will be listening at the provided port and assigned IP.
 /// If those are not free the instancing process will return an error.
@@ -102,6 +111,20 @@ pub struct NodeConfig {
     /// socket port to bind to the network listener.
     pub network_listener_port: u16,
     pub(crate) peer_id: Option<PeerId>,
+    /// Public address in the other address family, advertised to peers if dual-stack.
+    pub(crate) alt_addr: Option<SocketAddr>,
+    /// Where to capture the packets sent and received by the transport, if at all.
+    pub(crate) packet_capture: Option<PathBuf>,
+    /// Time without receiving anything from a peer after which the connection is closed.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Intro packets per second above which connecting peers have to echo a cookie.
+    pub(crate) intro_cookie_threshold: Option<u32>,
+    /// Intro packets per second above which connecting peers also have to solve a proof of work.
+    pub(crate) intro_pow_threshold: Option<u32>,
+    /// Where the routing history is persisted across restarts, if at all.
+    pub(crate) router_history: Option<PathBuf>,
+    /// Where the peers banned for misbehaving are persisted across restarts, if at all.
+    pub(crate) ban_list: Option<PathBuf>,
     pub(crate) config: Arc<Config>,
     /// At least one gateway is required for joining the network.
     /// Not necessary if this is an initial node.
@@ -114,6 +137,13 @@ pub struct NodeConfig {
     pub(crate) min_number_conn: Option<usize>,
     pub(crate) max_upstream_bandwidth: Option<Rate>,
     pub(crate) max_downstream_bandwidth: Option<Rate>,
+    pub(crate) max_cpu_usage: Option<Rate>,
+    pub(crate) max_storage_usage: Option<Rate>,
+    pub(crate) max_memory_usage: Option<usize>,
+    pub(crate) max_connections_per_ipv4_subnet: Option<usize>,
+    pub(crate) max_connections_per_ipv6_subnet: Option<usize>,
+    pub(crate) location_swap_interval: Option<Duration>,
+    pub(crate) seeding_weights: Option<SeedingWeights>,
 }
 
 impl NodeConfig {
@@ -132,10 +162,10 @@ impl NodeConfig {
             let mut buf = String::new();
             key_file.read_to_string(&mut buf)?;
 
-            let pub_key = rsa::RsaPublicKey::from_public_key_pem(&buf)?;
+            let pub_key = TransportPublicKey::from_public_key_pem(&buf)?;
 
             let address = Self::parse_socket_addr(address).await?;
-            let peer_id = PeerId::new(address, TransportPublicKey::from(pub_key));
+            let peer_id = PeerId::new(address, pub_key);
             gateways.push(InitPeerNode::new(peer_id, Location::from_address(&address)));
         }
         tracing::info!(
@@ -152,8 +182,24 @@ impl NodeConfig {
             key_pair: config.transport_keypair().clone(),
             gateways,
             peer_id: config.peer_id.clone(),
+            alt_addr: config.network_api.alt_public_address.map(|ip| {
+                let port = config
+                    .network_api
+                    .public_port
+                    .unwrap_or(config.network_api.port);
+                (ip, port).into()
+            }),
+            packet_capture: config.network_api.packet_capture.clone(),
+            idle_timeout: config.network_api.idle_timeout.map(Duration::from_secs),
+            intro_cookie_threshold: config.network_api.intro_cookie_threshold,
+            intro_pow_threshold: config.network_api.intro_pow_threshold,
+            router_history: Some(config.router_history()),
+            ban_list: Some(config.ban_list()),
             network_listener_ip: config.network_api.address,
             network_listener_port: config.network_api.port,
+            max_connections_per_ipv4_subnet: config.network_api.max_connections_per_ipv4_subnet,
+            max_connections_per_ipv6_subnet: config.network_api.max_connections_per_ipv6_subnet,
+            seeding_weights: config.network_api.seeding_weights(),
             config: Arc::new(config),
             location: None,
             max_hops_to_live: None,
@@ -162,6 +208,10 @@ impl NodeConfig {
             min_number_conn: None,
             max_upstream_bandwidth: None,
             max_downstream_bandwidth: None,
+            max_cpu_usage: None,
+            max_storage_usage: None,
+            max_memory_usage: None,
+            location_swap_interval: None,
         })
     }
 
@@ -266,6 +316,25 @@ impl NodeConfig {
         self
     }
 
+    /// Interval between attempts to swap this peer location with another peer.
+    pub fn location_swap_interval(&mut self, interval: Duration) -> &mut Self {
+        self.location_swap_interval = Some(interval);
+        self
+    }
+
+    /// Max number of connections to peers in the same IPv4 /24 and IPv6 /48 subnets.
+    pub fn max_connections_per_subnet(&mut self, ipv4: usize, ipv6: usize) -> &mut Self {
+        self.max_connections_per_ipv4_subnet = Some(ipv4);
+        self.max_connections_per_ipv6_subnet = Some(ipv6);
+        self
+    }
+
+    /// Weights of the factors considered when deciding which contracts to seed.
+    pub fn seeding_weights(&mut self, weights: SeedingWeights) -> &mut Self {
+        self.seeding_weights = Some(weights);
+        self
+    }
+
     pub fn with_peer_id(&mut self, peer_id: PeerId) -> &mut Self {
         self.peer_id = Some(peer_id);
         self
@@ -325,6 +394,7 @@ impl NodeConfig {
             .map(|node| PeerKeyLocation {
                 peer: node.peer_id.clone(),
                 location: Some(node.location),
+                alt_addr: None,
             })
             .collect();
 
@@ -356,6 +426,7 @@ async fn client_event_handling<ClientEv>(
     op_manager: Arc<OpManager>,
     mut client_events: ClientEv,
     mut client_responses: ClientResponsesReceiver,
+    cli_response_sender: ClientResponsesSender,
     node_controller: tokio::sync::mpsc::Sender<NodeEvent>,
 ) where
     ClientEv: ClientEventsProxy + Send + 'static,
@@ -382,7 +453,7 @@ async fn client_event_handling<ClientEv>(
                     node_controller.send(NodeEvent::Disconnect { cause: cause.clone() }).await.ok();
                     break;
                 }
-                process_open_request(req, op_manager.clone()).await;
+                process_open_request(req, op_manager.clone(), cli_response_sender.clone()).await;
             }
             res = client_responses.recv() => {
                 if let Some((cli_id, res)) = res {
@@ -400,7 +471,11 @@ async fn client_event_handling<ClientEv>(
 }
 
 #[inline]
-async fn process_open_request(request: OpenRequest<'static>, op_manager: Arc<OpManager>) {
+async fn process_open_request(
+    request: OpenRequest<'static>,
+    op_manager: Arc<OpManager>,
+    cli_response_sender: ClientResponsesSender,
+) {
     // this will indirectly start actions on the local contract executor
     let fut = async move {
         let client_id = request.client_id;
@@ -426,7 +501,7 @@ async fn process_open_request(request: OpenRequest<'static>, op_manager: Arc<OpM
                         contract,
                         related_contracts,
                         state,
-                        op_manager.ring.max_hops_to_live,
+                        op_manager.ring.hops_to_live(TransactionType::Put),
                     );
                     let _ = op_manager
                         .ch_outbound
@@ -496,7 +571,25 @@ async fn process_open_request(request: OpenRequest<'static>, op_manager: Arc<OpM
                     tracing::error!("Op not supported");
                 }
             },
-            ClientRequest::DelegateOp(_op) => todo!("FIXME: delegate op"),
+            ClientRequest::DelegateOp(op) => {
+                // contract attestation is only available to the local mode gateway
+                let res = op_manager
+                    .execute_delegate_request(op, None)
+                    .await
+                    .map_err(|err| {
+                        let kind = if err.is_request() {
+                            ErrorKind::RequestError(err.unwrap_request())
+                        } else {
+                            ErrorKind::Unhandled {
+                                cause: format!("{err}").into(),
+                            }
+                        };
+                        ClientError::from(kind)
+                    });
+                if cli_response_sender.send((client_id, res)).is_err() {
+                    tracing::debug!("client responses channel closed");
+                }
+            }
             ClientRequest::Disconnect { .. } => unreachable!(),
             _ => {
                 tracing::error!("Op not supported");
@@ -561,17 +654,6 @@ async fn report_result(
                         .await;
                     op_manager.ring.routing_finished(event);
                 }
-                // todo: handle failures, need to track timeouts and other potential failures
-                // OpOutcome::ContractOpFailure {
-                //     target_peer: Some(target_peer),
-                //     contract_location,
-                // } => {
-                //     op_manager.ring.routing_finished(RouteEvent {
-                //         peer: *target_peer,
-                //         contract_location,
-                //         outcome: RouteOutcome::Failure,
-                //     });
-                // }
                 OpOutcome::Incomplete | OpOutcome::Irrelevant => {}
             }
             if let Some(mut cb) = executor_callback {
@@ -777,6 +859,20 @@ async fn process_message_v1<CB>(
                 subscribe(op_manager, *key, None).await;
                 break;
             }
+            NetMessageV1::Swap(ref op) => {
+                let op_result =
+                    handle_op_request::<swap::SwapOp, _>(&op_manager, &mut conn_manager, op).await;
+                handle_op_not_available!(op_result);
+                return report_result(
+                    tx,
+                    op_result,
+                    &op_manager,
+                    executor_callback,
+                    cli_req,
+                    &mut *event_listener,
+                )
+                .await;
+            }
             _ => break, // Exit the loop if no applicable message type is found
         }
     }
@@ -1,14 +1,15 @@
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use dashmap::DashSet;
 use either::{Either, Left, Right};
 use futures::stream::FuturesUnordered;
 use futures::{FutureExt, StreamExt};
-use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tracing::Instrument;
 
@@ -18,8 +19,9 @@ use crate::message::NetMessageV1;
 use crate::node::PeerId;
 use crate::operations::connect::{self, ConnectMsg, ConnectRequest, ConnectResponse};
 use crate::transport::{
-    create_connection_handler, OutboundConnectionHandler, PeerConnection, TransportError,
-    TransportKeypair,
+    create_connection_handler, ConnectionMigration, InboundConnectionEvent,
+    OutboundConnectionHandler, PeerConnection, RelayUsage, TransportError, TransportKeypair,
+    UdpSocket,
 };
 use crate::{
     client_events::ClientId,
@@ -30,12 +32,15 @@ use crate::{
     },
     message::{MessageStats, NetMessage, NodeEvent, Transaction},
     node::{handle_aborted_op, process_message, NetEventRegister, NodeConfig, OpManager},
-    ring::PeerKeyLocation,
+    ring::{Misbehaviour, PeerKeyLocation},
     tracing::NetEventLog,
 };
 
 type P2pBridgeEvent = Either<(PeerId, Box<NetMessage>), NodeEvent>;
 
+/// How often the round-trip time of each connection is reported to the ring.
+const RTT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 pub(crate) struct P2pBridge {
     accepted_peers: Arc<DashSet<PeerId>>,
@@ -104,6 +109,10 @@ pub(in crate::node) struct P2pConnManager {
     listening_ip: IpAddr,
     listening_port: u16,
     is_gateway: bool,
+    packet_capture: Option<PathBuf>,
+    idle_timeout: Option<Duration>,
+    intro_cookie_threshold: Option<u32>,
+    intro_pow_threshold: Option<u32>,
 }
 
 impl P2pConnManager {
@@ -130,6 +139,10 @@ impl P2pConnManager {
             listening_ip: listener_ip,
             listening_port: listen_port,
             is_gateway: config.is_gateway,
+            packet_capture: config.packet_capture.clone(),
+            idle_timeout: config.idle_timeout,
+            intro_cookie_threshold: config.intro_cookie_threshold,
+            intro_pow_threshold: config.intro_pow_threshold,
         })
     }
 
@@ -153,6 +166,10 @@ impl P2pConnManager {
                 self.listening_ip,
                 self.listening_port,
                 self.is_gateway,
+                self.packet_capture.as_deref(),
+                self.idle_timeout,
+                self.intro_cookie_threshold,
+                self.intro_pow_threshold,
             )
             .await?;
 
@@ -166,6 +183,8 @@ impl P2pConnManager {
         let mut pending_inbound_gw_conns = HashMap::new();
         let mut pending_listening_gw_conns = FuturesUnordered::new();
         let mut gw_inbound_pending_connections = HashSet::new();
+        // last time the RTT of each connection was reported
+        let mut rtt_reported: HashMap<SocketAddr, Instant> = HashMap::new();
 
         loop {
             let notification_msg = notification_channel.0.recv().map(|m| match m {
@@ -203,8 +222,8 @@ impl P2pConnManager {
             };
 
             let new_inbound_connection = async {
-                match inbound_conn_handler.next_connection().await {
-                    Some(peer_conn) => {
+                match inbound_conn_handler.next_event().await {
+                    Some(InboundConnectionEvent::Connection(peer_conn)) => {
                         tracing::debug!(
                             remote = %peer_conn.remote_addr(),
                             "New inbound connection at gateway"
@@ -214,6 +233,14 @@ impl P2pConnManager {
                             peer_conn,
                         }))
                     }
+                    Some(InboundConnectionEvent::Migration(ConnectionMigration {
+                        previous_addr,
+                        remote_addr,
+                    })) => Ok(Right(ConnectionMigrated {
+                        previous_addr,
+                        remote_addr,
+                    })),
+                    Some(InboundConnectionEvent::RelayUsage(usage)) => Ok(Right(Relayed(usage))),
                     None => Ok(Right(ClosedChannel)),
                 }
             };
@@ -222,10 +249,19 @@ impl P2pConnManager {
                 msg = peer_connections.next(), if !peer_connections.is_empty() => {
                     let PeerConnectionInbound { conn, rx, msg } = match msg {
                         Some(Ok(peer_conn)) => peer_conn,
-                        Some(Err(err)) => {
+                        Some(Err((socket_addr, err))) => {
                             tracing::error!("Error in peer connection: {err}");
-                            if let TransportError::ConnectionClosed(socket_addr) = err {
-                                if let Some(peer) = self.connections.keys().find_map(|k| (k.addr == socket_addr).then(|| k.clone())) {
+                            rtt_reported.remove(&socket_addr);
+                            // decode errors are not counted, peers running a previous version of
+                            // the protocol would be banned otherwise
+                            let violation = matches!(err, TransportError::UnexpectedMessage(_));
+                            if violation || matches!(err, TransportError::ConnectionClosed(_)) {
+                                if let Some(peer) = self.peer_at(socket_addr) {
+                                    if violation {
+                                        op_manager
+                                            .ring
+                                            .report_misbehaviour(&peer, Misbehaviour::ProtocolViolation);
+                                    }
                                     op_manager.ring.prune_connection(peer.clone()).await;
                                     self.connections.remove(&peer);
                                 }
@@ -238,6 +274,15 @@ impl P2pConnManager {
                         }
                     };
                     let remote_addr = conn.remote_addr();
+                    let report_due = rtt_reported
+                        .get(&remote_addr)
+                        .map_or(true, |reported| reported.elapsed() >= RTT_REPORT_INTERVAL);
+                    if report_due {
+                        if let Some(peer) = self.peer_at(remote_addr) {
+                            op_manager.ring.report_rtt(&peer, conn.rtt());
+                            rtt_reported.insert(remote_addr, Instant::now());
+                        }
+                    }
                     let task = peer_connection_listener(rx, conn).boxed();
                     peer_connections.push(task);
                     Ok(Left((msg, Some(remote_addr))))
@@ -245,7 +290,7 @@ impl P2pConnManager {
                 msg = pending_listening_gw_conns.next(), if !pending_listening_gw_conns.is_empty() => {
                     let PeerConnectionInbound { conn, rx, msg } = match msg {
                         Some(Ok(gw_conn)) => gw_conn,
-                        Some(Err(err)) => {
+                        Some(Err((_, err))) => {
                             tracing::error!("Error in gateway connection: {err}");
                             continue;
                         }
@@ -350,12 +395,39 @@ impl P2pConnManager {
                             continue;
                         }
                         mut msg => {
+                            if let NetMessage::V1(NetMessageV1::Connect(ConnectMsg::Response {
+                                msg:
+                                    ConnectResponse::RelayAvailable {
+                                        relay,
+                                        joiner,
+                                        acceptor,
+                                    },
+                                ..
+                            })) = &msg
+                            {
+                                // a peer connected to both ends offered to relay, in case the
+                                // direct connection between the joiner and the acceptor fails
+                                let this_peer = op_manager.ring.get_peer_key();
+                                if this_peer.as_ref() == Some(joiner) {
+                                    outbound_conn_handler
+                                        .relay_through(acceptor.peer.addr, relay.peer.addr)
+                                        .await;
+                                } else if this_peer.as_ref() == Some(&acceptor.peer) {
+                                    outbound_conn_handler
+                                        .relay_through(joiner.addr, relay.peer.addr)
+                                        .await;
+                                }
+                                continue;
+                            }
+
                             if let NetMessage::V1(NetMessageV1::Connect(ConnectMsg::Response {
                                 msg:
                                     ConnectResponse::AcceptedBy {
                                         accepted,
                                         acceptor,
+                                        acceptor_alt_addr,
                                         joiner,
+                                        ..
                                     },
                                 ..
                             })) = &msg
@@ -372,7 +444,11 @@ impl P2pConnManager {
                                     let remote_peer = acceptor.peer.clone();
                                     let conn_fut = outbound_conn_handler
                                         .clone()
-                                        .connect(acceptor.peer.pub_key.clone(), acceptor.peer.addr)
+                                        .connect_any(
+                                            acceptor.peer.pub_key.clone(),
+                                            std::iter::once(acceptor.peer.addr)
+                                                .chain(*acceptor_alt_addr),
+                                        )
                                         .await
                                         .map(|peer_conn| (peer_conn, remote_peer))
                                         .boxed();
@@ -473,11 +549,16 @@ impl P2pConnManager {
                         }
                     }
                 }
-                Ok(Right(NodeAction(NodeEvent::ConnectPeer { peer, callback }))) => {
+                Ok(Right(NodeAction(NodeEvent::ConnectPeer {
+                    peer,
+                    alt_addr,
+                    callback,
+                }))) => {
                     tracing::info!(remote = %peer, this_peer = ?op_manager.ring.get_peer_key().unwrap(), "Connecting to peer");
                     let mut ob = outbound_conn_handler.clone();
                     let conn_fut = (async move {
-                        let c = ob.connect(peer.pub_key.clone(), peer.addr).await;
+                        let addrs = std::iter::once(peer.addr).chain(alt_addr);
+                        let c = ob.connect_any(peer.pub_key.clone(), addrs).await;
                         let pc = c.await;
                         if pc.is_ok() {
                             let _ = callback.send(Ok(())).await;
@@ -494,6 +575,8 @@ impl P2pConnManager {
                         Some(cause) => tracing::warn!("Shutting down node: {cause}"),
                         None => tracing::warn!("Shutting down node"),
                     }
+                    op_manager.ring.persist_router_history().await;
+                    op_manager.ring.persist_ban_list().await;
                     return Ok(());
                 }
                 Ok(Right(NodeAction(NodeEvent::DropConnection(peer_id)))) => {
@@ -523,6 +606,39 @@ impl P2pConnManager {
                     let task = peer_connection_listener(rx, peer_conn).boxed();
                     peer_connections.push(task);
                 }
+                Ok(Right(ConnectionMigrated {
+                    previous_addr,
+                    remote_addr,
+                })) => {
+                    if gw_inbound_pending_connections.remove(&previous_addr) {
+                        gw_inbound_pending_connections.insert(remote_addr);
+                    }
+                    if let Some(tx) = pending_inbound_gw_conns.remove(&previous_addr) {
+                        pending_inbound_gw_conns.insert(remote_addr, tx);
+                    }
+                    let Some(previous) = self
+                        .connections
+                        .keys()
+                        .find(|k| k.addr == previous_addr)
+                        .cloned()
+                    else {
+                        // the connect operation hasn't finished yet
+                        continue;
+                    };
+                    let peer = PeerId::new(remote_addr, previous.pub_key.clone());
+                    tracing::info!(%previous, %peer, "Peer changed address, keeping connection");
+                    if let Some(tx) = self.connections.remove(&previous) {
+                        self.connections.insert(peer.clone(), tx);
+                    }
+                    op_manager.ring.update_peer_addr(&previous, peer);
+                }
+                Ok(Right(Relayed(RelayUsage {
+                    source,
+                    target,
+                    bytes,
+                }))) => {
+                    op_manager.ring.report_relayed_bytes(source, target, bytes);
+                }
                 Ok(Right(ClosedChannel)) => {
                     tracing::info!("Notification channel closed");
                     break;
@@ -611,12 +727,30 @@ impl P2pConnManager {
             })) => {
                 *skip_list = self.connections.keys().cloned().collect();
             }
+            NetMessage::V1(NetMessageV1::Connect(ConnectMsg::Response {
+                msg:
+                    ConnectResponse::RelayAvailable {
+                        relay,
+                        joiner,
+                        acceptor,
+                    },
+                ..
+            })) => {
+                if self.bridge.op_manager.ring.get_peer_key().as_ref() == Some(&relay.peer) {
+                    // this peer is offering to relay, packets are only relayed between the
+                    // peers it offered to relay for
+                    outbound_conn_handler
+                        .allow_relay(joiner.addr, acceptor.peer.addr)
+                        .await;
+                }
+            }
             NetMessage::V1(NetMessageV1::Connect(ConnectMsg::Response {
                 msg:
                     ConnectResponse::AcceptedBy {
                         accepted,
                         acceptor,
                         joiner,
+                        ..
                     },
                 ..
             })) => {
@@ -673,7 +807,8 @@ impl P2pConnManager {
         }
 
         if let Some((_, conn)) = &mut connection {
-            conn.send(net_msg)
+            let priority = net_msg.priority();
+            conn.send_with_priority(net_msg, priority)
                 .await
                 .map_err(|_| ConnectionError::SendNotCompleted(peer))?;
         } else if let Some(conn) = self.connections.get(&peer) {
@@ -707,6 +842,14 @@ impl P2pConnManager {
             );
         }
     }
+
+    /// The connected peer at the given address, if any.
+    fn peer_at(&self, addr: SocketAddr) -> Option<PeerId> {
+        self.connections
+            .keys()
+            .find(|peer| peer.addr == addr)
+            .cloned()
+    }
 }
 
 enum ConnMngrActions {
@@ -720,6 +863,13 @@ enum ConnMngrActions {
         peer: PeerId,
         peer_conn: PeerConnection,
     },
+    /// The remote of an established connection moved to a new address
+    ConnectionMigrated {
+        previous_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    },
+    /// Bytes relayed between two connected peers
+    Relayed(RelayUsage),
     /// Accept connection
     AcceptConnection,
     NodeAction(NodeEvent),
@@ -741,27 +891,31 @@ struct PeerConnectionInbound {
     msg: Option<NetMessage>,
 }
 
+/// Listens for messages from a peer, on error returns the error along the address of the peer.
 async fn peer_connection_listener(
     mut rx: PeerConnChannelRecv,
     mut conn: PeerConnection,
-) -> Result<PeerConnectionInbound, TransportError> {
+) -> Result<PeerConnectionInbound, (SocketAddr, TransportError)> {
+    let remote_addr = conn.remote_addr();
     loop {
         tokio::select! {
             msg = rx.recv() => {
                 tracing::debug!(at=?conn.my_address(), from=%conn.remote_addr(), "Received message from channel");
-                let Some(msg) = msg else { break Err(TransportError::ConnectionClosed(conn.remote_addr())); };
+                let Some(msg) = msg else { break Err((remote_addr, TransportError::ConnectionClosed(remote_addr))); };
                 match msg {
                     Left(msg) => {
                         tracing::debug!(at=?conn.my_address(), from=%conn.remote_addr() ,"Sending message to peer. Msg: {msg}");
+                        let priority = msg.priority();
                         conn
-                            .send(msg)
-                            .await?;
+                            .send_with_priority(msg, priority)
+                            .await
+                            .map_err(|err| (remote_addr, err))?;
                     }
                     Right(action) => {
                         tracing::debug!(at=?conn.my_address(), from=%conn.remote_addr(), "Received action from channel");
                         match action {
                             ConnMngrActions::NodeAction(NodeEvent::DropConnection(_)) | ConnMngrActions::ClosedChannel => {
-                                break Err(TransportError::ConnectionClosed(conn.remote_addr()));
+                                break Err((remote_addr, TransportError::ConnectionClosed(remote_addr)));
                             }
                             ConnMngrActions::AcceptConnection => {
                                 return Ok(PeerConnectionInbound { conn, rx, msg: None });
@@ -772,12 +926,18 @@ async fn peer_connection_listener(
                 }
             }
             msg = conn.recv() => {
-                let Ok(msg) = msg.map_err(|error| {
-                    tracing::error!(at=?conn.my_address(), from=%conn.remote_addr(), "Error while receiving message: {error}");
-                }) else {
-                     break Err(TransportError::ConnectionClosed(conn.remote_addr()));
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(error @ TransportError::UnexpectedMessage(_)) => break Err((remote_addr, error)),
+                    Err(error) => {
+                        tracing::error!(at=?conn.my_address(), from=%conn.remote_addr(), "Error while receiving message: {error}");
+                        break Err((remote_addr, TransportError::ConnectionClosed(remote_addr)));
+                    }
+                };
+                let net_message = match decode_msg(&msg) {
+                    Ok(net_message) => net_message,
+                    Err(error) => break Err((remote_addr, error)),
                 };
-                let net_message = decode_msg(&msg).unwrap();
                 tracing::debug!(at=?conn.my_address(), from=%conn.remote_addr() ,"Received message from peer. Msg: {net_message}");
                 break Ok(PeerConnectionInbound { conn, rx, msg: Some(net_message) });
             }
@@ -786,6 +946,6 @@ async fn peer_connection_listener(
 }
 
 #[inline(always)]
-fn decode_msg(data: &[u8]) -> Result<NetMessage, ConnectionError> {
-    bincode::deserialize(data).map_err(|err| ConnectionError::Serialization(Some(err)))
+fn decode_msg(data: &[u8]) -> Result<NetMessage, TransportError> {
+    bincode::deserialize(data).map_err(TransportError::Serialization)
 }
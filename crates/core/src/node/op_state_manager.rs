@@ -2,17 +2,24 @@ use std::{cmp::Reverse, collections::BTreeSet, sync::Arc, time::Duration};
 
 use dashmap::{DashMap, DashSet};
 use either::Either;
+use freenet_stdlib::{
+    client_api::{DelegateRequest, HostResponse},
+    prelude::{ContractInstanceId, DelegateKey},
+};
 use tracing::Instrument;
 
 use crate::{
     config::GlobalExecutor,
-    contract::{ContractError, ContractHandlerChannel, ContractHandlerEvent, SenderHalve},
+    contract::{
+        ContractError, ContractHandlerChannel, ContractHandlerEvent, ExecutorError, SenderHalve,
+    },
     message::{MessageStats, NetMessage, NodeEvent, Transaction, TransactionType},
     operations::{
-        connect::ConnectOp, get::GetOp, put::PutOp, subscribe::SubscribeOp, update::UpdateOp,
-        OpEnum, OpError,
+        connect::ConnectOp, get::GetOp, put::PutOp, subscribe::SubscribeOp, swap::SwapOp,
+        update::UpdateOp, OpEnum, OpError,
     },
-    ring::{LiveTransactionTracker, Ring},
+    ring::{Misbehaviour, PeerKeyLocation, Ring},
+    topology::meter::AttributionSource,
 };
 
 use super::{network_bridge::EventLoopNotificationsSender, NetEventRegister, NodeConfig, PeerId};
@@ -41,6 +48,7 @@ struct Ops {
     get: DashMap<Transaction, GetOp>,
     subscribe: DashMap<Transaction, SubscribeOp>,
     update: DashMap<Transaction, UpdateOp>,
+    swap: DashMap<Transaction, SwapOp>,
     completed: DashSet<Transaction>,
     under_progress: DashSet<Transaction>,
 }
@@ -56,7 +64,7 @@ pub(crate) struct OpManager {
 }
 
 impl OpManager {
-    pub(super) fn new<ER: NetEventRegister + Clone>(
+    pub(crate) fn new<ER: NetEventRegister + Clone>(
         notification_channel: EventLoopNotificationsSender,
         ch_outbound: ContractHandlerChannel<SenderHalve>,
         config: &NodeConfig,
@@ -78,13 +86,8 @@ impl OpManager {
             tracing::info_span!(parent: current_span, "garbage_cleanup_task")
         };
         GlobalExecutor::spawn(
-            garbage_cleanup_task(
-                rx,
-                ops.clone(),
-                ring.live_tx_tracker.clone(),
-                event_register,
-            )
-            .instrument(garbage_span),
+            garbage_cleanup_task(rx, ops.clone(), ring.clone(), event_register)
+                .instrument(garbage_span),
         );
 
         Ok(Self {
@@ -130,6 +133,59 @@ impl OpManager {
         self.ch_outbound.send_to_handler(msg).await
     }
 
+    /// Like [`Self::notify_contract_handler`], attributing the resources used to handle the
+    /// event to the peer which requested it, and blaming it for any invalid state.
+    pub async fn notify_contract_handler_from(
+        &self,
+        peer: &PeerKeyLocation,
+        msg: ContractHandlerEvent,
+    ) -> Result<ContractHandlerEvent, ContractError> {
+        let (res, usage) = self.ch_outbound.send_to_handler_metered(msg).await?;
+        self.ring
+            .report_execution_usage(AttributionSource::Peer(peer.clone()), usage);
+        if let ContractHandlerEvent::PutResponse {
+            new_value: Err(err),
+        }
+        | ContractHandlerEvent::UpdateResponse {
+            new_value: Err(err),
+        } = &res
+        {
+            if err.is_invalid_state() {
+                self.ring
+                    .report_misbehaviour(&peer.peer, Misbehaviour::InvalidState);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Executes a delegate request of a local client, attributing the resources used to
+    /// the delegate.
+    pub async fn execute_delegate_request(
+        &self,
+        request: DelegateRequest<'static>,
+        attested_contract: Option<ContractInstanceId>,
+    ) -> Result<HostResponse, ExecutorError> {
+        let key = delegate_key(&request);
+        let (res, usage) = self
+            .ch_outbound
+            .send_to_handler_metered(ContractHandlerEvent::DelegateQuery {
+                request,
+                attested_contract,
+            })
+            .await
+            .map_err(|err| ExecutorError::other(anyhow::anyhow!("{err}")))?;
+        if let Some(key) = key {
+            self.ring
+                .report_execution_usage(AttributionSource::Delegate(key), usage);
+        }
+        match res {
+            ContractHandlerEvent::DelegateResponse { response } => response,
+            _ => Err(ExecutorError::other(anyhow::anyhow!(
+                "unexpected response from the contract handler"
+            ))),
+        }
+    }
+
     pub async fn push(&self, id: Transaction, op: OpEnum) -> Result<(), OpError> {
         if let Some(tx) = self.ops.under_progress.remove(&id) {
             if tx.timed_out() {
@@ -164,6 +220,11 @@ impl OpManager {
                 check_id_op!(id.transaction_type(), TransactionType::Update);
                 self.ops.update.insert(id, op);
             }
+            OpEnum::Swap(op) => {
+                #[cfg(debug_assertions)]
+                check_id_op!(id.transaction_type(), TransactionType::Swap);
+                self.ops.swap.insert(id, op);
+            }
         }
         Ok(())
     }
@@ -200,6 +261,7 @@ impl OpManager {
                 .remove(id)
                 .map(|(_k, v)| v)
                 .map(OpEnum::Update),
+            TransactionType::Swap => self.ops.swap.remove(id).map(|(_k, v)| v).map(OpEnum::Swap),
         };
         self.ops.under_progress.insert(*id);
         Ok(op)
@@ -226,9 +288,10 @@ impl OpManager {
 async fn garbage_cleanup_task<ER: NetEventRegister>(
     mut new_transactions: tokio::sync::mpsc::Receiver<Transaction>,
     ops: Arc<Ops>,
-    live_tx_tracker: LiveTransactionTracker,
+    ring: Arc<Ring>,
     mut event_register: ER,
 ) {
+    let live_tx_tracker = &ring.live_tx_tracker;
     const CLEANUP_INTERVAL: Duration = Duration::from_secs(5);
     let mut tick = tokio::time::interval(CLEANUP_INTERVAL);
     tick.tick().await;
@@ -260,6 +323,7 @@ async fn garbage_cleanup_task<ER: NetEventRegister>(
                         TransactionType::Get => ops.get.remove(&tx).is_none(),
                         TransactionType::Subscribe => ops.subscribe.remove(&tx).is_none(),
                         TransactionType::Update => ops.update.remove(&tx).is_none(),
+                        TransactionType::Swap => ops.swap.remove(&tx).is_none(),
                     };
                     let timed_out = tx.timed_out();
                     if still_waiting && !timed_out {
@@ -268,6 +332,7 @@ async fn garbage_cleanup_task<ER: NetEventRegister>(
                         if still_waiting && timed_out {
                             ops.under_progress.remove(&tx);
                             ops.completed.remove(&tx);
+                            report_timeout(&ring, &tx);
                         }
                         live_tx_tracker.remove_finished_transaction(tx);
                     }
@@ -294,8 +359,10 @@ async fn garbage_cleanup_task<ER: NetEventRegister>(
                         TransactionType::Get => ops.get.remove(&tx).is_some(),
                         TransactionType::Subscribe => ops.subscribe.remove(&tx).is_some(),
                         TransactionType::Update => ops.update.remove(&tx).is_some(),
+                        TransactionType::Swap => ops.swap.remove(&tx).is_some(),
                     };
                     if removed {
+                        report_timeout(&ring, &tx);
                         live_tx_tracker.remove_finished_transaction(tx);
                     }
                 }
@@ -303,3 +370,22 @@ async fn garbage_cleanup_task<ER: NetEventRegister>(
         }
     }
 }
+
+/// Blames the last peer the transaction was sent to for it timing out, the peers
+/// before it have no say in whether the next hops respond.
+fn report_timeout(ring: &Ring, tx: &Transaction) {
+    if let Some(peer) = ring.live_tx_tracker.last_hop(tx) {
+        ring.report_misbehaviour(&peer, Misbehaviour::Timeout);
+    }
+}
+
+/// The delegate a request is addressed to.
+fn delegate_key(request: &DelegateRequest<'_>) -> Option<DelegateKey> {
+    match request {
+        DelegateRequest::ApplicationMessages { key, .. }
+        | DelegateRequest::GetSecretRequest { key, .. }
+        | DelegateRequest::UnregisterDelegate(key) => Some(key.clone()),
+        DelegateRequest::RegisterDelegate { delegate, .. } => Some(delegate.key().clone()),
+        _ => None,
+    }
+}
@@ -7,7 +7,7 @@ use super::{
     network_bridge::{
         event_loop_notification_channel, p2p_protoc::P2pConnManager, EventLoopNotificationsReceiver,
     },
-    NetEventRegister, PeerId,
+    NetEventRegister, NodeHandle, PeerId,
 };
 use crate::transport::TransportPublicKey;
 use crate::{
@@ -65,7 +65,7 @@ impl NodeP2P {
 
     pub(crate) async fn build<CH, const CLIENTS: usize, ER>(
         config: NodeConfig,
-        clients: [BoxedClient; CLIENTS],
+        mut clients: [BoxedClient; CLIENTS],
         event_register: ER,
         ch_builder: CH::Builder,
     ) -> anyhow::Result<NodeP2P>
@@ -98,6 +98,12 @@ impl NodeP2P {
             contract::contract_handling(contract_handler)
                 .instrument(tracing::info_span!(parent: parent_span.clone(), "contract_handling")),
         );
+        let node = NodeHandle {
+            op_manager: op_manager.clone(),
+        };
+        for client in &mut clients {
+            client.attach_node(&node);
+        }
         let clients = ClientEventsCombinator::new(clients);
         let (node_controller_tx, node_controller_rx) = tokio::sync::mpsc::channel(1);
         GlobalExecutor::spawn(
@@ -105,6 +111,7 @@ impl NodeP2P {
                 op_manager.clone(),
                 clients,
                 client_responses,
+                cli_response_sender.clone(),
                 node_controller_tx,
             )
             .instrument(tracing::info_span!(parent: parent_span, "client_event_handling")),
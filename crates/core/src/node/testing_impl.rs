@@ -1,10 +1,10 @@
 use std::{
     collections::{HashMap, HashSet},
     fmt::Write,
-    net::Ipv6Addr,
+    net::{Ipv4Addr, Ipv6Addr},
     num::NonZeroUsize,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, OnceLock},
     time::{Duration, Instant},
 };
 
@@ -28,7 +28,7 @@ use crate::{
     message::{MessageStats, NetMessage, NetMessageV1, NodeEvent, Transaction},
     node::{InitPeerNode, NetEventRegister, NodeConfig},
     operations::connect,
-    ring::{Distance, Location, PeerKeyLocation},
+    ring::{Distance, Location, PeerKeyLocation, Ring},
     tracing::TestEventListener,
 };
 
@@ -265,6 +265,8 @@ pub(super) struct Builder<ER> {
     event_register: ER,
     contracts: Vec<(ContractContainer, WrappedState, bool)>,
     contract_subscribers: HashMap<ContractKey, Vec<PeerKeyLocation>>,
+    /// Ring of the node, set once the node is running.
+    ring: Arc<OnceLock<Arc<Ring>>>,
 }
 
 impl<ER: NetEventRegister> Builder<ER> {
@@ -284,6 +286,7 @@ impl<ER: NetEventRegister> Builder<ER> {
             event_register,
             contracts: Vec::new(),
             contract_subscribers: HashMap::new(),
+            ring: Arc::new(OnceLock::new()),
         }
     }
 }
@@ -306,6 +309,7 @@ pub struct SimNetwork {
     min_connections: usize,
     start_backoff: Duration,
     add_noise: bool,
+    rings: Vec<(NodeLabel, Arc<OnceLock<Arc<Ring>>>)>,
 }
 
 impl SimNetwork {
@@ -338,6 +342,7 @@ impl SimNetwork {
             min_connections,
             start_backoff: Duration::from_millis(1),
             add_noise: false,
+            rings: Vec::with_capacity(nodes + gateways),
         };
         net.config_gateways(
             gateways
@@ -366,13 +371,50 @@ impl SimNetwork {
         self.clean_up_tmp_dirs = false;
     }
 
+    /// Interval between location swap attempts of the nodes, must be set before starting them.
+    pub fn with_location_swap_interval(&mut self, interval: Duration) {
+        for (node, _) in &mut self.nodes {
+            node.config.location_swap_interval(interval);
+        }
+    }
+
+    /// Max connections of the nodes to peers in the same IPv4 /24 and IPv6 /48 subnets, must
+    /// be set before starting them.
+    #[allow(unused)]
+    pub fn with_subnet_limits(&mut self, ipv4: usize, ipv6: usize) {
+        for (node, _) in &mut self.gateways {
+            node.config.max_connections_per_subnet(ipv4, ipv6);
+        }
+        for (node, _) in &mut self.nodes {
+            node.config.max_connections_per_subnet(ipv4, ipv6);
+        }
+    }
+
+    /// Moves the given regular nodes into the same IPv4 /24 subnet, as if they were run by
+    /// a single operator. Must be called before starting the nodes.
+    #[cfg(test)]
+    pub(crate) fn with_nodes_in_subnet(&mut self, labels: &[NodeLabel], subnet: Ipv4Addr) {
+        let [a, b, c, _] = subnet.octets();
+        let in_subnet = self
+            .nodes
+            .iter_mut()
+            .filter(|(_, label)| labels.contains(label));
+        for (host, (node, label)) in (1..=u8::MAX).zip(in_subnet) {
+            let addr = (Ipv4Addr::new(a, b, c, host), node.peer_key.addr.port()).into();
+            let peer = PeerId::new(addr, node.peer_key.pub_key.clone());
+            node.config.with_peer_id(peer.clone());
+            node.peer_key = peer.clone();
+            self.event_listener.add_node(label.clone(), peer);
+        }
+    }
+
     async fn config_gateways(&mut self, num: NonZeroUsize) {
         info!("Building {} gateways", num);
         let mut configs = Vec::with_capacity(num.into());
         for node_no in 0..num.into() {
             let label = NodeLabel::gateway(node_no);
             let port = crate::util::get_free_port().unwrap();
-            let keypair = crate::transport::TransportKeypair::new();
+            let keypair = crate::transport::TransportKeypair::new_ed25519();
             let id = PeerId::new((Ipv6Addr::LOCALHOST, port).into(), keypair.public().clone());
             let location = Location::random();
 
@@ -382,6 +424,8 @@ impl SimNetwork {
             config.key_pair = keypair;
             config.network_listener_ip = Ipv6Addr::LOCALHOST.into();
             config.network_listener_port = port;
+            config.router_history = None;
+            config.ban_list = None;
             config
                 .with_location(location)
                 .max_hops_to_live(self.ring_max_htl)
@@ -455,6 +499,10 @@ impl SimNetwork {
             let port = crate::util::get_free_port().unwrap();
             config.network_listener_port = port;
             config.network_listener_ip = Ipv6Addr::LOCALHOST.into();
+            config.router_history = None;
+            config.ban_list = None;
+            // identities which can sign location swaps, like the gateways'
+            config.key_pair = crate::transport::TransportKeypair::new_ed25519();
             config
                 .max_hops_to_live(self.ring_max_htl)
                 .rnd_if_htl_above(self.rnd_if_htl_above)
@@ -513,6 +561,7 @@ impl SimNetwork {
             if let Some(specs) = node_spec {
                 node.append_contracts(specs.owned_contracts, specs.contract_subscribers);
             }
+            self.rings.push((label.clone(), node.ring.clone()));
             self.labels.push((label, node.peer_key.clone()));
 
             let node_task = async move { node.run_node(user_events, span).await };
@@ -548,6 +597,7 @@ impl SimNetwork {
             } else {
                 tracing::info_span!("in_mem_node", %node.peer_key)
             };
+            self.rings.push((label.clone(), node.ring.clone()));
             self.labels.push((label, node.peer_key.clone()));
 
             let node_task = async move { node.run_node(user_events, span).await };
@@ -573,6 +623,127 @@ impl SimNetwork {
         peers
     }
 
+    /// Rings of the nodes which are already running.
+    #[cfg(test)]
+    fn running_rings(&self) -> Vec<(NodeLabel, Arc<Ring>)> {
+        self.rings
+            .iter()
+            .filter_map(|(label, ring)| Some((label.clone(), ring.get()?.clone())))
+            .collect()
+    }
+
+    /// Ring of the given node, if it's already running.
+    #[cfg(test)]
+    pub(crate) fn ring(&self, label: &NodeLabel) -> Option<Arc<Ring>> {
+        self.running_rings()
+            .into_iter()
+            .find_map(|(other, ring)| (&other == label).then_some(ring))
+    }
+
+    /// Reassigns the locations of the regular nodes between them at random while keeping
+    /// their connections, so the ring no longer matches the network topology.
+    #[cfg(test)]
+    pub(crate) fn shuffle_locations(&self) {
+        let rings = self.running_rings();
+        let (nodes, mut locations): (Vec<_>, Vec<_>) = rings
+            .iter()
+            .filter(|(label, _)| label.is_node())
+            .filter_map(|(_, ring)| Some((ring.clone(), ring.own_location().location?)))
+            .unzip();
+        locations.shuffle(&mut rand::thread_rng());
+        for (ring, location) in nodes.iter().zip(locations) {
+            ring.update_location(Some(location));
+        }
+        let by_peer: HashMap<_, _> = rings
+            .iter()
+            .filter_map(|(_, ring)| Some((ring.get_peer_key()?, ring.clone())))
+            .collect();
+        for (_, ring) in &rings {
+            for neighbour in ring.connected_peers() {
+                if let Some(location) = by_peer
+                    .get(&neighbour.peer)
+                    .and_then(|n| n.own_location().location)
+                {
+                    ring.update_connection_location(&neighbour.peer, location);
+                }
+            }
+        }
+    }
+
+    /// Makes every node try swapping its location and waits, up to `timeout`, for the swaps
+    /// to finish. Then moves the clock of the swap limits past them, so every round starts
+    /// without the limits of the previous ones.
+    #[cfg(test)]
+    pub(crate) async fn location_swap_round(&self, timeout: Duration) {
+        let rings = self.running_rings();
+        for (_, ring) in &rings {
+            ring.request_location_swap();
+        }
+        let deadline = Instant::now() + timeout;
+        // let the swaps start before checking whether they are done
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        while Instant::now() < deadline && rings.iter().any(|(_, ring)| ring.swaps.lock().busy()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for (_, ring) in &rings {
+            ring.swaps
+                .lock()
+                .advance_time(Duration::from_secs(60 * 60 * 24));
+        }
+    }
+
+    /// Fraction of the routes, from a random node towards a random location, in which greedily
+    /// forwarding to the neighbour closest to the location ends at the node closest to it.
+    ///
+    /// Routes are sampled with a fixed seed, so measures of the same network are comparable.
+    #[cfg(test)]
+    pub(crate) fn greedy_routing_success(&self, samples: usize) -> f64 {
+        use rand::{Rng, SeedableRng};
+        let topology: HashMap<PeerId, (Location, Vec<PeerId>)> = self
+            .running_rings()
+            .into_iter()
+            .filter_map(|(_, ring)| {
+                let location = ring.own_location().location?;
+                let neighbours = ring.connected_peers().into_iter().map(|p| p.peer);
+                Some((ring.get_peer_key()?, (location, neighbours.collect())))
+            })
+            .collect();
+        let mut peers: Vec<_> = topology.keys().collect();
+        peers.sort();
+        if peers.is_empty() || samples == 0 {
+            return 0.0;
+        }
+        let distance = |peer: &PeerId, target: Location| topology[peer].0.distance(target);
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+        let mut succeeded = 0;
+        for _ in 0..samples {
+            let target = Location::new(rng.gen_range(0.0..=1.0));
+            let closest = peers
+                .iter()
+                .min_by_key(|peer| distance(peer, target))
+                .expect("not empty");
+            let mut current = *peers.choose(&mut rng).expect("not empty");
+            for _ in 0..peers.len() {
+                let Some(next) = topology[current]
+                    .1
+                    .iter()
+                    .filter(|n| topology.contains_key(*n))
+                    .min_by_key(|n| distance(n, target))
+                else {
+                    break;
+                };
+                if distance(next, target) >= distance(current, target) {
+                    break;
+                }
+                current = next;
+            }
+            if &current == closest {
+                succeeded += 1;
+            }
+        }
+        succeeded as f64 / samples as f64
+    }
+
     pub fn get_locations_by_node(&self) -> HashMap<NodeLabel, PeerKeyLocation> {
         let mut locations_by_node: HashMap<NodeLabel, PeerKeyLocation> = HashMap::new();
 
@@ -583,6 +754,7 @@ impl SimNetwork {
                 PeerKeyLocation {
                     peer: node.peer_key.clone(),
                     location: None,
+                    alt_addr: None,
                 },
             );
         }
@@ -592,6 +764,7 @@ impl SimNetwork {
                 PeerKeyLocation {
                     peer: node.peer_key.clone(),
                     location: config.location.into(),
+                    alt_addr: None,
                 },
             );
         }
@@ -995,6 +1168,7 @@ where
             config.op_manager.clone(),
             config.user_events.take().expect("should be set"),
             client_responses,
+            cli_response_sender.clone(),
             node_controller_tx,
         )
         .instrument(span),
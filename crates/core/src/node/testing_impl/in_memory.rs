@@ -40,6 +40,7 @@ impl<ER> Builder<ER> {
             self.event_register.clone(),
         )?);
         std::mem::drop(_guard);
+        let _ = self.ring.set(op_manager.ring.clone());
         let (executor_listener, executor_sender) = executor_channel(op_manager.clone());
         let contract_handler =
             MemoryContractHandler::build(ch_channel, executor_sender, self.contract_handler_name)
@@ -100,6 +101,7 @@ where
         use crate::contract::ContractHandlerEvent;
         for (contract, state, subscription) in contracts {
             let key: ContractKey = contract.key();
+            let state_size = state.size();
             self.op_manager
                 .notify_contract_handler(ContractHandlerEvent::PutQuery {
                     key,
@@ -114,7 +116,7 @@ where
                 self.op_manager.ring.get_peer_key().unwrap()
             );
             if subscription {
-                self.op_manager.ring.seed_contract(key);
+                self.op_manager.ring.seed_contract(key, Some(state_size));
             }
             if let Some(subscribers) = contract_subscribers.get(&key) {
                 // add contract subscribers
@@ -18,6 +18,7 @@ pub(crate) mod connect;
 pub(crate) mod get;
 pub(crate) mod put;
 pub(crate) mod subscribe;
+pub(crate) mod swap;
 pub(crate) mod update;
 
 pub(crate) trait Operation
@@ -160,6 +161,7 @@ pub(crate) enum OpEnum {
     Get(get::GetOp),
     Subscribe(subscribe::SubscribeOp),
     Update(update::UpdateOp),
+    Swap(swap::SwapOp),
 }
 
 impl OpEnum {
@@ -170,6 +172,7 @@ impl OpEnum {
             OpEnum::Get(op) => op,
             OpEnum::Subscribe(op) => op,
             OpEnum::Update(op) => op,
+            OpEnum::Swap(op) => op,
         } {
             pub fn id(&self) -> &Transaction;
             pub fn outcome(&self) -> OpOutcome;
@@ -205,6 +208,7 @@ try_from_op_enum!(
     TransactionType::Subscribe
 );
 try_from_op_enum!(OpEnum::Update, update::UpdateOp, TransactionType::Update);
+try_from_op_enum!(OpEnum::Swap, swap::SwapOp, TransactionType::Swap);
 
 pub(crate) enum OpOutcome<'a> {
     /// An op which involves a contract completed successfully.
@@ -218,12 +222,6 @@ pub(crate) enum OpOutcome<'a> {
         /// Transfer time of the payload.
         payload_transfer_time: Duration,
     },
-    // todo: handle failures stats when it does not complete successfully
-    // /// An op which involves a contract completed unsuccessfully.
-    // ContractOpFailure {
-    //     target_peer: Option<&'a PeerKeyLocation>,
-    //     contract_location: Location,
-    // },
     /// In transit contract operation.
     Incomplete,
     /// This operation stats are not relevant for this peer.
@@ -1,6 +1,7 @@
 //! Operation which seeks new connections in the ring.
 use std::borrow::Borrow;
 use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
@@ -15,7 +16,7 @@ use crate::message::{NetMessageV1, NodeEvent};
 use crate::ring::Ring;
 use crate::transport::TransportPublicKey;
 use crate::{
-    message::{InnerMessage, NetMessage, Transaction},
+    message::{InnerMessage, NetMessage, Transaction, TransactionType},
     node::{NetworkBridge, OpManager, PeerId},
     operations::OpEnum,
     ring::PeerKeyLocation,
@@ -142,6 +143,7 @@ impl Operation for ConnectOp {
                             query_target,
                             ideal_location,
                             joiner,
+                            joiner_alt_addr,
                             max_hops_to_live,
                             skip_list,
                         },
@@ -151,6 +153,7 @@ impl Operation for ConnectOp {
                     let PeerKeyLocation {
                         peer: this_peer,
                         location: Some(_),
+                        ..
                     } = &own_loc
                     else {
                         return Err(OpError::RingError(crate::ring::RingError::NoLocation));
@@ -181,6 +184,7 @@ impl Operation for ConnectOp {
                                 msg: ConnectRequest::CheckConnectivity {
                                     sender: own_loc.clone(),
                                     joiner: joiner.clone(),
+                                    joiner_alt_addr: *joiner_alt_addr,
                                     hops_to_live: *max_hops_to_live,
                                     max_hops_to_live: *max_hops_to_live,
                                     skip_list,
@@ -219,6 +223,7 @@ impl Operation for ConnectOp {
                                 query_target: query_target.clone(),
                                 ideal_location: *ideal_location,
                                 joiner: joiner.clone(),
+                                joiner_alt_addr: *joiner_alt_addr,
                                 max_hops_to_live: *max_hops_to_live,
                                 skip_list,
                             },
@@ -232,6 +237,7 @@ impl Operation for ConnectOp {
                     msg:
                         ConnectRequest::StartJoinReq {
                             joiner,
+                            joiner_alt_addr,
                             hops_to_live,
                             skip_list, //
                             ..
@@ -246,34 +252,45 @@ impl Operation for ConnectOp {
                     let new_peer_loc = PeerKeyLocation {
                         location: Some(assigned_location),
                         peer: joiner.clone(),
+                        alt_addr: *joiner_alt_addr,
                     };
 
+                    // the subnet limit only applies to the gateway own connections, the join is
+                    // still forwarded so peers sharing a subnet can connect to the rest of the network
                     let accepted = op_manager
                         .ring
                         .should_accept(assigned_location, Some(&joiner));
 
+                    // the joiner knows nothing about the network yet, so the gateway bounds
+                    // how far the request travels with its own estimate of the network size
+                    let hops_to_live =
+                        (*hops_to_live).min(op_manager.ring.hops_to_live(TransactionType::Connect));
+
                     let mut skip_list = skip_list.clone();
                     skip_list.push(joiner.clone());
+                    let expected_responses;
                     if let Some(updated_state) = forward_conn(
                         *id,
                         &op_manager.ring,
                         network_bridge,
                         (new_peer_loc.clone(), new_peer_loc.clone()),
-                        *hops_to_live,
+                        hops_to_live,
                         accepted, // gateway always accept the first connection
                         skip_list,
                     )
                     .await?
                     {
                         new_state = Some(updated_state);
+                        expected_responses = hops_to_live;
                     } else {
                         new_state = None;
+                        expected_responses = 1;
                     }
 
                     if accepted {
                         op_manager
                             .ring
-                            .add_connection(assigned_location, joiner.clone())
+                            .add_connection(assigned_location, joiner.clone(), *joiner_alt_addr)
                             .await;
                         tracing::debug!(tx = %id, at = %this_peer.peer, %joiner, "Accepting connection");
                     } else {
@@ -287,7 +304,9 @@ impl Operation for ConnectOp {
                         msg: ConnectResponse::AcceptedBy {
                             accepted,
                             acceptor: this_peer.clone(),
+                            acceptor_alt_addr: this_peer.alt_addr,
                             joiner: joiner.clone(),
+                            expected_responses: Some(expected_responses),
                         },
                     });
                 }
@@ -297,11 +316,16 @@ impl Operation for ConnectOp {
                         ConnectRequest::CheckConnectivity {
                             sender,
                             joiner,
+                            joiner_alt_addr,
                             hops_to_live,
                             skip_list,
                             ..
                         },
                 } => {
+                    let joiner = &PeerKeyLocation {
+                        alt_addr: *joiner_alt_addr,
+                        ..joiner.clone()
+                    };
                     //debug_assert_ne!(sender.peer, joiner.peer);
                     if sender.peer == joiner.peer {
                         tracing::warn!(
@@ -336,6 +360,7 @@ impl Operation for ConnectOp {
                         op_manager
                             .notify_node_event(NodeEvent::ConnectPeer {
                                 peer: joiner.peer.clone(),
+                                alt_addr: joiner.alt_addr,
                                 callback,
                             })
                             .await?;
@@ -348,7 +373,7 @@ impl Operation for ConnectOp {
                             // Add the connection to the ring
                             op_manager
                                 .ring
-                                .add_connection(joiner_loc, joiner.peer.clone())
+                                .add_connection(joiner_loc, joiner.peer.clone(), joiner.alt_addr)
                                 .await;
                             true
                         } else {
@@ -379,7 +404,9 @@ impl Operation for ConnectOp {
                     let response = ConnectResponse::AcceptedBy {
                         accepted: should_accept,
                         acceptor: this_peer.clone(),
+                        acceptor_alt_addr: this_peer.alt_addr,
                         joiner: joiner.peer.clone(),
+                        expected_responses: None,
                     };
 
                     return_msg = Some(ConnectMsg::Response {
@@ -397,9 +424,15 @@ impl Operation for ConnectOp {
                         ConnectResponse::AcceptedBy {
                             accepted,
                             acceptor,
+                            acceptor_alt_addr,
                             joiner,
+                            expected_responses,
                         },
                 } => {
+                    let acceptor = &PeerKeyLocation {
+                        alt_addr: *acceptor_alt_addr,
+                        ..acceptor.clone()
+                    };
                     tracing::debug!(
                         tx = %id,
                         at = %target.peer,
@@ -411,8 +444,16 @@ impl Operation for ConnectOp {
 
                     match self.state.as_mut() {
                         Some(ConnectState::ConnectingToNode(info)) => {
+                            if let Some(expected) = expected_responses {
+                                // the gateway may have lowered the hops the request travels,
+                                // in which case fewer responses than requested arrive
+                                let received = info.max_hops_to_live - info.remaining_connetions;
+                                info.remaining_connetions =
+                                    expected.saturating_sub(received).max(1);
+                            }
                             assert!(info.remaining_connetions > 0);
                             let remaining_connetions = info.remaining_connetions.saturating_sub(1);
+                            info.remaining_connetions = remaining_connetions;
 
                             if *accepted {
                                 tracing::debug!(
@@ -428,6 +469,7 @@ impl Operation for ConnectOp {
                                     .add_connection(
                                         acceptor.location.expect("location not found"),
                                         acceptor.peer.clone(),
+                                        acceptor.alt_addr,
                                     )
                                     .await;
                             } else {
@@ -440,15 +482,19 @@ impl Operation for ConnectOp {
                                 );
                             }
 
-                            let your_location: Location =
-                                target.location.expect("location not found");
-                            tracing::debug!(
-                                tx = %id,
-                                at = %this_peer_id,
-                                location = %your_location,
-                                "Updating assigned location"
-                            );
-                            op_manager.ring.update_location(target.location);
+                            // the location is only assigned when joining, afterwards it may
+                            // have been swapped while this connect op was in flight
+                            if op_manager.ring.own_location().location.is_none() {
+                                let your_location: Location =
+                                    target.location.expect("location not found");
+                                tracing::debug!(
+                                    tx = %id,
+                                    at = %this_peer_id,
+                                    location = %your_location,
+                                    "Updating assigned location"
+                                );
+                                op_manager.ring.update_location(target.location);
+                            }
 
                             if remaining_connetions == 0 {
                                 tracing::debug!(
@@ -457,6 +503,7 @@ impl Operation for ConnectOp {
                                     from = %sender.peer,
                                     "All available connections established",
                                 );
+                                op_manager.ring.record_connect_result(&info.accepted_by);
 
                                 try_clean_gw_connection(*id, network_bridge, info, target.clone())
                                     .await?;
@@ -486,7 +533,11 @@ impl Operation for ConnectOp {
                                     acceptor.location.expect("location not found for acceptor");
                                 op_manager
                                     .ring
-                                    .add_connection(acceptor_loc, acceptor.peer.clone())
+                                    .add_connection(
+                                        acceptor_loc,
+                                        acceptor.peer.clone(),
+                                        acceptor.alt_addr,
+                                    )
                                     .await;
                             } else {
                                 tracing::debug!(
@@ -515,7 +566,9 @@ impl Operation for ConnectOp {
                             let response = ConnectResponse::AcceptedBy {
                                 accepted: *accepted,
                                 acceptor: acceptor.clone(),
+                                acceptor_alt_addr: acceptor.alt_addr,
                                 joiner: joiner.clone(),
+                                expected_responses: None,
                             };
                             return_msg = Some(ConnectMsg::Response {
                                 id: *id,
@@ -578,6 +631,17 @@ impl Operation for ConnectOp {
                         }
                     }
                 }
+                ConnectMsg::Response {
+                    id,
+                    msg: ConnectResponse::RelayAvailable { relay, .. },
+                    ..
+                } => {
+                    // the relay is registered with the transport by the network bridge,
+                    // the operation itself carries on unchanged
+                    tracing::debug!(tx = %id, relay = %relay.peer, "Relay available for connection");
+                    new_state = self.state;
+                    return_msg = None;
+                }
                 _ => return Err(OpError::UnexpectedOpState),
             }
 
@@ -699,7 +763,7 @@ where
 {
     use crate::util::IterExt;
     let number_of_parallel_connections = {
-        let max_potential_conns_per_gw = op_manager.ring.max_hops_to_live;
+        let max_potential_conns_per_gw = op_manager.ring.hops_to_live(TransactionType::Connect);
         // e.g. 10 gateways and htl 5 -> only need 2 connections in parallel
         let needed_to_cover_max = op_manager.ring.max_connections / max_potential_conns_per_gw;
         gateways.iter().take(needed_to_cover_max).count().max(1)
@@ -772,7 +836,7 @@ where
     let mut op = initial_request(
         peer_pub_key,
         gateway.clone(),
-        op_manager.ring.max_hops_to_live,
+        op_manager.ring.hops_to_live(TransactionType::Connect),
         tx_id,
     );
     if let Some(mut backoff) = backoff {
@@ -859,6 +923,7 @@ where
         msg: ConnectRequest::StartJoinReq {
             joiner: None,
             joiner_key: peer_pub_key.clone(),
+            joiner_alt_addr: op_manager.ring.alt_addr(),
             hops_to_live: max_hops_to_live,
             max_hops_to_live,
             skip_list: vec![],
@@ -922,6 +987,11 @@ where
                 create_forward_message(id, &req_peer, &joiner, left_htl, &[req_peer.peer.clone()]);
             tracing::debug!(target: "network", "Forwarding connection request to {:?}", target_peer);
             network_bridge.send(&target_peer.peer, forward_msg).await?;
+            if accepted && req_peer.peer == joiner.peer {
+                // this peer keeps its connection with the joiner, so it can relay between
+                // the joiner and the target in case hole punching fails
+                offer_relay(id, ring, network_bridge, &joiner, &target_peer).await?;
+            }
             update_state_with_forward_info(&req_peer, left_htl)
         }
         None => handle_unforwardable_connection(id, accepted),
@@ -936,7 +1006,7 @@ fn select_forward_target(
     left_htl: usize,
     skip_list: &[PeerId],
 ) -> Option<PeerKeyLocation> {
-    if left_htl >= ring.rnd_if_htl_above {
+    if left_htl >= ring.random_walk_above_htl() {
         tracing::debug!(
             tx = %id,
             joiner = %joiner.peer,
@@ -970,6 +1040,7 @@ fn create_forward_message(
         msg: ConnectRequest::CheckConnectivity {
             sender: request_peer.clone(),
             joiner: joiner.clone(),
+            joiner_alt_addr: joiner.alt_addr,
             hops_to_live,
             max_hops_to_live: hops_to_live,
             skip_list: skip_list.to_vec(),
@@ -977,6 +1048,33 @@ fn create_forward_message(
     })
 }
 
+async fn offer_relay<NB>(
+    id: Transaction,
+    ring: &Ring,
+    network_bridge: &mut NB,
+    joiner: &PeerKeyLocation,
+    acceptor: &PeerKeyLocation,
+) -> Result<(), OpError>
+where
+    NB: NetworkBridge,
+{
+    let relay = ring.own_location();
+    for target in [joiner, acceptor] {
+        let msg = ConnectMsg::Response {
+            id,
+            sender: relay.clone(),
+            target: target.clone(),
+            msg: ConnectResponse::RelayAvailable {
+                relay: relay.clone(),
+                joiner: joiner.peer.clone(),
+                acceptor: acceptor.clone(),
+            },
+        };
+        network_bridge.send(&target.peer, msg.into()).await?;
+    }
+    Ok(())
+}
+
 fn update_state_with_forward_info(
     requester: &PeerKeyLocation,
     left_htl: usize,
@@ -1073,6 +1171,10 @@ mod messages {
                     msg: ConnectResponse::AcceptedBy { .. },
                     ..
                 } => write!(f, "AcceptedBy(id: {id})"),
+                Self::Response {
+                    msg: ConnectResponse::RelayAvailable { .. },
+                    ..
+                } => write!(f, "RelayAvailable(id: {id})"),
                 Self::Connected { .. } => write!(f, "Connected(id: {id})"),
                 ConnectMsg::Request { id, .. } => write!(f, "Request(id: {id})"),
             }
@@ -1085,6 +1187,8 @@ mod messages {
             // The peer who is trying to join, should be set when PeerConnection is established
             joiner: Option<PeerId>,
             joiner_key: TransportPublicKey,
+            // The address of the joiner in the other address family, if dual-stack
+            joiner_alt_addr: Option<SocketAddr>,
             hops_to_live: usize,
             max_hops_to_live: usize,
             // The list of peers to skip when forwarding the connection request, avoiding loops
@@ -1097,12 +1201,16 @@ mod messages {
             /// The ideal location of the peer to which you would connect.
             ideal_location: Location,
             joiner: PeerKeyLocation,
+            /// The address of the joiner in the other address family, if dual-stack
+            joiner_alt_addr: Option<SocketAddr>,
             max_hops_to_live: usize,
             skip_list: Vec<PeerId>,
         },
         CheckConnectivity {
             sender: PeerKeyLocation,
             joiner: PeerKeyLocation,
+            // The address of the joiner in the other address family, if dual-stack
+            joiner_alt_addr: Option<SocketAddr>,
             hops_to_live: usize,
             max_hops_to_live: usize,
             // The list of peers to skip when forwarding the connection request, avoiding loops
@@ -1118,16 +1226,31 @@ mod messages {
         AcceptedBy {
             accepted: bool,
             acceptor: PeerKeyLocation,
+            /// The address of the acceptor in the other address family, if dual-stack
+            acceptor_alt_addr: Option<SocketAddr>,
+            joiner: PeerId,
+            /// Responses the joiner should expect, set by the gateway which may forward the
+            /// request fewer hops than the joiner asked for.
+            expected_responses: Option<usize>,
+        },
+        /// The relay is connected to both the joiner and the peer the request was forwarded
+        /// to, and will relay packets between them if they can't connect directly.
+        RelayAvailable {
+            relay: PeerKeyLocation,
             joiner: PeerId,
+            acceptor: PeerKeyLocation,
         },
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
 
-    use crate::node::testing_impl::SimNetwork;
+    use crate::node::testing_impl::{NodeLabel, SimNetwork};
 
     /// Given a network of one node and one gateway test that both are connected.
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -1217,4 +1340,58 @@ mod test {
         sim_nw.print_ring_distribution();
         Ok(())
     }
+
+    /// Nodes run by a single operator from the same subnet trying to take over all the
+    /// connections of a gateway (an eclipse attack) only get as many as the subnet limit allows.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn eclipse_attempt_limited_by_subnet() -> anyhow::Result<()> {
+        const NUM_NODES: usize = 12usize;
+        const NUM_ATTACKERS: usize = 8usize;
+        const NUM_GW: usize = 1usize;
+        const MAX_HTL: usize = 3usize;
+        const RAND_IF_HTL_ABOVE: usize = 2usize;
+        const MAX_CONNS: usize = 10usize;
+        const MIN_CONNS: usize = 2usize;
+        const SUBNET_LIMIT: usize = 2usize;
+        const ATTACKER_SUBNET: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 0);
+        let mut sim_nw = SimNetwork::new(
+            "eclipse_attempt_limited_by_subnet",
+            NUM_GW,
+            NUM_NODES,
+            MAX_HTL,
+            RAND_IF_HTL_ABOVE,
+            MAX_CONNS,
+            MIN_CONNS,
+        )
+        .await;
+        let attackers: Vec<NodeLabel> = (1..=NUM_ATTACKERS)
+            .map(|node| format!("node-{node}").as_str().into())
+            .collect();
+        sim_nw.with_nodes_in_subnet(&attackers, ATTACKER_SUBNET);
+        sim_nw.with_subnet_limits(SUBNET_LIMIT, SUBNET_LIMIT);
+        sim_nw.start().await;
+        // attackers over the limit still join through other peers
+        sim_nw.check_connectivity(Duration::from_secs(10))?;
+
+        let gateway = sim_nw
+            .ring(&"gateway-0".into())
+            .expect("gateway should be running");
+        let (from_attackers, from_others): (Vec<_>, Vec<_>) = gateway
+            .connected_peers()
+            .into_iter()
+            .partition(|conn| match conn.peer.addr.ip() {
+                IpAddr::V4(ip) => ip.octets()[..3] == ATTACKER_SUBNET.octets()[..3],
+                IpAddr::V6(_) => false,
+            });
+        assert!(
+            from_attackers.len() <= SUBNET_LIMIT,
+            "gateway connected to {} peers in the attacker subnet",
+            from_attackers.len()
+        );
+        assert!(
+            !from_others.is_empty(),
+            "gateway didn't connect to any peer outside the attacker subnet"
+        );
+        Ok(())
+    }
 }
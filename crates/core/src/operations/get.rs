@@ -7,10 +7,11 @@ use freenet_stdlib::prelude::*;
 use crate::client_events::HostResult;
 use crate::{
     contract::{ContractHandlerEvent, StoreResponse},
-    message::{InnerMessage, NetMessage, Transaction},
+    message::{InnerMessage, NetMessage, Transaction, TransactionType},
     node::{NetworkBridge, OpManager, PeerId},
     operations::{OpInitialization, Operation},
     ring::{Location, PeerKeyLocation, RingError},
+    router::{RouteEvent, RouteOutcome},
 };
 
 use super::{OpEnum, OpError, OpOutcome, OperationResult};
@@ -78,7 +79,7 @@ pub(crate) async fn request_get(op_manager: &OpManager, get_op: GetOp) -> Result
                 retries: 0,
                 fetch_contract,
                 requester: None,
-                current_hop: op_manager.ring.max_hops_to_live,
+                current_hop: op_manager.ring.hops_to_live(TransactionType::Get),
             });
 
             let msg = GetMsg::RequestGet {
@@ -300,7 +301,7 @@ impl Operation for GetOp {
                         target: target.clone(),
                         sender: own_loc.clone(),
                         fetch_contract: *fetch_contract,
-                        htl: op_manager.ring.max_hops_to_live,
+                        htl: op_manager.ring.hops_to_live(TransactionType::Get),
                         skip_list: vec![own_loc.peer],
                     });
                 }
@@ -324,10 +325,13 @@ impl Operation for GetOp {
                     }
 
                     let get_result = op_manager
-                        .notify_contract_handler(ContractHandlerEvent::GetQuery {
-                            key,
-                            fetch_contract,
-                        })
+                        .notify_contract_handler_from(
+                            sender,
+                            ContractHandlerEvent::GetQuery {
+                                key,
+                                fetch_contract,
+                            },
+                        )
                         .await;
 
                     let (returned_key, contract, state) = match get_result {
@@ -354,6 +358,7 @@ impl Operation for GetOp {
                     };
 
                     tracing::debug!(tx = %id, "Contract {returned_key} found @ peer {}", target.peer);
+                    op_manager.ring.record_served_request(&key);
 
                     match self.state {
                         Some(GetState::AwaitingResponse { requester, .. }) => {
@@ -424,7 +429,16 @@ impl Operation for GetOp {
                             requester,
                             current_hop,
                         }) => {
-                            // todo: register in the stats for the outcome of the op that failed to get a response from this peer
+                            if let Some(s) = stats.as_mut() {
+                                if let Some(peer) = s.next_peer.take() {
+                                    // the peer this request was routed to failed to find the value
+                                    op_manager.ring.routing_finished(RouteEvent {
+                                        peer,
+                                        contract_location: s.contract_location,
+                                        outcome: RouteOutcome::Failure,
+                                    });
+                                }
+                            }
                             if retries < MAX_RETRIES {
                                 // no response received from this peer, so skip it in the next iteration
                                 let mut new_skip_list = skip_list.clone();
@@ -435,6 +449,9 @@ impl Operation for GetOp {
                                     .into_iter()
                                     .next()
                                 {
+                                    if let Some(s) = stats.as_mut() {
+                                        s.next_peer = Some(target.clone());
+                                    }
                                     return_msg = Some(GetMsg::SeekNode {
                                         id: *id,
                                         key: *key,
@@ -547,17 +564,20 @@ impl Operation for GetOp {
                             ..
                         })
                     );
-                    let should_subscribe = op_manager.ring.should_seed(&key);
+                    let should_subscribe = op_manager.ring.should_seed(&key, Some(value.size()));
                     let should_put = is_original_requester || should_subscribe;
 
                     if should_put {
                         let res = op_manager
-                            .notify_contract_handler(ContractHandlerEvent::PutQuery {
-                                key,
-                                state: value.clone(),
-                                related_contracts: RelatedContracts::default(), // fixme: i think we need to get the related contracts so the final put is ok
-                                contract: contract.clone(),
-                            })
+                            .notify_contract_handler_from(
+                                sender,
+                                ContractHandlerEvent::PutQuery {
+                                    key,
+                                    state: value.clone(),
+                                    related_contracts: RelatedContracts::default(), // fixme: i think we need to get the related contracts so the final put is ok
+                                    contract: contract.clone(),
+                                },
+                            )
                             .await?;
                         match res {
                             ContractHandlerEvent::PutResponse { new_value: Ok(_) } => {
@@ -185,10 +185,13 @@ impl Operation for PutOp {
                         "Puttting contract at target peer",
                     );
 
-                    if is_subscribed_contract || op_manager.ring.should_seed(&key) {
+                    if is_subscribed_contract
+                        || op_manager.ring.should_seed(&key, Some(value.size()))
+                    {
                         tracing::debug!(tx = %id, "Attempting contract value update");
                         put_contract(
                             op_manager,
+                            sender,
                             key,
                             value.clone(),
                             related_contracts.clone(),
@@ -219,6 +222,7 @@ impl Operation for PutOp {
                             // if already subscribed the value was already put and merging succeeded
                             put_contract(
                                 op_manager,
+                                sender,
                                 key,
                                 value.clone(),
                                 RelatedContracts::default(),
@@ -231,6 +235,7 @@ impl Operation for PutOp {
                         // should put in this location, no hops left
                         put_contract(
                             op_manager,
+                            sender,
                             key,
                             value.clone(),
                             RelatedContracts::default(),
@@ -272,6 +277,7 @@ impl Operation for PutOp {
                     tracing::debug!("Attempting contract value update");
                     let new_value = put_contract(
                         op_manager,
+                        sender,
                         *key,
                         new_value.clone(),
                         RelatedContracts::default(),
@@ -372,7 +378,7 @@ impl Operation for PutOp {
                     match self.state {
                         Some(PutState::AwaitingResponse { key, upstream }) => {
                             let is_subscribed_contract = op_manager.ring.is_seeding_contract(&key);
-                            if !is_subscribed_contract && op_manager.ring.should_seed(&key) {
+                            if !is_subscribed_contract && op_manager.ring.should_seed(&key, None) {
                                 tracing::debug!(tx = %id, %key, peer = %op_manager.ring.get_peer_key().unwrap(), "Contract not cached @ peer, caching");
                                 super::start_subscription_request(op_manager, key, true).await;
                             }
@@ -413,11 +419,12 @@ impl Operation for PutOp {
                         "Forwarding changes, trying put the contract"
                     );
 
-                    let should_seed = op_manager.ring.should_seed(&key);
+                    let should_seed = op_manager.ring.should_seed(&key, Some(new_value.size()));
                     if should_seed {
                         // after the contract has been cached, push the update query
                         put_contract(
                             op_manager,
+                            sender,
                             key,
                             new_value.clone(),
                             RelatedContracts::default(),
@@ -446,6 +453,7 @@ impl Operation for PutOp {
                             // if already subscribed the value was already put and merging succeeded
                             put_contract(
                                 op_manager,
+                                sender,
                                 key,
                                 new_value.clone(),
                                 RelatedContracts::default(),
@@ -453,7 +461,7 @@ impl Operation for PutOp {
                             )
                             .await?;
                             let (dropped_contract, old_subscribers) =
-                                op_manager.ring.seed_contract(key);
+                                op_manager.ring.seed_contract(key, Some(new_value.size()));
                             if let Some(key) = dropped_contract {
                                 for subscriber in old_subscribers {
                                     conn_manager
@@ -474,6 +482,7 @@ impl Operation for PutOp {
                         // should put in this location, no hops left
                         put_contract(
                             op_manager,
+                            sender,
                             key,
                             new_value.clone(),
                             RelatedContracts::default(),
@@ -711,6 +720,7 @@ pub(crate) async fn request_put(op_manager: &OpManager, mut put_op: PutOp) -> Re
 
 async fn put_contract(
     op_manager: &OpManager,
+    sender: &PeerKeyLocation,
     key: ContractKey,
     state: WrappedState,
     related_contracts: RelatedContracts<'static>,
@@ -718,12 +728,15 @@ async fn put_contract(
 ) -> Result<WrappedState, OpError> {
     // after the contract has been cached, push the update query
     match op_manager
-        .notify_contract_handler(ContractHandlerEvent::PutQuery {
-            key,
-            state,
-            related_contracts,
-            contract: Some(contract.clone()),
-        })
+        .notify_contract_handler_from(
+            sender,
+            ContractHandlerEvent::PutQuery {
+                key,
+                state,
+                related_contracts,
+                contract: Some(contract.clone()),
+            },
+        )
         .await
     {
         Ok(ContractHandlerEvent::PutResponse {
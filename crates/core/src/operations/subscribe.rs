@@ -11,7 +11,7 @@ use super::{OpEnum, OpError, OpInitialization, OpOutcome, Operation, OperationRe
 use crate::{
     client_events::HostResult,
     contract::ContractError,
-    message::{InnerMessage, NetMessage, Transaction},
+    message::{InnerMessage, NetMessage, Transaction, TransactionType},
     node::{NetworkBridge, OpManager, PeerId},
     ring::{Location, PeerKeyLocation, RingError},
 };
@@ -89,7 +89,7 @@ pub(crate) async fn request_subscribe(
             let new_state = Some(SubscribeState::AwaitingResponse {
                 skip_list: vec![],
                 retries: 0,
-                current_hop: op_manager.ring.max_hops_to_live,
+                current_hop: op_manager.ring.hops_to_live(TransactionType::Subscribe),
                 upstream_subscriber: None,
             });
             let msg = SubscribeMsg::RequestSub { id, key, target };
@@ -202,7 +202,7 @@ impl Operation for SubscribeOp {
                         target: target.clone(),
                         subscriber: sender.clone(),
                         skip_list: vec![sender.peer],
-                        htl: op_manager.ring.max_hops_to_live,
+                        htl: op_manager.ring.hops_to_live(TransactionType::Subscribe),
                         retries: 0,
                     });
                 }
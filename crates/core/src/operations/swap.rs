@@ -0,0 +1,934 @@
+//! Operation which swaps the locations of two peers in the ring.
+//!
+//! Peers periodically look for a partner through a short random walk and trade their
+//! locations with it when that shortens the distances to their respective neighbours,
+//! which makes the ring converge towards a small-world topology where greedy routing succeeds.
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use freenet_stdlib::client_api::ErrorKind;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{OpEnum, OpError, OpInitialization, OpOutcome, Operation, OperationResult};
+use crate::{
+    client_events::HostResult,
+    message::{InnerMessage, NetMessage, Transaction},
+    node::{NetworkBridge, OpManager, PeerId},
+    ring::{Location, PeerKeyLocation},
+    util::time_source::TimeSource,
+};
+
+pub(crate) use self::messages::SwapMsg;
+
+/// Max number of hops of the random walk looking for a swap partner.
+const SWAP_HTL: usize = 6;
+
+/// Max number of neighbour locations a peer can advertise when requesting a swap.
+const MAX_NEIGHBOURS: usize = 256;
+
+/// Distances are clamped to this value so peers sharing a location don't dominate the product.
+const MIN_DISTANCE: f64 = 1e-6;
+
+/// Time after which a swap initiated by this peer which didn't get an answer is abandoned.
+const SWAP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max number of swap requests a neighbour can route through this peer per `REQUEST_WINDOW`.
+const MAX_REQUESTS_PER_NEIGHBOUR: usize = 10;
+
+const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Max number of swaps this peer accepts as a partner per `ACCEPTED_SWAPS_WINDOW`, so a
+/// group of colluding peers cannot keep dragging it around the ring.
+const MAX_ACCEPTED_SWAPS: usize = 12;
+
+#[cfg(not(test))]
+const ACCEPTED_SWAPS_WINDOW: Duration = Duration::from_secs(60 * 60);
+#[cfg(test)]
+const ACCEPTED_SWAPS_WINDOW: Duration = Duration::from_secs(5);
+
+/// Time before swapping again with the same peer.
+#[cfg(not(test))]
+const PARTNER_COOLDOWN: Duration = Duration::from_secs(60 * 10);
+#[cfg(test)]
+const PARTNER_COOLDOWN: Duration = Duration::from_secs(2);
+
+#[cfg(not(test))]
+type SwapClock = crate::util::time_source::InstantTimeSrc;
+/// Tests drive swaps by hand and advance the clock between rounds.
+#[cfg(test)]
+type SwapClock = crate::util::time_source::MockTimeSource;
+
+/// Bookkeeping of the swaps this peer takes part in, bounds how much other peers
+/// can move this peer around the ring.
+pub(crate) struct SwapLimits<T: TimeSource = SwapClock> {
+    time_source: T,
+    in_progress: Option<(Transaction, Instant)>,
+    requests: HashMap<PeerId, VecDeque<Instant>>,
+    accepted: VecDeque<Instant>,
+    last_swap_with: HashMap<PeerId, Instant>,
+}
+
+impl Default for SwapLimits {
+    fn default() -> Self {
+        #[cfg(not(test))]
+        let time_source = SwapClock::new();
+        #[cfg(test)]
+        let time_source = SwapClock::new(Instant::now());
+        Self::new(time_source)
+    }
+}
+
+#[cfg(test)]
+impl SwapLimits<SwapClock> {
+    pub(crate) fn advance_time(&mut self, duration: Duration) {
+        self.time_source.advance_time(duration);
+    }
+}
+
+impl<T: TimeSource> SwapLimits<T> {
+    fn new(time_source: T) -> Self {
+        Self {
+            time_source,
+            in_progress: None,
+            requests: HashMap::new(),
+            accepted: VecDeque::new(),
+            last_swap_with: HashMap::new(),
+        }
+    }
+
+    /// Whether this peer is taking part in a swap, either as initiator or as partner.
+    pub(crate) fn busy(&self) -> bool {
+        let now = self.time_source.now();
+        self.in_progress
+            .as_ref()
+            .map(|(_, started)| now.duration_since(*started) < SWAP_TIMEOUT)
+            .unwrap_or(false)
+    }
+
+    /// Marks the start of a swap this peer takes part in, returns false if one is still running.
+    fn start(&mut self, id: Transaction) -> bool {
+        if self.busy() {
+            return false;
+        }
+        self.in_progress = Some((id, self.time_source.now()));
+        true
+    }
+
+    fn finish(&mut self, id: &Transaction) {
+        if matches!(&self.in_progress, Some((current, _)) if current == id) {
+            self.in_progress = None;
+        }
+    }
+
+    /// Whether a swap request routed by the given neighbour is within its rate limit.
+    fn allow_request(&mut self, neighbour: &PeerId) -> bool {
+        let now = self.time_source.now();
+        self.requests.retain(|_, reqs| {
+            while reqs
+                .front()
+                .filter(|t| now.duration_since(**t) >= REQUEST_WINDOW)
+                .is_some()
+            {
+                reqs.pop_front();
+            }
+            !reqs.is_empty()
+        });
+        let reqs = self.requests.entry(neighbour.clone()).or_default();
+        if reqs.len() >= MAX_REQUESTS_PER_NEIGHBOUR {
+            return false;
+        }
+        reqs.push_back(now);
+        true
+    }
+
+    /// Whether this peer can act as the partner of a swap with the given initiator.
+    fn can_accept(&mut self, initiator: &PeerId) -> bool {
+        if self.busy() {
+            return false;
+        }
+        let now = self.time_source.now();
+        self.last_swap_with
+            .retain(|_, last| now.duration_since(*last) < PARTNER_COOLDOWN);
+        while self
+            .accepted
+            .front()
+            .filter(|t| now.duration_since(**t) >= ACCEPTED_SWAPS_WINDOW)
+            .is_some()
+        {
+            self.accepted.pop_front();
+        }
+        !self.last_swap_with.contains_key(initiator) && self.accepted.len() < MAX_ACCEPTED_SWAPS
+    }
+
+    /// Records this peer agreed to swap with the initiator and waits for its confirmation.
+    fn accepted(&mut self, id: Transaction, initiator: PeerId) {
+        let now = self.time_source.now();
+        self.last_swap_with.insert(initiator, now);
+        self.accepted.push_back(now);
+        self.in_progress = Some((id, now));
+    }
+}
+
+/// Locations two peers agree to trade.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SwapAgreement {
+    id: Transaction,
+    initiator: PeerId,
+    initiator_location: Location,
+    partner: PeerId,
+    partner_location: Location,
+}
+
+impl SwapAgreement {
+    fn signed_data(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializable")
+    }
+
+    /// Location the given peer moves from and to, if it's a party of the swap.
+    fn movement(&self, peer: &PeerId) -> Option<(Location, Location)> {
+        if peer == &self.initiator {
+            Some((self.initiator_location, self.partner_location))
+        } else if peer == &self.partner {
+            Some((self.partner_location, self.initiator_location))
+        } else {
+            None
+        }
+    }
+}
+
+/// Agreement signed by the partner of a swap, pending the confirmation of the initiator.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SwapProposal {
+    agreement: SwapAgreement,
+    signature: Vec<u8>,
+}
+
+impl SwapProposal {
+    fn verify(&self) -> bool {
+        self.agreement
+            .partner
+            .pub_key()
+            .verify(&self.agreement.signed_data(), &self.signature)
+    }
+}
+
+/// Swap signed by both parties, proves to their neighbours the locations they move to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SignedSwap {
+    agreement: SwapAgreement,
+    partner_signature: Vec<u8>,
+    initiator_signature: Vec<u8>,
+}
+
+impl SignedSwap {
+    fn verify(&self) -> bool {
+        let data = self.agreement.signed_data();
+        self.agreement
+            .partner
+            .pub_key()
+            .verify(&data, &self.partner_signature)
+            && self
+                .agreement
+                .initiator
+                .pub_key()
+                .verify(&data, &self.initiator_signature)
+    }
+}
+
+/// Whether two peers at locations `a` and `b` should trade them. A swap which shortens the
+/// product of the distances from both peers to their neighbours is always taken, otherwise
+/// is taken with a probability equal to the ratio between the product before and after it.
+fn should_swap(
+    a: Location,
+    a_neighbours: &[Location],
+    b: Location,
+    b_neighbours: &[Location],
+    rng: &mut impl Rng,
+) -> bool {
+    let before = log_distances(a, a_neighbours, b) + log_distances(b, b_neighbours, a);
+    let after = log_distances(b, a_neighbours, b) + log_distances(a, b_neighbours, a);
+    after <= before || rng.gen::<f64>() < (before - after).exp()
+}
+
+/// Sum of the log distances from `location` to the neighbours, skipping the other swap party
+/// since the distance between both does not change.
+fn log_distances(location: Location, neighbours: &[Location], partner: Location) -> f64 {
+    neighbours
+        .iter()
+        .filter(|n| **n != partner)
+        .map(|n| location.distance(n).as_f64().max(MIN_DISTANCE).ln())
+        .sum()
+}
+
+fn valid_location(location: &Location) -> bool {
+    Location::try_from(location.as_f64()).is_ok()
+}
+
+/// Builds the message which starts a new swap from this peer, if it can take part in one.
+pub(crate) fn start_swap(ring: &crate::ring::Ring) -> Option<SwapMsg> {
+    let this_peer = ring.own_location();
+    // swaps are signed by both parties, legacy RSA identities can't sign them
+    if ring.is_gateway() || this_peer.location.is_none() || !this_peer.peer.pub_key().is_ed25519() {
+        return None;
+    }
+    let target = ring.random_connection(&[] as &[PeerId])?;
+    Some(SwapMsg::Start {
+        id: Transaction::new::<SwapMsg>(),
+        target,
+    })
+}
+
+#[derive(Debug)]
+enum SwapState {
+    /// Started a swap, waiting for the answer of a partner.
+    AwaitingResponse { location: Location },
+    /// Forwarded the random walk, waiting for the answer to route it back.
+    Forwarded { upstream: PeerKeyLocation },
+    /// Agreed to swap as partner, waiting for the initiator to confirm it before moving.
+    AwaitingConfirmation { agreement: SwapAgreement },
+    /// Routed back the agreement of a partner, waiting for the confirmation to route it to it.
+    Confirming { downstream: PeerKeyLocation },
+}
+
+pub(crate) struct SwapOp {
+    id: Transaction,
+    state: Option<SwapState>,
+}
+
+impl SwapOp {
+    pub(super) fn outcome(&self) -> OpOutcome {
+        OpOutcome::Irrelevant
+    }
+
+    pub(super) fn finalized(&self) -> bool {
+        self.state.is_none()
+    }
+
+    pub(super) fn to_host_result(&self) -> HostResult {
+        Err(ErrorKind::OperationError {
+            cause: "location swaps are not client operations".into(),
+        }
+        .into())
+    }
+}
+
+impl Operation for SwapOp {
+    type Message = SwapMsg;
+    type Result = ();
+
+    async fn load_or_init<'a>(
+        op_manager: &'a OpManager,
+        msg: &'a Self::Message,
+    ) -> Result<OpInitialization<Self>, OpError> {
+        let sender = msg.sender().map(|s| s.peer.clone());
+        let id = *msg.id();
+
+        match op_manager.pop(msg.id()) {
+            Ok(Some(OpEnum::Swap(swap_op))) => Ok(OpInitialization {
+                op: swap_op,
+                sender,
+            }),
+            Ok(Some(op)) => {
+                let _ = op_manager.push(id, op).await;
+                Err(OpError::OpNotPresent(id))
+            }
+            Ok(None) => Ok(OpInitialization {
+                op: Self { id, state: None },
+                sender,
+            }),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn id(&self) -> &Transaction {
+        &self.id
+    }
+
+    fn process_message<'a, NB: NetworkBridge>(
+        self,
+        conn_manager: &'a mut NB,
+        op_manager: &'a OpManager,
+        input: &'a Self::Message,
+    ) -> Pin<Box<dyn Future<Output = Result<OperationResult, OpError>> + Send + 'a>> {
+        Box::pin(async move {
+            let ring = &op_manager.ring;
+            match input {
+                SwapMsg::Start { id, target } => {
+                    if self.state.is_some() {
+                        return Err(OpError::invalid_transition(self.id));
+                    }
+                    let this_peer = ring.own_location();
+                    let Some(location) = this_peer.location else {
+                        return build_op_result(self.id, None, None);
+                    };
+                    if !ring.swaps.lock().start(*id) {
+                        tracing::debug!(tx = %id, "Swap already in progress");
+                        return build_op_result(self.id, None, None);
+                    }
+                    let mut neighbours = ring.connection_locations();
+                    neighbours.truncate(MAX_NEIGHBOURS);
+                    tracing::debug!(tx = %id, %location, first_hop = %target.peer, "Starting location swap");
+                    build_op_result(
+                        self.id,
+                        Some(SwapState::AwaitingResponse { location }),
+                        Some(SwapMsg::Request {
+                            id: *id,
+                            target: target.clone(),
+                            sender: this_peer.clone(),
+                            initiator: this_peer.clone(),
+                            neighbours,
+                            visited: vec![this_peer.peer],
+                            htl: SWAP_HTL,
+                        }),
+                    )
+                }
+                SwapMsg::Request {
+                    id,
+                    sender,
+                    initiator,
+                    neighbours,
+                    visited,
+                    htl,
+                    ..
+                } => {
+                    if self.state.is_some() {
+                        return Err(OpError::invalid_transition(self.id));
+                    }
+                    let this_peer = ring.own_location();
+                    // the first hop knows where the initiator is, don't take its word for it
+                    let initiator_location = if sender.peer == initiator.peer {
+                        ring.connection_location(&initiator.peer)
+                            .filter(|known| Some(*known) == initiator.location)
+                    } else {
+                        initiator.location
+                    };
+                    let Some(initiator_location) = initiator_location.filter(valid_location) else {
+                        tracing::debug!(tx = %id, "Invalid swap request location");
+                        return build_op_result(self.id, None, None);
+                    };
+                    if neighbours.len() > MAX_NEIGHBOURS
+                        || !neighbours.iter().all(valid_location)
+                        || visited.len() > SWAP_HTL
+                        || initiator.peer == this_peer.peer
+                    {
+                        tracing::debug!(tx = %id, "Invalid swap request");
+                        return build_op_result(self.id, None, None);
+                    }
+                    if !ring.swaps.lock().allow_request(&sender.peer) {
+                        tracing::debug!(tx = %id, neighbour = %sender.peer, "Too many swap requests, dropping");
+                        return build_op_result(self.id, None, None);
+                    }
+
+                    let htl = (*htl).min(SWAP_HTL);
+                    if htl > 1 {
+                        let mut skip_list = visited.clone();
+                        skip_list.extend([
+                            sender.peer.clone(),
+                            initiator.peer.clone(),
+                            this_peer.peer.clone(),
+                        ]);
+                        if let Some(next) = ring.random_connection(skip_list.as_slice()) {
+                            let mut visited = visited.clone();
+                            visited.push(this_peer.peer.clone());
+                            return build_op_result(
+                                self.id,
+                                Some(SwapState::Forwarded {
+                                    upstream: sender.clone(),
+                                }),
+                                Some(SwapMsg::Request {
+                                    id: *id,
+                                    target: next,
+                                    sender: this_peer,
+                                    initiator: PeerKeyLocation {
+                                        location: Some(initiator_location),
+                                        ..initiator.clone()
+                                    },
+                                    neighbours: neighbours.clone(),
+                                    visited,
+                                    htl: htl - 1,
+                                }),
+                            );
+                        }
+                    }
+
+                    // this peer is the end of the walk, decide whether to swap with the initiator;
+                    // it only moves once the initiator confirms the swap
+                    let mut proposal = None;
+                    if let Some(location) = this_peer.location.filter(|_| !ring.is_gateway()) {
+                        let own_neighbours = ring.connection_locations();
+                        let swaps = &mut *ring.swaps.lock();
+                        let accept = swaps.can_accept(&initiator.peer)
+                            && location != initiator_location
+                            && should_swap(
+                                initiator_location,
+                                neighbours,
+                                location,
+                                &own_neighbours,
+                                &mut rand::thread_rng(),
+                            );
+                        if accept {
+                            let agreement = SwapAgreement {
+                                id: *id,
+                                initiator: initiator.peer.clone(),
+                                initiator_location,
+                                partner: this_peer.peer.clone(),
+                                partner_location: location,
+                            };
+                            if let Some(signature) = ring.sign(&agreement.signed_data()) {
+                                swaps.accepted(*id, initiator.peer.clone());
+                                proposal = Some(SwapProposal {
+                                    agreement,
+                                    signature,
+                                });
+                            }
+                        }
+                    }
+                    let state = proposal
+                        .as_ref()
+                        .map(|proposal| SwapState::AwaitingConfirmation {
+                            agreement: proposal.agreement.clone(),
+                        });
+                    build_op_result(
+                        self.id,
+                        state,
+                        Some(SwapMsg::Response {
+                            id: *id,
+                            target: sender.clone(),
+                            sender: this_peer,
+                            proposal,
+                        }),
+                    )
+                }
+                SwapMsg::Response {
+                    id,
+                    sender,
+                    proposal,
+                    ..
+                } => match self.state {
+                    Some(SwapState::Forwarded { upstream }) => build_op_result(
+                        self.id,
+                        proposal.as_ref().map(|_| SwapState::Confirming {
+                            downstream: sender.clone(),
+                        }),
+                        Some(SwapMsg::Response {
+                            id: *id,
+                            target: upstream,
+                            sender: ring.own_location(),
+                            proposal: proposal.clone(),
+                        }),
+                    ),
+                    Some(SwapState::AwaitingResponse { location }) => {
+                        ring.swaps.lock().finish(id);
+                        let Some(proposal) = proposal else {
+                            tracing::debug!(tx = %id, "Swap declined");
+                            return build_op_result(self.id, None, None);
+                        };
+                        let this_peer = ring.own_location();
+                        let agreement = &proposal.agreement;
+                        if agreement.id != *id
+                            || agreement.initiator != this_peer.peer
+                            || agreement.initiator_location != location
+                            || !valid_location(&agreement.partner_location)
+                            || !proposal.verify()
+                        {
+                            tracing::warn!(tx = %id, partner = %agreement.partner, "Invalid swap agreement");
+                            return build_op_result(self.id, None, None);
+                        }
+                        if this_peer.location != Some(location) {
+                            tracing::warn!(tx = %id, "Location changed while swapping");
+                            return build_op_result(self.id, None, None);
+                        }
+                        let Some(initiator_signature) = ring.sign(&agreement.signed_data()) else {
+                            return build_op_result(self.id, None, None);
+                        };
+                        let swap = SignedSwap {
+                            agreement: agreement.clone(),
+                            partner_signature: proposal.signature.clone(),
+                            initiator_signature,
+                        };
+                        tracing::debug!(
+                            tx = %id,
+                            partner = %agreement.partner,
+                            from = %location,
+                            to = %agreement.partner_location,
+                            "Swapping location"
+                        );
+                        ring.update_location(Some(agreement.partner_location));
+                        announce_location(conn_manager, op_manager, &swap).await;
+                        build_op_result(
+                            self.id,
+                            None,
+                            Some(SwapMsg::Confirm {
+                                id: *id,
+                                target: sender.clone(),
+                                sender: ring.own_location(),
+                                swap,
+                            }),
+                        )
+                    }
+                    _ => Err(OpError::invalid_transition(self.id)),
+                },
+                SwapMsg::Confirm { id, swap, .. } => match self.state {
+                    Some(SwapState::Confirming { downstream }) => build_op_result(
+                        self.id,
+                        None,
+                        Some(SwapMsg::Confirm {
+                            id: *id,
+                            target: downstream,
+                            sender: ring.own_location(),
+                            swap: swap.clone(),
+                        }),
+                    ),
+                    Some(SwapState::AwaitingConfirmation { agreement }) => {
+                        ring.swaps.lock().finish(id);
+                        if swap.agreement != agreement || !swap.verify() {
+                            tracing::warn!(tx = %id, initiator = %agreement.initiator, "Invalid swap confirmation");
+                            return build_op_result(self.id, None, None);
+                        }
+                        if ring.own_location().location != Some(agreement.partner_location) {
+                            tracing::warn!(tx = %id, "Location changed while swapping");
+                            return build_op_result(self.id, None, None);
+                        }
+                        tracing::debug!(
+                            tx = %id,
+                            initiator = %agreement.initiator,
+                            from = %agreement.partner_location,
+                            to = %agreement.initiator_location,
+                            "Swapping location"
+                        );
+                        ring.update_location(Some(agreement.initiator_location));
+                        announce_location(conn_manager, op_manager, swap).await;
+                        build_op_result(self.id, None, None)
+                    }
+                    _ => Err(OpError::invalid_transition(self.id)),
+                },
+                SwapMsg::LocationChanged {
+                    id, sender, swap, ..
+                } => {
+                    // only accept moving to the location the neighbour agreed to take, from the
+                    // location it was known to be at
+                    let moved_to = swap
+                        .agreement
+                        .movement(&sender.peer)
+                        .filter(|(from, to)| {
+                            ring.connection_location(&sender.peer) == Some(*from)
+                                && sender.location == Some(*to)
+                                && valid_location(to)
+                        })
+                        .filter(|_| swap.verify());
+                    match moved_to {
+                        Some((_, location)) => {
+                            ring.update_connection_location(&sender.peer, location)
+                        }
+                        None => {
+                            tracing::warn!(tx = %id, neighbour = %sender.peer, "Invalid location change");
+                        }
+                    }
+                    build_op_result(self.id, None, None)
+                }
+            }
+        })
+    }
+}
+
+/// Lets the neighbours of this peer know about its new location.
+async fn announce_location<NB: NetworkBridge>(
+    conn_manager: &NB,
+    op_manager: &OpManager,
+    swap: &SignedSwap,
+) {
+    let this_peer = op_manager.ring.own_location();
+    let id = Transaction::new::<SwapMsg>();
+    for peer in op_manager.ring.connected_peers() {
+        let msg = SwapMsg::LocationChanged {
+            id,
+            target: peer.clone(),
+            sender: this_peer.clone(),
+            swap: swap.clone(),
+        };
+        if let Err(error) = conn_manager.send(&peer.peer, msg.into()).await {
+            tracing::debug!(%error, peer = %peer.peer, "Failed announcing new location");
+        }
+    }
+    op_manager.completed(id);
+}
+
+fn build_op_result(
+    id: Transaction,
+    state: Option<SwapState>,
+    msg: Option<SwapMsg>,
+) -> Result<OperationResult, OpError> {
+    Ok(OperationResult {
+        return_msg: msg.map(NetMessage::from),
+        state: state.map(|state| {
+            OpEnum::Swap(SwapOp {
+                id,
+                state: Some(state),
+            })
+        }),
+    })
+}
+
+mod messages {
+    use std::{borrow::Borrow, fmt::Display};
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(crate) enum SwapMsg {
+        /// Starts a swap from this peer, the walk looking for a partner begins at `target`.
+        Start {
+            id: Transaction,
+            target: PeerKeyLocation,
+        },
+        /// Random walk looking for a partner to swap locations with the initiator.
+        Request {
+            id: Transaction,
+            target: PeerKeyLocation,
+            sender: PeerKeyLocation,
+            initiator: PeerKeyLocation,
+            /// Locations of the initiator neighbours.
+            neighbours: Vec<Location>,
+            visited: Vec<PeerId>,
+            htl: usize,
+        },
+        /// Answer of the partner, routed back to the initiator along the walk.
+        Response {
+            id: Transaction,
+            target: PeerKeyLocation,
+            sender: PeerKeyLocation,
+            /// Swap signed by the partner if it accepted it.
+            proposal: Option<SwapProposal>,
+        },
+        /// Swap signed by both parties, routed from the initiator to the partner along the walk
+        /// so the partner moves too.
+        Confirm {
+            id: Transaction,
+            target: PeerKeyLocation,
+            sender: PeerKeyLocation,
+            swap: SignedSwap,
+        },
+        /// A neighbour changed its location after swapping it.
+        LocationChanged {
+            id: Transaction,
+            target: PeerKeyLocation,
+            sender: PeerKeyLocation,
+            swap: SignedSwap,
+        },
+    }
+
+    impl InnerMessage for SwapMsg {
+        fn id(&self) -> &Transaction {
+            match self {
+                Self::Start { id, .. } => id,
+                Self::Request { id, .. } => id,
+                Self::Response { id, .. } => id,
+                Self::Confirm { id, .. } => id,
+                Self::LocationChanged { id, .. } => id,
+            }
+        }
+
+        fn target(&self) -> Option<impl Borrow<PeerKeyLocation>> {
+            match self {
+                Self::Request { target, .. } => Some(target),
+                Self::Response { target, .. } => Some(target),
+                Self::Confirm { target, .. } => Some(target),
+                Self::LocationChanged { target, .. } => Some(target),
+                _ => None,
+            }
+        }
+
+        fn requested_location(&self) -> Option<Location> {
+            None
+        }
+    }
+
+    impl SwapMsg {
+        pub fn sender(&self) -> Option<&PeerKeyLocation> {
+            match self {
+                Self::Request { sender, .. } => Some(sender),
+                Self::Response { sender, .. } => Some(sender),
+                Self::Confirm { sender, .. } => Some(sender),
+                Self::LocationChanged { sender, .. } => Some(sender),
+                _ => None,
+            }
+        }
+    }
+
+    impl Display for SwapMsg {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let id = self.id();
+            match self {
+                Self::Start { .. } => write!(f, "Start(id: {id})"),
+                Self::Request { .. } => write!(f, "Request(id: {id})"),
+                Self::Response { .. } => write!(f, "Response(id: {id})"),
+                Self::Confirm { .. } => write!(f, "Confirm(id: {id})"),
+                Self::LocationChanged { .. } => write!(f, "LocationChanged(id: {id})"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{node::testing_impl::SimNetwork, transport::TransportKeypair};
+
+    fn locations(locs: &[f64]) -> Vec<Location> {
+        locs.iter().map(|l| Location::new(*l)).collect()
+    }
+
+    #[test]
+    fn swap_when_closer_to_neighbours() {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(7);
+        let a_neighbours = locations(&[0.88, 0.9, 0.92]);
+        let b_neighbours = locations(&[0.08, 0.1, 0.12]);
+        let (a, b) = (Location::new(0.1), Location::new(0.9));
+        assert!(should_swap(a, &a_neighbours, b, &b_neighbours, &mut rng));
+        // once swapped both peers are where they should be
+        assert!(!should_swap(b, &a_neighbours, a, &b_neighbours, &mut rng));
+    }
+
+    #[test]
+    fn swap_limits() {
+        let mut limits = SwapLimits::default();
+        let neighbour = PeerId::random();
+        for _ in 0..MAX_REQUESTS_PER_NEIGHBOUR {
+            assert!(limits.allow_request(&neighbour));
+        }
+        assert!(!limits.allow_request(&neighbour));
+        assert!(limits.allow_request(&PeerId::random()));
+        limits.advance_time(REQUEST_WINDOW);
+        assert!(limits.allow_request(&neighbour));
+
+        let initiator = PeerId::random();
+        let id = Transaction::new::<SwapMsg>();
+        assert!(limits.can_accept(&initiator));
+        limits.accepted(id, initiator.clone());
+        // busy until the initiator confirms the swap
+        assert!(!limits.can_accept(&PeerId::random()));
+        limits.finish(&id);
+        assert!(!limits.can_accept(&initiator));
+        limits.advance_time(PARTNER_COOLDOWN);
+        assert!(limits.can_accept(&initiator));
+
+        let id = Transaction::new::<SwapMsg>();
+        assert!(limits.start(id));
+        assert!(!limits.start(Transaction::new::<SwapMsg>()));
+        assert!(!limits.can_accept(&PeerId::random()));
+        limits.finish(&id);
+        assert!(limits.can_accept(&PeerId::random()));
+
+        // swaps which never finish are abandoned
+        assert!(limits.start(Transaction::new::<SwapMsg>()));
+        limits.advance_time(SWAP_TIMEOUT);
+        assert!(!limits.busy());
+    }
+
+    #[test]
+    fn signed_swap_verification() {
+        let initiator_key = TransportKeypair::new_ed25519();
+        let partner_key = TransportKeypair::new_ed25519();
+        let agreement = SwapAgreement {
+            id: Transaction::new::<SwapMsg>(),
+            initiator: PeerId::new(
+                "127.0.0.1:10000".parse().unwrap(),
+                initiator_key.public().clone(),
+            ),
+            initiator_location: Location::new(0.1),
+            partner: PeerId::new(
+                "127.0.0.1:10001".parse().unwrap(),
+                partner_key.public().clone(),
+            ),
+            partner_location: Location::new(0.9),
+        };
+        let data = agreement.signed_data();
+        let proposal = SwapProposal {
+            agreement: agreement.clone(),
+            signature: partner_key.sign(&data).unwrap(),
+        };
+        assert!(proposal.verify());
+        let forged = SwapProposal {
+            agreement: agreement.clone(),
+            signature: initiator_key.sign(&data).unwrap(),
+        };
+        assert!(!forged.verify());
+
+        let swap = SignedSwap {
+            agreement: agreement.clone(),
+            partner_signature: proposal.signature.clone(),
+            initiator_signature: initiator_key.sign(&data).unwrap(),
+        };
+        assert!(swap.verify());
+        let tampered = SignedSwap {
+            agreement: SwapAgreement {
+                partner_location: Location::new(0.5),
+                ..agreement.clone()
+            },
+            ..swap.clone()
+        };
+        assert!(!tampered.verify());
+        let unconfirmed = SignedSwap {
+            initiator_signature: vec![],
+            ..swap
+        };
+        assert!(!unconfirmed.verify());
+
+        assert_eq!(
+            agreement.movement(&agreement.initiator),
+            Some((Location::new(0.1), Location::new(0.9)))
+        );
+        assert_eq!(
+            agreement.movement(&agreement.partner),
+            Some((Location::new(0.9), Location::new(0.1)))
+        );
+        assert_eq!(agreement.movement(&PeerId::random()), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn location_swaps_improve_routing() -> anyhow::Result<()> {
+        const NUM_NODES: usize = 16usize;
+        const NUM_GW: usize = 1usize;
+        const MAX_HTL: usize = 6usize;
+        const RAND_IF_HTL_ABOVE: usize = 3usize;
+        const MAX_CONNS: usize = 5usize;
+        const MIN_CONNS: usize = 3usize;
+        const SAMPLES: usize = 1_000;
+        const ROUNDS: usize = 30;
+        /// Swapping is random, only require it to improve routing by a margin well below what
+        /// it usually achieves.
+        const MIN_IMPROVEMENT: f64 = 0.05;
+        let mut sim_nw = SimNetwork::new(
+            "location_swaps_improve_routing",
+            NUM_GW,
+            NUM_NODES,
+            MAX_HTL,
+            RAND_IF_HTL_ABOVE,
+            MAX_CONNS,
+            MIN_CONNS,
+        )
+        .await;
+        // swaps are triggered by hand in rounds below
+        sim_nw.with_location_swap_interval(Duration::from_secs(60 * 60 * 24));
+        sim_nw.start().await;
+        sim_nw.check_connectivity(Duration::from_secs(10))?;
+
+        sim_nw.shuffle_locations();
+        let scrambled = sim_nw.greedy_routing_success(SAMPLES);
+        for _ in 0..ROUNDS {
+            sim_nw.location_swap_round(Duration::from_secs(1)).await;
+        }
+        let converged = sim_nw.greedy_routing_success(SAMPLES);
+        tracing::info!(%scrambled, %converged, "Greedy routing success");
+        assert!(
+            converged >= scrambled + MIN_IMPROVEMENT,
+            "routing didn't improve after swapping locations ({scrambled} -> {converged})"
+        );
+        Ok(())
+    }
+}
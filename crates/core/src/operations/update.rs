@@ -1,9 +1,9 @@
+use either::Either;
 use freenet_stdlib::client_api::{ErrorKind, HostResponse};
-// TODO: complete update logic in the network
 use freenet_stdlib::prelude::*;
 
 use super::{OpEnum, OpError, OpInitialization, OpOutcome, Operation, OperationResult};
-use crate::contract::ContractHandlerEvent;
+use crate::contract::{ContractError, ContractHandlerEvent, StoreResponse};
 use crate::message::{InnerMessage, NetMessage, Transaction};
 use crate::ring::{Location, PeerKeyLocation, RingError};
 use crate::{
@@ -11,7 +11,7 @@ use crate::{
     node::{NetworkBridge, OpManager, PeerId},
 };
 
-pub(crate) use self::messages::UpdateMsg;
+pub(crate) use self::messages::{StateUpdate, UpdateMsg};
 
 pub(crate) struct UpdateOp {
     pub id: Transaction,
@@ -199,8 +199,14 @@ impl Operation for UpdateOp {
 
                     if is_subscribed_contract {
                         tracing::debug!("Peer is subscribed to contract. About to update it");
-                        update_contract(op_manager, *key, value.clone(), related_contracts.clone())
-                            .await?;
+                        update_contract(
+                            op_manager,
+                            sender,
+                            *key,
+                            Either::Left(value.clone()),
+                            related_contracts.clone(),
+                        )
+                        .await?;
                         tracing::debug!(
                             tx = %id,
                             "Successfully updated a value for contract {} @ {:?} - update",
@@ -234,7 +240,8 @@ impl Operation for UpdateOp {
                 UpdateMsg::BroadcastTo {
                     id,
                     key,
-                    new_value,
+                    update,
+                    summary,
                     sender,
                 } => {
                     if let Some(UpdateState::AwaitingResponse { .. }) = self.state {
@@ -245,14 +252,41 @@ impl Operation for UpdateOp {
                     let target = op_manager.ring.own_location();
 
                     tracing::debug!("Attempting contract value update - BroadcastTo - update");
-                    let new_value = update_contract(
+                    let is_delta = matches!(update, StateUpdate::Delta(_));
+                    let new_value = match update_contract(
                         op_manager,
+                        sender,
                         *key,
-                        new_value.clone(),
+                        update.clone().into(),
                         RelatedContracts::default(),
                     )
-                    .await?;
+                    .await
+                    {
+                        Ok(new_value) => new_value,
+                        Err(err) if is_delta => {
+                            // the delta was computed from a state this peer doesn't have,
+                            // fall back to the whole state
+                            tracing::debug!(
+                                tx = %id,
+                                %key,
+                                %err,
+                                "Failed applying state delta, requesting the whole state"
+                            );
+                            let request = UpdateMsg::RequestState {
+                                id: Transaction::new::<UpdateMsg>(),
+                                key: *key,
+                                sender: target,
+                                target: sender.clone(),
+                            };
+                            return build_op_result(self.id, None, Some(request), stats);
+                        }
+                        Err(err) => return Err(err),
+                    };
                     tracing::debug!("Contract successfully updated - BroadcastTo - update");
+                    // the sender has, at least, the state it summarized
+                    op_manager
+                        .ring
+                        .record_peer_summary(*key, sender.peer.clone(), summary.clone());
 
                     let broadcast_to = op_manager.get_broadcast_targets_update(key, &sender.peer);
 
@@ -291,35 +325,40 @@ impl Operation for UpdateOp {
                 } => {
                     let sender = op_manager.ring.own_location();
                     let mut broadcasted_to = *broadcasted_to;
+                    let summary = summarize_state(op_manager, *key, new_value.clone()).await;
+
+                    let mut updates = Vec::with_capacity(broadcast_to.len());
+                    for peer in broadcast_to.iter() {
+                        updates
+                            .push(state_update_for(op_manager, *key, new_value, &peer.peer).await);
+                    }
 
                     let mut broadcasting = Vec::with_capacity(broadcast_to.len());
 
-                    for peer in broadcast_to.iter() {
+                    for (peer, update) in broadcast_to.iter().zip(updates) {
                         let msg = UpdateMsg::BroadcastTo {
                             id: *id,
                             key: *key,
-                            new_value: new_value.clone(),
+                            update,
+                            summary: summary.clone(),
                             sender: sender.clone(),
                         };
                         let f = conn_manager.send(&peer.peer, msg.into());
                         broadcasting.push(f);
                     }
-                    let error_futures = futures::future::join_all(broadcasting)
-                        .await
-                        .into_iter()
-                        .enumerate()
-                        .filter_map(|(p, err)| {
-                            if let Err(err) = err {
-                                Some((p, err))
-                            } else {
-                                None
-                            }
-                        });
+                    let results = futures::future::join_all(broadcasting).await;
 
                     let mut incorrect_results = 0;
-                    for (peer_num, err) in error_futures {
-                        // remove the failed peers in reverse order
-                        let peer = broadcast_to.get(peer_num).unwrap();
+                    for (peer, result) in broadcast_to.iter().zip(results) {
+                        let Err(err) = result else {
+                            // following updates are sent to the peer as deltas from this state
+                            op_manager.ring.record_peer_summary(
+                                *key,
+                                peer.peer.clone(),
+                                summary.clone(),
+                            );
+                            continue;
+                        };
                         tracing::warn!(
                             "failed broadcasting update change to {} with error {}; dropping connection",
                             peer.peer,
@@ -335,10 +374,6 @@ impl Operation for UpdateOp {
                         "Successfully broadcasted update contract {key} to {broadcasted_to} peers - Broadcasting"
                     );
 
-                    let raw_state = State::from(new_value.clone());
-
-                    let summary = StateSummary::from(raw_state.into_bytes());
-
                     // Subscriber nodes have been notified of the change, the operation is complete
                     return_msg = Some(UpdateMsg::SuccessfulUpdate {
                         id: *id,
@@ -348,6 +383,36 @@ impl Operation for UpdateOp {
 
                     new_state = None;
                 }
+                UpdateMsg::RequestState {
+                    id, key, sender, ..
+                } => {
+                    if !op_manager.ring.is_seeding_contract(key) {
+                        tracing::debug!(tx = %id, %key, "Requested the state of a contract not seeded");
+                        return Err(OpError::RingError(RingError::NoCachingPeers(*key)));
+                    }
+                    let state = fetch_state(op_manager, *key).await?;
+                    let summary = summarize_state(op_manager, *key, state.clone()).await;
+                    tracing::debug!(
+                        tx = %id,
+                        %key,
+                        peer = %sender.peer,
+                        "Sending the whole state to a peer which couldn't apply a delta"
+                    );
+                    let msg = UpdateMsg::BroadcastTo {
+                        id: *id,
+                        key: *key,
+                        update: StateUpdate::State(state),
+                        summary: summary.clone(),
+                        sender: op_manager.ring.own_location(),
+                    };
+                    conn_manager.send(&sender.peer, msg.into()).await?;
+                    op_manager
+                        .ring
+                        .record_peer_summary(*key, sender.peer.clone(), summary);
+
+                    return_msg = None;
+                    new_state = None;
+                }
                 UpdateMsg::SuccessfulUpdate { id, summary, .. } => {
                     match self.state {
                         Some(UpdateState::AwaitingResponse { key, upstream }) => {
@@ -452,9 +517,7 @@ async fn try_to_broadcast(
                     .await?;
                 return Err(OpError::StatePushed);
             } else {
-                let raw_state = State::from(new_value);
-
-                let summary = StateSummary::from(raw_state.into_bytes());
+                let summary = summarize_state(op_manager, key, new_value).await;
 
                 new_state = None;
                 return_msg = Some(UpdateMsg::SuccessfulUpdate {
@@ -521,34 +584,116 @@ fn build_op_result(
 
 async fn update_contract(
     op_manager: &OpManager,
+    sender: &PeerKeyLocation,
     key: ContractKey,
-    state: WrappedState,
+    update: Either<WrappedState, StateDelta<'static>>,
     related_contracts: RelatedContracts<'static>,
 ) -> Result<WrappedState, OpError> {
     match op_manager
-        .notify_contract_handler(ContractHandlerEvent::UpdateQuery {
-            key,
-            state,
-            related_contracts,
-        })
+        .notify_contract_handler_from(
+            sender,
+            ContractHandlerEvent::UpdateQuery {
+                key,
+                update,
+                related_contracts,
+            },
+        )
         .await
     {
         Ok(ContractHandlerEvent::UpdateResponse {
             new_value: Ok(new_val),
         }) => Ok(new_val),
         Ok(ContractHandlerEvent::UpdateResponse {
-            new_value: Err(_err),
-        }) => {
-            // return Err(OpError::from(ContractError::StorageError(err)));
-            todo!("not a valid value update, notify back to requester")
-        }
+            new_value: Err(err),
+        }) => Err(err.into()),
         Err(err) => Err(err.into()),
         Ok(_) => Err(OpError::UnexpectedOpState),
     }
 }
 
+async fn fetch_state(op_manager: &OpManager, key: ContractKey) -> Result<WrappedState, OpError> {
+    match op_manager
+        .notify_contract_handler(ContractHandlerEvent::GetQuery {
+            key,
+            fetch_contract: false,
+        })
+        .await
+    {
+        Ok(ContractHandlerEvent::GetResponse {
+            response: Ok(StoreResponse {
+                state: Some(state), ..
+            }),
+            ..
+        }) => Ok(state),
+        Ok(ContractHandlerEvent::GetResponse {
+            response: Ok(_), ..
+        }) => Err(ContractError::ContractNotFound(key).into()),
+        Ok(ContractHandlerEvent::GetResponse {
+            response: Err(err), ..
+        }) => Err(err.into()),
+        Err(err) => Err(err.into()),
+        Ok(_) => Err(OpError::UnexpectedOpState),
+    }
+}
+
+/// Summary of the new state of a contract, peers receiving an update record it to send deltas
+/// from it back to this peer. Falls back to the whole state if the contract can't summarize it.
+async fn summarize_state(
+    op_manager: &OpManager,
+    key: ContractKey,
+    state: WrappedState,
+) -> StateSummary<'static> {
+    let err = match op_manager
+        .notify_contract_handler(ContractHandlerEvent::GetSummaryQuery {
+            key,
+            state: state.clone(),
+        })
+        .await
+    {
+        Ok(ContractHandlerEvent::GetSummaryResponse {
+            summary: Ok(summary),
+        }) => return summary,
+        Ok(ContractHandlerEvent::GetSummaryResponse { summary: Err(err) }) => err.to_string(),
+        Err(err) => err.to_string(),
+        Ok(_) => OpError::UnexpectedOpState.to_string(),
+    };
+    tracing::debug!(%key, %err, "Failed summarizing state, using the whole state as summary");
+    StateSummary::from(state.as_ref().to_vec())
+}
+
+/// The update to send to a peer holding the contract: the delta from the latest summary of its
+/// state, or the whole state when no summary is known or the delta isn't any smaller.
+async fn state_update_for(
+    op_manager: &OpManager,
+    key: ContractKey,
+    state: &WrappedState,
+    peer: &PeerId,
+) -> StateUpdate {
+    let Some(summary) = op_manager.ring.peer_summary(&key, peer) else {
+        return StateUpdate::State(state.clone());
+    };
+    match op_manager
+        .notify_contract_handler(ContractHandlerEvent::GetDeltaQuery {
+            key,
+            state: state.clone(),
+            summary,
+        })
+        .await
+    {
+        Ok(ContractHandlerEvent::GetDeltaResponse { delta: Ok(delta) })
+            if delta.size() < state.size() =>
+        {
+            StateUpdate::Delta(delta)
+        }
+        Ok(ContractHandlerEvent::GetDeltaResponse { delta: Err(err) }) => {
+            tracing::debug!(%key, %peer, %err, "Failed computing state delta, sending the whole state");
+            StateUpdate::State(state.clone())
+        }
+        _ => StateUpdate::State(state.clone()),
+    }
+}
+
 /// This will be called from the node when processing an open request
-// todo: new_state should be a delta when possible!
 pub(crate) fn start_op(
     key: ContractKey,
     new_state: WrappedState,
@@ -651,7 +796,10 @@ pub(crate) async fn request_update(
 mod messages {
     use std::{borrow::Borrow, fmt::Display};
 
-    use freenet_stdlib::prelude::{ContractKey, RelatedContracts, StateSummary, WrappedState};
+    use either::Either;
+    use freenet_stdlib::prelude::{
+        ContractKey, RelatedContracts, StateDelta, StateSummary, WrappedState,
+    };
     use serde::{Deserialize, Serialize};
 
     use crate::{
@@ -703,8 +851,37 @@ mod messages {
             id: Transaction,
             sender: PeerKeyLocation,
             key: ContractKey,
-            new_value: WrappedState,
+            update: StateUpdate,
+            /// Summary of the new state of the sender, so the receiver can send it deltas.
+            #[serde(deserialize_with = "StateSummary::deser_state_summary")]
+            summary: StateSummary<'static>,
         },
+        /// Requests the whole state of a contract from a peer which sent a delta that couldn't
+        /// be applied.
+        RequestState {
+            id: Transaction,
+            sender: PeerKeyLocation,
+            target: PeerKeyLocation,
+            key: ContractKey,
+        },
+    }
+
+    /// A change to the state of a contract sent to a peer holding it.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub(crate) enum StateUpdate {
+        /// Delta from the latest summary known of the state of the peer.
+        Delta(#[serde(deserialize_with = "StateDelta::deser_state_delta")] StateDelta<'static>),
+        /// The whole new state, sent when the state of the peer is unknown.
+        State(WrappedState),
+    }
+
+    impl From<StateUpdate> for Either<WrappedState, StateDelta<'static>> {
+        fn from(update: StateUpdate) -> Self {
+            match update {
+                StateUpdate::Delta(delta) => Either::Right(delta),
+                StateUpdate::State(state) => Either::Left(state),
+            }
+        }
     }
 
     impl InnerMessage for UpdateMsg {
@@ -716,6 +893,7 @@ mod messages {
                 UpdateMsg::SeekNode { id, .. } => id,
                 UpdateMsg::Broadcasting { id, .. } => id,
                 UpdateMsg::BroadcastTo { id, .. } => id,
+                UpdateMsg::RequestState { id, .. } => id,
             }
         }
 
@@ -724,6 +902,7 @@ mod messages {
                 UpdateMsg::RequestUpdate { target, .. } => Some(target),
                 UpdateMsg::SuccessfulUpdate { target, .. } => Some(target),
                 UpdateMsg::SeekNode { target, .. } => Some(target),
+                UpdateMsg::RequestState { target, .. } => Some(target),
                 _ => None,
             }
         }
@@ -734,6 +913,7 @@ mod messages {
                 UpdateMsg::SeekNode { key, .. } => Some(Location::from(key.id())),
                 UpdateMsg::Broadcasting { key, .. } => Some(Location::from(key.id())),
                 UpdateMsg::BroadcastTo { key, .. } => Some(Location::from(key.id())),
+                UpdateMsg::RequestState { key, .. } => Some(Location::from(key.id())),
                 _ => None,
             }
         }
@@ -744,6 +924,7 @@ mod messages {
             match self {
                 Self::SeekNode { sender, .. } => Some(sender),
                 Self::BroadcastTo { sender, .. } => Some(sender),
+                Self::RequestState { sender, .. } => Some(sender),
                 _ => None,
             }
         }
@@ -758,6 +939,7 @@ mod messages {
                 UpdateMsg::SeekNode { id, .. } => write!(f, "SeekNode(id: {id})"),
                 UpdateMsg::Broadcasting { id, .. } => write!(f, "Broadcasting(id: {id})"),
                 UpdateMsg::BroadcastTo { id, .. } => write!(f, "BroadcastTo(id: {id})"),
+                UpdateMsg::RequestState { id, .. } => write!(f, "RequestState(id: {id})"),
             }
         }
     }
@@ -781,3 +963,222 @@ pub enum UpdateState {
     },
     BroadcastOngoing,
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        config::{ConfigArgs, GlobalExecutor},
+        contract::{
+            self, executor_channel, ContractHandler, ExecutorToEventLoopChannel,
+            MemoryContractHandler, NetworkEventListenerHalve,
+        },
+        message::NetMessageV1,
+        node::{
+            event_loop_notification_channel, ConnectionError, EventLoopNotificationsReceiver,
+            NodeConfig,
+        },
+        tracing::TestEventListener,
+    };
+
+    /// Records the messages sent instead of sending them.
+    #[derive(Default)]
+    struct RecordingBridge {
+        sent: Mutex<Vec<(PeerId, NetMessage)>>,
+    }
+
+    impl NetworkBridge for RecordingBridge {
+        async fn drop_connection(&mut self, _peer: &PeerId) -> Result<(), ConnectionError> {
+            Ok(())
+        }
+
+        async fn send(&self, target: &PeerId, msg: NetMessage) -> Result<(), ConnectionError> {
+            self.sent.lock().push((target.clone(), msg));
+            Ok(())
+        }
+    }
+
+    /// Peer seeding a contract, with its contract handler running on the mock runtime.
+    struct TestPeer {
+        op_manager: Arc<OpManager>,
+        key: ContractKey,
+        state: WrappedState,
+        _channels: (
+            EventLoopNotificationsReceiver,
+            ExecutorToEventLoopChannel<NetworkEventListenerHalve>,
+        ),
+    }
+
+    impl TestPeer {
+        async fn new(name: &str) -> anyhow::Result<Self> {
+            let mut config_args = ConfigArgs::default();
+            config_args.id = Some(name.into());
+            let mut config = NodeConfig::new(config_args.build()?).await?;
+            config.router_history = None;
+            config.ban_list = None;
+            config
+                .with_peer_id(PeerKeyLocation::random().peer)
+                .with_location(Location::random());
+            let (notification_rx, notification_tx) = event_loop_notification_channel();
+            let (ops_ch_channel, ch_channel, _) = contract::contract_handler_channel();
+            let op_manager = Arc::new(OpManager::new(
+                notification_tx,
+                ops_ch_channel,
+                &config,
+                TestEventListener::new().await,
+            )?);
+            let (executor_listener, executor_sender) = executor_channel(op_manager.clone());
+            let contract_handler =
+                MemoryContractHandler::build(ch_channel, executor_sender, name.to_owned()).await?;
+            GlobalExecutor::spawn(contract::contract_handling(contract_handler));
+
+            let contract =
+                ContractContainer::Wasm(ContractWasmAPIVersion::V1(WrappedContract::new(
+                    Arc::new(ContractCode::from(vec![0, 1, 2, 3])),
+                    Parameters::from(vec![4, 5]),
+                )));
+            let key = contract.key();
+            let state = WrappedState::new((0..64).collect());
+            op_manager
+                .notify_contract_handler(ContractHandlerEvent::PutQuery {
+                    key,
+                    state: state.clone(),
+                    related_contracts: RelatedContracts::default(),
+                    contract: Some(contract),
+                })
+                .await?;
+            op_manager.ring.seed_contract(key, Some(state.size()));
+            Ok(Self {
+                op_manager,
+                key,
+                state,
+                _channels: (notification_rx, executor_listener),
+            })
+        }
+
+        async fn process(
+            &self,
+            bridge: &mut RecordingBridge,
+            state: UpdateState,
+            msg: UpdateMsg,
+        ) -> Result<OperationResult, OpError> {
+            let op = UpdateOp {
+                id: *msg.id(),
+                state: Some(state),
+                stats: None,
+            };
+            op.process_message(bridge, &self.op_manager, &msg).await
+        }
+    }
+
+    fn sent_update(bridge: &RecordingBridge, target: &PeerId) -> Option<StateUpdate> {
+        bridge.sent.lock().iter().find_map(|(peer, msg)| match msg {
+            NetMessage::V1(NetMessageV1::Update(UpdateMsg::BroadcastTo { update, .. }))
+                if peer == target =>
+            {
+                Some(update.clone())
+            }
+            _ => None,
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn update_sent_and_applied_as_delta() -> anyhow::Result<()> {
+        let peer = TestPeer::new("update-sent-and-applied-as-delta").await?;
+        let subscriber = PeerKeyLocation::random();
+        let summary = summarize_state(&peer.op_manager, peer.key, peer.state.clone()).await;
+        peer.op_manager
+            .ring
+            .record_peer_summary(peer.key, subscriber.peer.clone(), summary);
+
+        let mut new_state = peer.state.as_ref().to_vec();
+        new_state.extend([u8::MAX; 4]);
+        let new_state = WrappedState::new(new_state);
+        let mut bridge = RecordingBridge::default();
+        let broadcasting = UpdateMsg::Broadcasting {
+            id: Transaction::new::<UpdateMsg>(),
+            broadcasted_to: 0,
+            broadcast_to: vec![subscriber.clone()],
+            key: peer.key,
+            new_value: new_state.clone(),
+            upstream: PeerKeyLocation::random(),
+        };
+        peer.process(&mut bridge, UpdateState::BroadcastOngoing, broadcasting)
+            .await?;
+        let Some(StateUpdate::Delta(delta)) = sent_update(&bridge, &subscriber.peer) else {
+            anyhow::bail!("expected a delta to be sent to the subscriber");
+        };
+        assert!(delta.size() < new_state.size());
+
+        // the peer still holds the previous state, so it can apply the delta it sent
+        let broadcast_to = UpdateMsg::BroadcastTo {
+            id: Transaction::new::<UpdateMsg>(),
+            sender: subscriber.clone(),
+            key: peer.key,
+            update: StateUpdate::Delta(delta),
+            summary: summarize_state(&peer.op_manager, peer.key, new_state.clone()).await,
+        };
+        let result = peer
+            .process(&mut bridge, UpdateState::ReceivedRequest, broadcast_to)
+            .await?;
+        assert!(result.return_msg.is_none());
+        let state = fetch_state(&peer.op_manager, peer.key).await?;
+        assert_eq!(state.as_ref(), new_state.as_ref());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn whole_state_requested_when_delta_not_applicable() -> anyhow::Result<()> {
+        let peer = TestPeer::new("whole-state-requested-when-delta-not-applicable").await?;
+        let sender = PeerKeyLocation::random();
+
+        // delta from a state this peer never had
+        let other_state = WrappedState::new(vec![u8::MAX; 64]);
+        let summary = summarize_state(&peer.op_manager, peer.key, other_state.clone()).await;
+        let mut delta = summary.clone().into_bytes();
+        delta.extend([0; 4]);
+        let mut bridge = RecordingBridge::default();
+        let broadcast_to = UpdateMsg::BroadcastTo {
+            id: Transaction::new::<UpdateMsg>(),
+            sender: sender.clone(),
+            key: peer.key,
+            update: StateUpdate::Delta(StateDelta::from(delta)),
+            summary,
+        };
+        let result = peer
+            .process(&mut bridge, UpdateState::ReceivedRequest, broadcast_to)
+            .await?;
+        let Some(NetMessage::V1(NetMessageV1::Update(UpdateMsg::RequestState { target, .. }))) =
+            result.return_msg
+        else {
+            anyhow::bail!("expected the whole state to be requested");
+        };
+        assert_eq!(target, sender);
+        let state = fetch_state(&peer.op_manager, peer.key).await?;
+        assert_eq!(state.as_ref(), peer.state.as_ref());
+
+        // the requested peer answers with the whole state and sends deltas from it afterwards
+        let request = UpdateMsg::RequestState {
+            id: Transaction::new::<UpdateMsg>(),
+            sender: sender.clone(),
+            target: peer.op_manager.ring.own_location(),
+            key: peer.key,
+        };
+        peer.process(&mut bridge, UpdateState::ReceivedRequest, request)
+            .await?;
+        let Some(StateUpdate::State(state)) = sent_update(&bridge, &sender.peer) else {
+            anyhow::bail!("expected the whole state to be sent");
+        };
+        assert_eq!(state.as_ref(), peer.state.as_ref());
+        assert!(peer
+            .op_manager
+            .ring
+            .peer_summary(&peer.key, &sender.peer)
+            .is_some());
+        Ok(())
+    }
+}
@@ -22,36 +22,60 @@ use std::{
 use anyhow::bail;
 use dashmap::{mapref::one::Ref as DmRef, DashMap};
 use either::Either;
-use freenet_stdlib::prelude::{ContractInstanceId, ContractKey};
+use freenet_stdlib::prelude::{ContractInstanceId, ContractKey, StateSummary};
 use itertools::Itertools;
 use parking_lot::{Mutex, RwLock};
-use rand::seq::SliceRandom;
+use rand::seq::{IteratorRandom, SliceRandom};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::sync;
 use tracing::Instrument;
 
+use crate::contract::ExecutionUsage;
 use crate::message::TransactionType;
+use crate::topology::meter::{AttributionSource, ResourceType};
 use crate::topology::rate::Rate;
 use crate::topology::{Limits, TopologyAdjustment, TopologyManager};
 use crate::tracing::{NetEventLog, NetEventRegister};
-use crate::transport::TransportPublicKey;
+use crate::transport::{TransportKeypair, TransportPublicKey};
 use crate::util::Contains;
 use crate::{
     config::GlobalExecutor,
     message::Transaction,
     node::{self, EventLoopNotificationsSender, NodeConfig, PeerId},
-    operations::connect,
-    router::Router,
+    operations::{connect, swap},
+    router::{RouteHistory, Router},
 };
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+mod health;
+mod network_size;
+mod peer_summaries;
+mod reputation;
+mod seeding;
+
+pub use health::{
+    ContractSubscribers, DistanceBucket, LiveTransaction, RingHealth, SeededContract,
+};
+use network_size::NetworkSizeEstimator;
+use peer_summaries::PeerSummaries;
+pub(crate) use reputation::{Misbehaviour, Reputation};
+pub use seeding::SeedingWeights;
+use seeding::{SeedCandidate, SeedingManager};
+
+#[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 /// The location of a peer in the ring. This location allows routing towards the peer.
 pub struct PeerKeyLocation {
     pub peer: PeerId,
     /// An unspecified location means that the peer hasn't been asigned a location, yet.
     pub location: Option<Location>,
+    /// Address of the peer in the other address family, if it is reachable over both IPv4
+    /// and IPv6.
+    ///
+    /// Not serialized, so the encoding of messages and event logs holding peer locations
+    /// doesn't change. The connect messages carry it in fields of their own.
+    #[serde(skip)]
+    pub alt_addr: Option<SocketAddr>,
 }
 
 impl PeerKeyLocation {
@@ -60,6 +84,7 @@ impl PeerKeyLocation {
         PeerKeyLocation {
             peer: PeerId::random(),
             location: Some(Location::random()),
+            alt_addr: None,
         }
     }
 }
@@ -69,10 +94,42 @@ impl From<PeerId> for PeerKeyLocation {
         PeerKeyLocation {
             peer,
             location: None,
+            alt_addr: None,
         }
     }
 }
 
+// the alternate address is not part of the identity of the peer
+
+impl PartialEq for PeerKeyLocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.peer == other.peer && self.location == other.location
+    }
+}
+
+impl Eq for PeerKeyLocation {}
+
+impl Hash for PeerKeyLocation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.peer.hash(state);
+        self.location.hash(state);
+    }
+}
+
+impl Ord for PeerKeyLocation {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.peer
+            .cmp(&other.peer)
+            .then_with(|| self.location.cmp(&other.location))
+    }
+}
+
+impl PartialOrd for PeerKeyLocation {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl std::fmt::Debug for PeerKeyLocation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         <Self as Display>::fmt(self, f)
@@ -92,11 +149,15 @@ impl Display for PeerKeyLocation {
 pub(crate) struct Connection {
     location: PeerKeyLocation,
     open_at: Instant,
+    /// Last round-trip time reported by the transport for this connection
+    rtt: Option<Duration>,
 }
 
 #[derive(Clone)]
 pub(crate) struct LiveTransactionTracker {
     tx_per_peer: Arc<DashMap<PeerId, Vec<Transaction>>>,
+    /// Peer each transaction was last sent to.
+    last_hop: Arc<DashMap<Transaction, PeerId>>,
     missing_candidate_sender: sync::mpsc::Sender<PeerId>,
 }
 
@@ -114,10 +175,31 @@ impl LiveTransactionTracker {
     }
 
     pub fn add_transaction(&self, peer: PeerId, tx: Transaction) {
+        self.last_hop.insert(tx, peer.clone());
         self.tx_per_peer.entry(peer).or_default().push(tx);
     }
 
+    /// Peer the transaction was last sent to.
+    pub fn last_hop(&self, tx: &Transaction) -> Option<PeerId> {
+        self.last_hop.get(tx).map(|peer| peer.clone())
+    }
+
+    /// Transactions in progress and the peers each of them is waiting on.
+    pub fn transactions(&self) -> Vec<(Transaction, Vec<PeerId>)> {
+        let mut transactions: BTreeMap<Transaction, Vec<PeerId>> = BTreeMap::new();
+        for entry in self.tx_per_peer.iter() {
+            for tx in entry.value() {
+                transactions
+                    .entry(*tx)
+                    .or_default()
+                    .push(entry.key().clone());
+            }
+        }
+        transactions.into_iter().collect()
+    }
+
     pub fn remove_finished_transaction(&self, tx: Transaction) {
+        self.last_hop.remove(&tx);
         let keys_to_remove: Vec<PeerId> = self
             .tx_per_peer
             .iter()
@@ -138,6 +220,7 @@ impl LiveTransactionTracker {
         (
             Self {
                 tx_per_peer: Arc::new(DashMap::default()),
+                last_hop: Arc::new(DashMap::default()),
                 missing_candidate_sender: missing_peer,
             },
             rx,
@@ -146,6 +229,7 @@ impl LiveTransactionTracker {
 
     fn prune_transactions_from_peer(&self, peer: &PeerId) {
         self.tx_per_peer.remove(peer);
+        self.last_hop.retain(|_, last_hop| last_hop != peer);
     }
 
     fn has_live_connection(&self, peer: &PeerId) -> bool {
@@ -157,23 +241,6 @@ impl LiveTransactionTracker {
     }
 }
 
-#[derive(PartialEq, Clone, Copy)]
-struct Score(f64);
-
-impl PartialOrd for Score {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl Ord for Score {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal)
-    }
-}
-
-impl Eq for Score {}
-
 /// Thread safe and friendly data structure to keep track of the local knowledge
 /// of the state of the ring.
 ///
@@ -181,16 +248,28 @@ impl Eq for Score {}
 // multithreaded maps. In the future if performance requires it some of this can be moved
 // towards a more lock-free multithreading model if necessary.
 pub(crate) struct Ring {
-    pub rnd_if_htl_above: usize,
-    pub max_hops_to_live: usize,
+    /// Configured threshold above which connect requests are randomly forwarded, see
+    /// [`Ring::random_walk_above_htl`].
+    rnd_if_htl_above: usize,
+    /// Upper bound of the hops to live of any request, see [`Ring::hops_to_live`].
+    max_hops_to_live: usize,
+    /// Estimates the size of the network to adapt the hops to live of requests to it.
+    network_size: NetworkSizeEstimator,
     peer_key: Mutex<Option<PeerId>>,
-    peer_pub_key: TransportPublicKey,
+    /// Identity of this peer, used to sign the location swaps it agrees to.
+    key_pair: TransportKeypair,
+    /// Public address of this peer in the other address family, if dual-stack.
+    alt_addr: Option<SocketAddr>,
     pub max_connections: usize,
     pub min_connections: usize,
     router: Arc<RwLock<Router>>,
+    /// Recent routing events, persisted so the router keeps what it learnt across restarts.
+    route_history: Arc<Mutex<RouteHistory>>,
     topology_manager: RwLock<TopologyManager>,
     connections_by_location: RwLock<BTreeMap<Location, Vec<Connection>>>,
     location_for_peer: RwLock<BTreeMap<PeerId, Location>>,
+    /// Addresses in the other address family of the connected peers which have one
+    alt_addr_for_peer: DashMap<PeerId, SocketAddr>,
     own_location: AtomicU64,
     /// The container for subscriber is a vec instead of something like a hashset
     /// that would allow for blind inserts of duplicate peers subscribing because
@@ -198,12 +277,23 @@ pub(crate) struct Ring {
     /// of subscribers more often than inserting, and anyways is a relatively short sequence
     /// then is more optimal to just use a vector for it's compact memory layout.
     subscribers: DashMap<ContractKey, Vec<PeerKeyLocation>>,
+    /// Summaries of the state of the contracts held by other peers, updates are sent to them
+    /// as deltas from these.
+    peer_summaries: PeerSummaries,
     /// Contracts this peer is seeding.
-    seeding_contract: DashMap<ContractKey, Score>,
+    seeding: SeedingManager,
     /// Interim connections ongoing handshake or successfully open connections
     /// Is important to keep track of this so no more connections are accepted prematurely.
     open_connections: AtomicUsize,
+    /// Wakes up connection maintenance when a connection is lost so it's replaced right away
+    connection_lost: sync::Notify,
+    /// Limits on the location swaps this peer takes part in.
+    pub swaps: Mutex<swap::SwapLimits>,
+    /// Wakes up location swapping to try a swap right away.
+    swap_requested: sync::Notify,
     pub live_tx_tracker: LiveTransactionTracker,
+    /// Reputation of the peers this peer interacts with and peers banned for misbehaving.
+    reputation: Arc<Reputation>,
     // A peer which has been blacklisted to perform actions regarding a given contract.
     // todo: add blacklist
     // contract_blacklist: Arc<DashMap<ContractKey, Vec<Blacklisted>>>,
@@ -231,6 +321,20 @@ impl Ring {
 
     const DEFAULT_MAX_DOWNSTREAM_BANDWIDTH: Rate = Rate::new_per_second(1_000_000.0);
 
+    /// Half of a core spent executing contracts and delegates on behalf of other peers.
+    const DEFAULT_MAX_CPU_USAGE: Rate = Rate::new_per_second(0.5);
+
+    const DEFAULT_MAX_STORAGE_USAGE: Rate = Rate::new_per_second(1_000_000.0);
+
+    /// Bytes of WASM memory added by the executions on behalf of other peers.
+    const DEFAULT_MAX_MEMORY_USAGE: usize = 100 * 1024 * 1024;
+
+    /// Max connections to peers in the same IPv4 /24 subnet, likely run by the same operator.
+    const DEFAULT_MAX_CONNECTIONS_PER_IPV4_SUBNET: usize = 3;
+
+    /// Max connections to peers in the same IPv6 /48 subnet, usually assigned to a single site.
+    const DEFAULT_MAX_CONNECTIONS_PER_IPV6_SUBNET: usize = 3;
+
     /// Max number of subscribers for a contract.
     const MAX_SUBSCRIBERS: usize = 10;
 
@@ -241,13 +345,11 @@ impl Ring {
     const DEFAULT_RAND_WALK_ABOVE_HTL: usize = 7;
 
     /// Max hops to be performed for certain operations (e.g. propagating connection of a peer in the network).
+    /// Requests use fewer hops when the network is estimated to be small enough.
     const DEFAULT_MAX_HOPS_TO_LIVE: usize = 10;
 
-    /// Max number of seeding contracts.
-    const MAX_SEEDING_CONTRACTS: usize = 100;
-
-    /// Min number of seeding contracts.
-    const MIN_SEEDING_CONTRACTS: usize = Self::MAX_SEEDING_CONTRACTS / 4;
+    /// Interval between attempts to swap this peer location with another peer.
+    const DEFAULT_LOCATION_SWAP_INTERVAL: Duration = Duration::from_secs(60);
 
     pub fn new<ER: NetEventRegister + Clone>(
         config: &NodeConfig,
@@ -257,7 +359,6 @@ impl Ring {
     ) -> anyhow::Result<Arc<Self>> {
         let (live_tx_tracker, missing_candidate_rx) = LiveTransactionTracker::new();
 
-        let peer_pub_key = config.key_pair.public().clone();
         let peer_key = config.get_peer_id();
 
         // for location here consider -1 == None
@@ -299,33 +400,86 @@ impl Ring {
             Self::DEFAULT_MAX_DOWNSTREAM_BANDWIDTH
         };
 
-        let topology_manager = RwLock::new(TopologyManager::new(Limits {
-            max_upstream_bandwidth,
-            max_downstream_bandwidth,
-            min_connections,
-            max_connections,
-        }));
+        let max_cpu_usage = if let Some(v) = config.max_cpu_usage {
+            v
+        } else {
+            Self::DEFAULT_MAX_CPU_USAGE
+        };
 
-        let router = Arc::new(RwLock::new(Router::new(&[])));
-        GlobalExecutor::spawn(Self::refresh_router(router.clone(), event_register.clone()));
+        let max_storage_usage = if let Some(v) = config.max_storage_usage {
+            v
+        } else {
+            Self::DEFAULT_MAX_STORAGE_USAGE
+        };
+
+        let max_memory_usage = if let Some(v) = config.max_memory_usage {
+            v
+        } else {
+            Self::DEFAULT_MAX_MEMORY_USAGE
+        };
+
+        let max_connections_per_ipv4_subnet =
+            if let Some(v) = config.max_connections_per_ipv4_subnet {
+                v
+            } else {
+                Self::DEFAULT_MAX_CONNECTIONS_PER_IPV4_SUBNET
+            };
+
+        let max_connections_per_ipv6_subnet =
+            if let Some(v) = config.max_connections_per_ipv6_subnet {
+                v
+            } else {
+                Self::DEFAULT_MAX_CONNECTIONS_PER_IPV6_SUBNET
+            };
+
+        let reputation = Arc::new(Reputation::load(config.ban_list.clone()));
+
+        let topology_manager = RwLock::new(
+            TopologyManager::new(Limits {
+                max_upstream_bandwidth,
+                max_downstream_bandwidth,
+                max_cpu_usage,
+                max_storage_usage,
+                max_memory_usage,
+                min_connections,
+                max_connections,
+                max_connections_per_ipv4_subnet,
+                max_connections_per_ipv6_subnet,
+            })
+            .with_reputation(reputation.clone()),
+        );
+
+        let route_history = RouteHistory::load(config.router_history.clone());
+        let router = Arc::new(RwLock::new(Router::new(&route_history.events())));
+        let route_history = Arc::new(Mutex::new(route_history));
+        GlobalExecutor::spawn(Self::refresh_router(router.clone(), route_history.clone()));
 
         // Just initialize with a fake location, this will be later updated when the peer has an actual location assigned.
         let ring = Ring {
             rnd_if_htl_above,
             max_hops_to_live,
+            network_size: NetworkSizeEstimator::new(),
             max_connections,
             min_connections,
             router,
+            route_history,
             topology_manager,
             connections_by_location: RwLock::new(BTreeMap::new()),
             location_for_peer: RwLock::new(BTreeMap::new()),
+            alt_addr_for_peer: DashMap::new(),
             own_location,
             peer_key: Mutex::new(peer_key),
-            peer_pub_key,
+            key_pair: config.key_pair.clone(),
+            alt_addr: config.alt_addr,
             subscribers: DashMap::new(),
-            seeding_contract: DashMap::new(),
+            peer_summaries: PeerSummaries::new(),
+            seeding: SeedingManager::new(Box::new(config.seeding_weights.unwrap_or_default())),
             open_connections: AtomicUsize::new(0),
+            connection_lost: sync::Notify::new(),
+            swaps: Mutex::new(swap::SwapLimits::default()),
+            swap_requested: sync::Notify::new(),
             live_tx_tracker: live_tx_tracker.clone(),
+            reputation,
             event_register: Box::new(event_register),
             is_gateway,
         };
@@ -345,6 +499,14 @@ impl Ring {
             tracing::info_span!(parent: current_span, "connection_maintenance")
         };
 
+        let swap_interval = config
+            .location_swap_interval
+            .unwrap_or(Self::DEFAULT_LOCATION_SWAP_INTERVAL);
+        GlobalExecutor::spawn(
+            ring.clone()
+                .location_swapping(event_loop_notifier.clone(), swap_interval)
+                .instrument(span.clone()),
+        );
         GlobalExecutor::spawn(
             ring.clone()
                 .connection_maintenance(event_loop_notifier, live_tx_tracker, missing_candidate_rx)
@@ -369,8 +531,18 @@ impl Ring {
         }
     }
 
+    /// Public address of this peer in the other address family, if dual-stack.
+    pub fn alt_addr(&self) -> Option<SocketAddr> {
+        self.alt_addr
+    }
+
     pub fn get_peer_pub_key(&self) -> TransportPublicKey {
-        self.peer_pub_key.clone()
+        self.key_pair.public().clone()
+    }
+
+    /// Signs data with the identity of this peer, if it can sign.
+    pub(crate) fn sign(&self, data: &[u8]) -> Option<Vec<u8>> {
+        self.key_pair.sign(data)
     }
 
     pub fn is_gateway(&self) -> bool {
@@ -382,19 +554,15 @@ impl Ring {
             .load(std::sync::atomic::Ordering::Acquire)
     }
 
-    async fn refresh_router<ER: NetEventRegister>(router: Arc<RwLock<Router>>, register: ER) {
+    /// Periodically persists the routing history and rebuilds the router from it, so the
+    /// router is trained on the same events it will be restored from after a restart.
+    async fn refresh_router(router: Arc<RwLock<Router>>, route_history: Arc<Mutex<RouteHistory>>) {
         let mut interval = tokio::time::interval(Duration::from_secs(60 * 5));
         interval.tick().await;
         loop {
             interval.tick().await;
-            let history = register
-                .get_router_events(10_000)
-                .await
-                .map_err(|error| {
-                    tracing::error!(%error, "shutting down refresh router task");
-                    error
-                })
-                .expect("todo: propagate this to main thread");
+            Self::persist_route_history(&route_history).await;
+            let history = route_history.lock().events();
             if !history.is_empty() {
                 let router_ref = &mut *router.write();
                 *router_ref = Router::new(&history);
@@ -402,65 +570,103 @@ impl Ring {
         }
     }
 
-    /// Return if a contract is within appropiate seeding distance.
-    pub fn should_seed(&self, key: &ContractKey) -> bool {
-        const CACHING_DISTANCE: f64 = 0.05;
-        let caching_distance = Distance::new(CACHING_DISTANCE);
-        if self.seeding_contract.len() < Self::MIN_SEEDING_CONTRACTS {
-            return true;
+    /// Saves the routing history so the router can be rebuilt from it after a restart.
+    pub async fn persist_router_history(&self) {
+        Self::persist_route_history(&self.route_history).await;
+    }
+
+    async fn persist_route_history(route_history: &Mutex<RouteHistory>) {
+        let Some((path, data)) = route_history.lock().to_persist() else {
+            return;
+        };
+        let result = match data {
+            Ok(data) => crate::util::persist_atomically(&path, &data)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(error) => Err(error.into()),
+        };
+        if let Err(error) = result {
+            tracing::warn!(%error, ?path, "Failed persisting router history");
         }
-        let key_loc = Location::from(key);
-        let own_loc = self.own_location().location.expect("should be set");
-        if self.seeding_contract.len() < Self::MAX_SEEDING_CONTRACTS {
-            return own_loc.distance(key_loc) <= caching_distance;
+    }
+
+    /// Saves the peers currently banned so they stay banned after a restart.
+    pub async fn persist_ban_list(&self) {
+        let Some((path, data)) = self.reputation.to_persist() else {
+            return;
+        };
+        Self::persist_ban_list_data(path, data).await;
+    }
+
+    async fn persist_ban_list_data(path: std::path::PathBuf, data: bincode::Result<Vec<u8>>) {
+        let result = match data {
+            Ok(data) => crate::util::persist_atomically(&path, &data)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(error) => Err(error.into()),
+        };
+        if let Err(error) = result {
+            tracing::warn!(%error, ?path, "Failed persisting ban list");
         }
+    }
 
-        let contract_score = self.calculate_seed_score(key);
-        let r = self
-            .seeding_contract
-            .iter()
-            .min_by_key(|v| *v.value())
-            .unwrap();
-        let min_score = *r.value();
-        contract_score > min_score
+    /// Records evidence of a peer misbehaving, banning it if it misbehaved too much.
+    pub fn report_misbehaviour(&self, peer: &PeerId, misbehaviour: Misbehaviour) {
+        if !self.reputation.record(peer, misbehaviour) {
+            return;
+        }
+        // wake up connection maintenance so the connection with the peer is dropped
+        self.connection_lost.notify_one();
+        if let Some((path, data)) = self.reputation.to_persist() {
+            GlobalExecutor::spawn(Self::persist_ban_list_data(path, data));
+        }
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.reputation.is_banned(peer)
+    }
+
+    /// Return if a contract is worth seeding by this peer.
+    pub fn should_seed(&self, key: &ContractKey, state_size: Option<usize>) -> bool {
+        self.seeding
+            .should_seed(key, state_size, |key, size| self.seed_candidate(key, size))
     }
 
     /// Add a new subscription for this peer.
-    pub fn seed_contract(&self, key: ContractKey) -> (Option<ContractKey>, Vec<PeerKeyLocation>) {
-        let seed_score = self.calculate_seed_score(&key);
-        let mut old_subscribers = vec![];
-        let mut contract_to_drop = None;
-        if self.seeding_contract.len() < Self::MAX_SEEDING_CONTRACTS {
-            let dropped_contract = *self
-                .seeding_contract
-                .iter()
-                .min_by_key(|v| *v.value())
-                .unwrap()
-                .key();
-            self.seeding_contract.remove(&dropped_contract);
-            if let Some((_, mut subscribers_of_contract)) =
-                self.subscribers.remove(&dropped_contract)
-            {
-                std::mem::swap(&mut subscribers_of_contract, &mut old_subscribers);
-            }
-            contract_to_drop = Some(dropped_contract);
+    pub fn seed_contract(
+        &self,
+        key: ContractKey,
+        state_size: Option<usize>,
+    ) -> (Option<ContractKey>, Vec<PeerKeyLocation>) {
+        let dropped_contract = self
+            .seeding
+            .seed(key, state_size, |key, size| self.seed_candidate(key, size));
+        if let Some(dropped) = &dropped_contract {
+            self.peer_summaries.forget_contract(dropped);
         }
-        self.seeding_contract.insert(key, seed_score);
-        (contract_to_drop, old_subscribers)
+        let old_subscribers = dropped_contract
+            .and_then(|dropped| self.subscribers.remove(&dropped))
+            .map(|(_, subscribers)| subscribers)
+            .unwrap_or_default();
+        (dropped_contract, old_subscribers)
     }
 
-    fn calculate_seed_score(&self, key: &ContractKey) -> Score {
+    fn seed_candidate(&self, key: &ContractKey, state_size: Option<usize>) -> SeedCandidate {
         let location = self.own_location().location.expect("should be set");
         let key_loc = Location::from(key);
-        let distance = key_loc.distance(location);
-        let score = 0.5 - distance.as_f64();
-        Score(score)
+        let (requests, last_request) = self.topology_manager.read().requests_at(key_loc);
+        SeedCandidate {
+            distance: key_loc.distance(location),
+            requests,
+            since_last_request: last_request.map(|at| at.elapsed()),
+            state_size,
+        }
     }
 
     /// Whether this node already is seeding to this contract or not.
     #[inline]
     pub fn is_seeding_contract(&self, key: &ContractKey) -> bool {
-        self.seeding_contract.contains_key(key)
+        self.seeding.is_seeding(key)
     }
 
     /// Update this node location.
@@ -491,7 +697,11 @@ impl Ring {
             Some(Location(location))
         };
         let peer = self.get_peer_key().expect("peer key not set");
-        PeerKeyLocation { peer, location }
+        PeerKeyLocation {
+            peer,
+            location,
+            alt_addr: self.alt_addr,
+        }
     }
 
     /// Whether a node should accept a new node connection or not based
@@ -500,6 +710,25 @@ impl Ring {
     /// # Panic
     /// Will panic if the node checking for this condition has no location assigned.
     pub fn should_accept(&self, location: Location, peer: Option<&PeerId>) -> bool {
+        if let Some(peer_id) = peer {
+            if self.reputation.is_banned(peer_id) {
+                tracing::debug!(%peer_id, "Rejecting banned peer");
+                return false;
+            }
+        }
+
+        let neighbor_addrs = self.neighbor_addrs();
+        if let Some(peer_id) = peer {
+            if self
+                .topology_manager
+                .read()
+                .exceeds_subnet_limit(peer_id.addr.ip(), neighbor_addrs.iter().copied())
+            {
+                tracing::debug!(%peer_id, "Rejecting peer, too many connections to its subnet");
+                return false;
+            }
+        }
+
         let open_conn = self
             .open_connections
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
@@ -529,7 +758,12 @@ impl Ring {
         } else {
             self.topology_manager
                 .write()
-                .evaluate_new_connection(location, Instant::now())
+                .evaluate_new_connection(
+                    location,
+                    peer.map(|peer_id| peer_id.addr.ip()),
+                    neighbor_addrs,
+                    Instant::now(),
+                )
                 .unwrap_or(true)
         };
         if !accepted {
@@ -543,6 +777,71 @@ impl Ring {
         accepted
     }
 
+    /// Estimated number of peers in the network, from the distance to the neighbours of this
+    /// peer and the results of its connect requests.
+    pub fn network_size(&self) -> Option<f64> {
+        let neighbour_distances: Vec<_> = match self.own_location().location {
+            Some(own_location) => self
+                .connections_by_location
+                .read()
+                .keys()
+                .map(|location| own_location.distance(location))
+                .collect(),
+            None => vec![],
+        };
+        self.network_size.estimate(&neighbour_distances)
+    }
+
+    /// Hops to live for new requests of the given type, derived from the estimated size of the
+    /// network and bounded by the configured max.
+    pub fn hops_to_live(&self, tx_type: TransactionType) -> usize {
+        network_size::hops_to_live(
+            self.network_size(),
+            tx_type,
+            self.max_hops_to_live,
+            self.random_walk_hops(),
+        )
+    }
+
+    /// Connect requests with at least this number of remaining hops are forwarded to a random
+    /// peer instead of routed towards the joiner.
+    pub fn random_walk_above_htl(&self) -> usize {
+        self.hops_to_live(TransactionType::Connect)
+            .saturating_sub(self.random_walk_hops())
+    }
+
+    fn random_walk_hops(&self) -> usize {
+        self.max_hops_to_live.saturating_sub(self.rnd_if_htl_above)
+    }
+
+    /// Records the peers which accepted a connect request of this peer, routed towards its
+    /// own location, to estimate the size of the network.
+    pub fn record_connect_result<'a>(
+        &self,
+        acceptors: impl IntoIterator<Item = &'a PeerKeyLocation>,
+    ) {
+        let Some(own_location) = self.own_location().location else {
+            return;
+        };
+        let closest = acceptors
+            .into_iter()
+            .filter_map(|acceptor| acceptor.location)
+            .map(|location| own_location.distance(location))
+            .min();
+        if let Some(closest) = closest {
+            self.network_size.record_closest_peer(closest);
+        }
+    }
+
+    /// Addresses of the peers this peer is connected or connecting to.
+    fn neighbor_addrs(&self) -> Vec<IpAddr> {
+        self.location_for_peer
+            .read()
+            .keys()
+            .map(|peer| peer.addr.ip())
+            .collect()
+    }
+
     pub fn record_request(
         &self,
         recipient: PeerKeyLocation,
@@ -554,7 +853,14 @@ impl Ring {
             .record_request(recipient, target, request_type);
     }
 
-    pub async fn add_connection(&self, loc: Location, peer: PeerId) {
+    /// Record a request for a contract which was served by this peer.
+    pub fn record_served_request(&self, key: &ContractKey) {
+        self.topology_manager
+            .write()
+            .record_served_request(Location::from(key));
+    }
+
+    pub async fn add_connection(&self, loc: Location, peer: PeerId, alt_addr: Option<SocketAddr>) {
         tracing::info!(%peer, this = ?self.get_peer_key(), "Adding connection to peer");
         self.event_register
             .register_events(Either::Left(NetEventLog::connected(
@@ -568,10 +874,15 @@ impl Ring {
             location: PeerKeyLocation {
                 peer: peer.clone(),
                 location: Some(loc),
+                alt_addr,
             },
             open_at: Instant::now(),
+            rtt: None,
         });
         self.location_for_peer.write().insert(peer.clone(), loc);
+        if let Some(alt_addr) = alt_addr {
+            self.alt_addr_for_peer.insert(peer.clone(), alt_addr);
+        }
         std::mem::drop(cbl);
         self.refresh_density_request_cache()
     }
@@ -614,26 +925,135 @@ impl Ring {
         skip_list: impl Contains<PeerId>,
     ) -> Option<PeerKeyLocation> {
         let connections = self.connections_by_location.read();
-        let peers = connections.values().filter_map(|conns| {
+        let peers = self.routing_candidates(&connections, requesting, skip_list);
+        let router = &*self.router.read();
+        router
+            .select_peer(peers, target, |peer| self.reputation.score(&peer.peer))
+            .cloned()
+    }
+
+    /// Explains which connection [`Self::routing`] would select for the target location.
+    pub fn explain_routing(
+        &self,
+        target: Location,
+        requesting: Option<&PeerId>,
+        skip_list: impl Contains<PeerId>,
+    ) -> crate::router::RoutingExplanation {
+        let connections = self.connections_by_location.read();
+        let peers = self.routing_candidates(&connections, requesting, skip_list);
+        self.router
+            .read()
+            .explain(peers, target, |peer| self.reputation.score(&peer.peer))
+    }
+
+    /// Connections an op can be routed to, one per location, skipping banned peers, the peer
+    /// requesting the op and those in the skip list.
+    fn routing_candidates<'a>(
+        &'a self,
+        connections: &'a BTreeMap<Location, Vec<Connection>>,
+        requesting: Option<&'a PeerId>,
+        skip_list: impl Contains<PeerId> + 'a,
+    ) -> impl Iterator<Item = &'a PeerKeyLocation> + 'a {
+        connections.values().filter_map(move |conns| {
             let conn = conns.choose(&mut rand::thread_rng())?;
             if let Some(requester) = requesting {
                 if requester == &conn.location.peer {
                     return None;
                 }
             }
-            (!skip_list.has_element(&conn.location.peer)).then_some(&conn.location)
-        });
-        let router = &*self.router.read();
-        router.select_peer(peers, target).cloned()
+            (!skip_list.has_element(&conn.location.peer)
+                && !self.reputation.is_banned(&conn.location.peer))
+            .then_some(&conn.location)
+        })
+    }
+
+    /// Reports the position of this peer in the ring and the work it's carrying out.
+    pub fn health(&self) -> RingHealth {
+        let location = self.own_location().location;
+        let network_size = self.network_size();
+        let distances: Vec<_> = match location {
+            Some(location) => self
+                .connections_by_location
+                .read()
+                .iter()
+                .flat_map(|(conn_location, conns)| {
+                    std::iter::repeat(location.distance(conn_location).as_f64()).take(conns.len())
+                })
+                .collect(),
+            None => vec![],
+        };
+        let connections = distances.len();
+        let distance_buckets =
+            health::distance_buckets(distances, network_size.unwrap_or(connections as f64 + 1.0));
+        let small_world_deviation = health::small_world_deviation(&distance_buckets);
+
+        let mut seeding = if location.is_some() {
+            self.seeding
+                .scores(|key, size| self.seed_candidate(key, size))
+        } else {
+            vec![]
+        };
+        seeding.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        let seeding = seeding
+            .into_iter()
+            .map(|(key, score)| SeededContract {
+                key: key.to_string(),
+                score,
+            })
+            .collect();
+        let subscribers = self
+            .subscribers
+            .iter()
+            .map(|entry| ContractSubscribers {
+                key: entry.key().to_string(),
+                subscribers: entry.value().len(),
+            })
+            .collect();
+        let live_transactions = self
+            .live_tx_tracker
+            .transactions()
+            .into_iter()
+            .map(|(tx, peers)| LiveTransaction {
+                transaction: tx.to_string(),
+                peers,
+            })
+            .collect();
+
+        RingHealth {
+            peer: self.get_peer_key(),
+            location,
+            network_size,
+            connections,
+            distance_buckets,
+            small_world_deviation,
+            seeding,
+            subscribers,
+            live_transactions,
+        }
     }
 
     pub fn routing_finished(&self, event: crate::router::RouteEvent) {
+        if let crate::router::RouteOutcome::Failure = event.outcome {
+            self.report_misbehaviour(&event.peer.peer, Misbehaviour::FailedRoute);
+        }
         self.topology_manager
             .write()
             .report_outbound_request(event.peer.clone(), event.contract_location);
+        self.route_history.lock().record(event.clone());
         self.router.write().add_event(event);
     }
 
+    /// Get a random peer between the open connections, skipping those in the skip list.
+    pub fn random_connection(&self, skip_list: impl Contains<PeerId>) -> Option<PeerKeyLocation> {
+        self.connections_by_location
+            .read()
+            .values()
+            .flatten()
+            .filter(|conn| !skip_list.has_element(&conn.location.peer))
+            .choose(&mut rand::thread_rng())
+            .map(|conn| conn.location.clone())
+    }
+
     /// Get a random peer from the known ring connections.
     pub fn random_peer<F>(&self, filter_fn: F) -> Option<PeerKeyLocation>
     where
@@ -659,6 +1079,7 @@ impl Ring {
                 return Some(PeerKeyLocation {
                     peer: peer.clone(),
                     location: Some(*loc),
+                    alt_addr: self.alt_addr_for_peer.get(peer).map(|addr| *addr),
                 });
             }
         }
@@ -703,10 +1124,104 @@ impl Ring {
         self.subscribers.get(contract)
     }
 
+    /// Latest summary of the state of the contract the peer is known to have, if any.
+    pub fn peer_summary(
+        &self,
+        contract: &ContractKey,
+        peer: &PeerId,
+    ) -> Option<StateSummary<'static>> {
+        self.peer_summaries.get(contract, peer)
+    }
+
+    /// Records that the peer has, at least, the state of the contract summarized.
+    pub fn record_peer_summary(
+        &self,
+        contract: ContractKey,
+        peer: PeerId,
+        summary: StateSummary<'static>,
+    ) {
+        self.peer_summaries.record(contract, peer, summary);
+    }
+
     pub fn num_connections(&self) -> usize {
         self.connections_by_location.read().len()
     }
 
+    /// Locations of the peers this peer is connected to.
+    pub fn connection_locations(&self) -> Vec<Location> {
+        self.connections_by_location
+            .read()
+            .iter()
+            .filter(|(_, conns)| !conns.is_empty())
+            .map(|(loc, _)| *loc)
+            .collect()
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerKeyLocation> {
+        self.connections_by_location
+            .read()
+            .values()
+            .flatten()
+            .map(|conn| conn.location.clone())
+            .collect()
+    }
+
+    /// Location of a connected peer, as known by this peer.
+    pub fn connection_location(&self, peer: &PeerId) -> Option<Location> {
+        self.location_for_peer.read().get(peer).copied()
+    }
+
+    /// Moves a connected peer to the location it swapped to.
+    pub fn update_connection_location(&self, peer: &PeerId, loc: Location) {
+        let previous = {
+            let locations = &mut *self.location_for_peer.write();
+            let Some(known) = locations.get_mut(peer) else {
+                return;
+            };
+            std::mem::replace(known, loc)
+        };
+        if previous == loc {
+            return;
+        }
+        {
+            let conns = &mut *self.connections_by_location.write();
+            let Some(at_previous) = conns.get_mut(&previous) else {
+                return;
+            };
+            let Some(pos) = at_previous.iter().position(|c| &c.location.peer == peer) else {
+                return;
+            };
+            let mut conn = at_previous.swap_remove(pos);
+            if at_previous.is_empty() {
+                conns.remove(&previous);
+            }
+            conn.location.location = Some(loc);
+            conns.entry(loc).or_default().push(conn);
+        }
+        self.subscribers.alter_all(|_, mut subs| {
+            for sub in subs.iter_mut().filter(|l| &l.peer == peer) {
+                sub.location = Some(loc);
+            }
+            subs
+        });
+        self.refresh_density_request_cache();
+    }
+
+    /// Records the round-trip time measured by the transport for an open connection.
+    pub fn report_rtt(&self, peer: &PeerId, rtt: Duration) {
+        let Some(loc) = self.location_for_peer.read().get(peer).copied() else {
+            return;
+        };
+        if let Some(conn) = self
+            .connections_by_location
+            .write()
+            .get_mut(&loc)
+            .and_then(|conns| conns.iter_mut().find(|c| &c.location.peer == peer))
+        {
+            conn.rtt = Some(rtt);
+        }
+    }
+
     pub async fn prune_connection(&self, peer: PeerId) {
         #[cfg(debug_assertions)]
         {
@@ -714,6 +1229,7 @@ impl Ring {
         }
         self.live_tx_tracker.prune_transactions_from_peer(&peer);
         // This case would be when a connection is being open, so peer location hasn't been recorded yet and we can ignore everything below
+        self.alt_addr_for_peer.remove(&peer);
         let Some(loc) = self.location_for_peer.write().remove(&peer) else {
             self.open_connections
                 .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
@@ -723,10 +1239,13 @@ impl Ring {
             let conns = &mut *self.connections_by_location.write();
             if let Some(conns) = conns.get_mut(&loc) {
                 if let Some(pos) = conns.iter().position(|c| c.location.peer == peer) {
-                    conns.swap_remove(pos);
+                    let conn = conns.swap_remove(pos);
+                    tracing::debug!(%peer, rtt = ?conn.rtt, age = ?conn.open_at.elapsed(), "Connection removed");
                 }
             }
         }
+        self.connection_lost.notify_one();
+        self.peer_summaries.forget_peer(&peer);
         {
             self.subscribers.alter_all(|_, mut subs| {
                 if let Some(pos) = subs.iter().position(|l| l.location == Some(loc)) {
@@ -742,6 +1261,86 @@ impl Ring {
             .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
     }
 
+    /// Replaces the address of a connected peer which changed its public address, keeping its
+    /// location, subscriptions and live transactions instead of dropping the connection.
+    pub fn update_peer_addr(&self, previous: &PeerId, peer: PeerId) {
+        if let Some(txs) = self.live_tx_tracker.tx_per_peer.remove(previous) {
+            self.live_tx_tracker.tx_per_peer.insert(peer.clone(), txs.1);
+        }
+        let Some(loc) = self.location_for_peer.write().remove(previous) else {
+            return;
+        };
+        self.location_for_peer.write().insert(peer.clone(), loc);
+        if let Some((_, alt_addr)) = self.alt_addr_for_peer.remove(previous) {
+            self.alt_addr_for_peer.insert(peer.clone(), alt_addr);
+        }
+        {
+            let conns = &mut *self.connections_by_location.write();
+            if let Some(conn) = conns
+                .get_mut(&loc)
+                .and_then(|conns| conns.iter_mut().find(|c| &c.location.peer == previous))
+            {
+                conn.location.peer = peer.clone();
+            }
+        }
+        // the peer is sent whole states until it gets an update with the new address
+        self.peer_summaries.forget_peer(previous);
+        self.subscribers.alter_all(|_, mut subs| {
+            for sub in subs.iter_mut().filter(|l| &l.peer == previous) {
+                sub.peer = peer.clone();
+            }
+            subs
+        });
+        self.refresh_density_request_cache();
+    }
+
+    /// Attributes the bytes this peer relayed between two connected peers, so relaying counts
+    /// against the bandwidth limits like any other traffic.
+    pub fn report_relayed_bytes(&self, source: SocketAddr, target: SocketAddr, bytes: usize) {
+        let now = Instant::now();
+        let peers = self
+            .location_for_peer
+            .read()
+            .iter()
+            .filter(|(peer, _)| peer.addr == source || peer.addr == target)
+            .map(|(peer, loc)| PeerKeyLocation {
+                peer: peer.clone(),
+                location: Some(*loc),
+                alt_addr: self.alt_addr_for_peer.get(peer).map(|addr| *addr),
+            })
+            .collect::<Vec<_>>();
+        let topology_manager = &mut *self.topology_manager.write();
+        for peer in peers {
+            let resource = if peer.peer.addr == source {
+                ResourceType::InboundBandwidthBytes
+            } else {
+                ResourceType::OutboundBandwidthBytes
+            };
+            topology_manager.report_resource_usage(
+                &AttributionSource::Peer(peer),
+                resource,
+                bytes as f64,
+                now,
+            );
+        }
+    }
+
+    /// Attributes the resources used executing contracts or delegates to whoever caused it,
+    /// so expensive requests count against the resource limits.
+    pub(crate) fn report_execution_usage(&self, source: AttributionSource, usage: ExecutionUsage) {
+        let now = Instant::now();
+        let topology_manager = &mut *self.topology_manager.write();
+        for (resource, amount) in [
+            (ResourceType::CpuTimeSeconds, usage.cpu_time.as_secs_f64()),
+            (ResourceType::StorageBytes, usage.storage_bytes as f64),
+            (ResourceType::MemoryBytes, usage.memory_bytes as f64),
+        ] {
+            if amount > 0.0 {
+                topology_manager.report_resource_usage(&source, resource, amount, now);
+            }
+        }
+    }
+
     pub fn closest_to_location(
         &self,
         location: Location,
@@ -862,6 +1461,27 @@ impl Ring {
                     .collect()
             };
 
+            let banned: Vec<_> = self
+                .connections_by_location
+                .read()
+                .values()
+                .flatten()
+                .filter(|conn| self.reputation.is_banned(&conn.location.peer))
+                .map(|conn| conn.location.peer.clone())
+                .collect();
+            for peer in banned {
+                tracing::info!(%peer, "Dropping connection with banned peer");
+                notifier
+                    .send(Either::Right(crate::message::NodeEvent::DropConnection(
+                        peer,
+                    )))
+                    .await
+                    .map_err(|error| {
+                        tracing::debug!(?error, "Shutting down connection maintenance task");
+                        error
+                    })?;
+            }
+
             let adjustment = self.topology_manager.write().adjust_topology(
                 &neighbor_locations,
                 &self.own_location().location,
@@ -895,6 +1515,44 @@ impl Ring {
                 self.refresh_density_request_cache();
               }
               _ = check_interval.tick() => {}
+              _ = self.connection_lost.notified() => {}
+            }
+        }
+    }
+
+    /// Tries swapping the location of this peer right away instead of waiting for the next attempt.
+    #[cfg(test)]
+    pub(crate) fn request_location_swap(&self) {
+        self.swap_requested.notify_one();
+    }
+
+    /// Periodically tries swapping the location of this peer with another peer.
+    async fn location_swapping(
+        self: Arc<Self>,
+        notifier: EventLoopNotificationsSender,
+        interval: Duration,
+    ) -> anyhow::Result<()> {
+        if self.is_gateway {
+            // gateways keep their advertised location
+            return Ok(());
+        }
+        loop {
+            let wait = interval.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.swap_requested.notified() => {}
+            }
+            if self.get_peer_key().is_none() {
+                continue;
+            }
+            if let Some(msg) = swap::start_swap(&self) {
+                notifier
+                    .send(Either::Left(msg.into()))
+                    .await
+                    .map_err(|error| {
+                        tracing::debug!(?error, "Shutting down location swapping task");
+                        error
+                    })?;
             }
         }
     }
@@ -923,6 +1581,7 @@ impl Ring {
             msg: connect::ConnectRequest::FindOptimalPeer {
                 query_target,
                 ideal_location,
+                joiner_alt_addr: joiner.alt_addr,
                 joiner,
                 max_hops_to_live: missing_connections,
                 skip_list: skip_list.iter().map(|p| (*p).clone()).collect(),
@@ -1158,6 +1817,17 @@ pub(crate) enum RingError {
 mod test {
     use super::*;
 
+    #[test]
+    fn alt_addr_not_serialized() {
+        let mut peer = PeerKeyLocation::random();
+        let encoded = bincode::serialize(&(&peer.peer, &peer.location)).unwrap();
+        peer.alt_addr = Some((std::net::Ipv6Addr::LOCALHOST, 1234).into());
+        assert_eq!(bincode::serialize(&peer).unwrap(), encoded);
+        let decoded: PeerKeyLocation = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded.alt_addr, None);
+        assert_eq!(decoded, peer);
+    }
+
     #[test]
     fn location_dist() {
         let l0 = Location(0.);
@@ -1168,4 +1838,71 @@ mod test {
         let l1 = Location(0.50);
         assert!(l0.distance(l1) == Distance(0.25));
     }
+
+    #[test]
+    fn last_hop_tracked() {
+        let (tracker, _rx) = LiveTransactionTracker::new();
+        let tx = Transaction::new::<crate::operations::get::GetMsg>();
+        let (upstream, downstream) = (
+            PeerKeyLocation::random().peer,
+            PeerKeyLocation::random().peer,
+        );
+        tracker.add_transaction(upstream.clone(), tx);
+        tracker.add_transaction(downstream.clone(), tx);
+        assert_eq!(tracker.last_hop(&tx), Some(downstream.clone()));
+
+        tracker.prune_transactions_from_peer(&downstream);
+        assert_eq!(tracker.last_hop(&tx), None);
+
+        tracker.add_transaction(upstream, tx);
+        tracker.remove_finished_transaction(tx);
+        assert_eq!(tracker.last_hop(&tx), None);
+    }
+
+    #[tokio::test]
+    async fn health_reports_connections_and_work() -> anyhow::Result<()> {
+        let mut config_args = crate::config::ConfigArgs::default();
+        config_args.id = Some("ring-health".into());
+        let mut config = NodeConfig::new(config_args.build()?).await?;
+        config.router_history = None;
+        config.ban_list = None;
+        let own_peer = PeerId::random();
+        config
+            .with_peer_id(own_peer.clone())
+            .with_location(Location(0.5));
+        let (_notification_rx, notification_tx) = node::event_loop_notification_channel();
+        let ring = Ring::new(
+            &config,
+            notification_tx,
+            crate::tracing::TestEventListener::new().await,
+            false,
+        )?;
+
+        let peers = [0.51, 0.6, 0.9].map(|loc| (Location(loc), PeerId::random()));
+        for (loc, peer) in &peers {
+            ring.add_connection(*loc, peer.clone(), None).await;
+        }
+        let key: ContractKey = ContractInstanceId::new([1; 32]).into();
+        ring.add_subscriber(&key, PeerKeyLocation::random())
+            .unwrap();
+        let tx = Transaction::new::<crate::operations::get::GetMsg>();
+        ring.live_tx_tracker.add_transaction(peers[0].1.clone(), tx);
+
+        let health = ring.health();
+        assert_eq!(health.peer, Some(own_peer));
+        assert_eq!(health.location, Some(Location(0.5)));
+        assert_eq!(health.connections, 3);
+        let buckets = &health.distance_buckets;
+        assert_eq!(buckets.iter().map(|b| b.connections).sum::<usize>(), 3);
+        // only the connection at 0.4 from this peer is in the farthest bucket, [0.25, 0.5]
+        assert_eq!(buckets.last().map(|b| b.connections), Some(1));
+        assert!(health.small_world_deviation.is_some());
+        assert_eq!(health.subscribers.len(), 1);
+        assert_eq!(health.subscribers[0].key, key.to_string());
+        assert_eq!(health.subscribers[0].subscribers, 1);
+        assert_eq!(health.live_transactions.len(), 1);
+        assert_eq!(health.live_transactions[0].transaction, tx.to_string());
+        assert_eq!(health.live_transactions[0].peers, vec![peers[0].1.clone()]);
+        Ok(())
+    }
 }
@@ -0,0 +1,154 @@
+//! Diagnostics report about the position of a peer in the ring, see [`super::Ring::health`].
+use serde::{Deserialize, Serialize};
+
+use super::{Location, PeerId};
+
+/// Max distance between two locations in the ring.
+const MAX_DISTANCE: f64 = 0.5;
+
+/// Snapshot of the position of a peer in the ring and of the work it's carrying out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RingHealth {
+    pub peer: Option<PeerId>,
+    pub location: Option<Location>,
+    /// Estimated number of peers in the network.
+    pub network_size: Option<f64>,
+    pub connections: usize,
+    /// Connections grouped by their distance to this peer, closest first.
+    pub distance_buckets: Vec<DistanceBucket>,
+    /// Total variation distance, in `[0, 1]`, between the distribution of the connections over
+    /// the distance buckets and the ideal small world distribution. `None` without connections.
+    pub small_world_deviation: Option<f64>,
+    /// Contracts seeded by this peer, most valuable first.
+    pub seeding: Vec<SeededContract>,
+    pub subscribers: Vec<ContractSubscribers>,
+    pub live_transactions: Vec<LiveTransaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistanceBucket {
+    /// Distances in the bucket are in `[from, to)`.
+    pub from: f64,
+    pub to: f64,
+    pub connections: usize,
+    /// Fraction of the connections which would fall in the bucket if they followed the ideal
+    /// small world `1/d` distribution.
+    pub ideal_fraction: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeededContract {
+    pub key: String,
+    /// Value of seeding the contract according to the seeding policy of the peer.
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractSubscribers {
+    pub key: String,
+    pub subscribers: usize,
+}
+
+/// Transaction in progress and the peers it's waiting on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveTransaction {
+    pub transaction: String,
+    pub peers: Vec<PeerId>,
+}
+
+/// Groups the distances to the connections of a peer in buckets covering all possible
+/// distances, along with the fraction of connections expected in each of them in a small
+/// world network of the given size.
+///
+/// Each bucket covers distances twice as large as the previous one, so in a small world
+/// network the connections spread about evenly over them.
+pub(super) fn distance_buckets(
+    distances: impl IntoIterator<Item = f64>,
+    network_size: f64,
+) -> Vec<DistanceBucket> {
+    // in a network of N peers no connection is closer than about 1/N
+    let min_distance = (1.0 / network_size).min(MAX_DISTANCE / 2.0);
+    let num_buckets = ((MAX_DISTANCE / min_distance).log2().ceil() as usize).max(1);
+    let mut buckets: Vec<_> = (0..num_buckets)
+        .map(|bucket| {
+            // the closest bucket also holds any distance below the min distance
+            let from = if bucket == 0 {
+                0.0
+            } else {
+                MAX_DISTANCE / 2f64.powi((num_buckets - bucket) as i32)
+            };
+            let to = MAX_DISTANCE / 2f64.powi((num_buckets - bucket - 1) as i32);
+            DistanceBucket {
+                from,
+                to,
+                connections: 0,
+                ideal_fraction: ideal_fraction(from, to, min_distance),
+            }
+        })
+        .collect();
+    for distance in distances {
+        // distances of exactly the max distance fall in the last bucket
+        let bucket = buckets
+            .iter()
+            .rposition(|b| distance >= b.from)
+            .unwrap_or_default();
+        buckets[bucket].connections += 1;
+    }
+    buckets
+}
+
+/// Fraction of the distances following a `1/d` distribution between `min_distance` and the
+/// max distance which are within `[from, to)`.
+fn ideal_fraction(from: f64, to: f64, min_distance: f64) -> f64 {
+    let (from, to) = (from.max(min_distance), to.min(MAX_DISTANCE));
+    if to <= from {
+        return 0.0;
+    }
+    (to / from).ln() / (MAX_DISTANCE / min_distance).ln()
+}
+
+/// Total variation distance between the observed distribution of the connections over the
+/// buckets and the ideal one.
+pub(super) fn small_world_deviation(buckets: &[DistanceBucket]) -> Option<f64> {
+    let total = buckets.iter().map(|b| b.connections).sum::<usize>();
+    if total == 0 {
+        return None;
+    }
+    let deviation = buckets
+        .iter()
+        .map(|b| (b.connections as f64 / total as f64 - b.ideal_fraction).abs())
+        .sum::<f64>()
+        / 2.0;
+    Some(deviation)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn small_world_connections_close_to_ideal() {
+        const NETWORK_SIZE: f64 = 1_000.0;
+        let mut rng = rand::thread_rng();
+        let (min, max) = ((1.0 / NETWORK_SIZE).ln(), MAX_DISTANCE.ln());
+        let small_world = (0..1_000).map(|_| rng.gen_range(min..max).exp());
+        let buckets = distance_buckets(small_world, NETWORK_SIZE);
+        assert_eq!(buckets.len(), 9);
+        assert_eq!(buckets[0].from, 0.0);
+        assert_eq!(buckets.iter().map(|b| b.connections).sum::<usize>(), 1_000);
+        let ideal = buckets.iter().map(|b| b.ideal_fraction).sum::<f64>();
+        assert!((ideal - 1.0).abs() < 1e-9);
+        let small_world = small_world_deviation(&buckets).unwrap();
+
+        let uniform = (0..1_000).map(|_| rng.gen_range(0.0..=MAX_DISTANCE));
+        let buckets = distance_buckets(uniform, NETWORK_SIZE);
+        assert_eq!(buckets.last().unwrap().to, MAX_DISTANCE);
+        let uniform = small_world_deviation(&buckets).unwrap();
+
+        assert!(small_world < 0.1, "small world deviation {small_world}");
+        assert!(uniform > small_world * 2.0, "uniform deviation {uniform}");
+        assert_eq!(small_world_deviation(&[]), None);
+    }
+}
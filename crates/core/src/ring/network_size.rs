@@ -0,0 +1,176 @@
+//! Estimation of the number of peers in the network, used to adapt how many hops requests
+//! are allowed to travel to the size of the network.
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+
+use super::Distance;
+use crate::message::TransactionType;
+
+/// Min number of neighbours needed to estimate the network size from their locations.
+const MIN_NEIGHBOURS: usize = 3;
+
+/// Number of the latest connect results kept to estimate the network size.
+const MAX_CONNECT_SAMPLES: usize = 16;
+
+/// Extra hops over the expected length of a greedy route, to make up for dead ends and
+/// for the estimation error.
+const ROUTING_SLACK: usize = 2;
+
+/// Distances smaller than this are rounded up, so peers sharing a location don't make
+/// the network look infinitely big.
+const MIN_DISTANCE: f64 = 1e-9;
+
+/// Estimates the number of peers in the network from the distances to the neighbours of
+/// this peer and from the results of connect operations.
+pub(crate) struct NetworkSizeEstimator {
+    /// Distance between the location of recent connect requests and the closest peer
+    /// which answered them.
+    closest_peer_distances: Mutex<VecDeque<f64>>,
+}
+
+impl NetworkSizeEstimator {
+    pub fn new() -> Self {
+        Self {
+            closest_peer_distances: Mutex::new(VecDeque::with_capacity(MAX_CONNECT_SAMPLES)),
+        }
+    }
+
+    /// Records the distance between the location a connect request was routed towards and
+    /// the closest peer it reached.
+    pub fn record_closest_peer(&self, distance: Distance) {
+        let mut samples = self.closest_peer_distances.lock();
+        if samples.len() == MAX_CONNECT_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(distance.as_f64());
+    }
+
+    /// Estimated number of peers in the network, given the distances from this peer to its
+    /// neighbours. Returns `None` while there isn't enough evidence.
+    pub fn estimate(&self, neighbour_distances: &[Distance]) -> Option<f64> {
+        let from_neighbours = from_neighbours(neighbour_distances);
+        let from_connects = from_closest_peers(&self.closest_peer_distances.lock());
+        match (from_neighbours, from_connects) {
+            (Some(a), Some(b)) => Some((a * b).sqrt()),
+            (estimate, None) | (None, estimate) => estimate,
+        }
+    }
+}
+
+/// In a small world network the distance to the neighbours of a peer follows a `1/d`
+/// distribution between `1/N` and `1/2`, so the logarithm of the distances is uniformly
+/// distributed and its mean is half way between `ln(1/N)` and `ln(1/2)`.
+fn from_neighbours(distances: &[Distance]) -> Option<f64> {
+    if distances.len() < MIN_NEIGHBOURS {
+        return None;
+    }
+    let mean_ln = distances
+        .iter()
+        .map(|d| d.as_f64().max(MIN_DISTANCE).ln())
+        .sum::<f64>()
+        / distances.len() as f64;
+    let estimate = (-2.0 * mean_ln).exp() / 2.0;
+    // at least this peer and its neighbours are in the network
+    Some(estimate.max(distances.len() as f64 + 1.0))
+}
+
+/// The expected distance from a location to the closest of `N` peers spread over the ring
+/// is `1 / (2 * (N + 1))`.
+fn from_closest_peers(distances: &VecDeque<f64>) -> Option<f64> {
+    if distances.is_empty() {
+        return None;
+    }
+    let mean = distances.iter().map(|d| d.max(MIN_DISTANCE)).sum::<f64>() / distances.len() as f64;
+    Some((1.0 / (2.0 * mean) - 1.0).max(1.0))
+}
+
+/// Hops to live for requests of the given type in a network of the estimated size, never
+/// above the configured max. `random_walk_hops` are the hops connect requests spend
+/// randomly walking the network before being routed towards the joiner.
+pub(crate) fn hops_to_live(
+    network_size: Option<f64>,
+    tx_type: TransactionType,
+    max_hops_to_live: usize,
+    random_walk_hops: usize,
+) -> usize {
+    let Some(network_size) = network_size else {
+        // nothing known about the network yet, err on the side of reaching further
+        return max_hops_to_live;
+    };
+    // greedy routes in a small world network are about log2(N) hops long
+    let routing_hops = network_size.max(2.0).log2().ceil() as usize + ROUTING_SLACK;
+    let hops = match tx_type {
+        TransactionType::Connect => routing_hops + random_walk_hops,
+        TransactionType::Get
+        | TransactionType::Put
+        | TransactionType::Subscribe
+        | TransactionType::Update
+        | TransactionType::Swap => routing_hops,
+    };
+    hops.clamp(1, max_hops_to_live.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    /// Distances to the neighbours of a peer in a small world network of the given size.
+    fn small_world_distances(network_size: f64, neighbours: usize) -> Vec<Distance> {
+        let mut rng = rand::thread_rng();
+        let (min, max) = ((1.0 / network_size).ln(), 0.5f64.ln());
+        (0..neighbours)
+            .map(|_| Distance::new(rng.gen_range(min..max).exp()))
+            .collect()
+    }
+
+    #[test]
+    fn estimate_from_neighbours() {
+        let estimator = NetworkSizeEstimator::new();
+        assert_eq!(estimator.estimate(&small_world_distances(100.0, 2)), None);
+        for size in [50.0, 1_000.0, 100_000.0] {
+            let estimate = estimator
+                .estimate(&small_world_distances(size, 1_000))
+                .unwrap();
+            assert!(
+                estimate > size / 3.0 && estimate < size * 3.0,
+                "estimated {estimate} peers for a network of {size}"
+            );
+        }
+    }
+
+    #[test]
+    fn estimate_from_connect_results() {
+        let estimator = NetworkSizeEstimator::new();
+        assert_eq!(estimator.estimate(&[]), None);
+        let mut rng = rand::thread_rng();
+        for _ in 0..MAX_CONNECT_SAMPLES * 2 {
+            // the closest of 1000 peers spread over the ring
+            let closest = (0..1_000)
+                .map(|_| rng.gen_range(0.0..0.5))
+                .fold(f64::MAX, f64::min);
+            estimator.record_closest_peer(Distance::new(closest));
+        }
+        let estimate = estimator.estimate(&[]).unwrap();
+        assert!(
+            estimate > 300.0 && estimate < 3_000.0,
+            "estimated {estimate} peers for a network of 1000"
+        );
+    }
+
+    #[test]
+    fn hops_to_live_grow_with_network_size() {
+        const MAX_HTL: usize = 20;
+        let get_htl = |size| hops_to_live(size, TransactionType::Get, MAX_HTL, 3);
+        assert_eq!(get_htl(None), MAX_HTL);
+        assert_eq!(get_htl(Some(10.0)), 6);
+        assert!(get_htl(Some(10_000.0)) > get_htl(Some(10.0)));
+        assert_eq!(get_htl(Some(1e12)), MAX_HTL);
+        assert_eq!(
+            hops_to_live(Some(10.0), TransactionType::Connect, MAX_HTL, 3),
+            9
+        );
+    }
+}
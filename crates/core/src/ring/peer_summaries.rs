@@ -0,0 +1,78 @@
+//! Summaries of the state of contracts held by other peers, so updates to a contract can be
+//! sent to them as a delta from the state they have instead of as the whole new state.
+use dashmap::DashMap;
+use freenet_stdlib::prelude::{ContractKey, StateSummary};
+
+use crate::node::PeerId;
+
+pub(crate) struct PeerSummaries {
+    summaries: DashMap<(ContractKey, PeerId), StateSummary<'static>>,
+}
+
+impl PeerSummaries {
+    pub fn new() -> Self {
+        Self {
+            summaries: DashMap::new(),
+        }
+    }
+
+    /// Latest summary of the state of the contract the peer is known to have.
+    pub fn get(&self, key: &ContractKey, peer: &PeerId) -> Option<StateSummary<'static>> {
+        self.summaries
+            .get(&(*key, peer.clone()))
+            .map(|summary| summary.value().clone())
+    }
+
+    /// Records that the peer has, at least, the state summarized. Updates to a contract are
+    /// merged in any order, so the state of the peer can't fall behind it afterwards.
+    pub fn record(&self, key: ContractKey, peer: PeerId, summary: StateSummary<'static>) {
+        self.summaries.insert((key, peer), summary);
+    }
+
+    /// Forgets the summaries of all the contracts held by a peer, e.g. once disconnected.
+    pub fn forget_peer(&self, peer: &PeerId) {
+        self.summaries.retain(|(_, holder), _| holder != peer);
+    }
+
+    /// Forgets the summaries of a contract held by any peer, e.g. once no longer seeded.
+    pub fn forget_contract(&self, key: &ContractKey) {
+        self.summaries.retain(|(contract, _), _| contract != key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::ContractInstanceId;
+
+    use super::*;
+    use crate::ring::PeerKeyLocation;
+
+    #[test]
+    fn summaries_forgotten_with_peer_or_contract() {
+        let summaries = PeerSummaries::new();
+        let summary_of = |key: &ContractKey, peer: &PeerId| {
+            summaries.get(key, peer).map(StateSummary::into_bytes)
+        };
+        let key: ContractKey = ContractInstanceId::new([1; 32]).into();
+        let other_key: ContractKey = ContractInstanceId::new([2; 32]).into();
+        let peer = PeerKeyLocation::random().peer;
+        let other = PeerKeyLocation::random().peer;
+
+        summaries.record(key, peer.clone(), StateSummary::from(vec![1]));
+        summaries.record(other_key, peer.clone(), StateSummary::from(vec![2]));
+        summaries.record(key, other.clone(), StateSummary::from(vec![3]));
+        summaries.record(key, peer.clone(), StateSummary::from(vec![4]));
+        assert_eq!(summary_of(&key, &peer), Some(vec![4]));
+        assert_eq!(summary_of(&other_key, &other), None);
+
+        summaries.forget_peer(&peer);
+        assert_eq!(summary_of(&key, &peer), None);
+        assert_eq!(summary_of(&other_key, &peer), None);
+        assert_eq!(summary_of(&key, &other), Some(vec![3]));
+
+        summaries.record(other_key, other.clone(), StateSummary::from(vec![5]));
+        summaries.forget_contract(&key);
+        assert_eq!(summary_of(&key, &other), None);
+        assert_eq!(summary_of(&other_key, &other), Some(vec![5]));
+    }
+}
@@ -0,0 +1,285 @@
+//! Reputation of the peers this peer interacts with, built from evidence of misbehaviour,
+//! and the list of peers temporarily banned after misbehaving too much.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::node::PeerId;
+
+/// Time after which the penalty accumulated by a peer has halved.
+const PENALTY_HALF_LIFE: Duration = Duration::from_secs(30 * 60);
+
+/// Peers which accumulate this penalty from [attributable](Misbehaviour::attributable)
+/// evidence are banned.
+const BAN_THRESHOLD: f64 = 10.0;
+
+/// Max penalty accumulated from routine failures, which only lowers the score of a peer.
+const MAX_ROUTINE_PENALTY: f64 = 4.0;
+
+/// Duration of the first ban of a peer, doubled every time the peer is banned again.
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Max duration of a ban, bans expired for longer than this are forgotten.
+const MAX_BAN_DURATION: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Evidence of a peer misbehaving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Misbehaviour {
+    /// A request routed to the peer failed.
+    FailedRoute,
+    /// The peer was the last hop of an operation which timed out.
+    Timeout,
+    /// The peer sent a contract state or delta which failed the contract validation.
+    InvalidState,
+    /// The peer sent a message which couldn't be decoded or wasn't expected.
+    ProtocolViolation,
+}
+
+impl Misbehaviour {
+    /// Whether the misbehaviour is proof of the peer acting against the protocol,
+    /// rather than something which also happens to honest peers.
+    fn attributable(self) -> bool {
+        matches!(
+            self,
+            Misbehaviour::InvalidState | Misbehaviour::ProtocolViolation
+        )
+    }
+
+    fn penalty(self) -> f64 {
+        match self {
+            // routine in a healthy network, busy forwarders see plenty of these
+            Misbehaviour::FailedRoute | Misbehaviour::Timeout => 0.1,
+            Misbehaviour::InvalidState => 4.0,
+            Misbehaviour::ProtocolViolation => 5.0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Penalty {
+    /// Penalty from routine failures, capped at [MAX_ROUTINE_PENALTY].
+    routine: f64,
+    /// Penalty from attributable misbehaviour.
+    attributable: f64,
+    updated: Option<Instant>,
+}
+
+impl Penalty {
+    fn decay(&mut self, now: Instant) {
+        if let Some(updated) = self.updated {
+            let elapsed = now.saturating_duration_since(updated);
+            let factor = 0.5f64.powf(elapsed.as_secs_f64() / PENALTY_HALF_LIFE.as_secs_f64());
+            self.routine *= factor;
+            self.attributable *= factor;
+        }
+        self.updated = Some(now);
+    }
+
+    fn total(&self) -> f64 {
+        self.routine + self.attributable
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Ban {
+    peer: PeerId,
+    until: SystemTime,
+    /// Times the peer has been banned, including this one.
+    times: u32,
+}
+
+pub(crate) struct Reputation {
+    penalties: Mutex<HashMap<PeerId, Penalty>>,
+    /// Latest ban of every peer banned recently.
+    bans: Mutex<Vec<Ban>>,
+    /// Where the ban list is persisted, if at all.
+    path: Option<PathBuf>,
+}
+
+impl Reputation {
+    /// Loads the ban list persisted at the given path, if any.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let mut bans = Vec::new();
+        if let Some(path) = &path {
+            match std::fs::read(path) {
+                Ok(data) => match bincode::deserialize::<Vec<Ban>>(&data) {
+                    Ok(persisted) => bans = persisted,
+                    Err(error) => {
+                        tracing::warn!(%error, ?path, "Discarding corrupted ban list");
+                    }
+                },
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    tracing::warn!(%error, ?path, "Failed reading ban list");
+                }
+            }
+        }
+        age_out(&mut bans, SystemTime::now());
+        tracing::debug!(bans = bans.len(), "Loaded ban list");
+        Self {
+            penalties: Mutex::new(HashMap::new()),
+            bans: Mutex::new(bans),
+            path,
+        }
+    }
+
+    /// Score of the peer in `(0, 1]`, where 1 means it hasn't misbehaved recently.
+    pub fn score(&self, peer: &PeerId) -> f64 {
+        let penalty = self.penalties.lock().get_mut(peer).map_or(0.0, |penalty| {
+            penalty.decay(Instant::now());
+            penalty.total()
+        });
+        1.0 / (1.0 + penalty)
+    }
+
+    /// Records evidence of the peer misbehaving, returns whether the peer got banned.
+    pub fn record(&self, peer: &PeerId, misbehaviour: Misbehaviour) -> bool {
+        let now = Instant::now();
+        let mut penalties = self.penalties.lock();
+        let penalty = penalties.entry(peer.clone()).or_default();
+        penalty.decay(now);
+        if misbehaviour.attributable() {
+            penalty.attributable += misbehaviour.penalty();
+        } else {
+            penalty.routine = (penalty.routine + misbehaviour.penalty()).min(MAX_ROUTINE_PENALTY);
+        }
+        tracing::debug!(%peer, ?misbehaviour, penalty = penalty.total(), "Peer misbehaved");
+        if penalty.attributable < BAN_THRESHOLD {
+            return false;
+        }
+        penalties.remove(peer);
+        std::mem::drop(penalties);
+        self.ban(peer, SystemTime::now());
+        true
+    }
+
+    fn ban(&self, peer: &PeerId, now: SystemTime) {
+        let mut bans = self.bans.lock();
+        let times = bans
+            .iter()
+            .find(|ban| &ban.peer == peer)
+            .map_or(0, |ban| ban.times)
+            + 1;
+        bans.retain(|ban| &ban.peer != peer);
+        let duration = BAN_DURATION
+            .saturating_mul(2u32.saturating_pow(times - 1))
+            .min(MAX_BAN_DURATION);
+        tracing::warn!(%peer, ?duration, "Banning misbehaving peer");
+        bans.push(Ban {
+            peer: peer.clone(),
+            until: now + duration,
+            times,
+        });
+    }
+
+    /// Whether the peer is banned, peers reusing the public key of a banned peer from
+    /// another address are banned too.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        let now = SystemTime::now();
+        self.bans
+            .lock()
+            .iter()
+            .any(|ban| ban.until > now && ban.peer.pub_key == peer.pub_key)
+    }
+
+    /// Ages out old bans and encodes the rest to be persisted, returns `None` if
+    /// persistence is disabled.
+    pub fn to_persist(&self) -> Option<(PathBuf, bincode::Result<Vec<u8>>)> {
+        let path = self.path.clone()?;
+        let mut bans = self.bans.lock();
+        age_out(&mut bans, SystemTime::now());
+        Some((path, bincode::serialize(&*bans)))
+    }
+}
+
+fn age_out(bans: &mut Vec<Ban>, now: SystemTime) {
+    bans.retain(|ban| {
+        now.duration_since(ban.until)
+            .map_or(true, |expired_for| expired_for < MAX_BAN_DURATION)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ring::PeerKeyLocation;
+
+    #[test]
+    fn misbehaving_peer_banned() {
+        let reputation = Reputation::load(None);
+        let peer = PeerKeyLocation::random().peer;
+        let other = PeerKeyLocation::random().peer;
+
+        assert_eq!(reputation.score(&peer), 1.0);
+        assert!(!reputation.record(&peer, Misbehaviour::FailedRoute));
+        assert!(reputation.score(&peer) < 1.0);
+        assert_eq!(reputation.score(&other), 1.0);
+
+        let mut banned = false;
+        for _ in 0..3 {
+            banned = reputation.record(&peer, Misbehaviour::InvalidState);
+        }
+        assert!(banned);
+        assert!(reputation.is_banned(&peer));
+        assert!(!reputation.is_banned(&other));
+
+        let same_key = PeerId::new(other.addr, peer.pub_key.clone());
+        assert!(reputation.is_banned(&same_key));
+        let same_addr = PeerId::new(peer.addr, other.pub_key.clone());
+        assert!(!reputation.is_banned(&same_addr));
+    }
+
+    #[test]
+    fn routine_failures_never_ban() {
+        let reputation = Reputation::load(None);
+        let peer = PeerKeyLocation::random().peer;
+        for _ in 0..1000 {
+            assert!(!reputation.record(&peer, Misbehaviour::FailedRoute));
+            assert!(!reputation.record(&peer, Misbehaviour::Timeout));
+        }
+        assert!(!reputation.is_banned(&peer));
+        assert!((reputation.score(&peer) - 1.0 / (1.0 + MAX_ROUTINE_PENALTY)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn repeated_bans_last_longer() {
+        let reputation = Reputation::load(None);
+        let peer = PeerKeyLocation::random().peer;
+        let now = SystemTime::now();
+        reputation.ban(&peer, now);
+        reputation.ban(&peer, now);
+        let bans = reputation.bans.lock();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].times, 2);
+        assert_eq!(bans[0].until, now + BAN_DURATION * 2);
+    }
+
+    #[tokio::test]
+    async fn ban_list_reloaded_from_disk() -> anyhow::Result<()> {
+        let dir = crate::util::tests::get_temp_dir();
+        let path = dir.path().join("ban_list");
+        let (banned, expired) = (
+            PeerKeyLocation::random().peer,
+            PeerKeyLocation::random().peer,
+        );
+
+        let reputation = Reputation::load(Some(path.clone()));
+        reputation.ban(&banned, SystemTime::now());
+        reputation.ban(
+            &expired,
+            SystemTime::now() - BAN_DURATION - Duration::from_secs(1),
+        );
+        let (path, data) = reputation.to_persist().expect("persistence enabled");
+        crate::util::persist_atomically(&path, &data?).await?;
+
+        let reloaded = Reputation::load(Some(path));
+        assert!(reloaded.is_banned(&banned));
+        assert!(!reloaded.is_banned(&expired));
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! Policies deciding which contracts this peer caches and keeps seeding.
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use freenet_stdlib::prelude::ContractKey;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::Distance;
+use crate::util::time_source::{InstantTimeSrc, TimeSource};
+
+/// Max number of seeding contracts.
+const MAX_SEEDING_CONTRACTS: usize = 100;
+
+/// Min number of seeding contracts.
+const MIN_SEEDING_CONTRACTS: usize = MAX_SEEDING_CONTRACTS / 4;
+
+/// While under the max number of seeding contracts, contracts scoring at least as a
+/// contract at this distance which is not being requested are seeded.
+const CACHING_DISTANCE: f64 = 0.05;
+
+/// Recent requests at which the frequency factor is half way to its max.
+const HALF_FREQUENCY_REQUESTS: f64 = 5.0;
+
+/// Time after which the recency factor of a contract has halved.
+const RECENCY_HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+
+/// State size at which the size factor is half way to its max.
+const HALF_STATE_SIZE: f64 = 1024.0 * 1024.0;
+
+/// Time the score of the least valuable seeded contract is cached for.
+const MIN_SCORE_TTL: Duration = Duration::from_secs(30);
+
+/// What is known about a contract when deciding whether to seed it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SeedCandidate {
+    /// Distance between the contract and this peer.
+    pub distance: Distance,
+    /// Requests for the contract recently seen by this peer.
+    pub requests: usize,
+    /// Time since the latest request for the contract, if it has been requested recently.
+    pub since_last_request: Option<Duration>,
+    /// Size of the contract state in bytes, if known.
+    pub state_size: Option<usize>,
+}
+
+/// Decides how valuable it is for this peer to seed a contract.
+pub(crate) trait SeedingPolicy: Send + Sync {
+    /// The higher the score the more valuable it is to seed the contract.
+    fn score(&self, contract: &SeedCandidate) -> f64;
+}
+
+/// Weights of the factors mixed by the default seeding policy, every factor is
+/// normalized to `[0, 1]` before being weighted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SeedingWeights {
+    /// How close the contract is to this peer.
+    pub distance: f64,
+    /// How often the contract is requested.
+    pub frequency: f64,
+    /// How recently the contract was requested.
+    pub recency: f64,
+    /// Penalty for the size of the contract state.
+    pub size: f64,
+}
+
+impl SeedingWeights {
+    /// Seed contracts based on their distance to this peer alone.
+    pub fn distance_only() -> Self {
+        Self {
+            distance: 1.0,
+            frequency: 0.0,
+            recency: 0.0,
+            size: 0.0,
+        }
+    }
+}
+
+impl Default for SeedingWeights {
+    fn default() -> Self {
+        Self {
+            distance: 1.0,
+            frequency: 1.0,
+            recency: 0.25,
+            size: 0.25,
+        }
+    }
+}
+
+impl SeedingPolicy for SeedingWeights {
+    fn score(&self, contract: &SeedCandidate) -> f64 {
+        let closeness = 1.0 - 2.0 * contract.distance.as_f64();
+        let requests = contract.requests as f64;
+        let frequency = requests / (requests + HALF_FREQUENCY_REQUESTS);
+        let recency = contract.since_last_request.map_or(0.0, |elapsed| {
+            0.5f64.powf(elapsed.as_secs_f64() / RECENCY_HALF_LIFE.as_secs_f64())
+        });
+        let size = contract
+            .state_size
+            .map_or(0.0, |size| size as f64 / (size as f64 + HALF_STATE_SIZE));
+        self.distance * closeness + self.frequency * frequency + self.recency * recency
+            - self.size * size
+    }
+}
+
+/// Contracts seeded by this peer, chosen by a [`SeedingPolicy`].
+pub(crate) struct SeedingManager<T: TimeSource = InstantTimeSrc> {
+    policy: Box<dyn SeedingPolicy>,
+    /// Seeded contracts and the size of their state, if known.
+    seeding: DashMap<ContractKey, Option<usize>>,
+    /// Least valuable seeded contract, its score and when it was scored. Scoring every seeded
+    /// contract on each request is expensive, so it's only done again after `MIN_SCORE_TTL`
+    /// or when the seeded contracts change.
+    least_valuable: Mutex<Option<(ContractKey, f64, Instant)>>,
+    time_source: T,
+}
+
+impl SeedingManager {
+    pub fn new(policy: Box<dyn SeedingPolicy>) -> Self {
+        Self::with_time_source(policy, InstantTimeSrc::new())
+    }
+}
+
+#[cfg(test)]
+impl SeedingManager<crate::util::time_source::MockTimeSource> {
+    fn advance_time(&mut self, duration: Duration) {
+        self.time_source.advance_time(duration);
+    }
+}
+
+impl<T: TimeSource> SeedingManager<T> {
+    fn with_time_source(policy: Box<dyn SeedingPolicy>, time_source: T) -> Self {
+        Self {
+            policy,
+            seeding: DashMap::new(),
+            least_valuable: Mutex::new(None),
+            time_source,
+        }
+    }
+
+    pub fn is_seeding(&self, key: &ContractKey) -> bool {
+        self.seeding.contains_key(key)
+    }
+
+    /// Whether the contract is worth seeding, `candidate` returns what is currently known
+    /// about a contract given its state size.
+    pub fn should_seed(
+        &self,
+        key: &ContractKey,
+        state_size: Option<usize>,
+        candidate: impl Fn(&ContractKey, Option<usize>) -> SeedCandidate,
+    ) -> bool {
+        if self.seeding.len() < MIN_SEEDING_CONTRACTS {
+            return true;
+        }
+        let score = self.policy.score(&candidate(key, state_size));
+        if self.seeding.len() < MAX_SEEDING_CONTRACTS {
+            // while there is room the state size is not penalized
+            let threshold = SeedCandidate {
+                distance: Distance::new(CACHING_DISTANCE),
+                requests: 0,
+                since_last_request: None,
+                state_size,
+            };
+            return score >= self.policy.score(&threshold);
+        }
+        self.least_valuable(&candidate)
+            .map_or(true, |(_, min_score)| score > min_score)
+    }
+
+    /// Starts seeding the contract, returns the least valuable contract dropped to make
+    /// room for it, if any.
+    pub fn seed(
+        &self,
+        key: ContractKey,
+        state_size: Option<usize>,
+        candidate: impl Fn(&ContractKey, Option<usize>) -> SeedCandidate,
+    ) -> Option<ContractKey> {
+        let mut dropped = None;
+        if self.seeding.len() >= MAX_SEEDING_CONTRACTS && !self.seeding.contains_key(&key) {
+            if let Some((least_valuable, _)) = self.least_valuable(&candidate) {
+                self.seeding.remove(&least_valuable);
+                dropped = Some(least_valuable);
+            }
+        }
+        self.seeding.insert(key, state_size);
+        *self.least_valuable.lock() = None;
+        dropped
+    }
+
+    /// Seeded contracts and their current scores.
+    pub fn scores(
+        &self,
+        candidate: impl Fn(&ContractKey, Option<usize>) -> SeedCandidate,
+    ) -> Vec<(ContractKey, f64)> {
+        self.seeding
+            .iter()
+            .map(|e| (*e.key(), self.policy.score(&candidate(e.key(), *e.value()))))
+            .collect()
+    }
+
+    fn least_valuable(
+        &self,
+        candidate: impl Fn(&ContractKey, Option<usize>) -> SeedCandidate,
+    ) -> Option<(ContractKey, f64)> {
+        let now = self.time_source.now();
+        let cached = &mut *self.least_valuable.lock();
+        if let Some((key, score, scored_at)) = *cached {
+            if now.duration_since(scored_at) < MIN_SCORE_TTL {
+                return Some((key, score));
+            }
+        }
+        let least_valuable = self
+            .scores(candidate)
+            .into_iter()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        *cached = least_valuable.map(|(key, score)| (key, score, now));
+        least_valuable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::{HashMap, HashSet},
+    };
+
+    use freenet_stdlib::prelude::ContractInstanceId;
+    use rand::{distributions::WeightedIndex, prelude::Distribution, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        ring::Location, topology::request_density_tracker::RequestDensityTracker,
+        util::time_source::MockTimeSource,
+    };
+
+    const STATE_SIZE: Option<usize> = Some(10 * 1024);
+
+    /// Simulated time between requests.
+    const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+    type Candidate<'a> = &'a dyn Fn(&ContractKey, Option<usize>) -> SeedCandidate;
+
+    /// Simulates the GET requests seen by a peer at location 0.5 for contracts which
+    /// popularity follows a power law unrelated to their location. `serve` returns whether
+    /// a request was served from the cache, and caches the contract if it's worth it.
+    /// Returns the fraction of requests served from the cache.
+    fn get_hit_rate(mut serve: impl FnMut(&ContractKey, Candidate) -> bool) -> f64 {
+        const CONTRACTS: usize = 2_000;
+        const REQUESTS: usize = 20_000;
+
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(7);
+        let own_location = Location::new(0.5);
+        let contracts: Vec<ContractKey> = (0..CONTRACTS)
+            .map(|_| ContractInstanceId::new(rng.gen()).into())
+            .collect();
+        let popularity = WeightedIndex::new((1..=CONTRACTS).map(|rank| 1.0 / rank as f64))
+            .expect("valid weights");
+
+        let mut requests = RequestDensityTracker::new(1_000);
+        let mut last_requests = HashMap::new();
+        let mut now = Instant::now();
+        let mut hits = 0;
+        for _ in 0..REQUESTS {
+            now += REQUEST_INTERVAL;
+            let key = &contracts[popularity.sample(&mut rng)];
+            requests.sample(Location::from(key));
+            last_requests.insert(*key, now);
+            let candidate = |key: &ContractKey, state_size| {
+                let location = Location::from(key);
+                SeedCandidate {
+                    distance: own_location.distance(location),
+                    requests: requests.requests_at(location).0,
+                    since_last_request: last_requests.get(key).map(|at| now - *at),
+                    state_size,
+                }
+            };
+            if serve(key, &candidate) {
+                hits += 1;
+            }
+        }
+        hits as f64 / REQUESTS as f64
+    }
+
+    /// Policy of the peer before [`SeedingPolicy`] was introduced: seeds everything under the
+    /// min, contracts within the caching distance under the max, and once full replaces the
+    /// farthest seeded contract with closer ones.
+    fn previous_policy() -> impl FnMut(&ContractKey, Candidate) -> bool {
+        let mut seeding = HashSet::new();
+        move |key, candidate| {
+            if seeding.contains(key) {
+                return true;
+            }
+            let score = |key: &ContractKey| 0.5 - candidate(key, None).distance.as_f64();
+            let seed = if seeding.len() < MIN_SEEDING_CONTRACTS {
+                true
+            } else if seeding.len() < MAX_SEEDING_CONTRACTS {
+                candidate(key, None).distance.as_f64() <= CACHING_DISTANCE
+            } else {
+                let farthest = *seeding
+                    .iter()
+                    .min_by(|a, b| score(*a).total_cmp(&score(*b)))
+                    .expect("full");
+                let replace = score(key) > score(&farthest);
+                if replace {
+                    seeding.remove(&farthest);
+                }
+                replace
+            };
+            if seed {
+                seeding.insert(*key);
+            }
+            false
+        }
+    }
+
+    #[test]
+    fn popular_contracts_improve_hit_rate() {
+        let previous = get_hit_rate(previous_policy());
+        let mut seeding = SeedingManager::with_time_source(
+            Box::new(SeedingWeights::default()),
+            MockTimeSource::new(Instant::now()),
+        );
+        let weighted = get_hit_rate(|key, candidate| {
+            seeding.advance_time(REQUEST_INTERVAL);
+            if seeding.is_seeding(key) {
+                return true;
+            }
+            if seeding.should_seed(key, STATE_SIZE, candidate) {
+                seeding.seed(*key, STATE_SIZE, candidate);
+            }
+            false
+        });
+        assert!(
+            weighted > previous * 2.0,
+            "weighted hit rate {weighted} not better than the previous policy {previous}"
+        );
+    }
+
+    #[test]
+    fn recently_requested_contracts_replace_stale_ones() {
+        let mut seeding = SeedingManager::with_time_source(
+            Box::new(SeedingWeights::default()),
+            MockTimeSource::new(Instant::now()),
+        );
+        let key: ContractKey = ContractInstanceId::new([u8::MAX; 32]).into();
+        let since_seeded_requested = Cell::new(Duration::ZERO);
+        let candidate = |other: &ContractKey, state_size| SeedCandidate {
+            distance: Distance::new(0.1),
+            requests: 1,
+            since_last_request: Some(if other == &key {
+                Duration::ZERO
+            } else {
+                since_seeded_requested.get()
+            }),
+            state_size,
+        };
+        for i in 0..MAX_SEEDING_CONTRACTS {
+            seeding.seed(
+                ContractInstanceId::new([i as u8; 32]).into(),
+                None,
+                candidate,
+            );
+        }
+        // as valuable as the seeded contracts while they are requested as recently
+        assert!(!seeding.should_seed(&key, None, candidate));
+
+        since_seeded_requested.set(RECENCY_HALF_LIFE * 2);
+        // the least valuable contract is not scored again right away
+        assert!(!seeding.should_seed(&key, None, candidate));
+        seeding.advance_time(MIN_SCORE_TTL);
+        assert!(seeding.should_seed(&key, None, candidate));
+    }
+
+    #[test]
+    fn distance_only_matches_caching_distance() {
+        let seeding = SeedingManager::new(Box::new(SeedingWeights::distance_only()));
+        for i in 0..MIN_SEEDING_CONTRACTS {
+            seeding.seed(
+                ContractInstanceId::new([i as u8; 32]).into(),
+                None,
+                |_, _| unreachable!("no contract is dropped under the min"),
+            );
+        }
+        let key: ContractKey = ContractInstanceId::new([u8::MAX; 32]).into();
+        let at = |distance: f64| {
+            move |_: &ContractKey, state_size| SeedCandidate {
+                distance: Distance::new(distance),
+                requests: 10,
+                since_last_request: Some(Duration::ZERO),
+                state_size,
+            }
+        };
+        assert!(seeding.should_seed(&key, None, at(CACHING_DISTANCE - 0.01)));
+        assert!(!seeding.should_seed(&key, None, at(CACHING_DISTANCE + 0.01)));
+    }
+}
@@ -1,3 +1,4 @@
+mod history;
 mod isotonic_estimator;
 mod util;
 
@@ -7,6 +8,8 @@ use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use util::{Mean, TransferSpeed};
 
+pub(crate) use history::RouteHistory;
+
 /// # Usage
 /// Important when using this type:
 /// Need to periodically rebuild the Router using `history` for better predictions.
@@ -180,42 +183,93 @@ impl Router {
             .collect()
     }
 
+    /// Selects the peer to route to, `reputation` scores peers in `(0, 1]` so peers which
+    /// misbehaved are less likely to be selected.
     pub fn select_peer<'a>(
         &self,
         peers: impl IntoIterator<Item = &'a PeerKeyLocation>,
         target_location: Location,
+        reputation: impl Fn(&PeerKeyLocation) -> f64,
     ) -> Option<&'a PeerKeyLocation> {
-        if !self.has_sufficient_historical_data() {
-            // Find the peer with the minimum distance to the contract location,
-            // ignoring peers with no location
-            peers
-                .into_iter()
-                .filter_map(|peer| {
-                    peer.location
-                        .map(|loc| (peer, target_location.distance(loc)))
-                })
-                .min_by_key(|&(_, distance)| distance)
-                .map(|(peer, _)| peer)
-        } else {
-            // Find the peer with the minimum predicted routing outcome time
-            self.select_closest_peers(peers, &target_location)
-                .into_iter()
-                .map(|peer: &PeerKeyLocation| {
-                    let t = self.predict_routing_outcome(peer, target_location).expect(
-                        "Should always be Ok when has_sufficient_historical_data() is true",
-                    );
-                    (peer, t.time_to_response_start)
-                })
-                // Required because f64 doesn't implement Ord
-                .min_by(|&(_, time1), &(_, time2)| {
-                    time1
-                        .partial_cmp(&time2)
-                        .unwrap_or(std::cmp::Ordering::Equal)
-                })
-                .map(|(peer, _)| peer)
+        let scored = self.score_peers(peers, target_location, reputation);
+        self.cheapest(&scored).map(|scored| scored.peer)
+    }
+
+    /// Explains which peer [`Self::select_peer`] would select for the target location and why.
+    pub fn explain<'a>(
+        &self,
+        peers: impl IntoIterator<Item = &'a PeerKeyLocation>,
+        target_location: Location,
+        reputation: impl Fn(&PeerKeyLocation) -> f64,
+    ) -> RoutingExplanation {
+        let scored = self.score_peers(peers, target_location, reputation);
+        let selected = self.cheapest(&scored).map(|scored| scored.peer.clone());
+        let candidates = scored
+            .into_iter()
+            .map(|scored| {
+                let (prediction, error) = match scored.prediction {
+                    Ok(prediction) => (Some(prediction), None),
+                    Err(err) => (None, Some(err.to_string())),
+                };
+                RoutingCandidate {
+                    peer: scored.peer.clone(),
+                    distance: scored.distance,
+                    reputation: scored.reputation,
+                    prediction,
+                    error,
+                }
+            })
+            .collect();
+        RoutingExplanation {
+            target: target_location,
+            sufficient_data: self.has_sufficient_historical_data(),
+            estimators: EstimatorsData {
+                response_start_time: self.response_start_time_estimator.len(),
+                transfer_rate: self.transfer_rate_estimator.len(),
+                failure: self.failure_estimator.len(),
+                required: MIN_HISTORICAL_DATA,
+            },
+            candidates,
+            selected,
         }
     }
 
+    /// Scores the closest peers to the target location.
+    fn score_peers<'a>(
+        &self,
+        peers: impl IntoIterator<Item = &'a PeerKeyLocation>,
+        target_location: Location,
+        reputation: impl Fn(&PeerKeyLocation) -> f64,
+    ) -> Vec<ScoredPeer<'a>> {
+        self.select_closest_peers(peers, &target_location)
+            .into_iter()
+            .map(|peer| ScoredPeer {
+                peer,
+                distance: peer
+                    .location
+                    .map(|loc| target_location.distance(loc).as_f64())
+                    .unwrap_or(f64::MAX),
+                reputation: reputation(peer),
+                prediction: self.predict_routing_outcome(peer, target_location),
+            })
+            .collect()
+    }
+
+    /// The scored peer with the lowest routing cost.
+    fn cheapest<'s, 'a>(&self, scored: &'s [ScoredPeer<'a>]) -> Option<&'s ScoredPeer<'a>> {
+        let sufficient_data = self.has_sufficient_historical_data();
+        scored
+            .iter()
+            .filter_map(|peer| Some((peer, peer.cost(sufficient_data)?)))
+            // Required because f64 doesn't implement Ord
+            .min_by(|&(_, cost1), &(_, cost2)| {
+                cost1
+                    .partial_cmp(&cost2)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .map(|(peer, _)| peer)
+    }
+
     fn predict_routing_outcome(
         &self,
         peer: &PeerKeyLocation,
@@ -266,11 +320,37 @@ impl Router {
     }
 
     fn has_sufficient_historical_data(&self) -> bool {
-        let minimum_historical_data_for_global_prediction = 200;
-        self.response_start_time_estimator.len() >= minimum_historical_data_for_global_prediction
+        self.response_start_time_estimator.len() >= MIN_HISTORICAL_DATA
     }
 }
 
+/// A peer considered for routing, scored the same way when selecting and explaining the
+/// selection.
+struct ScoredPeer<'a> {
+    peer: &'a PeerKeyLocation,
+    distance: f64,
+    reputation: f64,
+    prediction: Result<RoutingPrediction, RoutingError>,
+}
+
+impl ScoredPeer<'_> {
+    /// Cost of routing to this peer: the predicted time until the response starts arriving
+    /// once the estimators have enough data, the distance to the target before that. Divided
+    /// by the reputation so peers which misbehaved are avoided.
+    fn cost(&self, sufficient_data: bool) -> Option<f64> {
+        let cost = if sufficient_data {
+            self.prediction.as_ref().ok()?.time_to_response_start
+        } else {
+            self.distance
+        };
+        Some(cost / self.reputation)
+    }
+}
+
+/// Number of successful routing events required before the estimators are used for
+/// routing instead of just picking the closest peer.
+const MIN_HISTORICAL_DATA: usize = 200;
+
 #[derive(Debug, thiserror::Error)]
 enum RoutingError {
     #[error("Insufficient data provided")]
@@ -283,12 +363,47 @@ enum RoutingError {
     },
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
-struct RoutingPrediction {
-    failure_probability: f64,
-    xfer_speed: TransferSpeed,
-    time_to_response_start: f64,
-    expected_total_time: f64,
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RoutingPrediction {
+    pub failure_probability: f64,
+    pub xfer_speed: TransferSpeed,
+    /// Seconds until the response starts arriving.
+    pub time_to_response_start: f64,
+    /// Seconds until the whole response is received, accounting for the cost of failures.
+    pub expected_total_time: f64,
+}
+
+/// Routing decision for a target location, see [`Router::explain`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingExplanation {
+    pub target: Location,
+    /// Whether the estimators had enough data to be used, otherwise the closest peer is selected.
+    pub sufficient_data: bool,
+    pub estimators: EstimatorsData,
+    /// Closest peers to the target which were considered, ordered by distance.
+    pub candidates: Vec<RoutingCandidate>,
+    pub selected: Option<PeerKeyLocation>,
+}
+
+/// Number of events each estimator has been trained with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EstimatorsData {
+    pub response_start_time: usize,
+    pub transfer_rate: usize,
+    pub failure: usize,
+    /// Events required by the response start time estimator before predictions are used.
+    pub required: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingCandidate {
+    pub peer: PeerKeyLocation,
+    pub distance: f64,
+    /// Score of the peer in `(0, 1]`, lower for peers which misbehaved recently.
+    pub reputation: f64,
+    pub prediction: Option<RoutingPrediction>,
+    /// Why a prediction couldn't be made for this peer.
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -333,7 +448,9 @@ mod tests {
         for _ in 0..10 {
             let contract_location = Location::random();
             // Pass a reference to the `peers` vector
-            let best = router.select_peer(&peers, contract_location).unwrap();
+            let best = router
+                .select_peer(&peers, contract_location, |_| 1.0)
+                .unwrap();
             let best_distance = best.location.unwrap().distance(contract_location);
             for peer in &peers {
                 // Dereference `best` when making the comparison
@@ -345,6 +462,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn misbehaving_peer_avoided() {
+        let router = Router::new(&[]);
+        let target = Location::new(0.5);
+        let closest = PeerKeyLocation {
+            location: Some(Location::new(0.51)),
+            ..PeerKeyLocation::random()
+        };
+        let second = PeerKeyLocation {
+            location: Some(Location::new(0.45)),
+            ..PeerKeyLocation::random()
+        };
+        let peers = [closest.clone(), second.clone()];
+
+        assert_eq!(router.select_peer(&peers, target, |_| 1.0), Some(&closest));
+        let reputation = |peer: &PeerKeyLocation| if peer == &closest { 0.1 } else { 1.0 };
+        assert_eq!(
+            router.select_peer(&peers, target, reputation),
+            Some(&second)
+        );
+        assert_eq!(
+            router.explain(&peers, target, reputation).selected,
+            Some(second)
+        );
+    }
+
     #[test]
     fn test_request_time() {
         // Define constants for the number of peers, number of events, and number of test iterations.
@@ -471,6 +614,73 @@ mod tests {
         assert_eq!(expected_iter.next(), asserted_iter.next());
     }
 
+    #[test]
+    fn explain_matches_selection() {
+        let peers = create_peers(25);
+        let target = Location::random();
+
+        let router = Router::new(&[]);
+        let explanation = router.explain(&peers, target, |_| 1.0);
+        assert!(!explanation.sufficient_data);
+        assert_eq!(explanation.candidates.len(), 2);
+        assert!(explanation
+            .candidates
+            .iter()
+            .all(|c| c.prediction.is_none() && c.error.is_some()));
+        assert_eq!(
+            explanation.selected.as_ref(),
+            router.select_peer(&peers, target, |_| 1.0)
+        );
+
+        let mut rng = rand::thread_rng();
+        let events: Vec<_> = (0..1000)
+            .map(|_| {
+                let peer = peers[rng.gen_range(0..peers.len())].clone();
+                let contract_location = Location::random();
+                let prediction = simulate_prediction(&mut rng, peer.clone(), contract_location);
+                RouteEvent {
+                    peer,
+                    contract_location,
+                    outcome: RouteOutcome::Success {
+                        time_to_response_start: Duration::from_secs_f64(
+                            prediction.time_to_response_start,
+                        ),
+                        payload_size: 1000,
+                        payload_transfer_time: Duration::from_secs_f64(
+                            1000.0 / prediction.xfer_speed.bytes_per_second,
+                        ),
+                    },
+                }
+            })
+            .collect();
+        let router = Router::new(&events);
+        let explanation = router.explain(&peers, target, |_| 1.0);
+        assert!(explanation.sufficient_data);
+        assert!(explanation.estimators.response_start_time >= explanation.estimators.required);
+        assert!(explanation
+            .candidates
+            .windows(2)
+            .all(|w| w[0].distance <= w[1].distance));
+        assert!(explanation
+            .candidates
+            .iter()
+            .all(|c| c.prediction.is_some()));
+        assert_eq!(
+            explanation.selected.as_ref(),
+            router.select_peer(&peers, target, |_| 1.0)
+        );
+
+        // both weigh the reputation of the peers the same way
+        let penalized = explanation.selected.unwrap();
+        let reputation = |peer: &PeerKeyLocation| if peer == &penalized { 1e-6 } else { 1.0 };
+        let explanation = router.explain(&peers, target, reputation);
+        assert_ne!(explanation.selected.as_ref(), Some(&penalized));
+        assert_eq!(
+            explanation.selected.as_ref(),
+            router.select_peer(&peers, target, reputation)
+        );
+    }
+
     fn simulate_prediction(
         random: &mut rand::rngs::ThreadRng,
         peer: PeerKeyLocation,
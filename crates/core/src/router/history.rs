@@ -0,0 +1,166 @@
+//! Window of the most recent routing events, persisted so the router doesn't have to
+//! learn from scratch after the node is restarted.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::RouteEvent;
+use crate::node::PeerId;
+
+/// Max number of events kept in the history.
+const MAX_EVENTS: usize = 10_000;
+
+/// Events older than this are dropped from the history.
+const MAX_EVENT_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Events of peers which have not been routed to for this long are dropped, since
+/// the peer most likely left the network.
+const PEER_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryEntry {
+    recorded_at: SystemTime,
+    event: RouteEvent,
+}
+
+pub(crate) struct RouteHistory {
+    /// Where the history is persisted, if at all.
+    path: Option<PathBuf>,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl RouteHistory {
+    /// Loads the history persisted at the given path, if any.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let mut entries = VecDeque::new();
+        if let Some(path) = &path {
+            match std::fs::read(path) {
+                Ok(data) => match bincode::deserialize::<Vec<HistoryEntry>>(&data) {
+                    Ok(persisted) => entries.extend(persisted),
+                    Err(error) => {
+                        tracing::warn!(%error, ?path, "Discarding corrupted router history");
+                    }
+                },
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    tracing::warn!(%error, ?path, "Failed reading router history");
+                }
+            }
+        }
+        let mut history = Self { path, entries };
+        history.age_out(SystemTime::now());
+        tracing::debug!(events = history.entries.len(), "Loaded router history");
+        history
+    }
+
+    pub fn record(&mut self, event: RouteEvent) {
+        self.entries.push_back(HistoryEntry {
+            recorded_at: SystemTime::now(),
+            event,
+        });
+        if self.entries.len() > MAX_EVENTS {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn events(&self) -> Vec<RouteEvent> {
+        self.entries.iter().map(|e| e.event.clone()).collect()
+    }
+
+    /// Ages out stale events and encodes the rest to be persisted, returns `None` if
+    /// persistence is disabled.
+    pub fn to_persist(&mut self) -> Option<(PathBuf, bincode::Result<Vec<u8>>)> {
+        let path = self.path.clone()?;
+        self.age_out(SystemTime::now());
+        Some((path, bincode::serialize(&self.entries)))
+    }
+
+    fn age_out(&mut self, now: SystemTime) {
+        let age = |entry: &HistoryEntry| {
+            now.duration_since(entry.recorded_at)
+                .unwrap_or(Duration::ZERO)
+        };
+        let mut last_seen: HashMap<&PeerId, Duration> = HashMap::new();
+        for entry in &self.entries {
+            let seen = last_seen
+                .entry(&entry.event.peer.peer)
+                .or_insert(Duration::MAX);
+            *seen = (*seen).min(age(entry));
+        }
+        let known_peers: HashSet<PeerId> = last_seen
+            .into_iter()
+            .filter(|(_, seen)| *seen < PEER_TTL)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        self.entries.retain(|entry| {
+            age(entry) < MAX_EVENT_AGE && known_peers.contains(&entry.event.peer.peer)
+        });
+        while self.entries.len() > MAX_EVENTS {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ring::{Location, PeerKeyLocation},
+        router::RouteOutcome,
+    };
+
+    fn event(peer: &PeerKeyLocation) -> RouteEvent {
+        RouteEvent {
+            peer: peer.clone(),
+            contract_location: Location::random(),
+            outcome: RouteOutcome::Failure,
+        }
+    }
+
+    #[test]
+    fn stale_peers_aged_out() {
+        let now = SystemTime::now();
+        let (gone, active) = (PeerKeyLocation::random(), PeerKeyLocation::random());
+        let mut history = RouteHistory::load(None);
+        history.entries.extend([
+            HistoryEntry {
+                recorded_at: now - PEER_TTL - Duration::from_secs(60),
+                event: event(&gone),
+            },
+            HistoryEntry {
+                recorded_at: now - PEER_TTL - Duration::from_secs(60),
+                event: event(&active),
+            },
+            HistoryEntry {
+                recorded_at: now - MAX_EVENT_AGE,
+                event: event(&active),
+            },
+        ]);
+        history.record(event(&active));
+        history.age_out(now);
+        let peers: Vec<_> = history.events().into_iter().map(|e| e.peer).collect();
+        assert_eq!(peers, vec![active.clone(), active]);
+    }
+
+    #[tokio::test]
+    async fn reloaded_from_disk() -> anyhow::Result<()> {
+        let dir = crate::util::tests::get_temp_dir();
+        let path = dir.path().join("router_history");
+        let peer = PeerKeyLocation::random();
+
+        let mut history = RouteHistory::load(Some(path.clone()));
+        for _ in 0..10 {
+            history.record(event(&peer));
+        }
+        let (path, data) = history.to_persist().expect("persistence enabled");
+        crate::util::persist_atomically(&path, &data?).await?;
+
+        let reloaded = RouteHistory::load(Some(path));
+        assert_eq!(reloaded.events().len(), 10);
+        Ok(())
+    }
+}
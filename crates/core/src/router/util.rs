@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Serialize)]
 pub(super) struct Mean {
@@ -32,7 +32,7 @@ impl Default for Mean {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
-pub(super) struct TransferSpeed {
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TransferSpeed {
     pub bytes_per_second: f64,
 }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
 
 use axum::extract::Path;
 use axum::response::IntoResponse;
@@ -12,6 +13,7 @@ use futures::FutureExt;
 use tokio::sync::mpsc;
 
 use crate::client_events::{ClientEventsProxy, ClientId, OpenRequest};
+use crate::node::NodeHandle;
 use crate::server::HostCallbackResult;
 
 use super::{errors::WebSocketApiError, path_handlers, AuthToken, ClientConnection};
@@ -29,6 +31,18 @@ impl std::ops::Deref for HttpGatewayRequest {
     }
 }
 
+/// The node served by the gateway, set once the node has been built.
+#[derive(Clone, Default)]
+pub(super) struct AttachedNode(Arc<OnceLock<NodeHandle>>);
+
+impl AttachedNode {
+    fn get(&self) -> Result<&NodeHandle, WebSocketApiError> {
+        self.0.get().ok_or_else(|| WebSocketApiError::NodeError {
+            error_cause: "Node not running yet".into(),
+        })
+    }
+}
+
 /// A gateway to access and interact with contracts through an HTTP interface.
 ///
 /// Contracts initially accessed through the gateway have to be compliant with the container contract
@@ -39,6 +53,7 @@ pub(crate) struct HttpGateway {
     pub attested_contracts: HashMap<AuthToken, (ContractInstanceId, ClientId)>,
     proxy_server_request: mpsc::Receiver<ClientConnection>,
     response_channels: HashMap<ClientId, mpsc::UnboundedSender<HostCallbackResult>>,
+    node: AttachedNode,
 }
 
 impl HttpGateway {
@@ -115,4 +130,10 @@ impl ClientEventsProxy for HttpGateway {
         }
         .boxed()
     }
+
+    fn attach_node(&mut self, node: &NodeHandle) {
+        if self.node.0.set(node.clone()).is_err() {
+            tracing::warn!("Http gateway already attached to a node");
+        }
+    }
 }
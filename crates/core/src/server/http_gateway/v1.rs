@@ -1,4 +1,11 @@
+use freenet_stdlib::prelude::ContractKey;
+
 use super::*;
+use crate::{
+    node::PeerId,
+    ring::{Location, RingHealth},
+    router::RoutingExplanation,
+};
 
 impl HttpGateway {
     /// Returns the uninitialized axum router to compose with other routing handling or websockets.
@@ -14,19 +21,30 @@ impl HttpGateway {
         let (proxy_request_sender, request_to_server) = mpsc::channel(1);
 
         let config = Config { localhost };
+        let node = AttachedNode::default();
 
-        let router = Router::new()
+        let mut router = Router::new()
             .route("/v1", get(home))
             .route("/v1/contract/web/:key/", get(web_home))
             .with_state(config)
-            .route("/v1/contract/web/:key/*path", get(web_subpages))
-            .layer(Extension(HttpGatewayRequest(proxy_request_sender)));
+            .route("/v1/contract/web/:key/*path", get(web_subpages));
+        if localhost {
+            // the node diagnostics expose its peers and what it's doing with them,
+            // so they are only served to local clients
+            router = router
+                .route("/v1/node/routing/:key", get(routing_explanation))
+                .route("/v1/node/health", get(ring_health));
+        }
+        let router = router
+            .layer(Extension(HttpGatewayRequest(proxy_request_sender)))
+            .layer(Extension(node.clone()));
 
         (
             Self {
                 proxy_server_request: request_to_server,
                 attested_contracts: HashMap::new(),
                 response_channels: HashMap::new(),
+                node,
             },
             router,
         )
@@ -77,3 +95,27 @@ async fn web_subpages(
         .map_err(|e| *e)
         .map(|r| r.into_response())
 }
+
+/// Explains how the node would route a request for the given contract.
+async fn routing_explanation(
+    Path(key): Path<String>,
+    Extension(node): Extension<AttachedNode>,
+) -> Result<axum::Json<RoutingExplanation>, WebSocketApiError> {
+    let key = ContractKey::from_id(key).map_err(|err| WebSocketApiError::InvalidParam {
+        error_cause: format!("{err}"),
+    })?;
+    let node = node.get()?;
+    Ok(axum::Json(node.op_manager.ring.explain_routing(
+        Location::from(&key),
+        None,
+        &[] as &[PeerId],
+    )))
+}
+
+/// Reports the position of the node in the ring and the work it's carrying out.
+async fn ring_health(
+    Extension(node): Extension<AttachedNode>,
+) -> Result<axum::Json<RingHealth>, WebSocketApiError> {
+    let node = node.get()?;
+    Ok(axum::Json(node.op_manager.ring.health()))
+}
@@ -7,6 +7,8 @@ use request_density_tracker::{CachedDensityMap, RequestDensityTracker};
 use std::cmp::Ordering;
 use std::{
     collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::Arc,
     time::Instant,
 };
 use tracing::{debug, error, event, info, span, Level};
@@ -19,12 +21,14 @@ pub(crate) mod rate;
 pub mod request_density_tracker;
 pub(crate) mod running_average;
 mod small_world_rand;
+mod subnet;
 
-use crate::ring::{Connection, PeerKeyLocation};
+use crate::ring::{Connection, PeerKeyLocation, Reputation};
 use crate::topology::meter::{AttributionSource, ResourceType};
 use crate::topology::rate::{Rate, RateProportion};
 use constants::*;
 use request_density_tracker::DensityMapError;
+use subnet::Subnet;
 
 /// The goal of `TopologyManager` is to select new connections such that the
 /// distribution of connections is as close as possible to the
@@ -51,10 +55,16 @@ pub(crate) struct TopologyManager {
     slow_connection_evaluator: ConnectionEvaluator,
     fast_connection_evaluator: ConnectionEvaluator,
     request_density_tracker: RequestDensityTracker,
+    /// Requests for contracts sent or served by this peer, weighed when deciding which
+    /// contracts to seed. Served requests are kept out of the density tracker so they don't
+    /// affect which connections are acquired.
+    seeding_request_tracker: RequestDensityTracker,
     pub(crate) outbound_request_counter: OutboundRequestCounter,
     /// Must be updated when new neightbors are discovered.
     cached_density_map: CachedDensityMap,
     connection_acquisition_strategy: ConnectionAcquisitionStrategy,
+    /// Lowers the value of peers which misbehaved when selecting connections to remove.
+    reputation: Option<Arc<Reputation>>,
 }
 
 impl TopologyManager {
@@ -73,14 +83,23 @@ impl TopologyManager {
             request_density_tracker: RequestDensityTracker::new(
                 REQUEST_DENSITY_TRACKER_WINDOW_SIZE,
             ),
+            seeding_request_tracker: RequestDensityTracker::new(
+                REQUEST_DENSITY_TRACKER_WINDOW_SIZE,
+            ),
             cached_density_map: CachedDensityMap::new(),
             outbound_request_counter: OutboundRequestCounter::new(
                 OUTBOUND_REQUEST_COUNTER_WINDOW_SIZE,
             ),
             connection_acquisition_strategy: ConnectionAcquisitionStrategy::Fast,
+            reputation: None,
         }
     }
 
+    pub(crate) fn with_reputation(mut self, reputation: Arc<Reputation>) -> Self {
+        self.reputation = Some(reputation);
+        self
+    }
+
     pub(crate) fn refresh_cache(
         &mut self,
         neighbor_locations: &BTreeMap<Location, Vec<Connection>>,
@@ -100,21 +119,60 @@ impl TopologyManager {
         debug!(%request_type, %recipient, "Recording request sent to peer");
 
         self.request_density_tracker.sample(target);
+        self.seeding_request_tracker.sample(target);
         self.outbound_request_counter.record_request(recipient);
     }
 
+    /// Record a request for a contract served by this peer, so it's accounted for when
+    /// deciding which contracts to seed.
+    pub(crate) fn record_served_request(&mut self, target: Location) {
+        self.seeding_request_tracker.sample(target);
+    }
+
+    /// Requests recently sent towards or served for the location and when the latest one was
+    /// seen.
+    pub(crate) fn requests_at(&self, location: Location) -> (usize, Option<Instant>) {
+        self.seeding_request_tracker.requests_at(location)
+    }
+
+    /// Whether connecting to a peer at the candidate address would exceed the max number of
+    /// connections to peers in its subnet, given the addresses of the current neighbors.
+    pub(crate) fn exceeds_subnet_limit(
+        &self,
+        candidate_addr: IpAddr,
+        neighbor_addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> bool {
+        let Some(subnet) = Subnet::of(candidate_addr) else {
+            return false;
+        };
+        let in_subnet = neighbor_addrs
+            .into_iter()
+            .filter(|addr| Subnet::of(*addr).as_ref() == Some(&subnet))
+            .count();
+        in_subnet >= self.limits.max_connections_in(&subnet)
+    }
+
     /// Decide whether to accept a connection from a new candidate peer based on its location
     /// and current neighbors and request density, along with how it compares to other
-    /// recent candidates.
+    /// recent candidates. Candidates in a subnet this peer already has as many connections
+    /// to as allowed are always rejected.
     pub(crate) fn evaluate_new_connection(
         &mut self,
         candidate_location: Location,
+        candidate_addr: Option<IpAddr>,
+        neighbor_addrs: impl IntoIterator<Item = IpAddr>,
         current_time: Instant,
     ) -> Result<bool, DensityMapError> {
         tracing::debug!(
             "Evaluating new connection for candidate location: {:?}",
             candidate_location
         );
+        if let Some(addr) = candidate_addr {
+            if self.exceeds_subnet_limit(addr, neighbor_addrs) {
+                tracing::debug!(%addr, "Rejecting candidate, too many connections to its subnet");
+                return Ok(false);
+            }
+        }
         let density_map = self
             .cached_density_map
             .get()
@@ -189,7 +247,6 @@ impl TopologyManager {
         }
     }
 
-    #[allow(dead_code)] // fixme: use this
     pub(crate) fn report_resource_usage(
         &mut self,
         attribution: &AttributionSource,
@@ -213,6 +270,7 @@ impl TopologyManager {
     /// Record an outbound request to a peer, along with the target Location of that request
     pub(crate) fn report_outbound_request(&mut self, peer: PeerKeyLocation, target: Location) {
         self.request_density_tracker.sample(target);
+        self.seeding_request_tracker.sample(target);
         self.outbound_request_counter.record_request(peer);
     }
 
@@ -501,11 +559,17 @@ impl TopologyManager {
                     let request_count =
                         self.outbound_request_counter.get_request_count(&peer) as f64;
 
-                    let value_per_usage = request_count / source_usage.per_second();
+                    let reputation = self
+                        .reputation
+                        .as_ref()
+                        .map_or(1.0, |reputation| reputation.score(&peer.peer));
+
+                    let value_per_usage = request_count * reputation / source_usage.per_second();
 
                     event!(
                         Level::DEBUG,
                         request_count = request_count,
+                        reputation = reputation,
                         usage = source_usage.per_second(),
                         value_per_usage = value_per_usage
                     );
@@ -553,8 +617,13 @@ mod tests {
         let mut topology_manager = TopologyManager::new(Limits {
             max_upstream_bandwidth: Rate::new_per_second(1000.0),
             max_downstream_bandwidth: Rate::new_per_second(1000.0),
+            max_cpu_usage: Rate::new_per_second(1000.0),
+            max_storage_usage: Rate::new_per_second(1000.0),
+            max_memory_usage: 1000,
             min_connections: 5,
             max_connections: 200,
+            max_connections_per_ipv4_subnet: 3,
+            max_connections_per_ipv6_subnet: 3,
         });
         let mut current_neighbors = std::collections::BTreeMap::new();
 
@@ -628,7 +697,7 @@ mod tests {
     }
 
     use super::*;
-    use crate::ring::Distance;
+    use crate::ring::{Distance, Misbehaviour};
     use crate::test_utils::with_tracing;
     use std::time::Duration;
 
@@ -639,7 +708,12 @@ mod tests {
             let limits = Limits {
                 max_upstream_bandwidth: Rate::new_per_second(1000.0),
                 max_downstream_bandwidth: Rate::new_per_second(1000.0),
+                max_cpu_usage: Rate::new_per_second(1000.0),
+                max_storage_usage: Rate::new_per_second(1000.0),
+                max_memory_usage: 1000,
                 max_connections: 200,
+                max_connections_per_ipv4_subnet: 3,
+                max_connections_per_ipv6_subnet: 3,
                 min_connections: 5,
             };
             let mut resource_manager = TopologyManager::new(limits);
@@ -753,6 +827,115 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_remove_misbehaving_connection() {
+        with_tracing(|| {
+            let reputation = Arc::new(Reputation::load(None));
+            let mut resource_manager =
+                setup_topology_manager(1000.0).with_reputation(reputation.clone());
+            let peers = generate_random_peers(5);
+            // Total bw usage will be way higher than the limit of 1000
+            let bw_usage_by_peer = vec![1000, 1100, 1200, 2000, 1600];
+            let report_time = Instant::now() - SOURCE_RAMP_UP_DURATION - Duration::from_secs(30);
+            report_resource_usage(
+                &mut resource_manager,
+                &peers,
+                &bw_usage_by_peer,
+                report_time,
+            );
+            let requests_per_peer = vec![20, 19, 18, 9, 9];
+            report_outbound_requests(&mut resource_manager, &peers, &requests_per_peer);
+            // the most valuable peer by usage sent invalid states
+            for _ in 0..2 {
+                reputation.record(&peers[0].peer, Misbehaviour::InvalidState);
+            }
+            let mut neighbor_locations = BTreeMap::new();
+            for peer in &peers {
+                neighbor_locations.insert(peer.location.unwrap(), vec![]);
+            }
+
+            let adjustment =
+                resource_manager.adjust_topology(&neighbor_locations, &None, Instant::now());
+            match adjustment {
+                TopologyAdjustment::RemoveConnections(removed) => {
+                    assert_eq!(removed, vec![peers[0].clone()]);
+                }
+                _ => panic!("Expected to remove a peer, adjustment was {:?}", adjustment),
+            }
+        });
+    }
+
+    #[test]
+    fn test_reject_candidates_exceeding_subnet_limit() {
+        let mut topology_manager = setup_topology_manager(1000.0);
+        let ip = |a, b, c, d| IpAddr::from(std::net::Ipv4Addr::new(a, b, c, d));
+        let neighbors = [ip(203, 0, 113, 1), ip(203, 0, 113, 2), ip(198, 51, 100, 1)];
+
+        assert!(!topology_manager.exceeds_subnet_limit(ip(203, 0, 113, 3), neighbors));
+        let neighbors = [ip(203, 0, 113, 3), ip(203, 0, 113, 4)]
+            .into_iter()
+            .chain(neighbors)
+            .collect::<Vec<_>>();
+        assert!(topology_manager.exceeds_subnet_limit(ip(203, 0, 113, 5), neighbors.clone()));
+        assert!(!topology_manager.exceeds_subnet_limit(ip(198, 51, 100, 2), neighbors.clone()));
+        assert!(!topology_manager.exceeds_subnet_limit(ip(127, 0, 0, 1), neighbors.clone()));
+
+        let accepted = topology_manager
+            .evaluate_new_connection(
+                Location::random(),
+                Some(ip(203, 0, 113, 5)),
+                neighbors,
+                Instant::now(),
+            )
+            .unwrap();
+        assert!(!accepted);
+    }
+
+    #[test]
+    fn test_remove_connections_exceeding_cpu_usage() {
+        with_tracing(|| {
+            let mut resource_manager = setup_topology_manager(1000.0);
+            let peers = generate_random_peers(5);
+            // Bandwidth usage is within acceptable bounds
+            let bw_usage_by_peer = vec![150, 200, 100, 100, 200];
+            let report_time = Instant::now() - SOURCE_RAMP_UP_DURATION - Duration::from_secs(30);
+            report_resource_usage(
+                &mut resource_manager,
+                &peers,
+                &bw_usage_by_peer,
+                report_time,
+            );
+            // Two peers push contract executions onto us, exceeding the CPU limit of 1000
+            let cpu_usage_by_peer = [(1, 400.0), (3, 900.0)];
+            for (ix, cpu_usage) in cpu_usage_by_peer {
+                for seconds in 1..600 {
+                    resource_manager.report_resource_usage(
+                        &AttributionSource::Peer(peers[ix].clone()),
+                        ResourceType::CpuTimeSeconds,
+                        cpu_usage,
+                        report_time - Duration::from_secs(600 - seconds),
+                    );
+                }
+            }
+            let requests_per_peer = vec![20, 19, 18, 19, 9];
+            report_outbound_requests(&mut resource_manager, &peers, &requests_per_peer);
+
+            let mut neighbor_locations = BTreeMap::new();
+            for peer in &peers {
+                neighbor_locations.insert(peer.location.unwrap(), vec![]);
+            }
+
+            let adjustment =
+                resource_manager.adjust_topology(&neighbor_locations, &None, Instant::now());
+            match adjustment {
+                TopologyAdjustment::RemoveConnections(removed) => {
+                    assert_eq!(removed, vec![peers[3].clone()]);
+                }
+                _ => panic!("Expected to remove a peer, adjustment was {:?}", adjustment),
+            }
+        });
+    }
+
     // Test with no adjustment because the usage is within acceptable bounds
     #[test]
     fn test_no_adjustment() {
@@ -838,7 +1021,12 @@ mod tests {
             // This won't be used
             max_upstream_bandwidth: Rate::new_per_second(100000.0),
             max_downstream_bandwidth: Rate::new_per_second(max_downstream_rate),
+            max_cpu_usage: Rate::new_per_second(1000.0),
+            max_storage_usage: Rate::new_per_second(1000.0),
+            max_memory_usage: 1000,
             max_connections: 200,
+            max_connections_per_ipv4_subnet: 3,
+            max_connections_per_ipv6_subnet: 3,
             min_connections: 5,
         };
         TopologyManager::new(limits)
@@ -918,7 +1106,12 @@ mod tests {
         let limits = Limits {
             max_upstream_bandwidth: Rate::new_per_second(1000.0),
             max_downstream_bandwidth: Rate::new_per_second(1000.0),
+            max_cpu_usage: Rate::new_per_second(1000.0),
+            max_storage_usage: Rate::new_per_second(1000.0),
+            max_memory_usage: 1000,
             max_connections: 200,
+            max_connections_per_ipv4_subnet: 3,
+            max_connections_per_ipv6_subnet: 3,
             min_connections: 5,
         };
         let mut topology_manager = TopologyManager::new(limits);
@@ -926,7 +1119,12 @@ mod tests {
         let new_limits = Limits {
             max_upstream_bandwidth: Rate::new_per_second(2000.0),
             max_downstream_bandwidth: Rate::new_per_second(2000.0),
+            max_cpu_usage: Rate::new_per_second(1000.0),
+            max_storage_usage: Rate::new_per_second(1000.0),
+            max_memory_usage: 1000,
             max_connections: 200,
+            max_connections_per_ipv4_subnet: 3,
+            max_connections_per_ipv6_subnet: 3,
             min_connections: 5,
         };
         topology_manager.update_limits(new_limits);
@@ -940,6 +1138,25 @@ mod tests {
             Rate::new_per_second(2000.0)
         );
     }
+
+    #[test]
+    fn served_requests_only_weighed_for_seeding() {
+        let mut topology_manager = setup_topology_manager(1000.0);
+        let contract = Location::new(0.3);
+        topology_manager.record_served_request(contract);
+        assert_eq!(topology_manager.requests_at(contract).0, 1);
+        assert_eq!(
+            topology_manager
+                .request_density_tracker
+                .requests_at(contract)
+                .0,
+            0
+        );
+
+        let peer = PeerKeyLocation::random();
+        topology_manager.report_outbound_request(peer, contract);
+        assert_eq!(topology_manager.requests_at(contract).0, 2);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -958,8 +1175,17 @@ struct Usage {
 pub(crate) struct Limits {
     pub max_upstream_bandwidth: Rate,
     pub max_downstream_bandwidth: Rate,
+    /// CPU seconds per second which can be spent executing contracts and delegates.
+    pub max_cpu_usage: Rate,
+    pub max_storage_usage: Rate,
+    /// Bytes of WASM memory, compared against the peak memory reported by each source.
+    pub max_memory_usage: usize,
     pub min_connections: usize,
     pub max_connections: usize,
+    /// Max connections to peers in the same IPv4 /24 subnet.
+    pub max_connections_per_ipv4_subnet: usize,
+    /// Max connections to peers in the same IPv6 /48 subnet.
+    pub max_connections_per_ipv6_subnet: usize,
 }
 
 impl Limits {
@@ -967,6 +1193,17 @@ impl Limits {
         match resource_type {
             ResourceType::OutboundBandwidthBytes => self.max_upstream_bandwidth,
             ResourceType::InboundBandwidthBytes => self.max_downstream_bandwidth,
+            ResourceType::CpuTimeSeconds => self.max_cpu_usage,
+            ResourceType::StorageBytes => self.max_storage_usage,
+            // a gauge, so the limit is a level rather than an amount per second
+            ResourceType::MemoryBytes => Rate::new_per_second(self.max_memory_usage as f64),
+        }
+    }
+
+    fn max_connections_in(&self, subnet: &Subnet) -> usize {
+        match subnet {
+            Subnet::V4(_) => self.max_connections_per_ipv4_subnet,
+            Subnet::V6(_) => self.max_connections_per_ipv6_subnet,
         }
     }
 }
@@ -36,6 +36,8 @@ impl Meter {
     }
 
     /// The measured usage rate for a resource attributed to a specific source.
+    ///
+    /// For [gauges](ResourceType::is_gauge) this is the peak level reported within the window.
     pub(crate) fn attributed_usage_rate(
         &self,
         attribution: &AttributionSource,
@@ -47,7 +49,7 @@ impl Meter {
                 match attribution_meters.map.get(resource) {
                     Some(meter) => {
                         // Get the current measurement value
-                        meter.get_rate_at_time(at_time)
+                        Self::measured_usage(&meter, resource, at_time)
                     }
                     None => Some(Rate::new(0.0, Duration::from_secs(1))), // No meter found for the given resource
                 }
@@ -144,7 +146,7 @@ impl Meter {
             .filter_map(|t| {
                 t.map
                     .get(resource)
-                    .and_then(|m| m.get_rate_at_time(at_time))
+                    .and_then(|m| Self::measured_usage(&m, resource, at_time))
             })
             .collect();
 
@@ -164,10 +166,21 @@ impl Meter {
         sorted_rates.get(estimated_index).cloned()
     }
 
+    fn measured_usage(
+        meter: &RunningAverage,
+        resource: &ResourceType,
+        at_time: Instant,
+    ) -> Option<Rate> {
+        if resource.is_gauge() {
+            meter.max_sample().map(Rate::new_per_second)
+        } else {
+            meter.get_rate_at_time(at_time)
+        }
+    }
+
     /// Report the use of a resource. This should be done in the lowest-level
     /// functions that consume the resource, taking an AttributionMeter
     /// as a parameter.
-    #[allow(dead_code)] // fixme: use this
     pub(crate) fn report(
         &mut self,
         attribution: &AttributionSource,
@@ -188,7 +201,6 @@ impl Meter {
     }
 }
 
-#[allow(dead_code)] // todo use this
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub(crate) enum AttributionSource {
     Peer(PeerKeyLocation),
@@ -199,13 +211,28 @@ pub(crate) enum AttributionSource {
 pub(crate) enum ResourceType {
     InboundBandwidthBytes,
     OutboundBandwidthBytes,
+    /// Seconds of CPU time spent executing contracts and delegates.
+    CpuTimeSeconds,
+    /// Bytes of contract state written to storage.
+    StorageBytes,
+    /// Peak bytes of WASM memory added while executing contracts and delegates.
+    MemoryBytes,
 }
 
 impl ResourceType {
-    pub(crate) fn all() -> [ResourceType; 2] {
+    /// Whether the resource is measured as a level held rather than an amount consumed
+    /// over time, so its usage is not divided by the time it was measured over.
+    pub(crate) fn is_gauge(&self) -> bool {
+        matches!(self, ResourceType::MemoryBytes)
+    }
+
+    pub(crate) fn all() -> [ResourceType; 5] {
         [
             ResourceType::InboundBandwidthBytes,
             ResourceType::OutboundBandwidthBytes,
+            ResourceType::CpuTimeSeconds,
+            ResourceType::StorageBytes,
+            ResourceType::MemoryBytes,
         ]
     }
 }
@@ -350,4 +377,22 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_meter_gauge_reports_peak() {
+        let mut meter = Meter::new_with_window_size(100);
+        let attribution = AttributionSource::Peer(PeerKeyLocation::random());
+        let now = Instant::now();
+
+        meter.report(&attribution, ResourceType::MemoryBytes, 100.0, now);
+        meter.report(&attribution, ResourceType::MemoryBytes, 50.0, now);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            meter
+                .attributed_usage_rate(&attribution, &ResourceType::MemoryBytes, later)
+                .unwrap()
+                .per_second(),
+            100.0
+        );
+    }
 }
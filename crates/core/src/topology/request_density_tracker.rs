@@ -1,5 +1,6 @@
 use crate::ring::{Connection, Location};
 use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
 use thiserror::Error;
 
 /// Tracks requests sent by a node to its neighbors and creates a density map, which
@@ -8,6 +9,8 @@ use thiserror::Error;
 pub(crate) struct RequestDensityTracker {
     /// Amount of requests done to an specific location.
     request_locations: BTreeMap<Location, usize>,
+    /// When the latest request to an specific location was done.
+    last_requests: BTreeMap<Location, Instant>,
     /// Request locations sorted by order of execution.
     request_list: VecDeque<Location>,
     window_size: usize,
@@ -18,6 +21,7 @@ impl RequestDensityTracker {
     pub(crate) fn new(window_size: usize) -> Self {
         Self {
             request_locations: BTreeMap::new(),
+            last_requests: BTreeMap::new(),
             request_list: VecDeque::with_capacity(window_size),
             window_size,
             samples: 0,
@@ -29,6 +33,7 @@ impl RequestDensityTracker {
 
         self.request_list.push_back(value);
         *self.request_locations.entry(value).or_insert(0) += 1;
+        self.last_requests.insert(value, Instant::now());
 
         if self.request_list.len() > self.window_size {
             if let Some(oldest) = self.request_list.pop_front() {
@@ -36,12 +41,19 @@ impl RequestDensityTracker {
                     *count -= 1;
                     if *count == 0 {
                         self.request_locations.remove(&oldest);
+                        self.last_requests.remove(&oldest);
                     }
                 }
             }
         }
     }
 
+    /// Requests to the location within the window and when the latest one was done.
+    pub(crate) fn requests_at(&self, location: Location) -> (usize, Option<Instant>) {
+        let count = self.request_locations.get(&location).copied().unwrap_or(0);
+        (count, self.last_requests.get(&location).copied())
+    }
+
     pub(crate) fn create_density_map(
         &self,
         neighbor_locations: &BTreeMap<Location, Vec<Connection>>,
@@ -375,6 +387,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_requests_at() {
+        let mut sw = RequestDensityTracker::new(3);
+        sw.sample(Location::new(0.21));
+        sw.sample(Location::new(0.21));
+        sw.sample(Location::new(0.62));
+        assert_eq!(sw.requests_at(Location::new(0.21)).0, 2);
+        assert!(sw.requests_at(Location::new(0.21)).1.is_some());
+
+        sw.sample(Location::new(0.62));
+        sw.sample(Location::new(0.62));
+        assert_eq!(sw.requests_at(Location::new(0.21)), (0, None));
+        assert_eq!(sw.requests_at(Location::new(0.62)).0, 3);
+    }
+
     #[test]
     #[should_panic(expected = "assertion failed: !neighbors.is_empty()")]
     fn test_empty_neighbors_error() {
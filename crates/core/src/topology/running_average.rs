@@ -35,6 +35,15 @@ impl RunningAverage {
         }
     }
 
+    /// The largest sample in the window, for measurements which are a level rather
+    /// than an amount consumed over time.
+    pub(crate) fn max_sample(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .map(|(_, value)| *value)
+            .reduce(f64::max)
+    }
+
     pub(crate) fn get_rate_at_time(&self, now: Instant) -> Option<Rate> {
         if self.samples.is_empty() {
             return None;
@@ -75,6 +84,21 @@ mod tests {
         assert_eq!(running_avg.sum_samples, 18.0);
     }
 
+    #[test]
+    fn test_max_sample() {
+        let mut running_avg = RunningAverage::new(2);
+        let now = Instant::now();
+        assert!(running_avg.max_sample().is_none());
+
+        running_avg.insert_with_time(now, 4.0);
+        running_avg.insert_with_time(now + Duration::from_secs(1), 2.0);
+        assert_eq!(running_avg.max_sample(), Some(4.0));
+
+        // the largest sample is evicted from the window
+        running_avg.insert_with_time(now + Duration::from_secs(2), 1.0);
+        assert_eq!(running_avg.max_sample(), Some(2.0));
+    }
+
     #[test]
     fn test_per_second_measurement() {
         let max_samples = 3;
@@ -0,0 +1,75 @@
+//! Subnets peers are grouped by to limit how many connections this peer holds to peers which
+//! are likely run by the same operator, so a single operator can't surround this peer with
+//! nodes it controls (a.k.a. an eclipse attack).
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Subnet {
+    /// First 24 bits of an IPv4 address.
+    V4([u8; 3]),
+    /// First 48 bits of an IPv6 address.
+    V6([u16; 3]),
+}
+
+impl Subnet {
+    /// Subnet of the address, IPv4-mapped IPv6 addresses belong to the IPv4 subnet.
+    ///
+    /// Loopback, private (RFC 1918 and IPv6 unique local) and link-local addresses don't belong
+    /// to any subnet, peers using them are in the same local network as this peer, as is
+    /// the case of LAN and container deployments.
+    pub fn of(ip: IpAddr) -> Option<Self> {
+        if ip.is_loopback() {
+            return None;
+        }
+        match ip {
+            IpAddr::V4(ip) => {
+                if ip.is_private() || ip.is_link_local() {
+                    return None;
+                }
+                let [a, b, c, _] = ip.octets();
+                Some(Subnet::V4([a, b, c]))
+            }
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => Self::of(ip.into()),
+                None => {
+                    let [a, b, c, ..] = ip.segments();
+                    let unique_local = a & 0xfe00 == 0xfc00;
+                    let link_local = a & 0xffc0 == 0xfe80;
+                    if unique_local || link_local {
+                        return None;
+                    }
+                    Some(Subnet::V6([a, b, c]))
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn subnet_of_address() {
+        let v4 = |a, b, c, d| Subnet::of(Ipv4Addr::new(a, b, c, d).into());
+        assert_eq!(v4(203, 0, 113, 1), v4(203, 0, 113, 254));
+        assert_ne!(v4(203, 0, 113, 1), v4(203, 0, 114, 1));
+        assert_eq!(v4(127, 0, 0, 1), None);
+        assert_eq!(v4(10, 1, 2, 3), None);
+        assert_eq!(v4(172, 17, 0, 2), None);
+        assert_eq!(v4(192, 168, 1, 10), None);
+        assert_eq!(v4(169, 254, 0, 1), None);
+
+        let mapped = Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped();
+        assert_eq!(Subnet::of(mapped.into()), v4(203, 0, 113, 1));
+
+        let v6 = |s: &str| Subnet::of(s.parse::<Ipv6Addr>().unwrap().into());
+        assert_eq!(v6("2001:db8:1::1"), v6("2001:db8:1:ffff::2"));
+        assert_ne!(v6("2001:db8:1::1"), v6("2001:db8:2::1"));
+        assert_eq!(Subnet::of(Ipv6Addr::LOCALHOST.into()), None);
+        assert_eq!(v6("fd00:1:2::1"), None);
+        assert_eq!(v6("fe80::1"), None);
+    }
+}
@@ -134,6 +134,7 @@ impl<'a> NetEventLog<'a> {
                 connected: PeerKeyLocation {
                     peer,
                     location: Some(location),
+                    alt_addr: None,
                 },
             }),
         }
@@ -167,6 +168,7 @@ impl<'a> NetEventLog<'a> {
                         connected: PeerKeyLocation {
                             peer: acceptor.peer.clone(),
                             location: acceptor.location,
+                            alt_addr: None,
                         },
                     })
                 } else {
@@ -493,11 +495,13 @@ async fn send_to_metrics_server(
                 PeerKeyLocation {
                     peer: from_peer,
                     location: Some(from_loc),
+                    alt_addr: None,
                 },
             connected:
                 PeerKeyLocation {
                     peer: to_peer,
                     location: Some(to_loc),
+                    alt_addr: None,
                 },
         }) => {
             let msg = PeerChange::added_connection_msg(
@@ -1278,10 +1282,12 @@ pub(super) mod test {
                         this: PeerKeyLocation {
                             peer: peer_id.clone(),
                             location: Some(loc),
+                            alt_addr: None,
                         },
                         connected: PeerKeyLocation {
                             peer: other.clone(),
                             location: Some(*location),
+                            alt_addr: None,
                         },
                     }),
                 }))
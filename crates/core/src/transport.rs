@@ -3,16 +3,29 @@
 //!
 //! Please see `docs/architecture/transport.md` for more information.
 //!
-use std::{borrow::Cow, io, net::SocketAddr};
+use std::{
+    borrow::Cow,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
 
+use bytes::Bytes;
 use futures::Future;
-use tokio::net::UdpSocket;
 
+#[cfg(target_os = "linux")]
+mod batch_io;
+mod capture;
+mod congestion_control;
 mod connection_handler;
 mod crypto;
+#[cfg(test)]
+mod emulated_socket;
+mod intro_gate;
 mod packet_data;
+mod packet_pool;
 mod peer_connection;
 mod rate_limiter;
+mod relay;
 // todo: optimize trackers
 mod received_packet_tracker;
 mod sent_packet_tracker;
@@ -22,13 +35,19 @@ type MessagePayload = Vec<u8>;
 
 type PacketId = u32;
 
-use self::{packet_data::PacketData, peer_connection::StreamId};
+use self::{packet_data::PacketData, packet_pool::RecvBatch, peer_connection::StreamId};
 
-pub use self::crypto::TransportKeypair;
+pub use self::{
+    capture::{read_capture, CaptureDecoder, CapturedPacket, Direction, TimelineEntry},
+    crypto::TransportKeypair,
+};
 pub(crate) use self::{
-    connection_handler::{create_connection_handler, OutboundConnectionHandler},
+    connection_handler::{
+        create_connection_handler, ConnectionMigration, InboundConnectionEvent,
+        OutboundConnectionHandler, RelayUsage,
+    },
     crypto::TransportPublicKey,
-    peer_connection::PeerConnection,
+    peer_connection::{MessagePriority, PeerConnection},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -67,19 +86,235 @@ pub(crate) trait Socket: Sized + Send + Sync + 'static {
         buf: &[u8],
         target: SocketAddr,
     ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Receives a batch of packets into `batch`, returns how many were received.
+    ///
+    /// By default receives a single packet.
+    fn recv_batch(&self, batch: &mut RecvBatch) -> impl Future<Output = io::Result<usize>> + Send {
+        async move {
+            let (size, addr) = self.recv_from(&mut batch.buffers[0]).await?;
+            batch.clear();
+            batch.received.push((size, addr));
+            Ok(1)
+        }
+    }
+
+    /// Sends a batch of packets, returns how many were sent before the first error.
+    ///
+    /// By default sends the packets one by one.
+    fn send_batch(
+        &self,
+        packets: &[(SocketAddr, Bytes)],
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        async move {
+            for (sent, (target, packet)) in packets.iter().enumerate() {
+                if let Err(err) = self.send_to(packet, *target).await {
+                    if sent == 0 {
+                        return Err(err);
+                    }
+                    return Ok(sent);
+                }
+            }
+            Ok(packets.len())
+        }
+    }
+}
+
+/// UDP socket of a node. Binding the unspecified IPv6 address accepts both IPv4 and IPv6 on
+/// dual-stack systems, and falls back to IPv4 only where IPv6 is not available.
+pub(crate) struct UdpSocket {
+    inner: tokio::net::UdpSocket,
+    /// Whether the socket is bound to an IPv6 address, so IPv4 remotes must be mapped
+    ipv6: bool,
+}
+
+impl UdpSocket {
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn bind_dual_stack(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+        use socket2::{Domain, Protocol, Type};
+        let socket = socket2::Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    }
+}
+
+/// A dual-stack socket reports IPv4 remotes as IPv4-mapped IPv6 addresses.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
 }
 
 impl Socket for UdpSocket {
     async fn bind(addr: SocketAddr) -> io::Result<Self> {
-        Self::bind(addr).await
+        let socket = match addr {
+            SocketAddr::V6(v6) if v6.ip().is_unspecified() => match Self::bind_dual_stack(addr) {
+                Ok(socket) => socket,
+                Err(error) => {
+                    tracing::warn!(%error, "failed to bind IPv6, listening on IPv4 only");
+                    std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?
+                }
+            },
+            addr => std::net::UdpSocket::bind(addr)?,
+        };
+        socket.set_nonblocking(true)?;
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        Ok(Self {
+            inner: tokio::net::UdpSocket::from_std(socket)?,
+            ipv6,
+        })
     }
 
     async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.recv_from(buf).await
+        let (size, addr) = self.inner.recv_from(buf).await?;
+        Ok((size, canonical_addr(addr)))
     }
 
     async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
-        self.send_to(buf, target).await
+        let target = match target {
+            SocketAddr::V4(v4) if self.ipv6 => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            target => target,
+        };
+        self.inner.send_to(buf, target).await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        loop {
+            self.inner.readable().await?;
+            match self.inner.try_io(tokio::io::Interest::READABLE, || {
+                batch_io::recv_batch(self.inner.as_raw_fd(), batch)
+            }) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send_batch(&self, packets: &[(SocketAddr, Bytes)]) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        loop {
+            self.inner.writable().await?;
+            match self.inner.try_io(tokio::io::Interest::WRITABLE, || {
+                batch_io::send_batch(self.inner.as_raw_fd(), packets, self.ipv6)
+            }) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Packets sent between two sockets on localhost, measured by the benchmarks in `benches/`.
+#[doc(hidden)]
+pub mod socket_bench {
+    use std::{io, net::Ipv4Addr, sync::Arc, time::Duration};
+
+    use tokio::sync::Semaphore;
+
+    use super::{
+        packet_data::MAX_PACKET_SIZE,
+        packet_pool::{self, RecvBatch, MAX_BATCH_SIZE},
+        Socket, UdpSocket,
+    };
+
+    /// Packets in flight at most, so the sender doesn't overflow the receive buffer of the
+    /// receiver and the measure isn't dominated by dropped packets.
+    const IN_FLIGHT: usize = 2 * MAX_BATCH_SIZE;
+
+    /// How long the receiver waits for the next packet before counting the rest as lost.
+    const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Sends and receives `count` packets one at a time from freshly allocated buffers,
+    /// returns how many were received.
+    pub async fn unbatched(count: usize) -> io::Result<usize> {
+        let sender = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let receiver = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let target = receiver.local_addr()?;
+        let window = Arc::new(Semaphore::new(IN_FLIGHT));
+        let receiving = tokio::spawn({
+            let window = window.clone();
+            async move {
+                let mut buf = [0u8; MAX_PACKET_SIZE];
+                let mut received = 0;
+                while received < count {
+                    let recv = receiver.recv_from(&mut buf);
+                    let Ok(Ok(_)) = tokio::time::timeout(RECV_TIMEOUT, recv).await else {
+                        break;
+                    };
+                    received += 1;
+                    window.add_permits(1);
+                }
+                window.close();
+                received
+            }
+        });
+        let payload = [0xAA; MAX_PACKET_SIZE];
+        for _ in 0..count {
+            let Ok(permit) = window.acquire().await else {
+                break;
+            };
+            permit.forget();
+            let packet = payload.to_vec();
+            sender.send_to(&packet, target).await?;
+        }
+        Ok(receiving.await.expect("receiver task"))
+    }
+
+    /// Sends and receives `count` packets in batches from pooled buffers, returns how many
+    /// were received.
+    pub async fn batched(count: usize) -> io::Result<usize> {
+        let sender = <UdpSocket as Socket>::bind((Ipv4Addr::LOCALHOST, 0).into()).await?;
+        let receiver = <UdpSocket as Socket>::bind((Ipv4Addr::LOCALHOST, 0).into()).await?;
+        let target = receiver.local_addr()?;
+        let window = Arc::new(Semaphore::new(IN_FLIGHT));
+        let receiving = tokio::spawn({
+            let window = window.clone();
+            async move {
+                let mut batch = RecvBatch::default();
+                let mut received = 0;
+                while received < count {
+                    let recv = receiver.recv_batch(&mut batch);
+                    let Ok(Ok(batch_size)) = tokio::time::timeout(RECV_TIMEOUT, recv).await else {
+                        break;
+                    };
+                    received += batch_size;
+                    window.add_permits(batch_size);
+                }
+                window.close();
+                received
+            }
+        });
+        let payload = [0xAA; MAX_PACKET_SIZE];
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        let mut sent = 0;
+        while sent < count {
+            let batch_size = (count - sent).min(MAX_BATCH_SIZE);
+            let Ok(permits) = window.acquire_many(batch_size as u32).await else {
+                break;
+            };
+            permits.forget();
+            batch.extend((0..batch_size).map(|_| (target, packet_pool::packet(&payload))));
+            let mut batch_sent = 0;
+            while batch_sent < batch.len() {
+                batch_sent += sender.send_batch(&batch[batch_sent..]).await?;
+            }
+            batch.clear();
+            sent += batch_size;
+        }
+        Ok(receiving.await.expect("receiver task"))
     }
 }
 
@@ -88,6 +323,7 @@ mod tests {
     use super::*;
     use crate::transport::received_packet_tracker::ReportResult;
     use crate::transport::sent_packet_tracker::{ResendAction, MESSAGE_CONFIRMATION_TIMEOUT};
+    use crate::transport::symmetric_message::ReceiptRange;
 
     #[test]
     fn test_packet_send_receive_acknowledge_flow() {
@@ -127,4 +363,49 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_selective_ack_fast_retransmit_flow() {
+        let mut sent_tracker = sent_packet_tracker::tests::mock_sent_packet_tracker();
+        let mut received_tracker = received_packet_tracker::tests::mock_received_packet_tracker();
+
+        for id in 1..=10 {
+            sent_tracker.report_sent_packet(id, vec![id as u8].into());
+        }
+
+        // packet 4 is lost, the rest arrive out of order
+        for id in [2u32, 1, 3, 6, 5, 7, 8, 10, 9] {
+            assert_eq!(
+                received_tracker.report_received_packet(id),
+                ReportResult::Ok
+            );
+        }
+
+        let ranges = ReceiptRange::encode(&received_tracker.get_receipts());
+        assert_eq!(
+            ranges,
+            vec![
+                ReceiptRange { first: 1, len: 3 },
+                ReceiptRange { first: 5, len: 6 },
+            ]
+        );
+        let receipts: Vec<_> = ranges
+            .into_iter()
+            .flat_map(ReceiptRange::packet_ids)
+            .collect();
+        sent_tracker.report_received_receipts(&receipts);
+
+        // the lost packet is resent before the confirmation timeout
+        match sent_tracker.get_resend() {
+            ResendAction::Resend(packet_id, packet) => {
+                assert_eq!(packet_id, 4);
+                sent_tracker.report_sent_packet(packet_id, packet);
+            }
+            _ => panic!("Expected fast retransmit of packet 4"),
+        }
+        assert!(matches!(
+            sent_tracker.get_resend(),
+            ResendAction::WaitUntil(_)
+        ));
+    }
 }
@@ -0,0 +1,204 @@
+//! Batched UDP I/O through `recvmmsg` and `sendmmsg`, so the listener and the rate limiter
+//! can move a whole batch of packets with a single syscall.
+//!
+//! Both functions expect a non-blocking socket and return `WouldBlock` when the socket
+//! is not ready.
+
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::RawFd,
+    ptr,
+};
+
+use bytes::Bytes;
+
+use super::packet_pool::{RecvBatch, MAX_BATCH_SIZE};
+
+/// Receives up to a batch of packets, returns the number of packets received.
+pub(super) fn recv_batch(fd: RawFd, batch: &mut RecvBatch) -> io::Result<usize> {
+    // SAFETY: all-zero is a valid value for these plain C structs
+    let mut addrs: [libc::sockaddr_storage; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    let len = batch.buffers.len().min(MAX_BATCH_SIZE);
+    for (i, buffer) in batch.buffers.iter_mut().take(len).enumerate() {
+        iovecs[i] = libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        };
+        let header = &mut headers[i].msg_hdr;
+        header.msg_name = ptr::addr_of_mut!(addrs[i]).cast();
+        header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        header.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+        header.msg_iovlen = 1;
+    }
+
+    // SAFETY: every header points to a buffer and an address that outlive the call
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            len as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    batch.clear();
+    for (header, addr) in headers.iter().zip(&addrs).take(received as usize) {
+        let addr = super::canonical_addr(from_sockaddr(addr)?);
+        batch.received.push((header.msg_len as usize, addr));
+    }
+    Ok(received as usize)
+}
+
+/// Sends up to a batch of packets, returns the number of packets sent.
+///
+/// IPv4 targets are sent as IPv4-mapped addresses when the socket is an IPv6 one.
+pub(super) fn send_batch(
+    fd: RawFd,
+    packets: &[(SocketAddr, Bytes)],
+    ipv6_socket: bool,
+) -> io::Result<usize> {
+    // SAFETY: all-zero is a valid value for these plain C structs
+    let mut addrs: [libc::sockaddr_storage; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    let len = packets.len().min(MAX_BATCH_SIZE);
+    for (i, (target, packet)) in packets.iter().take(len).enumerate() {
+        let target = match *target {
+            SocketAddr::V4(v4) if ipv6_socket => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            target => target,
+        };
+        iovecs[i] = libc::iovec {
+            // sendmmsg never writes through the pointer
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        };
+        let header = &mut headers[i].msg_hdr;
+        header.msg_namelen = to_sockaddr(target, &mut addrs[i]);
+        header.msg_name = ptr::addr_of_mut!(addrs[i]).cast();
+        header.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+        header.msg_iovlen = 1;
+    }
+
+    // SAFETY: every header points to a packet and an address that outlive the call
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            len as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+fn from_sockaddr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a `sockaddr_in`
+            let addr =
+                unsafe { &*(addr as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            Ok(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )
+            .into())
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a `sockaddr_in6`
+            let addr =
+                unsafe { &*(addr as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            Ok(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )
+            .into())
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected address family {family}"),
+        )),
+    }
+}
+
+fn to_sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: `sockaddr_storage` is large enough and aligned for any address
+            unsafe { ptr::write((storage as *mut libc::sockaddr_storage).cast(), sockaddr) };
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: `sockaddr_storage` is large enough and aligned for any address
+            unsafe { ptr::write((storage as *mut libc::sockaddr_storage).cast(), sockaddr) };
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn send_and_receive_batch() -> io::Result<()> {
+        let sender = UdpSocket::bind("127.0.0.1:0")?;
+        let receiver = UdpSocket::bind("127.0.0.1:0")?;
+        let target = receiver.local_addr()?;
+        let packets: Vec<_> = (0..4u8)
+            .map(|i| (target, Bytes::from(vec![i; 10 + i as usize])))
+            .collect();
+        assert_eq!(send_batch(sender.as_raw_fd(), &packets, false)?, 4);
+
+        let mut batch = RecvBatch::default();
+        let mut received = 0;
+        while received < 4 {
+            match recv_batch(receiver.as_raw_fd(), &mut batch) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+            for (packet, source) in batch.packets() {
+                assert_eq!(source, sender.local_addr()?);
+                assert_eq!(packet, &*packets[received].1);
+                received += 1;
+            }
+        }
+        Ok(())
+    }
+}
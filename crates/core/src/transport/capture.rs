@@ -0,0 +1,759 @@
+//! Opt-in capture of every packet sent and received by the transport, for debugging.
+//!
+//! Packets are recorded as they go through the socket, still encrypted, and written to a
+//! capture file by a dedicated thread, so the socket never waits on the disk. The file is
+//! rotated once it grows over [`MAX_FILE_SIZE`]. The session keys of every connection are
+//! written to a sidecar keylog (`<capture>.keylog`, readable by the owner only), in the spirit
+//! of `SSLKEYLOGFILE`, so a capture can be decrypted offline with [`CaptureDecoder`]. The
+//! keylog is rotated along with its capture, and each new keylog starts with the keys logged so
+//! far. Keys replaced by a rekey are not logged, the decoder learns them from the decrypted
+//! `Rekey` messages.
+//!
+//! The capture file starts with [`MAGIC`] followed by one record per packet:
+//!
+//! | field     | size     | notes                                  |
+//! |-----------|----------|----------------------------------------|
+//! | timestamp | 8        | microseconds since the UNIX epoch      |
+//! | direction | 1        | 0 for received, 1 for sent             |
+//! | family    | 1        | 4 or 6                                 |
+//! | address   | 4 or 16  |                                        |
+//! | port      | 2        |                                        |
+//! | length    | 2        |                                        |
+//! | packet    | length   | as sent or received through the socket |
+//!
+//! All integers are big endian.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use aes_gcm::{Aes128Gcm, KeyInit};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+
+use super::{
+    packet_data::{PacketData, UnknownEncryption, MAX_PACKET_SIZE},
+    packet_pool::RecvBatch,
+    relay,
+    symmetric_message::{ReceiptRange, SymmetricMessage, SymmetricMessagePayload},
+    PacketId, Socket,
+};
+
+const MAGIC: [u8; 8] = *b"FRTPCAP1";
+
+/// Size after which the capture file is rotated.
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Amount of capture files kept, including the current one.
+const MAX_FILES: usize = 4;
+
+/// Buffered records are flushed to disk at least this often.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records waiting to be written, further records are dropped while the writer is behind.
+const QUEUE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// Records the packets going through the socket and the session keys of the connections,
+/// which are written to disk by a dedicated thread.
+pub(crate) struct PacketCapture {
+    queue: mpsc::SyncSender<CaptureEvent>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum CaptureEvent {
+    Packet {
+        timestamp: Duration,
+        direction: Direction,
+        remote_addr: SocketAddr,
+        packet: Vec<u8>,
+    },
+    Keys {
+        remote_addr: SocketAddr,
+        keys: SessionKeys,
+    },
+    Close,
+}
+
+/// Inbound and outbound session keys of a connection, from the perspective of this peer.
+type SessionKeys = ([u8; 16], [u8; 16]);
+
+struct CaptureWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    keylog: File,
+    /// Keys logged so far, carried over to the keylog of the next capture file
+    keys: HashMap<SocketAddr, SessionKeys>,
+    written: u64,
+    last_flush: Instant,
+}
+
+impl PacketCapture {
+    /// Starts a new capture at `path`, keys are logged to `<path>.keylog`.
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = CaptureWriter::create(path.to_path_buf(), HashMap::new())?;
+        let (queue, events) = mpsc::sync_channel(QUEUE_SIZE);
+        let writer = std::thread::Builder::new()
+            .name("packet-capture".into())
+            .spawn(move || loop {
+                let event = match events.recv_timeout(FLUSH_INTERVAL) {
+                    Ok(CaptureEvent::Close) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    Ok(event) => event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if let Err(error) = writer.flush() {
+                            tracing::debug!(%error, "failed flushing packet capture");
+                        }
+                        continue;
+                    }
+                };
+                if let Err(error) = writer.handle(event) {
+                    tracing::debug!(%error, "failed writing packet capture");
+                }
+            })?;
+        Ok(Self {
+            queue,
+            writer: Some(writer),
+        })
+    }
+
+    pub(super) fn record(&self, direction: Direction, remote_addr: SocketAddr, packet: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.enqueue(CaptureEvent::Packet {
+            timestamp,
+            direction,
+            remote_addr,
+            packet: packet.to_vec(),
+        });
+    }
+
+    /// Logs the session keys of a new connection, from the perspective of this peer.
+    pub(super) fn log_keys(&self, remote_addr: SocketAddr, inbound: [u8; 16], outbound: [u8; 16]) {
+        self.enqueue(CaptureEvent::Keys {
+            remote_addr,
+            keys: (inbound, outbound),
+        });
+    }
+
+    fn enqueue(&self, event: CaptureEvent) {
+        if let Err(mpsc::TrySendError::Full(_)) = self.queue.try_send(event) {
+            tracing::debug!("packet capture writer is behind, dropping record");
+        }
+    }
+}
+
+impl Drop for PacketCapture {
+    fn drop(&mut self) {
+        // waits for the queued records to be written
+        let _ = self.queue.send(CaptureEvent::Close);
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl CaptureWriter {
+    fn create(path: PathBuf, keys: HashMap<SocketAddr, SessionKeys>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        file.write_all(&MAGIC)?;
+        let mut keylog = create_keylog(&keylog_path(&path))?;
+        for (remote_addr, keys) in &keys {
+            keylog.write_all(keylog_line(*remote_addr, keys).as_bytes())?;
+        }
+        Ok(Self {
+            path,
+            file,
+            keylog,
+            keys,
+            written: MAGIC.len() as u64,
+            last_flush: Instant::now(),
+        })
+    }
+
+    fn handle(&mut self, event: CaptureEvent) -> io::Result<()> {
+        match event {
+            CaptureEvent::Packet {
+                timestamp,
+                direction,
+                remote_addr,
+                packet,
+            } => self.write(timestamp, direction, remote_addr, &packet),
+            CaptureEvent::Keys { remote_addr, keys } => {
+                self.keylog
+                    .write_all(keylog_line(remote_addr, &keys).as_bytes())?;
+                self.keys.insert(remote_addr, keys);
+                Ok(())
+            }
+            CaptureEvent::Close => Ok(()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        timestamp: Duration,
+        direction: Direction,
+        remote_addr: SocketAddr,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        if self.written >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        let file = &mut self.file;
+        file.write_u64::<BigEndian>(timestamp.as_micros() as u64)?;
+        file.write_u8(match direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        })?;
+        let addr_size = match remote_addr.ip() {
+            IpAddr::V4(ip) => {
+                file.write_u8(4)?;
+                file.write_all(&ip.octets())?;
+                4
+            }
+            IpAddr::V6(ip) => {
+                file.write_u8(6)?;
+                file.write_all(&ip.octets())?;
+                16
+            }
+        };
+        file.write_u16::<BigEndian>(remote_addr.port())?;
+        file.write_u16::<BigEndian>(packet.len() as u16)?;
+        file.write_all(packet)?;
+        self.written += (8 + 1 + 1 + addr_size + 2 + 2 + packet.len()) as u64;
+
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Moves `capture` to `capture.1`, `capture.1` to `capture.2` and so on along with their
+    /// keylogs, dropping the oldest files, and starts a new capture file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for n in (1..MAX_FILES - 1).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                let to = rotated_path(&self.path, n + 1);
+                fs::rename(keylog_path(&from), keylog_path(&to))?;
+                fs::rename(from, to)?;
+            }
+        }
+        let to = rotated_path(&self.path, 1);
+        fs::rename(keylog_path(&self.path), keylog_path(&to))?;
+        fs::rename(&self.path, to)?;
+        let keys = std::mem::take(&mut self.keys);
+        *self = Self::create(self.path.clone(), keys)?;
+        Ok(())
+    }
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+    }
+}
+
+/// Creates an empty keylog readable by the owner only, replacing any previous one.
+fn create_keylog(path: &Path) -> io::Result<File> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+fn keylog_line(remote_addr: SocketAddr, (inbound, outbound): &SessionKeys) -> String {
+    format!(
+        "{remote_addr} {} {}\n",
+        bs58::encode(inbound).into_string(),
+        bs58::encode(outbound).into_string()
+    )
+}
+
+fn keylog_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".keylog");
+    path.into()
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{n}"));
+    path.into()
+}
+
+/// Socket recording every packet it sends or receives when a capture is set.
+pub(super) struct CapturingSocket<S> {
+    inner: S,
+    capture: Option<Arc<PacketCapture>>,
+}
+
+impl<S> CapturingSocket<S> {
+    pub(super) fn new(inner: S, capture: Option<Arc<PacketCapture>>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl<S: Socket> Socket for CapturingSocket<S> {
+    async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::new(S::bind(addr).await?, None))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, addr) = self.inner.recv_from(buf).await?;
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Received, addr, &buf[..size]);
+        }
+        Ok((size, addr))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let size = self.inner.send_to(buf, target).await?;
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Sent, target, buf);
+        }
+        Ok(size)
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        let received = self.inner.recv_batch(batch).await?;
+        if let Some(capture) = &self.capture {
+            for (packet, addr) in batch.packets() {
+                capture.record(Direction::Received, addr, packet);
+            }
+        }
+        Ok(received)
+    }
+
+    async fn send_batch(&self, packets: &[(SocketAddr, Bytes)]) -> io::Result<usize> {
+        let sent = self.inner.send_batch(packets).await?;
+        if let Some(capture) = &self.capture {
+            for (target, packet) in &packets[..sent] {
+                capture.record(Direction::Sent, *target, packet);
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// A packet read back from a capture file.
+pub struct CapturedPacket {
+    pub timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    pub remote_addr: SocketAddr,
+    pub packet: Vec<u8>,
+}
+
+/// Reads all the packets in a capture file.
+pub fn read_capture(path: &Path) -> io::Result<Vec<CapturedPacket>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a packet capture file",
+        ));
+    }
+    let mut packets = vec![];
+    loop {
+        let timestamp = match file.read_u64::<BigEndian>() {
+            Ok(timestamp) => timestamp,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        };
+        match read_record(&mut file, timestamp) {
+            Ok(packet) => packets.push(packet),
+            // the last record may be cut short if the node didn't shut down cleanly
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(packets)
+}
+
+fn read_record(file: &mut impl Read, timestamp: u64) -> io::Result<CapturedPacket> {
+    let direction = match file.read_u8()? {
+        0 => Direction::Received,
+        1 => Direction::Sent,
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let ip = match file.read_u8()? {
+        4 => {
+            let mut octets = [0; 4];
+            file.read_exact(&mut octets)?;
+            IpAddr::from(Ipv4Addr::from(octets))
+        }
+        6 => {
+            let mut octets = [0; 16];
+            file.read_exact(&mut octets)?;
+            IpAddr::from(Ipv6Addr::from(octets))
+        }
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let port = file.read_u16::<BigEndian>()?;
+    let mut packet = vec![0; file.read_u16::<BigEndian>()? as usize];
+    file.read_exact(&mut packet)?;
+    Ok(CapturedPacket {
+        timestamp: DateTime::from_timestamp_micros(timestamp as i64).unwrap_or_default(),
+        direction,
+        remote_addr: (ip, port).into(),
+        packet,
+    })
+}
+
+/// Turns captured packets into a timeline of the decrypted messages, using the session keys
+/// from the keylog.
+#[derive(Default)]
+pub struct CaptureDecoder {
+    keys: HashMap<SocketAddr, ConnectionKeys>,
+    /// Keylog lines already loaded, every rotated keylog repeats the keys logged before it
+    loaded: HashSet<String>,
+    seen: HashSet<(Direction, SocketAddr, PacketId)>,
+}
+
+/// Keys of a connection, the newest last.
+#[derive(Default)]
+struct ConnectionKeys {
+    inbound: Vec<Aes128Gcm>,
+    outbound: Vec<Aes128Gcm>,
+}
+
+impl CaptureDecoder {
+    /// Loads the session keys from a keylog written along with a capture, keylogs of several
+    /// files of the same capture can be loaded.
+    pub fn load_keylog(&mut self, path: &Path) -> io::Result<()> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid keylog line: {line}"),
+            )
+        };
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !self.loaded.insert(line.clone()) {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(addr), Some(inbound), Some(outbound)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let addr = addr.parse().map_err(|_| invalid(&line))?;
+            let decode_key = |key: &str| -> io::Result<[u8; 16]> {
+                let key = bs58::decode(key).into_vec().map_err(|_| invalid(&line))?;
+                key.try_into().map_err(|_| invalid(&line))
+            };
+            let keys = self.keys.entry(addr).or_default();
+            keys.inbound
+                .push(Aes128Gcm::new(&decode_key(inbound)?.into()));
+            keys.outbound
+                .push(Aes128Gcm::new(&decode_key(outbound)?.into()));
+        }
+        Ok(())
+    }
+
+    /// Decodes the next packet of the capture, packets must be decoded in the order they
+    /// were captured so rekeys and resends are tracked.
+    pub fn decode(&mut self, captured: &CapturedPacket) -> TimelineEntry {
+        let mut entry = TimelineEntry {
+            timestamp: captured.timestamp,
+            direction: captured.direction,
+            remote_addr: captured.remote_addr,
+            relay_addr: None,
+            size: captured.packet.len(),
+            packet_id: None,
+            receipts: vec![],
+            resend: false,
+            summary: "undecryptable, handshake or unknown connection".into(),
+        };
+        let mut packet = captured.packet.as_slice();
+        if let Some((addr, relayed)) = relay::unwrap(packet) {
+            entry.relay_addr = Some(captured.remote_addr);
+            entry.remote_addr = addr;
+            packet = relayed;
+        }
+        if packet.len() > MAX_PACKET_SIZE {
+            entry.summary = "oversized".into();
+            return entry;
+        }
+        let Some(msg) = self.decrypt(captured.direction, entry.remote_addr, packet) else {
+            return entry;
+        };
+
+        if let SymmetricMessagePayload::Rekey { key } = &msg.payload {
+            let keys = self.keys.entry(entry.remote_addr).or_default();
+            let new_key = Aes128Gcm::new(&(*key).into());
+            match captured.direction {
+                Direction::Received => keys.inbound.push(new_key),
+                Direction::Sent => keys.outbound.push(new_key),
+            }
+        }
+        entry.resend = !self
+            .seen
+            .insert((captured.direction, entry.remote_addr, msg.packet_id));
+        entry.packet_id = Some(msg.packet_id);
+        entry.summary = summary(&msg.payload);
+        entry.receipts = msg.confirm_receipt;
+        entry
+    }
+
+    fn decrypt(
+        &mut self,
+        direction: Direction,
+        remote_addr: SocketAddr,
+        packet: &[u8],
+    ) -> Option<SymmetricMessage> {
+        let packet = PacketData::<UnknownEncryption>::from_buf(packet);
+        let try_keys = |keys: &ConnectionKeys| {
+            let keys = match direction {
+                Direction::Received => &keys.inbound,
+                Direction::Sent => &keys.outbound,
+            };
+            keys.iter()
+                .rev()
+                .find_map(|key| packet.try_decrypt_sym(key).ok())
+        };
+        if let Some(decrypted) = self.keys.get(&remote_addr).and_then(try_keys) {
+            return SymmetricMessage::deser(decrypted.data()).ok();
+        }
+        // the remote may have moved to a new address since the keys were logged
+        let (previous_addr, decrypted) = self
+            .keys
+            .iter()
+            .filter(|(addr, _)| **addr != remote_addr)
+            .find_map(|(addr, keys)| Some((*addr, try_keys(keys)?)))?;
+        let keys = self.keys.remove(&previous_addr)?;
+        self.keys.insert(remote_addr, keys);
+        SymmetricMessage::deser(decrypted.data()).ok()
+    }
+}
+
+fn summary(payload: &SymmetricMessagePayload) -> String {
+    use SymmetricMessagePayload::*;
+    match payload {
+        AckConnection { result: Ok(_) } => "AckConnection".into(),
+        AckConnection { result: Err(error) } => format!("AckConnection error: {error}"),
+        AckConnectionV2 { .. } => "AckConnectionV2".into(),
+        AckConnectionV3 { features, .. } => format!("AckConnectionV3 features={features:#04x}"),
+        ShortMessage { payload } => format!("ShortMessage ({} bytes)", payload.len()),
+        StreamFragment {
+            stream_id,
+            total_length_bytes,
+            fragment_number,
+            payload,
+        } => format!(
+            "StreamFragment stream={stream_id} fragment={fragment_number} ({} of {total_length_bytes} bytes)",
+            payload.len()
+        ),
+        StreamParity {
+            stream_id,
+            first_fragment,
+            fragments,
+            parity_index,
+            ..
+        } => format!(
+            "StreamParity stream={stream_id} fragments={first_fragment}..={} parity={parity_index}",
+            *first_fragment as usize + fragments.len().saturating_sub(1)
+        ),
+        NoOp => "NoOp".into(),
+        Ping { seq } => format!("Ping seq={seq}"),
+        Pong { seq } => format!("Pong seq={seq}"),
+        Rekey { .. } => "Rekey".into(),
+        PathChallenge { .. } => "PathChallenge".into(),
+        PathResponse { .. } => "PathResponse".into(),
+        PmtuProbe { size, .. } => format!("PmtuProbe size={size}"),
+        PmtuProbeAck { size } => format!("PmtuProbeAck size={size}"),
+        ReceiptRanges { ranges } => {
+            let ranges: Vec<_> = ranges
+                .iter()
+                .map(|&ReceiptRange { first, len }| {
+                    format!("{first}..={}", first.wrapping_add(len as u32).wrapping_sub(1))
+                })
+                .collect();
+            format!("ReceiptRanges [{}]", ranges.join(", "))
+        }
+    }
+}
+
+/// A captured packet as shown in the timeline.
+pub struct TimelineEntry {
+    pub timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    pub remote_addr: SocketAddr,
+    /// Peer the packet went through, if it was relayed
+    pub relay_addr: Option<SocketAddr>,
+    pub size: usize,
+    pub packet_id: Option<u32>,
+    pub receipts: Vec<u32>,
+    /// Whether a packet with the same id was already sent to or received from the remote
+    pub resend: bool,
+    pub summary: String,
+}
+
+impl Display for TimelineEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arrow = match self.direction {
+            Direction::Received => "<-",
+            Direction::Sent => "->",
+        };
+        write!(
+            f,
+            "{} {arrow} {} {:>5}B",
+            self.timestamp.format("%H:%M:%S%.6f"),
+            self.remote_addr,
+            self.size
+        )?;
+        if let Some(relay_addr) = self.relay_addr {
+            write!(f, " via {relay_addr}")?;
+        }
+        match self.packet_id {
+            Some(packet_id) => write!(f, " #{packet_id}")?,
+            None => write!(f, " #?")?,
+        }
+        write!(f, " {}", self.summary)?;
+        if !self.receipts.is_empty() {
+            write!(f, " receipts={:?}", self.receipts)?;
+        }
+        if self.resend {
+            write!(f, " [resend]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::packet_data::SymmetricKey;
+
+    #[test]
+    fn decode_capture() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("capture");
+        let remote_addr: SocketAddr = "127.0.0.1:31337".parse()?;
+        let inbound = rand::random::<[u8; 16]>();
+        let outbound = rand::random::<[u8; 16]>();
+        let rekeyed = rand::random::<[u8; 16]>();
+
+        let capture = PacketCapture::create(&path)?;
+        capture.log_keys(remote_addr, inbound, outbound);
+        let outbound_key = SymmetricKey::new(outbound);
+        let packets = [
+            (0, SymmetricMessagePayload::NoOp, &outbound_key),
+            (0, SymmetricMessagePayload::NoOp, &outbound_key),
+            (
+                1,
+                SymmetricMessagePayload::Rekey { key: rekeyed },
+                &outbound_key,
+            ),
+            (
+                2,
+                SymmetricMessagePayload::NoOp,
+                &SymmetricKey::new(rekeyed),
+            ),
+        ];
+        for (packet_id, payload, key) in packets {
+            let packet =
+                SymmetricMessage::serialize_msg_to_packet_data(packet_id, payload, key, vec![])?;
+            capture.record(Direction::Sent, remote_addr, packet.data());
+        }
+        let inbound_packet = SymmetricMessage::serialize_msg_to_packet_data(
+            0,
+            SymmetricMessagePayload::NoOp,
+            &SymmetricKey::new(inbound),
+            vec![0, 1],
+        )?;
+        // the remote moved to a new address
+        let new_addr: SocketAddr = "127.0.0.1:31338".parse()?;
+        capture.record(Direction::Received, new_addr, inbound_packet.data());
+        drop(capture);
+
+        let mut decoder = CaptureDecoder::default();
+        decoder.load_keylog(&keylog_path(&path))?;
+        let timeline: Vec<_> = read_capture(&path)?
+            .iter()
+            .map(|packet| decoder.decode(packet))
+            .collect();
+        assert_eq!(timeline.len(), 5);
+        assert_eq!(timeline[0].packet_id, Some(0));
+        assert!(!timeline[0].resend);
+        assert!(timeline[1].resend);
+        assert_eq!(timeline[2].summary, "Rekey");
+        assert_eq!(timeline[3].packet_id, Some(2));
+        assert_eq!(timeline[4].direction, Direction::Received);
+        assert_eq!(timeline[4].remote_addr, new_addr);
+        assert_eq!(timeline[4].receipts, vec![0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_capture() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("capture");
+        let mut writer = CaptureWriter::create(path.clone(), HashMap::new())?;
+        let remote_addr: SocketAddr = "127.0.0.1:31337".parse()?;
+        let keys = (rand::random(), rand::random());
+        writer.handle(CaptureEvent::Keys { remote_addr, keys })?;
+        for _ in 0..MAX_FILES + 1 {
+            writer.write(Duration::ZERO, Direction::Sent, remote_addr, &[0; 10])?;
+            writer.written = MAX_FILE_SIZE;
+        }
+        drop(writer);
+        assert!(path.exists());
+        let expected_keylog = keylog_line(remote_addr, &keys);
+        assert_eq!(fs::read_to_string(keylog_path(&path))?, expected_keylog);
+        for n in 1..MAX_FILES {
+            let rotated = rotated_path(&path, n);
+            assert_eq!(read_capture(&rotated)?.len(), 1);
+            // the keys of the connection are carried over to every rotated keylog
+            assert_eq!(fs::read_to_string(keylog_path(&rotated))?, expected_keylog);
+        }
+        assert!(!rotated_path(&path, MAX_FILES).exists());
+        assert!(!keylog_path(&rotated_path(&path, MAX_FILES)).exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keylog_owner_only() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("capture");
+        // a keylog left behind by a previous capture is replaced
+        fs::write(keylog_path(&path), "stale keys\n")?;
+        fs::set_permissions(keylog_path(&path), fs::Permissions::from_mode(0o644))?;
+        drop(PacketCapture::create(&path)?);
+        let metadata = fs::metadata(keylog_path(&path))?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(metadata.len(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+use std::time::{Duration, Instant};
+
+/// Initial congestion window in packets (RFC 6928).
+const INITIAL_WINDOW: f64 = 10.0;
+
+/// The window never shrinks below this amount of packets, so a connection can always make
+/// progress even after repeated losses.
+const MIN_WINDOW: f64 = 2.0;
+
+/// Upper bound for the window in packets, the node-wide rate limiter still applies on top.
+const MAX_WINDOW: f64 = 20_000.0;
+
+/// CUBIC scaling constant (RFC 8312).
+const CUBIC_C: f64 = 0.4;
+
+/// Multiplicative decrease factor applied to the window on a congestion event (RFC 8312).
+const CUBIC_BETA: f64 = 0.7;
+
+/// RTT estimator gains (RFC 6298).
+const RTT_ALPHA: f64 = 1.0 / 8.0;
+const RTT_BETA: f64 = 1.0 / 4.0;
+
+/// Used as RTT estimate until the first sample arrives.
+const INITIAL_RTT: Duration = Duration::from_millis(300);
+
+/// Smoothed round-trip time estimate for a connection following RFC 6298.
+///
+/// Samples include the time the remote waits to batch receipts together, so values will be
+/// biased upwards by up to `MAX_CONFIRMATION_DELAY`.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RttEstimator {
+    smoothed: Option<Duration>,
+    variance: Duration,
+    min: Option<Duration>,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self {
+            smoothed: None,
+            variance: Duration::ZERO,
+            min: None,
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: Duration) {
+        self.min = Some(self.min.map_or(sample, |min| min.min(sample)));
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variance = sample / 2;
+            }
+            Some(srtt) => {
+                let deviation = if srtt > sample {
+                    srtt - sample
+                } else {
+                    sample - srtt
+                };
+                self.variance = self.variance.mul_f64(1.0 - RTT_BETA) + deviation.mul_f64(RTT_BETA);
+                self.smoothed = Some(srtt.mul_f64(1.0 - RTT_ALPHA) + sample.mul_f64(RTT_ALPHA));
+            }
+        }
+    }
+
+    /// Returns the smoothed RTT, or a conservative default if no sample has been taken yet.
+    pub fn smoothed(&self) -> Duration {
+        self.smoothed.unwrap_or(INITIAL_RTT)
+    }
+
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    pub fn has_samples(&self) -> bool {
+        self.smoothed.is_some()
+    }
+}
+
+/// Per-connection CUBIC congestion controller (RFC 8312).
+///
+/// The controller is fed by the [`SentPacketTracker`](super::sent_packet_tracker::SentPacketTracker)
+/// with acknowledged bytes, RTT samples and losses, and decides how many bytes may be in flight
+/// at any given time. All the window arithmetic is done in packets of `mss` bytes.
+#[derive(Debug)]
+pub(super) struct CongestionController {
+    /// Maximum segment size in bytes, used to convert between bytes and packets
+    mss: usize,
+    /// Congestion window in packets
+    cwnd: f64,
+    /// Slow start threshold in packets
+    ssthresh: f64,
+    /// Window size in packets right before the last congestion event
+    w_max: f64,
+    /// Window estimate for the TCP-friendly region
+    w_est: f64,
+    /// Time it takes to grow back to `w_max` after a congestion event
+    k: f64,
+    /// Start of the current congestion avoidance epoch
+    epoch_start: Option<Instant>,
+    /// Losses of packets sent before this instant belong to an already handled congestion event
+    recovery_until: Option<Instant>,
+    rtt: RttEstimator,
+}
+
+impl CongestionController {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: INITIAL_WINDOW,
+            ssthresh: f64::INFINITY,
+            w_max: 0.0,
+            w_est: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            recovery_until: None,
+            rtt: RttEstimator::new(),
+        }
+    }
+
+    /// Current congestion window in bytes.
+    pub fn window(&self) -> usize {
+        (self.cwnd * self.mss as f64) as usize
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Report a round-trip time measured without acknowledging any data, e.g. by a ping.
+    pub fn on_rtt_sample(&mut self, sample: Duration) {
+        self.rtt.add_sample(sample);
+    }
+
+    /// Whether a packet of `packet_size` bytes can be sent given the amount of bytes that
+    /// are currently waiting for a receipt.
+    pub fn can_send(&self, bytes_in_flight: usize, packet_size: usize) -> bool {
+        // always allow at least one packet in flight so the connection can't starve
+        bytes_in_flight == 0 || bytes_in_flight + packet_size <= self.window()
+    }
+
+    /// Report that `acked_bytes` were acknowledged by the remote, optionally with the round-trip
+    /// time measured for them.
+    pub fn on_ack(&mut self, now: Instant, acked_bytes: usize, rtt_sample: Option<Duration>) {
+        if let Some(sample) = rtt_sample {
+            self.rtt.add_sample(sample);
+        }
+        let acked = acked_bytes as f64 / self.mss as f64;
+
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd = (self.cwnd + acked).min(MAX_WINDOW);
+            return;
+        }
+
+        let epoch_start = match self.epoch_start {
+            Some(start) => start,
+            None => {
+                if self.cwnd < self.w_max {
+                    self.k = ((self.w_max - self.cwnd) / CUBIC_C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = self.cwnd;
+                }
+                self.w_est = self.cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+
+        let t = (now - epoch_start + self.rtt.smoothed()).as_secs_f64();
+        let w_cubic = CUBIC_C * (t - self.k).powi(3) + self.w_max;
+        self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * acked / self.cwnd;
+        let target = w_cubic.max(self.w_est);
+
+        if target > self.cwnd {
+            self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+        } else {
+            // grow very slowly while in the plateau around w_max
+            self.cwnd += 0.01 * acked / self.cwnd;
+        }
+        self.cwnd = self.cwnd.min(MAX_WINDOW);
+    }
+
+    /// Report that a packet sent at `sent_at` was considered lost.
+    pub fn on_loss(&mut self, now: Instant, sent_at: Instant) {
+        if self.recovery_until.map_or(false, |until| sent_at < until) {
+            // already reacted to this congestion event
+            return;
+        }
+        self.recovery_until = Some(now);
+
+        // fast convergence: release bandwidth faster if the window hasn't recovered since
+        // the previous congestion event
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.cwnd = (self.cwnd * CUBIC_BETA).max(MIN_WINDOW);
+        self.ssthresh = self.cwnd;
+        self.epoch_start = None;
+    }
+
+    /// How long a sender which is blocked by the window should wait before checking again.
+    pub fn blocked_poll_interval(&self) -> Duration {
+        (self.rtt.smoothed() / 8).clamp(Duration::from_millis(1), Duration::from_millis(50))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    #[test]
+    fn slow_start_doubles_window_per_rtt() {
+        let mut cc = CongestionController::new(MSS);
+        let now = Instant::now();
+        let initial = cc.window();
+        cc.on_ack(now, initial, Some(Duration::from_millis(50)));
+        assert_eq!(cc.window(), initial * 2);
+    }
+
+    #[test]
+    fn loss_reduces_window_once_per_event() {
+        let mut cc = CongestionController::new(MSS);
+        let start = Instant::now();
+        cc.on_ack(start, 10 * MSS, Some(Duration::from_millis(50)));
+        let before = cc.window();
+
+        let now = start + Duration::from_millis(600);
+        cc.on_loss(now, start);
+        let after = cc.window();
+        assert_eq!(after, (before as f64 * CUBIC_BETA) as usize);
+
+        // a second packet from the same flight is lost, window stays the same
+        cc.on_loss(
+            now + Duration::from_millis(1),
+            start + Duration::from_millis(1),
+        );
+        assert_eq!(cc.window(), after);
+
+        // a packet sent after the congestion event is lost, reduce again
+        cc.on_loss(
+            now + Duration::from_millis(700),
+            now + Duration::from_millis(10),
+        );
+        assert!(cc.window() < after);
+    }
+
+    #[test]
+    fn window_never_below_minimum() {
+        let mut cc = CongestionController::new(MSS);
+        let mut now = Instant::now();
+        for _ in 0..100 {
+            now += Duration::from_secs(1);
+            cc.on_loss(now, now);
+        }
+        assert_eq!(cc.window(), (MIN_WINDOW * MSS as f64) as usize);
+    }
+
+    #[test]
+    fn cubic_recovers_towards_previous_max() {
+        let mut cc = CongestionController::new(MSS);
+        let mut now = Instant::now();
+        cc.on_ack(now, 90 * MSS, Some(Duration::from_millis(20)));
+        let w_max = cc.window();
+        now += Duration::from_millis(600);
+        cc.on_loss(now, now - Duration::from_millis(1));
+        let reduced = cc.window();
+        assert!(reduced < w_max);
+
+        // keep acknowledging a full window every RTT for a few seconds
+        for _ in 0..300 {
+            now += Duration::from_millis(20);
+            let window = cc.window();
+            cc.on_ack(now, window, Some(Duration::from_millis(20)));
+        }
+        assert!(cc.window() >= w_max);
+    }
+
+    #[test]
+    fn always_allows_one_packet_in_flight() {
+        let cc = CongestionController::new(MSS);
+        assert!(cc.can_send(0, cc.window() * 2));
+        assert!(!cc.can_send(cc.window(), 1));
+    }
+
+    #[test]
+    fn rtt_estimation() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.smoothed(), INITIAL_RTT);
+        rtt.add_sample(Duration::from_millis(100));
+        assert_eq!(rtt.smoothed(), Duration::from_millis(100));
+        assert_eq!(rtt.variance(), Duration::from_millis(50));
+        for _ in 0..100 {
+            rtt.add_sample(Duration::from_millis(200));
+        }
+        let srtt = rtt.smoothed().as_millis();
+        assert!((199..=200).contains(&srtt), "{srtt}");
+        assert_eq!(rtt.min(), Some(Duration::from_millis(100)));
+    }
+}
@@ -1,34 +1,72 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::transport::crypto::TransportSecretKey;
+use crate::transport::crypto::{EphemeralKey, TransportSecretKey};
 use crate::transport::packet_data::{AssymetricRSA, UnknownEncryption};
 use crate::transport::symmetric_message::OutboundConnection;
-use aes_gcm::{Aes128Gcm, KeyInit};
+use bytes::Bytes;
 use futures::{
     stream::{FuturesUnordered, StreamExt},
     Future,
 };
 use futures::{FutureExt, TryFutureExt};
-use tokio::net::UdpSocket;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task;
 
 use super::packet_data::SymmetricAES;
 use super::{
+    capture::{CapturingSocket, PacketCapture},
     crypto::{TransportKeypair, TransportPublicKey},
-    packet_data::MAX_PACKET_SIZE,
-    peer_connection::{PeerConnection, RemoteConnection},
+    intro_gate::{self, Admission, IntroGate},
+    packet_data::{SymmetricKey, MAX_PACKET_SIZE},
+    packet_pool::{self, RecvBatch},
+    peer_connection::{
+        InboundKey, OutboundKey, PeerConnection, RemoteConnection, DEFAULT_IDLE_TIMEOUT,
+    },
+    relay,
     sent_packet_tracker::SentPacketTracker,
     symmetric_message::{SymmetricMessage, SymmetricMessagePayload},
-    PacketData, Socket, TransportError,
+    PacketData, Socket, TransportError, UdpSocket,
 };
 
-const PROTOC_VERSION: [u8; 2] = 1u16.to_le_bytes();
+/// Version 2 of the handshake uses Ed25519 identities and ephemeral X25519 keys to derive
+/// the session keys, which gives forward secrecy.
+const PROTOC_VERSION: [u8; 2] = 2u16.to_le_bytes();
+
+/// Version 1 of the handshake, used with peers which have an RSA identity. Session keys are
+/// the ones exchanged in the intro and ack packets.
+const LEGACY_PROTOC_VERSION: [u8; 2] = 1u16.to_le_bytes();
+
+/// Optional features announced after the ephemeral key in version 2 intro packets, and
+/// returned in the ack by peers which support announcing them.
+const FEATURE_FEC: u8 = 1;
+const FEATURE_PING: u8 = 2;
+
+/// Features supported by this peer.
+const FEATURES: u8 = FEATURE_FEC | FEATURE_PING;
+
+/// Minimum time between path challenges sent to the same new address of a remote.
+const PATH_CHALLENGE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Packets from the same unknown IP checked per second for belonging to an established
+/// connection whose remote changed address, the rest are handled as intro packets.
+const PATH_CHECKS_PER_SECOND: u32 = 20;
+
+/// Delay before connecting to the next address of a dual-stack remote, unless the previous
+/// attempt failed earlier (RFC 8305).
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How often the bytes relayed for other peers are reported.
+const RELAY_USAGE_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes relayed from one peer to another per `RELAY_USAGE_REPORT_INTERVAL`, packets beyond
+/// it are dropped.
+const RELAY_BYTES_PER_INTERVAL: usize = 512 * 1024;
 
 // Constants for interval increase
 const INITIAL_INTERVAL: Duration = Duration::from_millis(200);
@@ -36,6 +74,7 @@ const INTERVAL_INCREASE_FACTOR: u64 = 2;
 const MAX_INTERVAL: Duration = Duration::from_millis(5000); // Maximum interval limit
 
 const DEFAULT_BW_TRACKER_WINDOW_SIZE: Duration = Duration::from_secs(10);
+/// Node-wide outbound ceiling, per connection throughput is adapted by the congestion controller.
 const BANDWITH_LIMIT: usize = 1024 * 1024 * 10; // 10 MB/s
 
 type ConnectionHandlerMessage = (SocketAddr, Vec<u8>);
@@ -57,35 +96,80 @@ struct GatewayMessage {
     resp_tx: oneshot::Sender<bool>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn create_connection_handler<S: Socket>(
     keypair: TransportKeypair,
     listen_host: IpAddr,
     listen_port: u16,
     is_gateway: bool,
+    packet_capture: Option<&Path>,
+    idle_timeout: Option<Duration>,
+    intro_cookie_threshold: Option<u32>,
+    intro_pow_threshold: Option<u32>,
 ) -> Result<(OutboundConnectionHandler, InboundConnectionHandler), TransportError> {
+    let capture = packet_capture
+        .map(PacketCapture::create)
+        .transpose()?
+        .map(Arc::new);
     // Bind the UDP socket to the specified port
     let socket = S::bind((listen_host, listen_port).into()).await?;
-    let (och, new_connection_notifier) = OutboundConnectionHandler::config_listener(
-        Arc::new(socket),
+    let (och, ich) = OutboundConnectionHandler::config_listener(
+        Arc::new(CapturingSocket::new(socket, capture.clone())),
         keypair,
         is_gateway,
         (listen_host, listen_port).into(),
+        IntroGate::with_thresholds(
+            intro_cookie_threshold.unwrap_or(intro_gate::COOKIE_THRESHOLD),
+            intro_pow_threshold.unwrap_or(intro_gate::POW_THRESHOLD),
+        ),
+        capture,
+        idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
     )?;
-    Ok((
-        och,
-        InboundConnectionHandler {
-            new_connection_notifier,
-        },
-    ))
+    Ok((och, ich))
 }
 
 pub(crate) struct InboundConnectionHandler {
     new_connection_notifier: mpsc::Receiver<PeerConnection>,
+    migration_notifier: mpsc::Receiver<ConnectionMigration>,
+    relay_usage_notifier: mpsc::Receiver<RelayUsage>,
+}
+
+pub(crate) enum InboundConnectionEvent {
+    /// A remote peer opened a connection with this gateway
+    Connection(PeerConnection),
+    /// The remote of an established connection moved to a new address
+    Migration(ConnectionMigration),
+    /// This peer forwarded packets between two of its connections
+    RelayUsage(RelayUsage),
+}
+
+/// An established connection whose remote changed its public address, e.g. because its NAT
+/// mapping changed. The connection keeps working at the new address.
+pub(crate) struct ConnectionMigration {
+    pub previous_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+}
+
+/// Bytes relayed from `source` to `target` since the last report.
+pub(crate) struct RelayUsage {
+    pub source: SocketAddr,
+    pub target: SocketAddr,
+    pub bytes: usize,
 }
 
 impl InboundConnectionHandler {
-    pub async fn next_connection(&mut self) -> Option<PeerConnection> {
-        self.new_connection_notifier.recv().await
+    pub async fn next_event(&mut self) -> Option<InboundConnectionEvent> {
+        tokio::select! {
+            conn = self.new_connection_notifier.recv() => {
+                conn.map(InboundConnectionEvent::Connection)
+            }
+            migration = self.migration_notifier.recv() => {
+                migration.map(InboundConnectionEvent::Migration)
+            }
+            usage = self.relay_usage_notifier.recv() => {
+                usage.map(InboundConnectionEvent::RelayUsage)
+            }
+        }
     }
 }
 
@@ -100,10 +184,15 @@ impl OutboundConnectionHandler {
         keypair: TransportKeypair,
         is_gateway: bool,
         socket_addr: SocketAddr,
-    ) -> Result<(Self, mpsc::Receiver<PeerConnection>), TransportError> {
+        intro_gate: IntroGate,
+        capture: Option<Arc<PacketCapture>>,
+        idle_timeout: Duration,
+    ) -> Result<(Self, InboundConnectionHandler), TransportError> {
         // Channel buffer is one so senders will await until the receiver is ready, important for bandwidth limiting
         let (conn_handler_sender, conn_handler_receiver) = mpsc::channel(100);
         let (new_connection_sender, new_connection_notifier) = mpsc::channel(100);
+        let (migration_sender, migration_notifier) = mpsc::channel(100);
+        let (relay_usage_sender, relay_usage_notifier) = mpsc::channel(100);
 
         // Channel buffer is one so senders will await until the receiver is ready, important for bandwidth limiting
         let (outbound_sender, outbound_recv) = mpsc::channel(1);
@@ -114,8 +203,19 @@ impl OutboundConnectionHandler {
             remote_connections: BTreeMap::new(),
             connection_handler: conn_handler_receiver,
             new_connection_notifier: new_connection_sender,
+            migration_notifier: migration_sender,
+            relay_usage_notifier: relay_usage_sender,
+            relay_queue: relay::relay_queue(outbound_sender.clone()),
             outbound_packets: outbound_sender,
             this_addr: socket_addr,
+            intro_gate,
+            relay_candidates: BTreeMap::new(),
+            relayed_remotes: BTreeMap::new(),
+            relay_pairs: BTreeMap::new(),
+            relay_usage: BTreeMap::new(),
+            path_checks: PathCheckLimiter::default(),
+            capture,
+            idle_timeout,
         };
         let bw_tracker = super::rate_limiter::PacketRateLimiter::new(
             DEFAULT_BW_TRACKER_WINDOW_SIZE,
@@ -128,7 +228,14 @@ impl OutboundConnectionHandler {
         task::spawn(bw_tracker.rate_limiter(BANDWITH_LIMIT, socket));
         task::spawn(transport.listen());
 
-        Ok((connection_handler, new_connection_notifier))
+        Ok((
+            connection_handler,
+            InboundConnectionHandler {
+                new_connection_notifier,
+                migration_notifier,
+                relay_usage_notifier,
+            },
+        ))
     }
 
     #[cfg(test)]
@@ -137,8 +244,34 @@ impl OutboundConnectionHandler {
         socket: Arc<impl Socket>,
         keypair: TransportKeypair,
         is_gateway: bool,
-    ) -> Result<(Self, mpsc::Receiver<PeerConnection>), TransportError> {
-        Self::config_listener(socket, keypair, is_gateway, socket_addr)
+    ) -> Result<(Self, InboundConnectionHandler), TransportError> {
+        Self::config_listener(
+            socket,
+            keypair,
+            is_gateway,
+            socket_addr,
+            IntroGate::new(),
+            None,
+            DEFAULT_IDLE_TIMEOUT,
+        )
+    }
+
+    /// Registers a peer connected to both this peer and `remote_addr` which offered to relay
+    /// the packets between them, used if a connection attempt to `remote_addr` fails.
+    pub async fn relay_through(&mut self, remote_addr: SocketAddr, relay_addr: SocketAddr) {
+        let _ = self
+            .send_queue
+            .send((remote_addr, ConnectionEvent::RelayAvailable { relay_addr }))
+            .await;
+    }
+
+    /// Allows relaying packets between two remotes connected to this peer, once this peer
+    /// offered to relay between them. Packets between any other remotes are not relayed.
+    pub async fn allow_relay(&mut self, remote_addr: SocketAddr, other_addr: SocketAddr) {
+        let _ = self
+            .send_queue
+            .send((remote_addr, ConnectionEvent::RelayAllowed { other_addr }))
+            .await;
     }
 
     pub async fn connect(
@@ -171,6 +304,54 @@ impl OutboundConnectionHandler {
             })
             .boxed()
     }
+
+    /// Connects to a remote reachable at several addresses, typically one per address family.
+    ///
+    /// IPv6 addresses are tried first, a new attempt starts every [`CONNECTION_ATTEMPT_DELAY`]
+    /// or as soon as the previous one fails, and the first connection established wins. The
+    /// attempts still ongoing are dropped then, and the listener discards their connections.
+    pub async fn connect_any(
+        &mut self,
+        remote_public_key: TransportPublicKey,
+        remote_addrs: impl IntoIterator<Item = SocketAddr>,
+    ) -> Pin<Box<dyn Future<Output = Result<PeerConnection, TransportError>> + Send>> {
+        let mut remote_addrs: Vec<_> = remote_addrs.into_iter().collect();
+        remote_addrs.sort_by_key(|addr| addr.is_ipv4());
+        remote_addrs.dedup();
+        if let [remote_addr] = remote_addrs[..] {
+            return self.connect(remote_public_key, remote_addr).await;
+        }
+        let mut handler = self.clone();
+        async move {
+            let mut pending = remote_addrs.into_iter().peekable();
+            let mut attempts = FuturesUnordered::new();
+            let mut last_error = None;
+            loop {
+                if attempts.is_empty() {
+                    let Some(remote_addr) = pending.next() else {
+                        return Err(last_error.unwrap_or(
+                            TransportError::ConnectionEstablishmentFailure {
+                                cause: "no address to connect to".into(),
+                            },
+                        ));
+                    };
+                    attempts.push(handler.connect(remote_public_key.clone(), remote_addr).await);
+                }
+                tokio::select! {
+                    result = attempts.next() => match result {
+                        Some(Ok(connection)) => return Ok(connection),
+                        Some(Err(error)) => last_error = Some(error),
+                        None => {}
+                    },
+                    _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.peek().is_some() => {
+                        let remote_addr = pending.next().expect("peeked");
+                        attempts.push(handler.connect(remote_public_key.clone(), remote_addr).await);
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
 }
 
 pub enum Message {
@@ -191,8 +372,28 @@ struct UdpPacketsListener<S = UdpSocket> {
     this_peer_keypair: TransportKeypair,
     is_gateway: bool,
     new_connection_notifier: mpsc::Sender<PeerConnection>,
-    outbound_packets: mpsc::Sender<(SocketAddr, Arc<[u8]>)>,
+    migration_notifier: mpsc::Sender<ConnectionMigration>,
+    relay_usage_notifier: mpsc::Sender<RelayUsage>,
+    /// Packets relayed for other peers, dropped instead of waiting when the queue is full
+    relay_queue: mpsc::Sender<(SocketAddr, Bytes)>,
+    outbound_packets: mpsc::Sender<(SocketAddr, Bytes)>,
     this_addr: SocketAddr,
+    /// Decides which intro packets are worth decrypting on gateways
+    intro_gate: IntroGate,
+    /// Peers which offered to relay to a remote in case connecting to it directly fails
+    relay_candidates: BTreeMap<SocketAddr, SocketAddr>,
+    /// Remotes connected through a relay, with the address of the relay
+    relayed_remotes: BTreeMap<SocketAddr, SocketAddr>,
+    /// Remotes this peer offered to relay for, with the remotes their packets can be relayed to
+    relay_pairs: BTreeMap<SocketAddr, BTreeSet<SocketAddr>>,
+    /// Bytes relayed for other peers since the last report, by source and target
+    relay_usage: BTreeMap<(SocketAddr, SocketAddr), usize>,
+    /// Limits the packets from unknown addresses decrypted to check for path migrations
+    path_checks: PathCheckLimiter,
+    /// Set when packets are being captured, to log the session keys of new connections
+    capture: Option<Arc<PacketCapture>>,
+    /// Time without receiving anything after which connections are closed
+    idle_timeout: Duration,
 }
 
 type OngoingConnection = (
@@ -213,7 +414,7 @@ type GwOngoingConnectionResult = Option<
             (
                 RemoteConnection,
                 InboundRemoteConnection,
-                PacketData<SymmetricAES>,
+                Option<PacketData<SymmetricAES>>,
             ),
             (TransportError, SocketAddr),
         >,
@@ -230,8 +431,11 @@ impl<T> Drop for UdpPacketsListener<T> {
 
 impl<S: Socket> UdpPacketsListener<S> {
     async fn listen(mut self) -> Result<(), TransportError> {
-        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let mut batch = RecvBatch::default();
         let mut ongoing_connections: BTreeMap<SocketAddr, OngoingConnection> = BTreeMap::new();
+        // keys of the remotes being connected to, needed to retry through a relay
+        let mut connecting_keys: BTreeMap<SocketAddr, TransportPublicKey> = BTreeMap::new();
+        let mut relay_usage_report = tokio::time::interval(RELAY_USAGE_REPORT_INTERVAL);
         let mut gw_ongoing_connections: BTreeMap<SocketAddr, OngoingConnection> = BTreeMap::new();
         let mut connection_tasks = FuturesUnordered::new();
         let mut gw_connection_tasks = FuturesUnordered::new();
@@ -239,36 +443,85 @@ impl<S: Socket> UdpPacketsListener<S> {
         loop {
             tokio::select! {
                 // Handling of inbound packets
-                recv_result = self.socket_listener.recv_from(&mut buf) => {
+                recv_result = self.socket_listener.recv_batch(&mut batch) => {
                     match recv_result {
-                        Ok((size, remote_addr)) => {
-                            let packet_data = PacketData::from_buf(&buf[..size]);
-                            if let Some(remote_conn) = self.remote_connections.remove(&remote_addr){
-                                let _ = remote_conn.inbound_packet_sender.send(packet_data).await;
-                                self.remote_connections.insert(remote_addr, remote_conn);
-                                continue;
-                            }
+                        Ok(_) => {
+                            for (packet, remote_addr) in batch.packets() {
+                                let relayed = if self.may_be_relayed(remote_addr) {
+                                    relay::unwrap(packet)
+                                } else {
+                                    None
+                                };
+                                let (packet, remote_addr) = match relayed {
+                                    Some((peer_addr, packet)) if self.relayed_remotes.get(&peer_addr) == Some(&remote_addr) => {
+                                        // a connection going through a relay
+                                        (packet, peer_addr)
+                                    }
+                                    Some((target_addr, packet)) => {
+                                        self.relay_packet(remote_addr, target_addr, packet);
+                                        continue;
+                                    }
+                                    None => (packet, remote_addr),
+                                };
+                                if packet.len() > MAX_PACKET_SIZE {
+                                    tracing::debug!(%remote_addr, size = packet.len(), "dropping oversized packet");
+                                    continue;
+                                }
+                                let mut packet_data = PacketData::from_buf(packet);
+                                if let Some(remote_conn) = self.remote_connections.remove(&remote_addr){
+                                    match remote_conn.inbound_packet_sender.send(packet_data).await {
+                                        Ok(()) => {
+                                            self.remote_connections.insert(remote_addr, remote_conn);
+                                            continue;
+                                        }
+                                        Err(mpsc::error::SendError(packet)) => {
+                                            // the connection was closed, e.g. after being idle for too long,
+                                            // the remote may be connecting again
+                                            tracing::debug!(%remote_addr, "connection closed, forgetting remote");
+                                            self.forget_relay_pairs(remote_addr);
+                                            packet_data = packet;
+                                        }
+                                    }
+                                }
+
+                                if let Some((packets_sender, open_connection)) = ongoing_connections.remove(&remote_addr) {
+                                    if packets_sender.send(packet_data).await.is_err() {
+                                        // it can happen that the connection is established but the channel is closed because the task completed
+                                        // but we still haven't polled the result future
+                                        tracing::debug!(%remote_addr, "failed to send packet to remote");
+                                    }
+                                    ongoing_connections.insert(remote_addr, (packets_sender, open_connection));
+                                    continue;
+                                }
 
-                            if let Some((packets_sender, open_connection)) = ongoing_connections.remove(&remote_addr) {
-                                if packets_sender.send(packet_data).await.is_err() {
-                                    // it can happen that the connection is established but the channel is closed because the task completed
-                                    // but we still haven't polled the result future
-                                    tracing::debug!(%remote_addr, "failed to send packet to remote");
+                                // packets are checked at a limited rate per source, and only those whose
+                                // key hint matches a connection are decrypted, so this is cheaper than
+                                // the intro gate
+                                if self.check_path_migration(&packet_data, remote_addr).await {
+                                    continue;
                                 }
-                                ongoing_connections.insert(remote_addr, (packets_sender, open_connection));
-                                continue;
-                            }
 
-                            if !self.is_gateway {
-                                tracing::debug!(%remote_addr, "unexpected packet from remote");
-                                continue;
+                                if !self.is_gateway {
+                                    tracing::debug!(%remote_addr, "unexpected packet from remote");
+                                    continue;
+                                }
+                                let intro_packet = match self.intro_gate.admit(packet, remote_addr) {
+                                    Admission::Accept(intro_packet) => intro_packet,
+                                    Admission::Retry(retry) => {
+                                        tracing::debug!(%remote_addr, "intro gate closed, asking remote to retry");
+                                        // don't wait for the rate limiter while flooded, the remote resends its intro packet anyway
+                                        let _ = self.outbound_packets.try_send((remote_addr, retry));
+                                        continue;
+                                    }
+                                    Admission::Drop => continue,
+                                };
+                                let packet_data = PacketData::from_buf(intro_packet);
+                                let gw_ongoing_connection = self.gateway_connection(packet_data, remote_addr, gw_outbound_tx.clone());
+                                let task = tokio::spawn(gw_ongoing_connection.map_err(move |error| {
+                                    (error, remote_addr)
+                                }));
+                                gw_connection_tasks.push(task);
                             }
-                            let packet_data = PacketData::from_buf(&buf[..size]);
-                            let gw_ongoing_connection = self.gateway_connection(packet_data, remote_addr, gw_outbound_tx.clone());
-                            let task = tokio::spawn(gw_ongoing_connection.map_err(move |error| {
-                                (error, remote_addr)
-                            }));
-                            gw_connection_tasks.push(task);
                         }
                         Err(e) => {
                             // TODO: this should panic and be propagate to the main task or retry and eventually fail
@@ -283,9 +536,12 @@ impl<S: Socket> UdpPacketsListener<S> {
                     };
 
                     if let Some(remote) = self.remote_connections.remove(&remote_addr) {
-                        let _ = remote.inbound_packet_sender.send(packet).await;
-                        self.remote_connections.insert(remote_addr, remote);
-                        let _ = resp_tx.send(true);
+                        if remote.inbound_packet_sender.send(packet).await.is_ok() {
+                            self.remote_connections.insert(remote_addr, remote);
+                            let _ = resp_tx.send(true);
+                        } else {
+                            self.forget_relay_pairs(remote_addr);
+                        }
                     }
                 }
                 gw_connection_handshake = gw_connection_tasks.next(), if !gw_connection_tasks.is_empty() => {
@@ -307,10 +563,12 @@ impl<S: Socket> UdpPacketsListener<S> {
                                 continue;
                             }
 
-                            sent_tracker.lock().report_sent_packet(
-                                SymmetricMessage::FIRST_PACKET_ID,
-                                outbound_ack_packet.prepared_send(),
-                            );
+                            if let Some(outbound_ack_packet) = outbound_ack_packet {
+                                sent_tracker.lock().report_sent_packet(
+                                    SymmetricMessage::FIRST_PACKET_ID,
+                                    outbound_ack_packet.prepared_send_retained(),
+                                );
+                            }
                         }
                         Err((error, remote_addr)) => {
                             tracing::error!(%error, ?remote_addr, "Failed to establish gateway connection");
@@ -326,7 +584,14 @@ impl<S: Socket> UdpPacketsListener<S> {
                     };
                     match res.expect("task shouldn't panic") {
                         Ok((outbound_remote_conn, inbound_remote_connection)) => {
+                            connecting_keys.remove(&outbound_remote_conn.remote_addr);
                             if let Some((_, result_sender)) = ongoing_connections.remove(&outbound_remote_conn.remote_addr) {
+                                if result_sender.is_closed() {
+                                    // nobody waits for it anymore, e.g. `connect_any` connected to
+                                    // another address of the remote first, so don't keep it open
+                                    tracing::debug!(%outbound_remote_conn.remote_addr, "connection no longer wanted, dropping it");
+                                    continue;
+                                }
                                 tracing::debug!(%outbound_remote_conn.remote_addr, "connection established");
                                 self.remote_connections.insert(outbound_remote_conn.remote_addr, inbound_remote_connection);
                                 let _ = result_sender.send(Ok(outbound_remote_conn)).map_err(|_| {
@@ -337,6 +602,26 @@ impl<S: Socket> UdpPacketsListener<S> {
                             }
                         }
                         Err((error, remote_addr)) => {
+                            let remote_public_key = connecting_keys.remove(&remote_addr);
+                            let relay_addr = self.relay_candidates.remove(&remote_addr);
+                            if self.relayed_remotes.remove(&remote_addr).is_none() {
+                                if let (Some(relay_addr), Some(remote_public_key), Some((packets_sender, _))) =
+                                    (relay_addr, remote_public_key, ongoing_connections.get_mut(&remote_addr))
+                                {
+                                    tracing::info!(%error, %remote_addr, %relay_addr, "direct connection failed, connecting through relay");
+                                    self.relayed_remotes.insert(remote_addr, relay_addr);
+                                    let (ongoing_connection, relayed_packets_sender) = self.traverse_nat(
+                                        remote_addr, remote_public_key.clone(), Some(relay_addr),
+                                    );
+                                    *packets_sender = relayed_packets_sender;
+                                    connecting_keys.insert(remote_addr, remote_public_key);
+                                    let task = tokio::spawn(ongoing_connection.map_err(move |error| {
+                                        (error, remote_addr)
+                                    }));
+                                    connection_tasks.push(task);
+                                    continue;
+                                }
+                            }
                             tracing::error!(%error, ?remote_addr, "Failed to establish connection");
                             if let Some((_, result_sender)) = ongoing_connections.remove(&remote_addr) {
                                 let _ = result_sender.send(Err(error));
@@ -347,21 +632,197 @@ impl<S: Socket> UdpPacketsListener<S> {
                 // Handling of connection events
                 connection_event = self.connection_handler.recv() => {
                     let Some((remote_addr, event)) = connection_event else { return Ok(()); };
-                    let ConnectionEvent::ConnectionStart { remote_public_key, open_connection } = event;
-                    tracing::debug!(%remote_addr, "attempting to establish connection");
-                    let (ongoing_connection, packets_sender) = self.traverse_nat(
-                        remote_addr,  remote_public_key,
-                    );
-                    let task = tokio::spawn(ongoing_connection.map_err(move |error| {
-                        (error, remote_addr)
-                    }));
-                    connection_tasks.push(task);
-                    ongoing_connections.insert(remote_addr, (packets_sender, open_connection));
+                    match event {
+                        ConnectionEvent::ConnectionStart { remote_public_key, open_connection } => {
+                            tracing::debug!(%remote_addr, "attempting to establish connection");
+                            self.relayed_remotes.remove(&remote_addr);
+                            let (ongoing_connection, packets_sender) = self.traverse_nat(
+                                remote_addr, remote_public_key.clone(), None,
+                            );
+                            let task = tokio::spawn(ongoing_connection.map_err(move |error| {
+                                (error, remote_addr)
+                            }));
+                            connection_tasks.push(task);
+                            ongoing_connections.insert(remote_addr, (packets_sender, open_connection));
+                            connecting_keys.insert(remote_addr, remote_public_key);
+                        }
+                        ConnectionEvent::RelayAvailable { relay_addr } => {
+                            tracing::debug!(%remote_addr, %relay_addr, "relay available for remote");
+                            self.relay_candidates.insert(remote_addr, relay_addr);
+                        }
+                        ConnectionEvent::RelayAllowed { other_addr } => {
+                            tracing::debug!(%remote_addr, %other_addr, "relaying between remotes allowed");
+                            self.relay_pairs.entry(remote_addr).or_default().insert(other_addr);
+                            self.relay_pairs.entry(other_addr).or_default().insert(remote_addr);
+                        }
+                    }
                 },
+                _ = relay_usage_report.tick() => {
+                    for ((source, target), bytes) in std::mem::take(&mut self.relay_usage) {
+                        let _ = self.relay_usage_notifier.try_send(RelayUsage { source, target, bytes });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether packets from `remote_addr` can carry a relay header, because either this peer
+    /// relays for the remote or the remote relays for this peer.
+    fn may_be_relayed(&self, remote_addr: SocketAddr) -> bool {
+        self.relay_pairs.contains_key(&remote_addr)
+            || self
+                .relayed_remotes
+                .values()
+                .any(|relay| *relay == remote_addr)
+    }
+
+    /// Forwards a packet between two remotes connected to this peer, so they can communicate
+    /// when they can't reach each other directly. The packet stays end-to-end encrypted.
+    ///
+    /// Only packets between remotes this peer offered to relay for are forwarded, up to
+    /// `RELAY_BYTES_PER_INTERVAL`, and without waiting for the rate limiter.
+    fn relay_packet(&mut self, source: SocketAddr, target: SocketAddr, packet: &[u8]) {
+        let allowed = self
+            .relay_pairs
+            .get(&source)
+            .map_or(false, |targets| targets.contains(&target));
+        if !allowed
+            || !self.remote_connections.contains_key(&source)
+            || !self.remote_connections.contains_key(&target)
+        {
+            tracing::debug!(%source, %target, "refusing to relay packet between peers without a relay offer");
+            return;
+        }
+        let relayed_bytes = self.relay_usage.entry((source, target)).or_default();
+        let relayed = relay::wrap(source, packet);
+        let relayed_len = relayed.len();
+        if *relayed_bytes + relayed_len > RELAY_BYTES_PER_INTERVAL {
+            tracing::trace!(%source, %target, "relay bandwidth exhausted, dropping packet");
+            return;
+        }
+        match self.relay_queue.try_send((target, relayed)) {
+            Ok(()) => *relayed_bytes += relayed_len,
+            Err(_) => tracing::trace!(%source, %target, "relay queue full, dropping packet"),
+        }
+    }
+
+    /// Stops relaying for a remote whose connection closed or moved to another address.
+    fn forget_relay_pairs(&mut self, remote_addr: SocketAddr) {
+        if let Some(others) = self.relay_pairs.remove(&remote_addr) {
+            for other in others {
+                if let Some(targets) = self.relay_pairs.get_mut(&other) {
+                    targets.remove(&remote_addr);
+                    if targets.is_empty() {
+                        self.relay_pairs.remove(&other);
+                    }
+                }
             }
         }
     }
 
+    /// Checks whether a packet from an unknown address belongs to an established connection,
+    /// which happens when the public address of the remote changes (e.g. its NAT mapping is
+    /// renewed). The remote must echo a random token sent to the new address before the
+    /// connection is moved there, so a replayed or spoofed packet can't redirect it.
+    ///
+    /// The connection is found from the key hint in the nonce of the packet, and only the
+    /// connections whose inbound key has that hint try to decrypt it.
+    ///
+    /// Returns whether the packet belongs to an established connection.
+    async fn check_path_migration(
+        &mut self,
+        packet: &PacketData<UnknownEncryption>,
+        remote_addr: SocketAddr,
+    ) -> bool {
+        let Some(hint) = packet.key_hint() else {
+            return false;
+        };
+        // checked before looking at the connections, so a flood of packets from unknown
+        // addresses only gets as far as the intro gate
+        if !self.path_checks.allow(remote_addr.ip(), Instant::now()) {
+            tracing::debug!(%remote_addr, "too many packets from unknown address, not checking for migrated connections");
+            return false;
+        }
+        let mut candidates =
+            self.remote_connections
+                .iter_mut()
+                .filter_map(|(addr, remote_conn)| {
+                    let path = remote_conn.path.as_mut()?;
+                    let inbound_key = {
+                        let key = path.inbound_key.read();
+                        (key.hint() == hint).then(|| key.clone())?
+                    };
+                    Some((*addr, path, inbound_key))
+                });
+        let Some((previous_addr, path, msg)) = candidates.find_map(|(addr, path, inbound_key)| {
+            let decrypted = packet.try_decrypt_sym(&inbound_key).ok()?;
+            let msg = SymmetricMessage::deser(decrypted.data()).ok()?;
+            Some((addr, path, msg))
+        }) else {
+            return false;
+        };
+
+        if let SymmetricMessagePayload::PathResponse { token } = msg.payload {
+            if !path.is_challenged(remote_addr, Some(token)) {
+                tracing::debug!(%previous_addr, %remote_addr, "unexpected path response");
+                return true;
+            }
+            path.challenge = None;
+            if path.address_changes.send(remote_addr).await.is_err() {
+                tracing::debug!(%previous_addr, "connection dropped before migrating it");
+                self.remote_connections.remove(&previous_addr);
+                self.forget_relay_pairs(previous_addr);
+                return true;
+            }
+            tracing::info!(%previous_addr, %remote_addr, "remote changed address, migrating connection");
+            if let Some(remote_conn) = self.remote_connections.remove(&previous_addr) {
+                self.remote_connections.insert(remote_addr, remote_conn);
+            }
+            self.forget_relay_pairs(previous_addr);
+            let _ = self
+                .migration_notifier
+                .send(ConnectionMigration {
+                    previous_addr,
+                    remote_addr,
+                })
+                .await;
+            return true;
+        }
+
+        if path.is_challenged(remote_addr, None) {
+            // the remote keeps sending until it gets a receipt, don't answer each packet
+            return true;
+        }
+        tracing::debug!(%previous_addr, %remote_addr, "packet from a new address of the remote, sending path challenge");
+        let token = rand::random::<[u8; 8]>();
+        let packet_id = path
+            .last_packet_id
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+        let outbound_key = path.outbound_key.read().clone();
+        match SymmetricMessage::serialize_msg_to_packet_data(
+            packet_id,
+            SymmetricMessagePayload::PathChallenge { token },
+            &outbound_key,
+            vec![],
+        ) {
+            Ok(challenge) => {
+                let _ = self
+                    .outbound_packets
+                    .send((remote_addr, challenge.prepared_send()))
+                    .await;
+                path.challenge = Some(PathChallenge {
+                    remote_addr,
+                    token,
+                    sent_at: Instant::now(),
+                });
+            }
+            Err(error) => {
+                tracing::error!(%error, %remote_addr, "failed to serialize path challenge");
+            }
+        }
+        true
+    }
+
     fn gateway_connection(
         &mut self,
         remote_intro_packet: PacketData<UnknownEncryption>,
@@ -372,7 +833,7 @@ impl<S: Socket> UdpPacketsListener<S> {
             (
                 RemoteConnection,
                 InboundRemoteConnection,
-                PacketData<SymmetricAES>,
+                Option<PacketData<SymmetricAES>>,
             ),
             TransportError,
         >,
@@ -381,6 +842,8 @@ impl<S: Socket> UdpPacketsListener<S> {
         let secret = self.this_peer_keypair.secret.clone();
         let outbound_packets = self.outbound_packets.clone();
         let socket_listener = self.socket_listener.clone();
+        let capture = self.capture.clone();
+        let idle_timeout = self.idle_timeout;
 
         async move {
             let decrypted_intro_packet =
@@ -388,15 +851,13 @@ impl<S: Socket> UdpPacketsListener<S> {
                     tracing::debug!(%remote_addr, %err, "Failed to decrypt intro packet");
                     err
                 })?;
-            let protoc = &decrypted_intro_packet[..PROTOC_VERSION.len()];
-            let outbound_key_bytes =
-                &decrypted_intro_packet[PROTOC_VERSION.len()..PROTOC_VERSION.len() + 16];
-            let outbound_key = Aes128Gcm::new_from_slice(outbound_key_bytes).map_err(|_| {
+            let intro = IntroPacket::deser(&decrypted_intro_packet).ok_or_else(|| {
                 TransportError::ConnectionEstablishmentFailure {
-                    cause: "invalid symmetric key".into(),
+                    cause: "invalid intro packet".into(),
                 }
             })?;
-            if protoc != PROTOC_VERSION {
+            let outbound_key = SymmetricKey::new(intro.key);
+            if !intro.is_supported() {
                 let packet = SymmetricMessage::ack_error(&outbound_key)?;
                 outbound_packets
                     .send((remote_addr, packet.prepared_send()))
@@ -405,16 +866,31 @@ impl<S: Socket> UdpPacketsListener<S> {
                 return Err(TransportError::ConnectionEstablishmentFailure {
                     cause: format!(
                         "remote is using a different protocol version: {:?}",
-                        String::from_utf8_lossy(protoc)
+                        u16::from_le_bytes(intro.protoc)
                     )
                     .into(),
                 });
             }
 
+            let ephemeral_key = EphemeralKey::new();
             let inbound_key_bytes = rand::random::<[u8; 16]>();
-            let inbound_key = Aes128Gcm::new(&inbound_key_bytes.into());
-            let outbound_ack_packet =
-                SymmetricMessage::ack_ok(&outbound_key, inbound_key_bytes, remote_addr)?;
+            let inbound_key = SymmetricKey::new(inbound_key_bytes);
+            let outbound_ack_packet = match intro.ephemeral_key {
+                Some(_) => SymmetricMessage::ack_ok_v2(
+                    &outbound_key,
+                    inbound_key_bytes,
+                    ephemeral_key.public_bytes(),
+                    remote_addr,
+                    intro.features.map(|_| FEATURES),
+                )?,
+                None => SymmetricMessage::ack_ok(&outbound_key, inbound_key_bytes, remote_addr)?,
+            };
+            let session = SessionKeys::new(
+                &ephemeral_key,
+                intro.ephemeral_key,
+                inbound_key_bytes,
+                intro.key,
+            );
 
             let mut buf = [0u8; MAX_PACKET_SIZE];
             let mut waiting_time = INITIAL_INTERVAL;
@@ -456,12 +932,17 @@ impl<S: Socket> UdpPacketsListener<S> {
                             continue;
                         }
 
-                        let _ = packet.try_decrypt_sym(&inbound_key).map_err(|_| {
-                            tracing::debug!(%remote_addr, "Failed to decrypt packet with inbound key");
-                            TransportError::ConnectionEstablishmentFailure {
-                                cause: "invalid symmetric key".into(),
-                            }
-                        })?;
+                        // the remote may have already switched to the session keys if our ack
+                        // got through but their confirmation was lost
+                        let _ = packet
+                            .try_decrypt_sym(&inbound_key)
+                            .or_else(|_| packet.try_decrypt_sym(&session.inbound_key()))
+                            .map_err(|_| {
+                                tracing::debug!(%remote_addr, "Failed to decrypt packet with inbound key");
+                                TransportError::ConnectionEstablishmentFailure {
+                                    cause: "invalid symmetric key".into(),
+                                }
+                            })?;
                     }
                     Ok(Err(_)) => {
                         return Err(TransportError::ChannelClosed);
@@ -482,27 +963,41 @@ impl<S: Socket> UdpPacketsListener<S> {
                 break;
             }
 
+            if let Some(capture) = &capture {
+                capture.log_keys(remote_addr, session.inbound, session.outbound);
+            }
             let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
 
             let (inbound_packet_tx, inbound_packet_rx) = mpsc::channel(100);
+            let (address_changes_tx, address_changes_rx) = mpsc::channel(1);
             let remote_conn = RemoteConnection {
                 outbound_packets,
-                outbound_symmetric_key: outbound_key,
+                outbound_symmetric_key: Arc::new(parking_lot::RwLock::new(session.outbound_key())),
                 remote_addr,
                 sent_tracker: sent_tracker.clone(),
                 last_packet_id: Arc::new(AtomicU32::new(0)),
                 inbound_packet_recv: inbound_packet_rx,
-                inbound_symmetric_key: inbound_key,
-                inbound_symmetric_key_bytes: inbound_key_bytes,
+                inbound_symmetric_key: Arc::new(parking_lot::RwLock::new(session.inbound_key())),
+                inbound_symmetric_key_bytes: session.inbound,
                 my_address: None,
+                protoc_v2: intro.ephemeral_key.is_some(),
+                fec: intro.supports(FEATURE_FEC),
+                pings: intro.supports(FEATURE_PING),
+                idle_timeout,
+                address_changes: address_changes_rx,
             };
 
             let inbound_conn = InboundRemoteConnection {
                 inbound_packet_sender: inbound_packet_tx,
                 inbound_intro_packet: None,
                 inbound_checked_times: 0,
+                path: ConnectionPath::new(&remote_conn, address_changes_tx),
             };
 
+            // acks of version 2 can't be read by the remote once it derived the session keys,
+            // so only legacy acks are tracked for resending
+            let outbound_ack_packet = intro.ephemeral_key.is_none().then_some(outbound_ack_packet);
+
             tracing::debug!("returning connection at gw");
             Ok((remote_conn, inbound_conn, outbound_ack_packet))
         }
@@ -519,6 +1014,7 @@ impl<S: Socket> UdpPacketsListener<S> {
         &mut self,
         remote_addr: SocketAddr,
         remote_public_key: TransportPublicKey,
+        relay_addr: Option<SocketAddr>,
     ) -> (
         impl Future<Output = Result<(RemoteConnection, InboundRemoteConnection), TransportError>>
             + Send
@@ -548,21 +1044,25 @@ impl<S: Socket> UdpPacketsListener<S> {
             remote_addr: SocketAddr,
             packet: &PacketData<UnknownEncryption>,
             transport_secret_key: &TransportSecretKey,
-            outbound_sym_key: &mut Option<Aes128Gcm>,
+            remote_intro: &mut Option<IntroPacket>,
             state: &mut ConnectionState,
         ) -> Result<(), ()> {
             // probably the first packet to punch through the NAT
             if let Ok(decrypted_intro_packet) = packet.try_decrypt_asym(transport_secret_key) {
                 tracing::debug!(%remote_addr, "received intro packet");
-                let protoc = &decrypted_intro_packet.data()[..PROTOC_VERSION.len()];
-                if protoc != PROTOC_VERSION {
-                    todo!("return error");
+                let Some(intro) = IntroPacket::deser(decrypted_intro_packet.data()) else {
+                    tracing::debug!(%remote_addr, "invalid intro packet");
+                    return Err(());
+                };
+                if !intro.is_supported() {
+                    tracing::debug!(
+                        %remote_addr,
+                        protoc = u16::from_le_bytes(intro.protoc),
+                        "remote is using a different protocol version"
+                    );
+                    return Err(());
                 }
-                let outbound_key_bytes =
-                    &decrypted_intro_packet.data()[PROTOC_VERSION.len()..PROTOC_VERSION.len() + 16];
-                let outbound_key =
-                    Aes128Gcm::new_from_slice(outbound_key_bytes).expect("correct length");
-                *outbound_sym_key = Some(outbound_key.clone());
+                *remote_intro = Some(intro);
                 *state = ConnectionState::RemoteInbound {
                     intro_packet: packet.assert_assymetric(),
                 };
@@ -572,8 +1072,14 @@ impl<S: Socket> UdpPacketsListener<S> {
             Err(())
         }
 
-        let outbound_packets = self.outbound_packets.clone();
+        let outbound_packets = match relay_addr {
+            Some(relay_addr) => relay::relayed_sender(relay_addr, self.outbound_packets.clone()),
+            None => self.outbound_packets.clone(),
+        };
         let transport_secret_key = self.this_peer_keypair.secret.clone();
+        let capture = self.capture.clone();
+        let idle_timeout = self.idle_timeout;
+        let protoc = protoc_version(&self.this_peer_keypair.public, &remote_public_key);
         let (inbound_from_remote, mut next_inbound) =
             mpsc::channel::<PacketData<UnknownEncryption>>(1);
         let this_addr = self.this_addr;
@@ -587,15 +1093,26 @@ impl<S: Socket> UdpPacketsListener<S> {
             let mut failures = 0;
 
             let inbound_sym_key_bytes = rand::random::<[u8; 16]>();
-            let inbound_sym_key = Aes128Gcm::new(&inbound_sym_key_bytes.into());
+            let inbound_sym_key = SymmetricKey::new(inbound_sym_key_bytes);
+
+            let ephemeral_key = EphemeralKey::new();
 
-            let mut outbound_sym_key: Option<Aes128Gcm> = None;
+            let mut remote_intro: Option<IntroPacket> = None;
             let outbound_intro_packet = {
-                let mut data = [0u8; { 16 + PROTOC_VERSION.len() }];
-                data[..PROTOC_VERSION.len()].copy_from_slice(&PROTOC_VERSION);
-                data[PROTOC_VERSION.len()..].copy_from_slice(&inbound_sym_key_bytes);
-                PacketData::<_, MAX_PACKET_SIZE>::encrypt_with_pubkey(&data, &remote_public_key)
+                let intro = IntroPacket {
+                    protoc,
+                    key: inbound_sym_key_bytes,
+                    ephemeral_key: (protoc == PROTOC_VERSION).then(|| ephemeral_key.public_bytes()),
+                    features: (protoc == PROTOC_VERSION).then_some(FEATURES),
+                };
+                PacketData::<_, MAX_PACKET_SIZE>::encrypt_with_pubkey(
+                    &intro.serialize(),
+                    &remote_public_key,
+                )
             };
+            // replaced by the intro packet prefixed with a cookie if the remote asks for it
+            let mut outbound_intro = Bytes::copy_from_slice(outbound_intro_packet.data());
+            let mut answered_retries: Vec<intro_gate::Retry> = Vec::new();
 
             let mut sent_tracker = SentPacketTracker::new();
 
@@ -604,25 +1121,35 @@ impl<S: Socket> UdpPacketsListener<S> {
                     ConnectionState::StartOutbound { .. } => {
                         tracing::debug!(%remote_addr, "sending protocol version and inbound key");
                         outbound_packets
-                            .send((remote_addr, outbound_intro_packet.data().into()))
+                            .send((remote_addr, outbound_intro.clone()))
                             .await
                             .map_err(|_| TransportError::ChannelClosed)?;
                     }
                     ConnectionState::RemoteInbound { .. } => {
                         tracing::debug!(%remote_addr, "sending back protocol version and inbound key to remote");
-                        let our_inbound = SymmetricMessage::ack_ok(
-                            outbound_sym_key.as_ref().expect("should be set"),
-                            inbound_sym_key_bytes,
-                            remote_addr,
-                        )?;
+                        let remote_intro = remote_intro.as_ref().expect("should be set");
+                        let outbound_sym_key = SymmetricKey::new(remote_intro.key);
+                        let our_inbound = match remote_intro.ephemeral_key {
+                            Some(_) => SymmetricMessage::ack_ok_v2(
+                                &outbound_sym_key,
+                                inbound_sym_key_bytes,
+                                ephemeral_key.public_bytes(),
+                                remote_addr,
+                                remote_intro.features.map(|_| FEATURES),
+                            )?,
+                            None => SymmetricMessage::ack_ok(
+                                &outbound_sym_key,
+                                inbound_sym_key_bytes,
+                                remote_addr,
+                            )?,
+                        };
+                        let our_inbound = packet_pool::retained_packet(our_inbound.data());
                         outbound_packets
-                            .send((remote_addr, our_inbound.data().into()))
+                            .send((remote_addr, our_inbound.clone()))
                             .await
                             .map_err(|_| TransportError::ChannelClosed)?;
-                        sent_tracker.report_sent_packet(
-                            SymmetricMessage::FIRST_PACKET_ID,
-                            our_inbound.data().into(),
-                        );
+                        sent_tracker
+                            .report_sent_packet(SymmetricMessage::FIRST_PACKET_ID, our_inbound);
                     }
                 }
                 let next_inbound = tokio::time::timeout(timeout, next_inbound.recv());
@@ -644,56 +1171,36 @@ impl<S: Socket> UdpPacketsListener<S> {
                                         tracing::debug!(%remote_addr, ?symmetric_message.payload, "received symmetric packet");
                                     }
 
-                                    match symmetric_message.payload {
+                                    let (
+                                        outbound_key_bytes,
+                                        my_address,
+                                        remote_ephemeral,
+                                        features,
+                                    ) = match symmetric_message.payload {
                                         SymmetricMessagePayload::AckConnection {
                                             result:
                                                 Ok(OutboundConnection {
                                                     key,
                                                     remote_addr: my_address,
                                                 }),
-                                        } => {
-                                            let outbound_sym_key = Aes128Gcm::new_from_slice(&key)
-                                                .map_err(|_| {
-                                                    TransportError::ConnectionEstablishmentFailure {
-                                                        cause: "invalid symmetric key".into(),
-                                                    }
-                                                })?;
-                                            outbound_packets
-                                                .send((
-                                                    remote_addr,
-                                                    SymmetricMessage::ack_ok(
-                                                        &outbound_sym_key,
-                                                        inbound_sym_key_bytes,
-                                                        remote_addr,
-                                                    )?
-                                                    .data()
-                                                    .into(),
-                                                ))
-                                                .await
-                                                .map_err(|_| TransportError::ChannelClosed)?;
-                                            let (inbound_sender, inbound_recv) = mpsc::channel(100);
-                                            return Ok((
-                                                RemoteConnection {
-                                                    outbound_packets: outbound_packets.clone(),
-                                                    outbound_symmetric_key: outbound_sym_key,
-                                                    remote_addr,
-                                                    sent_tracker: Arc::new(
-                                                        parking_lot::Mutex::new(sent_tracker),
-                                                    ),
-                                                    last_packet_id: Arc::new(AtomicU32::new(0)),
-                                                    inbound_packet_recv: inbound_recv,
-                                                    inbound_symmetric_key: inbound_sym_key,
-                                                    inbound_symmetric_key_bytes:
-                                                        inbound_sym_key_bytes,
-                                                    my_address: Some(my_address),
+                                        } => (key, my_address, None, 0),
+                                        SymmetricMessagePayload::AckConnectionV2 {
+                                            connection:
+                                                OutboundConnection {
+                                                    key,
+                                                    remote_addr: my_address,
                                                 },
-                                                InboundRemoteConnection {
-                                                    inbound_packet_sender: inbound_sender,
-                                                    inbound_intro_packet: None,
-                                                    inbound_checked_times: 0,
+                                            ephemeral_key,
+                                        } => (key, my_address, Some(ephemeral_key), 0),
+                                        SymmetricMessagePayload::AckConnectionV3 {
+                                            connection:
+                                                OutboundConnection {
+                                                    key,
+                                                    remote_addr: my_address,
                                                 },
-                                            ));
-                                        }
+                                            ephemeral_key,
+                                            features,
+                                        } => (key, my_address, Some(ephemeral_key), features),
                                         SymmetricMessagePayload::AckConnection {
                                             result: Err(err),
                                         } => {
@@ -708,7 +1215,94 @@ impl<S: Socket> UdpPacketsListener<S> {
                                             failures += 1;
                                             continue;
                                         }
+                                    };
+                                    let outbound_sym_key = SymmetricKey::new(outbound_key_bytes);
+                                    outbound_packets
+                                        .send((
+                                            remote_addr,
+                                            SymmetricMessage::ack_ok(
+                                                &outbound_sym_key,
+                                                inbound_sym_key_bytes,
+                                                remote_addr,
+                                            )?
+                                            .prepared_send(),
+                                        ))
+                                        .await
+                                        .map_err(|_| TransportError::ChannelClosed)?;
+                                    let session = SessionKeys::new(
+                                        &ephemeral_key,
+                                        remote_ephemeral,
+                                        inbound_sym_key_bytes,
+                                        outbound_key_bytes,
+                                    );
+                                    if let Some(capture) = &capture {
+                                        capture.log_keys(
+                                            remote_addr,
+                                            session.inbound,
+                                            session.outbound,
+                                        );
+                                    }
+                                    let (inbound_sender, inbound_recv) = mpsc::channel(100);
+                                    let (address_changes_tx, address_changes_rx) = mpsc::channel(1);
+                                    let remote_conn = RemoteConnection {
+                                        outbound_packets: outbound_packets.clone(),
+                                        outbound_symmetric_key: Arc::new(parking_lot::RwLock::new(
+                                            session.outbound_key(),
+                                        )),
+                                        remote_addr,
+                                        sent_tracker: Arc::new(parking_lot::Mutex::new(
+                                            sent_tracker,
+                                        )),
+                                        last_packet_id: Arc::new(AtomicU32::new(0)),
+                                        inbound_packet_recv: inbound_recv,
+                                        inbound_symmetric_key: Arc::new(parking_lot::RwLock::new(
+                                            session.inbound_key(),
+                                        )),
+                                        inbound_symmetric_key_bytes: session.inbound,
+                                        my_address: Some(my_address),
+                                        protoc_v2: remote_ephemeral.is_some(),
+                                        fec: features & FEATURE_FEC != 0,
+                                        pings: features & FEATURE_PING != 0,
+                                        idle_timeout,
+                                        address_changes: address_changes_rx,
+                                    };
+                                    let path =
+                                        ConnectionPath::new(&remote_conn, address_changes_tx);
+                                    return Ok((
+                                        remote_conn,
+                                        InboundRemoteConnection {
+                                            inbound_packet_sender: inbound_sender,
+                                            inbound_intro_packet: None,
+                                            inbound_checked_times: 0,
+                                            path,
+                                        },
+                                    ));
+                                }
+
+                                // a gateway under load asks for a cookie and proof of work first, retries
+                                // are not authenticated so they count as failures as well
+                                if let Some(retry) = intro_gate::Retry::parse(packet.data()) {
+                                    failures += 1;
+                                    if retry.difficulty > intro_gate::MAX_POW_DIFFICULTY
+                                        || answered_retries.contains(&retry)
+                                    {
+                                        continue;
+                                    }
+                                    if answered_retries.len() >= intro_gate::MAX_ANSWERED_RETRIES {
+                                        tracing::debug!(%remote_addr, "too many intro retries requested, ignoring them");
+                                        continue;
                                     }
+                                    tracing::debug!(%remote_addr, difficulty = retry.difficulty, "remote requested intro retry");
+                                    let intro = outbound_intro_packet.data().to_vec();
+                                    let answer = {
+                                        let retry = retry.clone();
+                                        task::spawn_blocking(move || retry.answer(&intro))
+                                            .await
+                                            .map_err(|e| anyhow::anyhow!(e))?
+                                    };
+                                    outbound_intro = answer.into();
+                                    answered_retries.push(retry);
+                                    continue;
                                 }
 
                                 // probably the first packet to punch through the NAT
@@ -716,7 +1310,7 @@ impl<S: Socket> UdpPacketsListener<S> {
                                     remote_addr,
                                     &packet,
                                     &transport_secret_key,
-                                    &mut outbound_sym_key,
+                                    &mut remote_intro,
                                     &mut state,
                                 )
                                 .is_ok()
@@ -741,26 +1335,53 @@ impl<S: Socket> UdpPacketsListener<S> {
                                     continue;
                                 }
                                 // if is not an intro packet, the connection is successful and we can proceed
+                                let remote_intro =
+                                    remote_intro.expect("should be set at this stage");
+                                let session = SessionKeys::new(
+                                    &ephemeral_key,
+                                    remote_intro.ephemeral_key,
+                                    inbound_sym_key_bytes,
+                                    remote_intro.key,
+                                );
+                                if let Some(capture) = &capture {
+                                    capture.log_keys(
+                                        remote_addr,
+                                        session.inbound,
+                                        session.outbound,
+                                    );
+                                }
                                 let (inbound_sender, inbound_recv) = mpsc::channel(1);
+                                let (address_changes_tx, address_changes_rx) = mpsc::channel(1);
+                                let remote_conn = RemoteConnection {
+                                    outbound_packets: outbound_packets.clone(),
+                                    outbound_symmetric_key: Arc::new(parking_lot::RwLock::new(
+                                        session.outbound_key(),
+                                    )),
+                                    remote_addr,
+                                    sent_tracker: Arc::new(parking_lot::Mutex::new(
+                                        SentPacketTracker::new(),
+                                    )),
+                                    last_packet_id: Arc::new(AtomicU32::new(0)),
+                                    inbound_packet_recv: inbound_recv,
+                                    inbound_symmetric_key: Arc::new(parking_lot::RwLock::new(
+                                        session.inbound_key(),
+                                    )),
+                                    inbound_symmetric_key_bytes: session.inbound,
+                                    my_address: None,
+                                    protoc_v2: remote_intro.ephemeral_key.is_some(),
+                                    fec: remote_intro.supports(FEATURE_FEC),
+                                    pings: remote_intro.supports(FEATURE_PING),
+                                    idle_timeout,
+                                    address_changes: address_changes_rx,
+                                };
+                                let path = ConnectionPath::new(&remote_conn, address_changes_tx);
                                 return Ok((
-                                    RemoteConnection {
-                                        outbound_packets: outbound_packets.clone(),
-                                        outbound_symmetric_key: outbound_sym_key
-                                            .expect("should be set at this stage"),
-                                        remote_addr,
-                                        sent_tracker: Arc::new(parking_lot::Mutex::new(
-                                            SentPacketTracker::new(),
-                                        )),
-                                        last_packet_id: Arc::new(AtomicU32::new(0)),
-                                        inbound_packet_recv: inbound_recv,
-                                        inbound_symmetric_key: inbound_sym_key,
-                                        inbound_symmetric_key_bytes: inbound_sym_key_bytes,
-                                        my_address: None,
-                                    },
+                                    remote_conn,
                                     InboundRemoteConnection {
                                         inbound_packet_sender: inbound_sender,
                                         inbound_intro_packet: Some(intro_packet.clone()),
                                         inbound_checked_times: 0,
+                                        path,
                                     },
                                 ));
                             }
@@ -816,12 +1437,84 @@ enum ConnectionEvent {
         remote_public_key: TransportPublicKey,
         open_connection: oneshot::Sender<Result<RemoteConnection, TransportError>>,
     },
+    /// A peer connected to both this peer and the remote offered to relay between them
+    RelayAvailable { relay_addr: SocketAddr },
+    /// This peer offered to relay between the remote and another remote
+    RelayAllowed { other_addr: SocketAddr },
 }
 
 struct InboundRemoteConnection {
     inbound_packet_sender: mpsc::Sender<PacketData<UnknownEncryption>>,
     inbound_intro_packet: Option<PacketData<AssymetricRSA>>,
     inbound_checked_times: usize,
+    /// Only set if the remote can answer path challenges, otherwise the connection is not
+    /// migrated when the remote address changes
+    path: Option<ConnectionPath>,
+}
+
+/// State used to validate a new address of the remote of an established connection.
+struct ConnectionPath {
+    inbound_key: InboundKey,
+    outbound_key: OutboundKey,
+    last_packet_id: Arc<AtomicU32>,
+    address_changes: mpsc::Sender<SocketAddr>,
+    challenge: Option<PathChallenge>,
+}
+
+struct PathChallenge {
+    remote_addr: SocketAddr,
+    token: [u8; 8],
+    sent_at: Instant,
+}
+
+/// Counts the packets from each unknown IP checked for belonging to an established connection
+/// over the current second.
+#[derive(Default)]
+struct PathCheckLimiter {
+    window_start: Option<Instant>,
+    checks: HashMap<IpAddr, u32>,
+}
+
+impl PathCheckLimiter {
+    fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self
+            .window_start
+            .map_or(true, |start| now - start >= Duration::from_secs(1))
+        {
+            self.window_start = Some(now);
+            self.checks.clear();
+        }
+        let checks = self.checks.entry(ip).or_default();
+        *checks += 1;
+        *checks <= PATH_CHECKS_PER_SECOND
+    }
+}
+
+impl ConnectionPath {
+    fn new(
+        remote_conn: &RemoteConnection,
+        address_changes: mpsc::Sender<SocketAddr>,
+    ) -> Option<Self> {
+        remote_conn.protoc_v2.then(|| Self {
+            inbound_key: remote_conn.inbound_symmetric_key.clone(),
+            outbound_key: remote_conn.outbound_symmetric_key.clone(),
+            last_packet_id: remote_conn.last_packet_id.clone(),
+            address_changes,
+            challenge: None,
+        })
+    }
+
+    /// Whether a challenge is pending for the given address. When a token is given it must
+    /// match the challenge, otherwise the challenge must have been sent recently.
+    fn is_challenged(&self, remote_addr: SocketAddr, token: Option<[u8; 8]>) -> bool {
+        self.challenge.as_ref().is_some_and(|challenge| {
+            challenge.remote_addr == remote_addr
+                && match token {
+                    Some(token) => challenge.token == token,
+                    None => challenge.sent_at.elapsed() < PATH_CHALLENGE_INTERVAL,
+                }
+        })
+    }
 }
 
 impl InboundRemoteConnection {
@@ -844,6 +1537,113 @@ impl InboundRemoteConnection {
     }
 }
 
+/// Protocol version to use in the intro packet, version 2 requires both peers to have an
+/// Ed25519 identity so both sides of a connection always agree on the version.
+fn protoc_version(this: &TransportPublicKey, remote: &TransportPublicKey) -> [u8; 2] {
+    if this.is_ed25519() && remote.is_ed25519() {
+        PROTOC_VERSION
+    } else {
+        LEGACY_PROTOC_VERSION
+    }
+}
+
+/// Content of an intro packet:
+/// `protocol version | inbound key | ephemeral key (version 2) | features (version 2)`.
+struct IntroPacket {
+    protoc: [u8; 2],
+    /// Key the sender of the intro packet expects to receive packets encrypted with
+    key: [u8; 16],
+    ephemeral_key: Option<[u8; 32]>,
+    /// Missing in intro packets of peers which don't announce their features
+    features: Option<u8>,
+}
+
+impl IntroPacket {
+    const MIN_SIZE: usize = PROTOC_VERSION.len() + 16;
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::MIN_SIZE + 33);
+        data.extend_from_slice(&self.protoc);
+        data.extend_from_slice(&self.key);
+        if let Some(ephemeral_key) = &self.ephemeral_key {
+            data.extend_from_slice(ephemeral_key);
+            // older peers ignore the trailing bytes
+            data.extend(self.features);
+        }
+        data
+    }
+
+    fn deser(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::MIN_SIZE {
+            return None;
+        }
+        let mut protoc = [0u8; 2];
+        protoc.copy_from_slice(&data[..PROTOC_VERSION.len()]);
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&data[PROTOC_VERSION.len()..Self::MIN_SIZE]);
+        let ephemeral_key = (protoc == PROTOC_VERSION)
+            .then(|| data.get(Self::MIN_SIZE..Self::MIN_SIZE + 32))
+            .flatten()
+            .map(|bytes| {
+                let mut ephemeral_key = [0u8; 32];
+                ephemeral_key.copy_from_slice(bytes);
+                ephemeral_key
+            });
+        let features = ephemeral_key
+            .and_then(|_| data.get(Self::MIN_SIZE + 32))
+            .copied();
+        Some(Self {
+            protoc,
+            key,
+            ephemeral_key,
+            features,
+        })
+    }
+
+    fn supports(&self, feature: u8) -> bool {
+        self.features
+            .is_some_and(|features| features & feature != 0)
+    }
+
+    fn is_supported(&self) -> bool {
+        (self.protoc == PROTOC_VERSION && self.ephemeral_key.is_some())
+            || self.protoc == LEGACY_PROTOC_VERSION
+    }
+}
+
+/// Symmetric keys used by an established connection.
+struct SessionKeys {
+    inbound: [u8; 16],
+    outbound: [u8; 16],
+}
+
+impl SessionKeys {
+    /// With protocol version 2 the keys exchanged during the handshake are mixed with the
+    /// ephemeral keys of both peers, otherwise they are used as is.
+    fn new(
+        ephemeral_key: &EphemeralKey,
+        remote_ephemeral_key: Option<[u8; 32]>,
+        inbound: [u8; 16],
+        outbound: [u8; 16],
+    ) -> Self {
+        match remote_ephemeral_key {
+            Some(remote) => Self {
+                inbound: ephemeral_key.session_key(remote, inbound),
+                outbound: ephemeral_key.session_key(remote, outbound),
+            },
+            None => Self { inbound, outbound },
+        }
+    }
+
+    fn inbound_key(&self) -> SymmetricKey {
+        SymmetricKey::new(self.inbound)
+    }
+
+    fn outbound_key(&self) -> SymmetricKey {
+        SymmetricKey::new(self.outbound)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::{
@@ -886,6 +1686,8 @@ mod test {
     struct MockSocket {
         inbound: Mutex<mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>>,
         this: SocketAddr,
+        /// Address other sockets see, changes when simulating a new NAT mapping
+        external: parking_lot::Mutex<SocketAddr>,
         packet_drop_policy: PacketDropPolicy,
         num_packets_sent: AtomicUsize,
         rng: Mutex<rand::rngs::SmallRng>,
@@ -902,6 +1704,7 @@ mod test {
             MockSocket {
                 inbound: Mutex::new(inbound),
                 this: addr,
+                external: parking_lot::Mutex::new(addr),
                 packet_drop_policy,
                 num_packets_sent: AtomicUsize::new(0),
                 rng: Mutex::new(rand::rngs::SmallRng::seed_from_u64(
@@ -909,6 +1712,18 @@ mod test {
                 )),
             }
         }
+
+        /// Simulates the NAT in front of this socket mapping it to a new public address.
+        async fn rebind(&self, addr: SocketAddr) {
+            let channels = CHANNELS
+                .get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
+                .clone();
+            let mut channels = channels.write().await;
+            let previous = std::mem::replace(&mut *self.external.lock(), addr);
+            if let Some(sender) = channels.remove(&previous) {
+                channels.insert(addr, sender);
+            }
+        }
     }
 
     impl Socket for MockSocket {
@@ -960,8 +1775,9 @@ mod test {
                 return Ok(0);
             };
             // tracing::trace!(?target, ?self.this, "sending packet to remote");
+            let external = *self.external.lock();
             sender
-                .send((self.this, buf.to_vec()))
+                .send((external, buf.to_vec()))
                 .map_err(|_| std::io::ErrorKind::ConnectionAborted)?;
             // tracing::trace!(?target, ?self.this, "packet sent to remote");
             Ok(buf.len())
@@ -975,7 +1791,7 @@ mod test {
                 .clone();
             loop {
                 if let Ok(mut channels) = channels.try_write() {
-                    channels.remove(&self.this);
+                    channels.remove(&*self.external.lock());
                     break;
                 }
                 // unorthodox blocking here but shouldn't be a problem for testing
@@ -987,7 +1803,7 @@ mod test {
     async fn set_peer_connection(
         packet_drop_policy: PacketDropPolicy,
     ) -> anyhow::Result<(TransportPublicKey, OutboundConnectionHandler, SocketAddr)> {
-        set_peer_connection_in(packet_drop_policy, false)
+        set_peer_connection_in(packet_drop_policy, false, TransportKeypair::new_ed25519())
             .await
             .map(|(pk, (o, _), s)| (pk, o, s))
     }
@@ -1002,25 +1818,25 @@ mod test {
         ),
         anyhow::Error,
     > {
-        set_peer_connection_in(packet_drop_policy, true)
+        set_peer_connection_in(packet_drop_policy, true, TransportKeypair::new_ed25519())
             .await
-            .map(|(pk, (_, i), s)| (pk, i, s))
+            .map(|(pk, (_, i), s)| (pk, i.new_connection_notifier, s))
     }
 
     async fn set_peer_connection_in(
         packet_drop_policy: PacketDropPolicy,
         gateway: bool,
+        peer_keypair: TransportKeypair,
     ) -> Result<
         (
             TransportPublicKey,
-            (OutboundConnectionHandler, mpsc::Receiver<PeerConnection>),
+            (OutboundConnectionHandler, InboundConnectionHandler),
             SocketAddr,
         ),
         anyhow::Error,
     > {
         static PORT: AtomicU16 = AtomicU16::new(25000);
 
-        let peer_keypair = TransportKeypair::new();
         let peer_pub = peer_keypair.public.clone();
         let port = PORT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         let socket = Arc::new(
@@ -1349,6 +2165,162 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn simulate_gateway_connection_behind_intro_gate() -> anyhow::Result<()> {
+        let (_peer_pub, mut peer, _peer_addr) = set_peer_connection(Default::default()).await?;
+        // thresholds of zero keep the gate closed and require a proof of work for every intro,
+        // the port is outside the range used by `set_peer_connection_in`
+        let gw_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24100).into();
+        let gw_keypair = TransportKeypair::new_ed25519();
+        let gw_pub = gw_keypair.public.clone();
+        let socket = Arc::new(MockSocket::test_config(Default::default(), gw_addr).await);
+        let (_gw, mut gw_handler) = OutboundConnectionHandler::config_listener(
+            socket,
+            gw_keypair,
+            true,
+            gw_addr,
+            IntroGate::with_thresholds(0, 0),
+            None,
+            DEFAULT_IDLE_TIMEOUT,
+        )?;
+
+        let gw = tokio::spawn(async move {
+            let gw_conn = gw_handler.new_connection_notifier.recv();
+            let mut conn = tokio::time::timeout(Duration::from_secs(10), gw_conn)
+                .await?
+                .ok_or(anyhow::anyhow!("no connection"))?;
+            let msg = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await??;
+            assert_eq!(bincode::deserialize::<String>(&msg)?, "foo");
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let peer = tokio::spawn(async move {
+            let gw_conn = peer.connect(gw_pub, gw_addr).await;
+            let mut conn = tokio::time::timeout(Duration::from_secs(10), gw_conn).await??;
+            conn.send("foo".to_string()).await?;
+            let _ = tokio::time::timeout(Duration::from_secs(1), conn.recv()).await;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let (a, b) = tokio::try_join!(peer, gw)?;
+        a?;
+        b?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn simulate_gateway_connection_protocol_versions() -> anyhow::Result<()> {
+        // legacy RSA identities fall back to the first version of the handshake
+        for (peer_keypair, gw_keypair) in [
+            (
+                TransportKeypair::new_ed25519(),
+                TransportKeypair::new_ed25519(),
+            ),
+            (TransportKeypair::new(), TransportKeypair::new_ed25519()),
+            (TransportKeypair::new_ed25519(), TransportKeypair::new()),
+        ] {
+            let (_peer_pub, (mut peer, _), _peer_addr) =
+                set_peer_connection_in(Default::default(), false, peer_keypair).await?;
+            let (gw_pub, (_, mut gw_conn), gw_addr) =
+                set_peer_connection_in(Default::default(), true, gw_keypair).await?;
+
+            let gw = tokio::spawn(async move {
+                let gw_conn = gw_conn.new_connection_notifier.recv();
+                let mut conn = tokio::time::timeout(Duration::from_secs(10), gw_conn)
+                    .await?
+                    .ok_or(anyhow::anyhow!("no connection"))?;
+                let msg = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await??;
+                assert_eq!(bincode::deserialize::<String>(&msg)?, "foo");
+                Ok::<_, anyhow::Error>(())
+            });
+
+            let peer = tokio::spawn(async move {
+                let gw_conn = peer.connect(gw_pub, gw_addr).await;
+                let mut conn = tokio::time::timeout(Duration::from_secs(10), gw_conn).await??;
+                conn.send("foo".to_string()).await?;
+                // keep the connection alive until the gateway has received the message
+                let _ = tokio::time::timeout(Duration::from_secs(1), conn.recv()).await;
+                Ok::<_, anyhow::Error>(())
+            });
+
+            let (a, b) = tokio::try_join!(peer, gw)?;
+            a?;
+            b?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn path_checks_limited_per_source() {
+        let mut limiter = PathCheckLimiter::default();
+        let now = Instant::now();
+        let source = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        for _ in 0..PATH_CHECKS_PER_SECOND {
+            assert!(limiter.allow(source, now));
+        }
+        assert!(!limiter.allow(source, now));
+        assert!(limiter.allow(other, now));
+        assert!(limiter.allow(source, now + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn simulate_connection_migration() -> anyhow::Result<()> {
+        let (gw_pub, (_, mut gw_handler), gw_addr) =
+            set_peer_connection_in(Default::default(), true, TransportKeypair::new_ed25519())
+                .await?;
+        // the peer socket is set up here since the test changes its address, the ports are
+        // outside the range used by `set_peer_connection_in`
+        let peer_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24000).into();
+        let migrated_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24001).into();
+        let socket = Arc::new(MockSocket::test_config(Default::default(), peer_addr).await);
+        let (mut peer, _peer_handler) = OutboundConnectionHandler::test_set_up(
+            peer_addr,
+            socket.clone(),
+            TransportKeypair::new_ed25519(),
+            false,
+        )?;
+
+        let gw = tokio::spawn(async move {
+            let gw_conn = gw_handler.new_connection_notifier.recv();
+            let mut conn = tokio::time::timeout(Duration::from_secs(10), gw_conn)
+                .await?
+                .ok_or(anyhow::anyhow!("no connection"))?;
+            let msg = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await??;
+            assert_eq!(bincode::deserialize::<String>(&msg)?, "foo");
+            assert_eq!(conn.remote_addr(), peer_addr);
+
+            let msg = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await??;
+            assert_eq!(bincode::deserialize::<String>(&msg)?, "bar");
+            assert_eq!(conn.remote_addr(), migrated_addr);
+            let migration = gw_handler
+                .migration_notifier
+                .try_recv()
+                .map_err(|_| anyhow::anyhow!("migration not notified"))?;
+            assert_eq!(migration.previous_addr, peer_addr);
+            assert_eq!(migration.remote_addr, migrated_addr);
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let peer = tokio::spawn(async move {
+            let gw_conn = peer.connect(gw_pub, gw_addr).await;
+            let mut conn = tokio::time::timeout(Duration::from_secs(10), gw_conn).await??;
+            conn.send("foo".to_string()).await?;
+            // wait until the message is acknowledged before changing address
+            let _ = tokio::time::timeout(Duration::from_secs(1), conn.recv()).await;
+            socket.rebind(migrated_addr).await;
+            conn.send("bar".to_string()).await?;
+            // keep the connection alive so the path challenge is answered and the message resent
+            let _ = tokio::time::timeout(Duration::from_secs(5), conn.recv()).await;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let (a, b) = tokio::try_join!(peer, gw)?;
+        a?;
+        b?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn simulate_gateway_connection_drop_first_packets_of_gateway() -> anyhow::Result<()> {
         // crate::config::set_logger(Some(tracing::level_filters::LevelFilter::TRACE));
@@ -1628,4 +2600,291 @@ mod test {
         }
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn simulate_streamed_message_over_emulated_network() -> anyhow::Result<()> {
+        use crate::transport::emulated_socket::{EmulatedNetwork, Latency, LinkConditions};
+        use crate::util::time_source::MockTimeSource;
+
+        let network = EmulatedNetwork::with_time_source(42, MockTimeSource::new(Instant::now()));
+        // link delays are computed in the time of the network, which is moved in fixed steps
+        let clock = {
+            let network = network.clone();
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(Duration::from_millis(1));
+                loop {
+                    tick.tick().await;
+                    network.advance_time(Duration::from_millis(1));
+                }
+            })
+        };
+        let conditions = LinkConditions {
+            latency: Latency::Normal {
+                mean: Duration::from_millis(30),
+                std_dev: Duration::from_millis(5),
+            },
+            jitter: Duration::from_millis(5),
+            loss: 0.02,
+            duplication: 0.01,
+            reordering: 0.05,
+            bandwidth: Some(2 * 1024 * 1024),
+            ..Default::default()
+        };
+        // the link is lossier from b to a, where the receipts go
+        let a_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24200).into();
+        let b_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24201).into();
+        let a_keypair = TransportKeypair::new_ed25519();
+        let b_keypair = TransportKeypair::new_ed25519();
+        let (a_pub, b_pub) = (a_keypair.public.clone(), b_keypair.public.clone());
+        let a_socket = Arc::new(network.bind(a_addr, conditions.clone()));
+        let b_socket = Arc::new(network.bind(
+            b_addr,
+            LinkConditions {
+                loss: 0.1,
+                ..conditions
+            },
+        ));
+        let (mut a, _a_handler) =
+            OutboundConnectionHandler::test_set_up(a_addr, a_socket, a_keypair, false)?;
+        let (mut b, _b_handler) =
+            OutboundConnectionHandler::test_set_up(b_addr, b_socket, b_keypair, false)?;
+
+        let msg = "foo".repeat(30_000);
+        let expected = msg.clone();
+        let a = tokio::spawn(async move {
+            let conn = a.connect(b_pub, b_addr).await;
+            let mut conn = tokio::time::timeout(Duration::from_secs(10), conn).await??;
+            conn.send(msg).await?;
+            // keep the connection alive until b has received the whole message
+            let _ = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await;
+            Ok::<_, anyhow::Error>(())
+        });
+        let b = tokio::spawn(async move {
+            let conn = b.connect(a_pub, a_addr).await;
+            let mut conn = tokio::time::timeout(Duration::from_secs(10), conn).await??;
+            let msg = tokio::time::timeout(Duration::from_secs(20), conn.recv()).await??;
+            assert_eq!(bincode::deserialize::<String>(&msg)?, expected);
+            conn.send("done".to_string()).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let (a, b) = tokio::try_join!(a, b)?;
+        clock.abort();
+        a?;
+        b?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn simulate_relayed_connection() -> anyhow::Result<()> {
+        use crate::transport::emulated_socket::{EmulatedNetwork, LinkConditions};
+
+        let network = EmulatedNetwork::new(7);
+        let relay_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24300).into();
+        let a_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24301).into();
+        let b_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24302).into();
+        // a and b can only reach each other through the relay
+        network.block(a_addr, b_addr);
+        let relay_keypair = TransportKeypair::new_ed25519();
+        let a_keypair = TransportKeypair::new_ed25519();
+        let b_keypair = TransportKeypair::new_ed25519();
+        let relay_pub = relay_keypair.public.clone();
+        let (a_pub, b_pub) = (a_keypair.public.clone(), b_keypair.public.clone());
+        let (mut relay, mut relay_handler) = OutboundConnectionHandler::test_set_up(
+            relay_addr,
+            Arc::new(network.bind(relay_addr, LinkConditions::default())),
+            relay_keypair,
+            true,
+        )?;
+        let (mut a, _a_handler) = OutboundConnectionHandler::test_set_up(
+            a_addr,
+            Arc::new(network.bind(a_addr, LinkConditions::default())),
+            a_keypair,
+            false,
+        )?;
+        let (mut b, _b_handler) = OutboundConnectionHandler::test_set_up(
+            b_addr,
+            Arc::new(network.bind(b_addr, LinkConditions::default())),
+            b_keypair,
+            false,
+        )?;
+
+        let a_relay = a.connect(relay_pub.clone(), relay_addr).await;
+        let b_relay = b.connect(relay_pub, relay_addr).await;
+        let _a_relay = tokio::time::timeout(Duration::from_secs(10), a_relay).await??;
+        let _b_relay = tokio::time::timeout(Duration::from_secs(10), b_relay).await??;
+
+        relay.allow_relay(a_addr, b_addr).await;
+        a.relay_through(b_addr, relay_addr).await;
+        b.relay_through(a_addr, relay_addr).await;
+        let a = tokio::spawn(async move {
+            let conn = a.connect(b_pub, b_addr).await;
+            let mut conn = tokio::time::timeout(Duration::from_secs(60), conn).await??;
+            conn.send("foo".to_string()).await?;
+            let _ = tokio::time::timeout(Duration::from_secs(5), conn.recv()).await;
+            Ok::<_, anyhow::Error>(())
+        });
+        let b = tokio::spawn(async move {
+            let conn = b.connect(a_pub, a_addr).await;
+            let mut conn = tokio::time::timeout(Duration::from_secs(60), conn).await??;
+            let msg = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await??;
+            assert_eq!(bincode::deserialize::<String>(&msg)?, "foo");
+            conn.send("done".to_string()).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+        let (a, b) = tokio::try_join!(a, b)?;
+        a?;
+        b?;
+
+        // the relay reports the bytes it forwarded
+        let usage = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(InboundConnectionEvent::RelayUsage(usage)) =
+                    relay_handler.next_event().await
+                {
+                    break usage;
+                }
+            }
+        })
+        .await?;
+        assert!([a_addr, b_addr].contains(&usage.source));
+        assert!([a_addr, b_addr].contains(&usage.target));
+        assert!(usage.bytes > 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn relay_refused_without_offer() -> anyhow::Result<()> {
+        use crate::transport::emulated_socket::{EmulatedNetwork, LinkConditions};
+
+        let network = EmulatedNetwork::new(9);
+        let relay_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24310).into();
+        let a_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24311).into();
+        let b_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24312).into();
+        network.block(a_addr, b_addr);
+        let relay_keypair = TransportKeypair::new_ed25519();
+        let a_keypair = TransportKeypair::new_ed25519();
+        let b_keypair = TransportKeypair::new_ed25519();
+        let relay_pub = relay_keypair.public.clone();
+        let b_pub = b_keypair.public.clone();
+        let (_relay, _relay_handler) = OutboundConnectionHandler::test_set_up(
+            relay_addr,
+            Arc::new(network.bind(relay_addr, LinkConditions::default())),
+            relay_keypair,
+            true,
+        )?;
+        let (mut a, _a_handler) = OutboundConnectionHandler::test_set_up(
+            a_addr,
+            Arc::new(network.bind(a_addr, LinkConditions::default())),
+            a_keypair,
+            false,
+        )?;
+        let (mut b, _b_handler) = OutboundConnectionHandler::test_set_up(
+            b_addr,
+            Arc::new(network.bind(b_addr, LinkConditions::default())),
+            b_keypair,
+            false,
+        )?;
+        let a_relay = a.connect(relay_pub.clone(), relay_addr).await;
+        let b_relay = b.connect(relay_pub, relay_addr).await;
+        let _a_relay = tokio::time::timeout(Duration::from_secs(10), a_relay).await??;
+        let _b_relay = tokio::time::timeout(Duration::from_secs(10), b_relay).await??;
+
+        // a and b are both connected to the relay, but it never offered to relay between them
+        a.relay_through(b_addr, relay_addr).await;
+        let conn = a.connect(b_pub, b_addr).await;
+        let connected = tokio::time::timeout(Duration::from_secs(5), conn).await;
+        assert!(!matches!(connected, Ok(Ok(_))));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connect_any_falls_back_to_reachable_address() -> anyhow::Result<()> {
+        use crate::transport::emulated_socket::{EmulatedNetwork, LinkConditions};
+
+        let network = EmulatedNetwork::new(11);
+        let a_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24400).into();
+        let b_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24401).into();
+        // nothing listens at b's IPv6 address
+        let b_v6_addr: SocketAddr = (std::net::Ipv6Addr::LOCALHOST, 24401).into();
+        let a_keypair = TransportKeypair::new_ed25519();
+        let b_keypair = TransportKeypair::new_ed25519();
+        let (a_pub, b_pub) = (a_keypair.public.clone(), b_keypair.public.clone());
+        let (mut a, _a_handler) = OutboundConnectionHandler::test_set_up(
+            a_addr,
+            Arc::new(network.bind(a_addr, LinkConditions::default())),
+            a_keypair,
+            false,
+        )?;
+        let (mut b, _b_handler) = OutboundConnectionHandler::test_set_up(
+            b_addr,
+            Arc::new(network.bind(b_addr, LinkConditions::default())),
+            b_keypair,
+            false,
+        )?;
+
+        let a_conn = a.connect_any(b_pub, [b_addr, b_v6_addr]).await;
+        let b_conn = b.connect(a_pub, a_addr).await;
+        let (a_conn, _b_conn) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::try_join!(a_conn, b_conn)
+        })
+        .await??;
+        assert_eq!(a_conn.remote_addr(), b_addr);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn idle_connection_kept_alive_until_unreachable() -> anyhow::Result<()> {
+        use crate::transport::emulated_socket::{EmulatedNetwork, LinkConditions};
+
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+        let network = EmulatedNetwork::new(13);
+        let a_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24500).into();
+        let b_addr: SocketAddr = (Ipv4Addr::LOCALHOST, 24501).into();
+        let a_keypair = TransportKeypair::new_ed25519();
+        let b_keypair = TransportKeypair::new_ed25519();
+        let (a_pub, b_pub) = (a_keypair.public.clone(), b_keypair.public.clone());
+        let (mut a, _a_handler) = OutboundConnectionHandler::config_listener(
+            Arc::new(network.bind(a_addr, LinkConditions::default())),
+            a_keypair,
+            false,
+            a_addr,
+            IntroGate::new(),
+            None,
+            IDLE_TIMEOUT,
+        )?;
+        let (mut b, _b_handler) = OutboundConnectionHandler::config_listener(
+            Arc::new(network.bind(b_addr, LinkConditions::default())),
+            b_keypair,
+            false,
+            b_addr,
+            IntroGate::new(),
+            None,
+            IDLE_TIMEOUT,
+        )?;
+
+        let a_conn = a.connect(b_pub, b_addr).await;
+        let b_conn = b.connect(a_pub, a_addr).await;
+        let (mut a_conn, mut b_conn) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::try_join!(a_conn, b_conn)
+        })
+        .await??;
+        let b = tokio::spawn(async move { b_conn.recv().await });
+
+        // pings keep the connection open while nothing is sent
+        assert!(
+            tokio::time::timeout(IDLE_TIMEOUT * 2, a_conn.recv())
+                .await
+                .is_err(),
+            "idle connection was closed"
+        );
+        assert!(!b.is_finished());
+
+        network.block(a_addr, b_addr);
+        let closed = tokio::time::timeout(IDLE_TIMEOUT * 2, a_conn.recv()).await?;
+        assert!(matches!(closed, Err(TransportError::ConnectionClosed(addr)) if addr == b_addr));
+        let closed = tokio::time::timeout(IDLE_TIMEOUT * 2, b).await??;
+        assert!(matches!(closed, Err(TransportError::ConnectionClosed(addr)) if addr == a_addr));
+        Ok(())
+    }
 }
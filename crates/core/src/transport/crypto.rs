@@ -1,6 +1,23 @@
+use aes_gcm::{aead::Aead, Aes128Gcm, KeyInit};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand::rngs::OsRng;
-use rsa::{pkcs8, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use rsa::{pkcs8, BigUint, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519Secret};
+
+use super::TransportError;
+
+/// Context used to derive the key of data sealed to an Ed25519 identity.
+const SEAL_KEY_CONTEXT: &str = "freenet-core transport 2024-06 intro seal";
+
+/// Context used to derive session keys from the ephemeral Diffie-Hellman exchange.
+const SESSION_KEY_CONTEXT: &str = "freenet-core transport 2024-06 session key";
+
+const X25519_KEY_SIZE: usize = 32;
+
+/// Size overhead of data sealed to an Ed25519 identity: the ephemeral public key and
+/// the AEAD tag.
+pub(super) const SEAL_OVERHEAD: usize = X25519_KEY_SIZE + 16;
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TransportKeypair {
@@ -15,26 +32,39 @@ impl Default for TransportKeypair {
 }
 
 impl TransportKeypair {
+    /// Generates a new RSA identity, Ed25519 identities can't be decoded by older versions yet.
     pub fn new() -> Self {
         let mut rng = OsRng;
         // Key size, can be adjusted
         const BITS: usize = 2048;
         let priv_key = RsaPrivateKey::new(&mut rng, BITS).expect("failed to generate a key");
-        let pub_key = RsaPublicKey::from(&priv_key);
+        Self::from_private_key(priv_key)
+    }
+
+    /// Generates a new Ed25519 identity.
+    pub fn new_ed25519() -> Self {
+        Self::from_ed25519(SigningKey::generate(&mut OsRng))
+    }
 
+    pub fn from_private_key(priv_key: RsaPrivateKey) -> Self {
         TransportKeypair {
-            public: TransportPublicKey(pub_key),
-            secret: TransportSecretKey(priv_key),
+            public: TransportPublicKey::Rsa(RsaPublicKey::from(&priv_key)),
+            secret: TransportSecretKey::Rsa(priv_key),
         }
     }
 
-    pub fn from_private_key(priv_key: RsaPrivateKey) -> Self {
+    pub fn from_ed25519(signing_key: SigningKey) -> Self {
         TransportKeypair {
-            public: TransportPublicKey(RsaPublicKey::from(&priv_key)),
-            secret: TransportSecretKey(priv_key),
+            public: TransportPublicKey::Ed25519(signing_key.verifying_key()),
+            secret: TransportSecretKey::Ed25519(signing_key),
         }
     }
 
+    /// Reads a keypair from a PKCS#8 PEM encoded private key, either Ed25519 or RSA.
+    pub fn from_pkcs8_pem(pem: &str) -> Result<Self, pkcs8::Error> {
+        TransportSecretKey::from_pkcs8_pem(pem).map(Self::from)
+    }
+
     pub fn public(&self) -> &TransportPublicKey {
         &self.public
     }
@@ -42,18 +72,123 @@ impl TransportKeypair {
     pub(crate) fn secret(&self) -> &TransportSecretKey {
         &self.secret
     }
+
+    /// Signs data with this identity, legacy RSA identities can't sign.
+    pub(crate) fn sign(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match &self.secret {
+            TransportSecretKey::Rsa(_) => None,
+            TransportSecretKey::Ed25519(key) => Some(key.sign(data).to_bytes().to_vec()),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Hash)]
-pub struct TransportPublicKey(RsaPublicKey);
+/// Public identity of a peer.
+///
+/// RSA keys are still the default so nodes can interoperate with peers running older versions
+/// while the network is upgraded to Ed25519 keys.
+///
+/// RSA keys keep the encoding of older versions, the `{n, e}` struct of the modulus and the
+/// public exponent. Ed25519 keys are encoded with the same struct, a zero modulus (which no
+/// RSA key has) marks the exponent as holding the Ed25519 key.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub enum TransportPublicKey {
+    Rsa(RsaPublicKey),
+    Ed25519(VerifyingKey),
+}
 
 impl TransportPublicKey {
+    /// Encrypts data so it can only be read by the owner of the secret key.
+    ///
+    /// For Ed25519 keys the data is sealed using an ephemeral X25519 key exchanged with the
+    /// remote identity (converted to its X25519 form), the output is prefixed with the
+    /// ephemeral public key.
     pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
-        let mut rng = OsRng;
-        let padding = Pkcs1v15Encrypt;
-        self.0
-            .encrypt(&mut rng, padding, data)
-            .expect("failed to encrypt")
+        match self {
+            TransportPublicKey::Rsa(key) => {
+                let mut rng = OsRng;
+                let padding = Pkcs1v15Encrypt;
+                key.encrypt(&mut rng, padding, data)
+                    .expect("failed to encrypt")
+            }
+            TransportPublicKey::Ed25519(key) => {
+                let remote = X25519PublicKey::from(key.to_montgomery().to_bytes());
+                let ephemeral = X25519Secret::random_from_rng(OsRng);
+                let ephemeral_pub = X25519PublicKey::from(&ephemeral);
+                let cipher =
+                    seal_cipher(&ephemeral.diffie_hellman(&remote), &ephemeral_pub, &remote);
+                let encrypted = cipher
+                    .encrypt(&[0u8; 12].into(), data)
+                    .expect("failed to encrypt");
+                let mut sealed = Vec::with_capacity(X25519_KEY_SIZE + encrypted.len());
+                sealed.extend_from_slice(ephemeral_pub.as_bytes());
+                sealed.extend_from_slice(&encrypted);
+                sealed
+            }
+        }
+    }
+
+    /// Whether the signature of the data was made by the owner of this key.
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match self {
+            TransportPublicKey::Rsa(_) => false,
+            TransportPublicKey::Ed25519(key) => Signature::from_slice(signature)
+                .map(|signature| key.verify(data, &signature).is_ok())
+                .unwrap_or(false),
+        }
+    }
+
+    pub(crate) fn is_ed25519(&self) -> bool {
+        matches!(self, TransportPublicKey::Ed25519(_))
+    }
+
+    pub fn from_public_key_pem(pem: &str) -> Result<Self, pkcs8::spki::Error> {
+        use pkcs8::DecodePublicKey;
+
+        if let Ok(key) = VerifyingKey::from_public_key_pem(pem) {
+            return Ok(TransportPublicKey::Ed25519(key));
+        }
+        RsaPublicKey::from_public_key_pem(pem).map(TransportPublicKey::Rsa)
+    }
+}
+
+/// Encoding of public keys, named and shaped like the serde encoding of [`RsaPublicKey`].
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "RsaPublicKey")]
+struct PublicKeyEncoding {
+    n: BigUint,
+    e: BigUint,
+}
+
+impl Serialize for TransportPublicKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TransportPublicKey::Rsa(key) => key.serialize(serializer),
+            TransportPublicKey::Ed25519(key) => PublicKeyEncoding {
+                n: BigUint::default(),
+                e: BigUint::from_bytes_be(key.as_bytes()),
+            }
+            .serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TransportPublicKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let PublicKeyEncoding { n, e } = PublicKeyEncoding::deserialize(deserializer)?;
+        if n != BigUint::default() {
+            return RsaPublicKey::new(n, e)
+                .map(TransportPublicKey::Rsa)
+                .map_err(serde::de::Error::custom);
+        }
+        let encoded = e.to_bytes_be();
+        let mut bytes = [0u8; ed25519_dalek::PUBLIC_KEY_LENGTH];
+        let Some(padding) = bytes.len().checked_sub(encoded.len()) else {
+            return Err(serde::de::Error::custom("Ed25519 public key too long"));
+        };
+        bytes[padding..].copy_from_slice(&encoded);
+        VerifyingKey::from_bytes(&bytes)
+            .map(TransportPublicKey::Ed25519)
+            .map_err(serde::de::Error::custom)
     }
 }
 
@@ -61,32 +196,68 @@ impl std::fmt::Display for TransportPublicKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use pkcs8::EncodePublicKey;
 
-        let encoded = self.0.to_public_key_der().map_err(|_| std::fmt::Error)?;
-        write!(
-            f,
-            "{}",
-            bs58::encode(if encoded.as_bytes().len() > 16 {
-                &encoded.as_bytes()[..16]
-            } else {
-                encoded.as_bytes()
-            })
-            .into_string()
-        )
+        match self {
+            TransportPublicKey::Rsa(key) => {
+                let encoded = key.to_public_key_der().map_err(|_| std::fmt::Error)?;
+                write!(
+                    f,
+                    "{}",
+                    bs58::encode(if encoded.as_bytes().len() > 16 {
+                        &encoded.as_bytes()[..16]
+                    } else {
+                        encoded.as_bytes()
+                    })
+                    .into_string()
+                )
+            }
+            TransportPublicKey::Ed25519(key) => {
+                write!(f, "{}", bs58::encode(key.as_bytes()).into_string())
+            }
+        }
     }
 }
 
 impl From<RsaPublicKey> for TransportPublicKey {
     fn from(key: RsaPublicKey) -> Self {
-        TransportPublicKey(key)
+        TransportPublicKey::Rsa(key)
+    }
+}
+
+impl From<VerifyingKey> for TransportPublicKey {
+    fn from(key: VerifyingKey) -> Self {
+        TransportPublicKey::Ed25519(key)
     }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub(crate) struct TransportSecretKey(RsaPrivateKey);
+pub(crate) enum TransportSecretKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(SigningKey),
+}
 
 impl TransportSecretKey {
-    pub fn decrypt(&self, data: &[u8]) -> rsa::Result<Vec<u8>> {
-        self.0.decrypt(Pkcs1v15Encrypt, data)
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, TransportError> {
+        match self {
+            TransportSecretKey::Rsa(key) => Ok(key.decrypt(Pkcs1v15Encrypt, data)?),
+            TransportSecretKey::Ed25519(key) => {
+                if data.len() < SEAL_OVERHEAD {
+                    return Err(TransportError::PrivateKeyDecryptionError(aes_gcm::Error));
+                }
+                let secret = X25519Secret::from(key.to_scalar_bytes());
+                let this = X25519PublicKey::from(&secret);
+                let mut ephemeral_pub = [0u8; X25519_KEY_SIZE];
+                ephemeral_pub.copy_from_slice(&data[..X25519_KEY_SIZE]);
+                let ephemeral_pub = X25519PublicKey::from(ephemeral_pub);
+                let cipher = seal_cipher(
+                    &secret.diffie_hellman(&ephemeral_pub),
+                    &ephemeral_pub,
+                    &this,
+                );
+                cipher
+                    .decrypt(&[0u8; 12].into(), &data[X25519_KEY_SIZE..])
+                    .map_err(TransportError::PrivateKeyDecryptionError)
+            }
+        }
     }
 
     pub fn to_pkcs8_pem(&self) -> Result<Vec<u8>, pkcs8::Error> {
@@ -98,24 +269,173 @@ impl TransportSecretKey {
         #[cfg(windows)]
         let line_endings = pkcs8::LineEnding::CRLF;
 
-        self.0
-            .to_pkcs8_pem(line_endings)
-            .map(|s| s.as_str().as_bytes().to_vec())
+        match self {
+            TransportSecretKey::Rsa(key) => key
+                .to_pkcs8_pem(line_endings)
+                .map(|s| s.as_str().as_bytes().to_vec()),
+            TransportSecretKey::Ed25519(key) => key
+                .to_pkcs8_pem(line_endings)
+                .map(|s| s.as_str().as_bytes().to_vec()),
+        }
+    }
+
+    pub fn from_pkcs8_pem(pem: &str) -> Result<Self, pkcs8::Error> {
+        use pkcs8::DecodePrivateKey;
+
+        if let Ok(key) = SigningKey::from_pkcs8_pem(pem) {
+            return Ok(TransportSecretKey::Ed25519(key));
+        }
+        RsaPrivateKey::from_pkcs8_pem(pem).map(TransportSecretKey::Rsa)
+    }
+}
+
+impl From<TransportSecretKey> for TransportKeypair {
+    fn from(secret: TransportSecretKey) -> Self {
+        match secret {
+            TransportSecretKey::Rsa(key) => TransportKeypair::from_private_key(key),
+            TransportSecretKey::Ed25519(key) => TransportKeypair::from_ed25519(key),
+        }
+    }
+}
+
+fn seal_cipher(
+    shared_secret: &x25519_dalek::SharedSecret,
+    ephemeral_pub: &X25519PublicKey,
+    remote: &X25519PublicKey,
+) -> Aes128Gcm {
+    let mut material = [0u8; X25519_KEY_SIZE * 3];
+    material[..X25519_KEY_SIZE].copy_from_slice(shared_secret.as_bytes());
+    material[X25519_KEY_SIZE..X25519_KEY_SIZE * 2].copy_from_slice(ephemeral_pub.as_bytes());
+    material[X25519_KEY_SIZE * 2..].copy_from_slice(remote.as_bytes());
+    let key = blake3::derive_key(SEAL_KEY_CONTEXT, &material);
+    Aes128Gcm::new_from_slice(&key[..16]).expect("correct length")
+}
+
+/// Ephemeral X25519 key used during a handshake to derive session keys with forward secrecy.
+///
+/// Each side of a connection contributes one, once the handshake is over the secret is dropped
+/// and the session keys can't be recovered even if the long term identities are compromised.
+pub(super) struct EphemeralKey {
+    secret: X25519Secret,
+    public: X25519PublicKey,
+}
+
+impl EphemeralKey {
+    pub fn new() -> Self {
+        let secret = X25519Secret::random_from_rng(OsRng);
+        let public = X25519PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_bytes(&self) -> [u8; X25519_KEY_SIZE] {
+        self.public.to_bytes()
+    }
+
+    /// Derives the session key for a direction of the connection from the symmetric key
+    /// exchanged in the handshake for that direction and the remote ephemeral key.
+    pub fn session_key(&self, remote: [u8; X25519_KEY_SIZE], exchanged_key: [u8; 16]) -> [u8; 16] {
+        let shared = self.secret.diffie_hellman(&X25519PublicKey::from(remote));
+        let mut material = [0u8; X25519_KEY_SIZE + 16];
+        material[..X25519_KEY_SIZE].copy_from_slice(shared.as_bytes());
+        material[X25519_KEY_SIZE..].copy_from_slice(&exchanged_key);
+        let key = blake3::derive_key(SESSION_KEY_CONTEXT, &material);
+        let mut session_key = [0u8; 16];
+        session_key.copy_from_slice(&key[..16]);
+        session_key
     }
 }
 
 #[cfg(test)]
-#[test]
-fn key_sizes_and_decryption() {
-    let pair = TransportKeypair::new();
-    let sym_key_bytes = rand::random::<[u8; 16]>();
-    // use aes_gcm::KeyInit;
-    // let _sym_key = aes_gcm::aes::Aes128::new(&sym_key_bytes.into());
-    let encrypted: Vec<u8> = pair.public.encrypt(&sym_key_bytes);
-    assert!(
-        encrypted.len() <= super::packet_data::MAX_PACKET_SIZE,
-        "packet size is too big"
-    );
-    let bytes = pair.secret.decrypt(&encrypted).unwrap();
-    assert_eq!(bytes, sym_key_bytes.as_slice());
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_sizes_and_decryption() {
+        for pair in [TransportKeypair::new_ed25519(), TransportKeypair::new()] {
+            let sym_key_bytes = rand::random::<[u8; 16]>();
+            let encrypted: Vec<u8> = pair.public.encrypt(&sym_key_bytes);
+            assert!(
+                encrypted.len() <= super::super::packet_data::MAX_PACKET_SIZE,
+                "packet size is too big"
+            );
+            let bytes = pair.secret.decrypt(&encrypted).unwrap();
+            assert_eq!(bytes, sym_key_bytes.as_slice());
+        }
+    }
+
+    #[test]
+    fn sealed_data_only_readable_by_recipient() {
+        let pair = TransportKeypair::new_ed25519();
+        let other = TransportKeypair::new_ed25519();
+        let encrypted = pair.public.encrypt(b"some data");
+        assert_eq!(encrypted.len(), b"some data".len() + SEAL_OVERHEAD);
+        assert!(other.secret.decrypt(&encrypted).is_err());
+        assert!(pair
+            .secret
+            .decrypt(&encrypted[..SEAL_OVERHEAD - 1])
+            .is_err());
+    }
+
+    #[test]
+    fn signatures_only_valid_for_signer() {
+        let pair = TransportKeypair::new_ed25519();
+        let other = TransportKeypair::new_ed25519();
+        let signature = pair.sign(b"some data").unwrap();
+        assert!(pair.public.verify(b"some data", &signature));
+        assert!(!pair.public.verify(b"other data", &signature));
+        assert!(!other.public.verify(b"some data", &signature));
+        assert!(!pair.public.verify(b"some data", &signature[1..]));
+        assert!(TransportKeypair::new().sign(b"some data").is_none());
+    }
+
+    #[test]
+    fn ephemeral_session_keys_match() {
+        let alice = EphemeralKey::new();
+        let bob = EphemeralKey::new();
+        let exchanged = rand::random::<[u8; 16]>();
+        assert_eq!(
+            alice.session_key(bob.public_bytes(), exchanged),
+            bob.session_key(alice.public_bytes(), exchanged)
+        );
+        assert_ne!(
+            alice.session_key(bob.public_bytes(), exchanged),
+            alice.session_key(EphemeralKey::new().public_bytes(), exchanged)
+        );
+    }
+
+    #[test]
+    fn rsa_public_key_encoding_unchanged() {
+        let pair = TransportKeypair::new();
+        let TransportPublicKey::Rsa(rsa_key) = &pair.public else {
+            unreachable!()
+        };
+        // older versions encoded public keys as a newtype of the RSA key
+        let legacy = bincode::serialize(rsa_key).unwrap();
+        assert_eq!(bincode::serialize(&pair.public).unwrap(), legacy);
+        let decoded: TransportPublicKey = bincode::deserialize(&legacy).unwrap();
+        assert_eq!(decoded, pair.public);
+        let legacy = serde_json::to_string(rsa_key).unwrap();
+        assert_eq!(serde_json::to_string(&pair.public).unwrap(), legacy);
+        let decoded: TransportPublicKey = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(decoded, pair.public);
+
+        for public in [pair.public, TransportKeypair::new_ed25519().public] {
+            let decoded: TransportPublicKey =
+                bincode::deserialize(&bincode::serialize(&public).unwrap()).unwrap();
+            assert_eq!(decoded, public);
+            let decoded: TransportPublicKey =
+                serde_json::from_str(&serde_json::to_string(&public).unwrap()).unwrap();
+            assert_eq!(decoded, public);
+        }
+    }
+
+    #[test]
+    fn pem_round_trip() {
+        for pair in [TransportKeypair::new_ed25519(), TransportKeypair::new()] {
+            let pem = pair.secret.to_pkcs8_pem().unwrap();
+            let read =
+                TransportKeypair::from_pkcs8_pem(std::str::from_utf8(&pem).unwrap()).unwrap();
+            assert_eq!(read, pair);
+        }
+    }
 }
@@ -0,0 +1,491 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tokio::sync::Notify;
+
+use super::Socket;
+use crate::util::time_source::{InstantTimeSrc, MockTimeSource, TimeSource};
+
+/// Distribution of the one way latency of a link.
+#[derive(Clone, Debug)]
+pub(super) enum Latency {
+    Fixed(Duration),
+    Uniform(Range<Duration>),
+    /// Normally distributed, samples below zero are clamped
+    Normal {
+        mean: Duration,
+        std_dev: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut SmallRng) -> Duration {
+        match self {
+            Latency::Fixed(latency) => *latency,
+            Latency::Uniform(range) => rng.gen_range(range.clone()),
+            Latency::Normal { mean, std_dev } => {
+                // Box-Muller transform
+                let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let latency = mean.as_secs_f64() + z * std_dev.as_secs_f64();
+                Duration::from_secs_f64(latency.max(0.0))
+            }
+        }
+    }
+}
+
+/// Conditions applied to the packets a socket sends, giving each side of a link different
+/// conditions makes it asymmetric.
+#[derive(Clone, Debug)]
+pub(super) struct LinkConditions {
+    pub latency: Latency,
+    /// Upper bound of a uniformly distributed delay added to the latency of every packet
+    pub jitter: Duration,
+    /// Probability of a packet being lost
+    pub loss: f64,
+    /// Probability of a packet being delivered twice
+    pub duplication: f64,
+    /// Probability of a packet being held back for `reorder_delay`, so later packets overtake it
+    pub reordering: f64,
+    pub reorder_delay: Duration,
+    /// Bytes per second the link carries, packets sent faster than this queue behind each other
+    pub bandwidth: Option<usize>,
+    /// Packets which would wait longer than this in the queue of a capped link are dropped
+    pub max_queue_delay: Duration,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            latency: Latency::Fixed(Duration::ZERO),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_millis(50),
+            bandwidth: None,
+            max_queue_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+/// A network of [`EmulatedSocket`]s which deliver packets to each other according to the
+/// [`LinkConditions`] of the sender.
+///
+/// Every random decision is taken by a RNG seeded from the network seed and the order the
+/// sockets are bound in, and delivery times are computed with the network time source, so runs
+/// are reproducible. With a [`MockTimeSource`] time only moves with
+/// [`advance_time`](EmulatedNetwork::advance_time) and packets are read with
+/// [`EmulatedSocket::try_recv`].
+pub(super) struct EmulatedNetwork<T: TimeSource = InstantTimeSrc> {
+    inboxes: Mutex<HashMap<SocketAddr, Arc<Inbox>>>,
+    /// Pairs of addresses which can't reach each other
+    blocked: Mutex<HashSet<(SocketAddr, SocketAddr)>>,
+    time_source: Mutex<T>,
+    /// Wakes up receivers waiting for a delivery when the time of the network is advanced
+    time_advanced: Notify,
+    seed: u64,
+    bound_sockets: AtomicU64,
+    /// Keeps packets with the same delivery time in sending order
+    next_seq: AtomicU64,
+}
+
+impl EmulatedNetwork<InstantTimeSrc> {
+    pub(super) fn new(seed: u64) -> Arc<Self> {
+        Self::with_time_source(seed, InstantTimeSrc::new())
+    }
+}
+
+impl EmulatedNetwork<MockTimeSource> {
+    pub(super) fn advance_time(&self, duration: Duration) {
+        self.time_source.lock().advance_time(duration);
+        self.time_advanced.notify_waiters();
+    }
+}
+
+impl<T: TimeSource + Send + 'static> EmulatedNetwork<T> {
+    pub(super) fn with_time_source(seed: u64, time_source: T) -> Arc<Self> {
+        Arc::new(EmulatedNetwork {
+            inboxes: Mutex::new(HashMap::new()),
+            blocked: Mutex::new(HashSet::new()),
+            time_source: Mutex::new(time_source),
+            time_advanced: Notify::new(),
+            seed,
+            bound_sockets: AtomicU64::new(0),
+            next_seq: AtomicU64::new(0),
+        })
+    }
+
+    pub(super) fn bind(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        conditions: LinkConditions,
+    ) -> EmulatedSocket<T> {
+        let inbox = Arc::new(Inbox::default());
+        let previous = self.inboxes.lock().insert(addr, inbox.clone());
+        assert!(previous.is_none(), "address {addr} already bound");
+        let socket_idx = self.bound_sockets.fetch_add(1, Ordering::Relaxed);
+        EmulatedSocket {
+            network: self.clone(),
+            addr,
+            inbox,
+            conditions,
+            link: Mutex::new(Link {
+                rng: SmallRng::seed_from_u64(self.seed.wrapping_add(socket_idx)),
+                idle_at: self.now(),
+            }),
+        }
+    }
+
+    /// Drops every packet between `a` and `b`, like two peers behind symmetric NATs.
+    pub(super) fn block(&self, a: SocketAddr, b: SocketAddr) {
+        let mut blocked = self.blocked.lock();
+        blocked.insert((a, b));
+        blocked.insert((b, a));
+    }
+
+    fn now(&self) -> Instant {
+        self.time_source.lock().now()
+    }
+}
+
+#[derive(Default)]
+struct Inbox {
+    packets: Mutex<BinaryHeap<Reverse<Delivery>>>,
+    /// Wakes up a receiver when a packet is queued
+    queued: Notify,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Delivery {
+    deliver_at: Instant,
+    seq: u64,
+    from: SocketAddr,
+    packet: Vec<u8>,
+}
+
+struct Link {
+    rng: SmallRng,
+    /// When the last packet sent finishes going through the link, with a bandwidth cap
+    idle_at: Instant,
+}
+
+pub(super) struct EmulatedSocket<T: TimeSource = InstantTimeSrc> {
+    network: Arc<EmulatedNetwork<T>>,
+    addr: SocketAddr,
+    inbox: Arc<Inbox>,
+    conditions: LinkConditions,
+    link: Mutex<Link>,
+}
+
+impl<T: TimeSource + Send + 'static> EmulatedSocket<T> {
+    /// Returns the next packet due at the current time of the network, if any.
+    pub(super) fn try_recv(&self) -> Option<(SocketAddr, Vec<u8>)> {
+        self.next_delivery().ok()
+    }
+
+    /// Pops the next packet due, otherwise returns how long until the next one is.
+    fn next_delivery(&self) -> Result<(SocketAddr, Vec<u8>), Option<Duration>> {
+        let now = self.network.now();
+        let mut packets = self.inbox.packets.lock();
+        match packets.peek() {
+            Some(Reverse(delivery)) if delivery.deliver_at <= now => {
+                let Reverse(delivery) = packets.pop().expect("peeked");
+                Ok((delivery.from, delivery.packet))
+            }
+            Some(Reverse(delivery)) => Err(Some(delivery.deliver_at - now)),
+            None => Err(None),
+        }
+    }
+
+    /// Decides when, if at all, each copy of a packet sent now reaches the remote.
+    fn schedule(&self, len: usize) -> Vec<Instant> {
+        let now = self.network.now();
+        let conditions = &self.conditions;
+        let mut link = self.link.lock();
+
+        let mut departs_at = now;
+        if let Some(bandwidth) = conditions.bandwidth {
+            departs_at = link.idle_at.max(now);
+            if departs_at - now > conditions.max_queue_delay {
+                tracing::trace!(from = %self.addr, "link queue full, dropping packet");
+                return vec![];
+            }
+            link.idle_at = departs_at + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+        }
+        if link.rng.gen_bool(conditions.loss) {
+            return vec![];
+        }
+
+        let copies = if link.rng.gen_bool(conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut deliver_at = departs_at + conditions.latency.sample(&mut link.rng);
+                if !conditions.jitter.is_zero() {
+                    deliver_at += link.rng.gen_range(Duration::ZERO..conditions.jitter);
+                }
+                if link.rng.gen_bool(conditions.reordering) {
+                    deliver_at += conditions.reorder_delay;
+                }
+                deliver_at
+            })
+            .collect()
+    }
+}
+
+impl<T: TimeSource + Send + 'static> Socket for EmulatedSocket<T> {
+    async fn bind(_addr: SocketAddr) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "emulated sockets are bound through an `EmulatedNetwork`",
+        ))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        loop {
+            // register before checking so a packet queued in between isn't missed
+            let queued = self.inbox.queued.notified();
+            let time_advanced = self.network.time_advanced.notified();
+            tokio::pin!(queued, time_advanced);
+            queued.as_mut().enable();
+            time_advanced.as_mut().enable();
+            match self.next_delivery() {
+                Ok((from, packet)) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    return Ok((packet.len(), from));
+                }
+                Err(Some(wait)) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = queued => {}
+                        _ = time_advanced => {}
+                    }
+                }
+                Err(None) => queued.await,
+            }
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+        if self.network.blocked.lock().contains(&(self.addr, target)) {
+            return Ok(buf.len());
+        }
+        let Some(inbox) = self.network.inboxes.lock().get(&target).cloned() else {
+            return Ok(buf.len());
+        };
+        for deliver_at in self.schedule(buf.len()) {
+            inbox.packets.lock().push(Reverse(Delivery {
+                deliver_at,
+                seq: self.network.next_seq.fetch_add(1, Ordering::Relaxed),
+                from: self.addr,
+                packet: buf.to_vec(),
+            }));
+            inbox.queued.notify_waiters();
+        }
+        Ok(buf.len())
+    }
+}
+
+impl<T: TimeSource> Drop for EmulatedSocket<T> {
+    fn drop(&mut self) {
+        self.network.inboxes.lock().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(port: u16) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    fn mock_network(seed: u64) -> Arc<EmulatedNetwork<MockTimeSource>> {
+        EmulatedNetwork::with_time_source(seed, MockTimeSource::new(Instant::now()))
+    }
+
+    fn drain(socket: &EmulatedSocket<MockTimeSource>) -> Vec<u8> {
+        std::iter::from_fn(|| socket.try_recv())
+            .map(|(_, packet)| packet[0])
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn latency() {
+        let network = mock_network(0);
+        let conditions = LinkConditions {
+            latency: Latency::Fixed(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let a = network.bind(addr(1), conditions);
+        let b = network.bind(addr(2), LinkConditions::default());
+
+        a.send_to(&[1], addr(2)).await.unwrap();
+        assert!(b.try_recv().is_none());
+        network.advance_time(Duration::from_millis(49));
+        assert!(b.try_recv().is_none());
+        network.advance_time(Duration::from_millis(1));
+        assert_eq!(b.try_recv(), Some((addr(1), vec![1])));
+
+        // the other direction has no latency
+        b.send_to(&[2], addr(1)).await.unwrap();
+        assert_eq!(a.try_recv(), Some((addr(2), vec![2])));
+    }
+
+    #[tokio::test]
+    async fn reproducible_with_seed() {
+        async fn run(seed: u64) -> Vec<u8> {
+            let network = mock_network(seed);
+            let conditions = LinkConditions {
+                latency: Latency::Uniform(Duration::from_millis(10)..Duration::from_millis(30)),
+                jitter: Duration::from_millis(10),
+                loss: 0.2,
+                duplication: 0.1,
+                reordering: 0.1,
+                ..Default::default()
+            };
+            let a = network.bind(addr(1), conditions);
+            let b = network.bind(addr(2), LinkConditions::default());
+            for i in 0..100 {
+                a.send_to(&[i], addr(2)).await.unwrap();
+                network.advance_time(Duration::from_millis(1));
+            }
+            network.advance_time(Duration::from_secs(1));
+            drain(&b)
+        }
+
+        let received = run(7).await;
+        assert_eq!(received, run(7).await);
+        assert_ne!(received, run(8).await);
+        // some packets were lost and some overtook others
+        assert!(received.len() < 100);
+        assert!(received.windows(2).any(|w| w[0] > w[1]));
+    }
+
+    #[tokio::test]
+    async fn bandwidth_cap() {
+        let network = mock_network(0);
+        let conditions = LinkConditions {
+            // a 100 byte packet every 10ms
+            bandwidth: Some(10_000),
+            max_queue_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let a = network.bind(addr(1), conditions);
+        let b = network.bind(addr(2), LinkConditions::default());
+        for i in 0..10 {
+            let mut packet = vec![0; 100];
+            packet[0] = i;
+            a.send_to(&packet, addr(2)).await.unwrap();
+        }
+        assert_eq!(drain(&b), vec![0]);
+        network.advance_time(Duration::from_millis(10));
+        assert_eq!(drain(&b), vec![1]);
+        network.advance_time(Duration::from_secs(1));
+        // the packets which would have queued for more than 50ms were dropped
+        assert_eq!(drain(&b), vec![2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn blocked_link() {
+        let network = mock_network(0);
+        let a = network.bind(addr(1), LinkConditions::default());
+        let b = network.bind(addr(2), LinkConditions::default());
+        let c = network.bind(addr(3), LinkConditions::default());
+        network.block(addr(1), addr(2));
+        a.send_to(&[1], addr(2)).await.unwrap();
+        b.send_to(&[2], addr(1)).await.unwrap();
+        a.send_to(&[3], addr(3)).await.unwrap();
+        assert!(b.try_recv().is_none());
+        assert!(a.try_recv().is_none());
+        assert_eq!(drain(&c), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn duplication() {
+        let network = mock_network(0);
+        let conditions = LinkConditions {
+            duplication: 1.0,
+            ..Default::default()
+        };
+        let a = network.bind(addr(1), conditions);
+        let b = network.bind(addr(2), LinkConditions::default());
+        a.send_to(&[1], addr(2)).await.unwrap();
+        assert_eq!(drain(&b), vec![1, 1]);
+    }
+
+    #[test]
+    fn normal_latency() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let latency = Latency::Normal {
+            mean: Duration::from_millis(100),
+            std_dev: Duration::from_millis(10),
+        };
+        let samples: Vec<_> = (0..1000).map(|_| latency.sample(&mut rng)).collect();
+        let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+        assert!(mean > Duration::from_millis(95) && mean < Duration::from_millis(105));
+        assert!(samples.iter().any(|s| *s < Duration::from_millis(90)));
+    }
+
+    #[tokio::test]
+    async fn recv_waits_for_delivery() {
+        let network = EmulatedNetwork::new(0);
+        let conditions = LinkConditions {
+            latency: Latency::Fixed(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let a = network.bind(addr(1), conditions);
+        let b = network.bind(addr(2), LinkConditions::default());
+        let sent_at = Instant::now();
+        a.send_to(&[1], addr(2)).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (&[1][..], addr(1)));
+        assert!(sent_at.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn recv_waits_for_mock_time() {
+        let network = mock_network(0);
+        let conditions = LinkConditions {
+            latency: Latency::Fixed(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let a = network.bind(addr(1), conditions);
+        let b = network.bind(addr(2), LinkConditions::default());
+        a.send_to(&[1], addr(2)).await.unwrap();
+
+        let recv = tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (len, from) = b.recv_from(&mut buf).await.unwrap();
+            (buf[..len].to_vec(), from)
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!recv.is_finished());
+        network.advance_time(Duration::from_secs(60));
+        let received = tokio::time::timeout(Duration::from_secs(1), recv).await;
+        assert_eq!(received.unwrap().unwrap(), (vec![1], addr(1)));
+    }
+
+    #[tokio::test]
+    async fn bound_through_network_only() {
+        let bound = <EmulatedSocket as Socket>::bind(addr(1)).await;
+        assert_eq!(
+            bound.err().map(|err| err.kind()),
+            Some(std::io::ErrorKind::Unsupported)
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::util::time_source::{InstantTimeSrc, TimeSource};
+
+/// Window over which the rate of intro packets is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Intro packets per second above which remotes have to echo a cookie before their intro
+/// packet is decrypted, unless configured otherwise.
+pub(super) const COOKIE_THRESHOLD: u32 = 100;
+
+/// Intro packets per second above which remotes also have to solve a proof of work puzzle,
+/// unless configured otherwise.
+pub(super) const POW_THRESHOLD: u32 = 1000;
+
+/// Leading zero bits required once `POW_THRESHOLD` is crossed, one more bit is required each
+/// time the rate doubles.
+const MIN_POW_DIFFICULTY: u8 = 8;
+
+/// Highest difficulty a gateway asks for and a peer agrees to solve.
+pub(super) const MAX_POW_DIFFICULTY: u8 = 20;
+
+/// Retries a peer answers per connection attempt. Retries are not authenticated, so this
+/// bounds the work anyone spoofing them can make a connecting peer do.
+pub(super) const MAX_ANSWERED_RETRIES: usize = 3;
+
+/// An answered intro packet is admitted again at most this often. The remote resends it until
+/// it gets an answer, but a replayed one shouldn't cost a decryption each time.
+const REANSWER_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Cookies are valid for the time bucket they were issued in and the next one.
+const COOKIE_LIFETIME: Duration = Duration::from_secs(30);
+
+const COOKIE_SIZE: usize = 16;
+const RETRY_PREFIX: [u8; 4] = *b"FNRT";
+const GATED_INTRO_PREFIX: [u8; 4] = *b"FNCK";
+const RETRY_SIZE: usize = RETRY_PREFIX.len() + COOKIE_SIZE + 1;
+const GATED_INTRO_HEADER_SIZE: usize = GATED_INTRO_PREFIX.len() + COOKIE_SIZE + 1 + 8;
+
+type Cookie = [u8; COOKIE_SIZE];
+
+/// Protects a gateway from floods of intro packets, each of which otherwise costs an
+/// asymmetric decryption.
+///
+/// While the intro packet rate is low every intro packet is admitted. Above `COOKIE_THRESHOLD`
+/// unknown remotes get a retry packet with a cookie instead, bound to their address, and must
+/// send the intro packet again prefixed with it, so spoofed source addresses can't get
+/// through. Above `POW_THRESHOLD` the cookie also carries a difficulty and the remote must
+/// find a nonce whose hash with the cookie and the intro packet has that many leading zero
+/// bits. No state is kept per remote, cookies are verified by recomputing them, but answered
+/// intro packets are remembered while their cookie is valid, so resending one is answered
+/// again while replaying a solved proof of work is rate limited.
+pub(super) struct IntroGate<T: TimeSource = InstantTimeSrc> {
+    secret: [u8; 32],
+    started: Instant,
+    window_start: Instant,
+    window_intros: u32,
+    /// `None` while the gate is open, otherwise the proof of work difficulty required, with 0
+    /// meaning only a cookie is required
+    required: Option<u8>,
+    cookie_threshold: u32,
+    pow_threshold: u32,
+    /// When the answered intro packets were last admitted, by their proof of work hash and the
+    /// time bucket their cookie was issued in
+    admitted: BTreeMap<u64, HashMap<[u8; 32], Instant>>,
+    time_source: T,
+}
+
+/// What to do with a packet from an unknown remote.
+pub(super) enum Admission<'a> {
+    /// Decrypt the intro packet
+    Accept(&'a [u8]),
+    /// Send this retry packet back to the remote
+    Retry(Bytes),
+    Drop,
+}
+
+impl IntroGate<InstantTimeSrc> {
+    pub(super) fn new() -> Self {
+        Self::with_thresholds(COOKIE_THRESHOLD, POW_THRESHOLD)
+    }
+
+    pub(super) fn with_thresholds(cookie_threshold: u32, pow_threshold: u32) -> Self {
+        Self::with_time_source(cookie_threshold, pow_threshold, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> IntroGate<T> {
+    fn with_time_source(cookie_threshold: u32, pow_threshold: u32, time_source: T) -> Self {
+        let now = time_source.now();
+        IntroGate {
+            secret: rand::random(),
+            started: now,
+            window_start: now,
+            window_intros: 0,
+            required: None,
+            cookie_threshold,
+            pow_threshold,
+            admitted: BTreeMap::new(),
+            time_source,
+        }
+    }
+
+    pub(super) fn admit<'a>(&mut self, packet: &'a [u8], remote_addr: SocketAddr) -> Admission<'a> {
+        let now = self.time_source.now();
+        self.record_intro(now);
+
+        if let Some(gated) = GatedIntro::parse(packet) {
+            if let Some(bucket) = self.verify_cookie(&gated, remote_addr, now) {
+                let pow = proof_of_work(&gated.cookie, &blake3::hash(gated.intro), gated.nonce);
+                if !has_leading_zeros(&pow, gated.difficulty) {
+                    tracing::debug!(%remote_addr, "invalid proof of work in intro packet");
+                    return Admission::Drop;
+                }
+                if !self.admit_answer(bucket, pow, now) {
+                    tracing::debug!(%remote_addr, "intro packet answered recently");
+                    return Admission::Drop;
+                }
+                return Admission::Accept(gated.intro);
+            }
+            // the cookie expired, ask for a new one if it is still needed
+            return match self.required {
+                None => Admission::Accept(gated.intro),
+                Some(difficulty) => Admission::Retry(self.retry(remote_addr, difficulty, now)),
+            };
+        }
+
+        match self.required {
+            None => Admission::Accept(packet),
+            Some(difficulty) => Admission::Retry(self.retry(remote_addr, difficulty, now)),
+        }
+    }
+
+    fn record_intro(&mut self, now: Instant) {
+        let elapsed = now - self.window_start;
+        if elapsed >= RATE_WINDOW {
+            let rate = (self.window_intros as f64 / elapsed.as_secs_f64()) as u32;
+            self.required = self.difficulty_for(rate);
+            self.window_start = now;
+            self.window_intros = 0;
+        }
+        self.window_intros += 1;
+        // close the gate as soon as the threshold is crossed instead of waiting for the window
+        // to finish, it only opens again once a whole window stays under the threshold
+        let current = self.difficulty_for(self.window_intros);
+        if current > self.required {
+            tracing::debug!(difficulty = ?current, "intro packet rate increased, closing the gate");
+            self.required = current;
+        }
+    }
+
+    fn difficulty_for(&self, rate: u32) -> Option<u8> {
+        if rate < self.cookie_threshold {
+            return None;
+        }
+        if rate < self.pow_threshold {
+            return Some(0);
+        }
+        let doublings = (rate / self.pow_threshold.max(1))
+            .checked_ilog2()
+            .unwrap_or(0) as u8;
+        Some(
+            MIN_POW_DIFFICULTY
+                .saturating_add(doublings)
+                .min(MAX_POW_DIFFICULTY),
+        )
+    }
+
+    /// Returns the time bucket the cookie was issued in, if it is valid.
+    fn verify_cookie(
+        &self,
+        gated: &GatedIntro,
+        remote_addr: SocketAddr,
+        now: Instant,
+    ) -> Option<u64> {
+        let bucket = self.bucket(now);
+        [Some(bucket), bucket.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .find(|bucket| self.cookie(remote_addr, *bucket, gated.difficulty) == gated.cookie)
+    }
+
+    /// Whether the answered intro packet with the given proof of work hash wasn't admitted
+    /// within `REANSWER_INTERVAL`, hashes are forgotten once their cookie expires.
+    fn admit_answer(&mut self, bucket: u64, pow: [u8; 32], now: Instant) -> bool {
+        let oldest_valid = self.bucket(now).saturating_sub(1);
+        self.admitted.retain(|bucket, _| *bucket >= oldest_valid);
+        let admitted = self.admitted.entry(bucket).or_default();
+        if admitted
+            .get(&pow)
+            .is_some_and(|admitted_at| now - *admitted_at < REANSWER_INTERVAL)
+        {
+            return false;
+        }
+        admitted.insert(pow, now);
+        true
+    }
+
+    fn retry(&self, remote_addr: SocketAddr, difficulty: u8, now: Instant) -> Bytes {
+        Retry {
+            cookie: self.cookie(remote_addr, self.bucket(now), difficulty),
+            difficulty,
+        }
+        .serialize()
+    }
+
+    fn bucket(&self, now: Instant) -> u64 {
+        (now - self.started).as_secs() / COOKIE_LIFETIME.as_secs()
+    }
+
+    fn cookie(&self, remote_addr: SocketAddr, bucket: u64, difficulty: u8) -> Cookie {
+        let mut hasher = blake3::Hasher::new_keyed(&self.secret);
+        match remote_addr.ip() {
+            IpAddr::V4(ip) => hasher.update(&ip.octets()),
+            IpAddr::V6(ip) => hasher.update(&ip.octets()),
+        };
+        hasher.update(&remote_addr.port().to_le_bytes());
+        hasher.update(&bucket.to_le_bytes());
+        hasher.update(&[difficulty]);
+        let mut cookie = [0; COOKIE_SIZE];
+        cookie.copy_from_slice(&hasher.finalize().as_bytes()[..COOKIE_SIZE]);
+        cookie
+    }
+}
+
+/// Sent by a gateway in reply to an intro packet while its gate is closed:
+/// `prefix | cookie | difficulty`.
+#[derive(Clone, PartialEq, Eq)]
+pub(super) struct Retry {
+    cookie: Cookie,
+    pub difficulty: u8,
+}
+
+impl Retry {
+    pub(super) fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() != RETRY_SIZE || packet[..RETRY_PREFIX.len()] != RETRY_PREFIX {
+            return None;
+        }
+        let cookie = packet[RETRY_PREFIX.len()..RETRY_SIZE - 1].try_into().ok()?;
+        Some(Retry {
+            cookie,
+            difficulty: packet[RETRY_SIZE - 1],
+        })
+    }
+
+    fn serialize(&self) -> Bytes {
+        let mut packet = Vec::with_capacity(RETRY_SIZE);
+        packet.extend_from_slice(&RETRY_PREFIX);
+        packet.extend_from_slice(&self.cookie);
+        packet.push(self.difficulty);
+        packet.into()
+    }
+
+    /// Solves the proof of work and prefixes the intro packet with the cookie and the nonce.
+    /// Can take a while for high difficulties, so it should not run on the async runtime.
+    pub(super) fn answer(&self, intro: &[u8]) -> Vec<u8> {
+        let intro_hash = blake3::hash(intro);
+        let nonce = (0..)
+            .find(|nonce| {
+                has_leading_zeros(
+                    &proof_of_work(&self.cookie, &intro_hash, *nonce),
+                    self.difficulty,
+                )
+            })
+            .expect("a nonce is found long before exhausting the range");
+        let mut packet = Vec::with_capacity(GATED_INTRO_HEADER_SIZE + intro.len());
+        packet.extend_from_slice(&GATED_INTRO_PREFIX);
+        packet.extend_from_slice(&self.cookie);
+        packet.push(self.difficulty);
+        packet.extend_from_slice(&nonce.to_le_bytes());
+        packet.extend_from_slice(intro);
+        packet
+    }
+}
+
+/// An intro packet answering a retry: `prefix | cookie | difficulty | nonce | intro packet`.
+struct GatedIntro<'a> {
+    cookie: Cookie,
+    difficulty: u8,
+    nonce: u64,
+    intro: &'a [u8],
+}
+
+impl<'a> GatedIntro<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() <= GATED_INTRO_HEADER_SIZE
+            || packet[..GATED_INTRO_PREFIX.len()] != GATED_INTRO_PREFIX
+        {
+            return None;
+        }
+        let (cookie, rest) = packet[GATED_INTRO_PREFIX.len()..].split_at(COOKIE_SIZE);
+        let (difficulty, rest) = rest.split_at(1);
+        let (nonce, intro) = rest.split_at(8);
+        Some(GatedIntro {
+            cookie: cookie.try_into().ok()?,
+            difficulty: difficulty[0],
+            nonce: u64::from_le_bytes(nonce.try_into().ok()?),
+            intro,
+        })
+    }
+}
+
+/// Hash which must have leading zero bits, it covers the intro packet so a solved proof of
+/// work can't be reused for another intro packet.
+fn proof_of_work(cookie: &Cookie, intro_hash: &blake3::Hash, nonce: u64) -> [u8; 32] {
+    *blake3::Hasher::new()
+        .update(cookie)
+        .update(intro_hash.as_bytes())
+        .update(&nonce.to_le_bytes())
+        .finalize()
+        .as_bytes()
+}
+
+fn has_leading_zeros(hash: &[u8; 32], difficulty: u8) -> bool {
+    let leading = u128::from_be_bytes(hash[..16].try_into().unwrap());
+    leading.leading_zeros() >= difficulty as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::time_source::MockTimeSource;
+    use std::net::Ipv4Addr;
+
+    const INTRO: &[u8] = &[7; 256];
+
+    fn mock_gate(cookie_threshold: u32, pow_threshold: u32) -> IntroGate<MockTimeSource> {
+        IntroGate::with_time_source(
+            cookie_threshold,
+            pow_threshold,
+            MockTimeSource::new(Instant::now()),
+        )
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    fn expect_retry(admission: Admission) -> Retry {
+        match admission {
+            Admission::Retry(packet) => Retry::parse(&packet).expect("valid retry packet"),
+            _ => panic!("expected a retry"),
+        }
+    }
+
+    #[test]
+    fn open_below_threshold() {
+        let mut gate = mock_gate(10, 100);
+        for _ in 0..9 {
+            assert!(matches!(gate.admit(INTRO, addr(1)), Admission::Accept(p) if p == INTRO));
+        }
+        let retry = expect_retry(gate.admit(INTRO, addr(1)));
+        assert_eq!(retry.difficulty, 0);
+
+        // a quiet window opens the gate again
+        gate.time_source.advance_time(RATE_WINDOW);
+        gate.admit(INTRO, addr(1));
+        gate.time_source.advance_time(RATE_WINDOW);
+        assert!(matches!(gate.admit(INTRO, addr(1)), Admission::Accept(_)));
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let mut gate = mock_gate(0, 100);
+        let retry = expect_retry(gate.admit(INTRO, addr(1)));
+        let answer = retry.answer(INTRO);
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Accept(p) if p == INTRO));
+
+        // the cookie is bound to the address it was sent to
+        expect_retry(gate.admit(&answer, addr(2)));
+    }
+
+    #[test]
+    fn cookie_expires() {
+        let mut gate = mock_gate(0, 100);
+        let answer = expect_retry(gate.admit(INTRO, addr(1))).answer(INTRO);
+        gate.time_source.advance_time(COOKIE_LIFETIME);
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Accept(_)));
+        gate.time_source.advance_time(COOKIE_LIFETIME);
+        expect_retry(gate.admit(&answer, addr(1)));
+    }
+
+    fn has_proof_of_work(cookie: &Cookie, intro: &[u8], nonce: u64, difficulty: u8) -> bool {
+        has_leading_zeros(
+            &proof_of_work(cookie, &blake3::hash(intro), nonce),
+            difficulty,
+        )
+    }
+
+    #[test]
+    fn proof_of_work_required() {
+        let mut gate = mock_gate(0, 0);
+        let retry = expect_retry(gate.admit(INTRO, addr(1)));
+        assert!(retry.difficulty >= MIN_POW_DIFFICULTY);
+
+        let answer = retry.answer(INTRO);
+        let gated = GatedIntro::parse(&answer).unwrap();
+        assert!(has_proof_of_work(
+            &gated.cookie,
+            INTRO,
+            gated.nonce,
+            retry.difficulty
+        ));
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Accept(_)));
+
+        // the difficulty can't be lowered since it is part of the cookie
+        let cheated = Retry {
+            difficulty: 0,
+            ..retry
+        }
+        .answer(INTRO);
+        expect_retry(gate.admit(&cheated, addr(1)));
+    }
+
+    #[test]
+    fn invalid_nonce_dropped() {
+        let mut gate = mock_gate(0, 0);
+        let retry = expect_retry(gate.admit(INTRO, addr(1)));
+        let mut answer = retry.answer(INTRO);
+        let nonce_start = GATED_INTRO_HEADER_SIZE - 8;
+        let nonce = (0u64..)
+            .find(|nonce| !has_proof_of_work(&retry.cookie, INTRO, *nonce, retry.difficulty))
+            .unwrap();
+        answer[nonce_start..GATED_INTRO_HEADER_SIZE].copy_from_slice(&nonce.to_le_bytes());
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Drop));
+    }
+
+    #[test]
+    fn proof_of_work_bound_to_intro() {
+        let mut gate = mock_gate(0, 0);
+        let retry = expect_retry(gate.admit(INTRO, addr(1)));
+        let answer = retry.answer(INTRO);
+        let nonce = GatedIntro::parse(&answer).unwrap().nonce;
+        // another intro packet for which the nonce found is not a valid proof of work
+        let other_intro = (0..=u8::MAX)
+            .map(|byte| [byte; 256])
+            .find(|intro| !has_proof_of_work(&retry.cookie, intro, nonce, retry.difficulty))
+            .unwrap();
+        let mut forged = answer[..GATED_INTRO_HEADER_SIZE].to_vec();
+        forged.extend_from_slice(&other_intro);
+        assert!(matches!(gate.admit(&forged, addr(1)), Admission::Drop));
+    }
+
+    #[test]
+    fn resent_answer_admitted_again() {
+        let mut gate = mock_gate(0, 0);
+        let answer = expect_retry(gate.admit(INTRO, addr(1))).answer(INTRO);
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Accept(_)));
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Drop));
+
+        // the remote resends it if the answer got lost
+        gate.time_source.advance_time(REANSWER_INTERVAL);
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Accept(_)));
+        assert!(matches!(gate.admit(&answer, addr(1)), Admission::Drop));
+
+        // replays are forgotten along with the cookies which expired
+        gate.time_source.advance_time(COOKIE_LIFETIME * 2);
+        expect_retry(gate.admit(&answer, addr(1)));
+    }
+
+    #[test]
+    fn difficulty_scales_with_load() {
+        let gate = mock_gate(100, 1000);
+        assert_eq!(gate.difficulty_for(99), None);
+        assert_eq!(gate.difficulty_for(999), Some(0));
+        assert_eq!(gate.difficulty_for(1000), Some(MIN_POW_DIFFICULTY));
+        assert_eq!(gate.difficulty_for(4000), Some(MIN_POW_DIFFICULTY + 2));
+        assert_eq!(gate.difficulty_for(u32::MAX), Some(MAX_POW_DIFFICULTY));
+    }
+}
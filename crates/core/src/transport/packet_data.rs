@@ -1,15 +1,18 @@
 use std::marker::PhantomData;
-use std::{cell::RefCell, sync::Arc};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use aes_gcm::{
-    aead::{generic_array::GenericArray, rand_core::SeedableRng, AeadInPlace},
-    Aes128Gcm,
+    aead::{generic_array::GenericArray, AeadInPlace},
+    Aes128Gcm, KeyInit,
 };
-use rand::{prelude::SmallRng, thread_rng, Rng};
+use bytes::Bytes;
+use once_cell::sync::Lazy;
 
 use crate::transport::crypto::TransportPublicKey;
 
 use super::crypto::TransportSecretKey;
+use super::packet_pool;
 use super::TransportError;
 
 /// The maximum size of a received UDP packet, MTU typically is 1500
@@ -23,11 +26,66 @@ const TAG_SIZE: usize = 16;
 pub(super) const MAX_DATA_SIZE: usize = MAX_PACKET_SIZE - NONCE_SIZE - TAG_SIZE;
 const UDP_HEADER_SIZE: usize = 8;
 
-thread_local! {
-    // This must be very fast, but doesn't need to be cryptographically secure.
-    static RNG: RefCell<SmallRng> = RefCell::new(
-        SmallRng::from_rng(thread_rng()).expect("failed to create RNG")
-    );
+/// Packet size every path is assumed to support, path MTU discovery never goes below it.
+pub(in crate::transport) const MIN_PACKET_SIZE: usize = 1200;
+
+/// Space left for the data of a packet of the given size once encrypted.
+pub(super) const fn data_size(packet_size: usize) -> usize {
+    packet_size - NONCE_SIZE - TAG_SIZE
+}
+
+/// Size of the key hint at the start of the nonce of symmetrically encrypted packets.
+const KEY_HINT_SIZE: usize = 4;
+
+/// Identifies the key a packet is encrypted with without decrypting it, so the connection a
+/// packet arriving from an unknown address belongs to can be found cheaply.
+pub(super) type KeyHint = [u8; KEY_HINT_SIZE];
+
+/// Nonce the key hint is derived from. Packets are never encrypted with it since the counter
+/// in their nonces never reaches `u64::MAX`.
+const KEY_HINT_NONCE: [u8; NONCE_SIZE] = [0xff; NONCE_SIZE];
+
+/// Makes up the nonce of symmetrically encrypted packets after the key hint. It is shared by
+/// every key, so a nonce is never used twice with the same key, and starts at a random value
+/// below 2^63 so it never reaches `u64::MAX`.
+static NONCE_COUNTER: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(rand::random::<u64>() >> 1));
+
+/// Hint included in the nonce of every packet encrypted with the given key.
+fn key_hint(cipher: &Aes128Gcm) -> KeyHint {
+    let tag = cipher
+        .encrypt_in_place_detached(&KEY_HINT_NONCE.into(), b"key hint", &mut [])
+        .unwrap();
+    let mut hint = [0; KEY_HINT_SIZE];
+    hint.copy_from_slice(&tag[..KEY_HINT_SIZE]);
+    hint
+}
+
+/// Symmetric key of a connection along with its hint, which is derived once when the key is
+/// created instead of for every packet.
+#[derive(Clone)]
+pub(super) struct SymmetricKey {
+    cipher: Aes128Gcm,
+    hint: KeyHint,
+}
+
+impl SymmetricKey {
+    pub fn new(key: [u8; 16]) -> Self {
+        let cipher = Aes128Gcm::new(&key.into());
+        let hint = key_hint(&cipher);
+        Self { cipher, hint }
+    }
+
+    pub fn hint(&self) -> KeyHint {
+        self.hint
+    }
+}
+
+impl Deref for SymmetricKey {
+    type Target = Aes128Gcm;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cipher
+    }
 }
 
 struct AssertSize<const N: usize>;
@@ -118,8 +176,13 @@ impl<const N: usize> PacketData<SymmetricAES, N> {
         })
     }
 
-    pub fn prepared_send(self) -> Arc<[u8]> {
-        self.data[..self.size].into()
+    pub fn prepared_send(self) -> Bytes {
+        packet_pool::packet(&self.data[..self.size])
+    }
+
+    /// Like [`Self::prepared_send`], for packets kept until the remote acknowledges them.
+    pub fn prepared_send_retained(self) -> Bytes {
+        packet_pool::retained_packet(&self.data[..self.size])
     }
 }
 
@@ -137,8 +200,8 @@ impl<const N: usize> PacketData<AssymetricRSA, N> {
         }
     }
 
-    pub fn preparef_send(self) -> Arc<[u8]> {
-        self.data[..self.size].into()
+    pub fn preparef_send(self) -> Bytes {
+        packet_pool::packet(&self.data[..self.size])
     }
 }
 
@@ -155,11 +218,14 @@ impl<const N: usize> PacketData<Plaintext, N> {
         }
     }
 
-    pub(super) fn encrypt_symmetric(&self, cipher: &Aes128Gcm) -> PacketData<SymmetricAES, N> {
+    pub(super) fn encrypt_symmetric(&self, cipher: &SymmetricKey) -> PacketData<SymmetricAES, N> {
         _check_valid_size::<N>();
         debug_assert!(self.size <= MAX_DATA_SIZE);
 
-        let nonce: [u8; NONCE_SIZE] = RNG.with(|rng| rng.borrow_mut().gen());
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..KEY_HINT_SIZE].copy_from_slice(&cipher.hint());
+        nonce[KEY_HINT_SIZE..]
+            .copy_from_slice(&NONCE_COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
 
         let mut buffer = [0u8; N];
         buffer[..NONCE_SIZE].copy_from_slice(&nonce);
@@ -208,6 +274,16 @@ impl<const N: usize> PacketData<UnknownEncryption, N> {
             && self.data[..self.size] == actual_intro_packet.data[..actual_intro_packet.size]
     }
 
+    /// Key hint of the packet, assuming it is symmetrically encrypted.
+    pub(super) fn key_hint(&self) -> Option<KeyHint> {
+        if self.size < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+        let mut hint = [0; KEY_HINT_SIZE];
+        hint.copy_from_slice(&self.data[..KEY_HINT_SIZE]);
+        Some(hint)
+    }
+
     pub(super) fn try_decrypt_sym(
         &self,
         inbound_sym_key: &Aes128Gcm,
@@ -230,7 +306,7 @@ impl<const N: usize> PacketData<UnknownEncryption, N> {
             let mut data = [0; N];
             data[..decrypted.len()].copy_from_slice(&decrypted[..]);
             PacketData {
-                size: data.len(),
+                size: decrypted.len(),
                 data,
                 data_type: PhantomData,
             }
@@ -259,7 +335,6 @@ impl<DT: Encryption, const N: usize> PartialEq for PacketData<DT, N> {
 mod tests {
     use super::*;
     use aes_gcm::aead::rand_core::RngCore;
-    use aes_gcm::KeyInit;
     use rand::rngs::OsRng;
 
     #[test]
@@ -268,11 +343,8 @@ mod tests {
         let mut key = [0u8; 16];
         OsRng.fill_bytes(&mut key);
 
-        // Create a key object for AES-GCM
-        let key = GenericArray::from_slice(&key);
-
         // Create a new AES-128-GCM instance
-        let cipher = Aes128Gcm::new(key);
+        let cipher = SymmetricKey::new(key);
         let data = b"Hello, world!";
         let unencrypted_packet = PacketData::<_, 1000>::from_buf_plain(data);
         let encrypted_packet = unencrypted_packet.encrypt_symmetric(&cipher);
@@ -282,6 +354,23 @@ mod tests {
         test_decryption(encrypted_packet, &cipher, unencrypted_packet);
     }
 
+    #[test]
+    fn key_hint_identifies_key() {
+        let cipher = SymmetricKey::new([1; 16]);
+        let other = SymmetricKey::new([2; 16]);
+        let packet = PacketData::<_, 1000>::from_buf_plain(b"Hello, world!");
+        let first = PacketData::<_, 1000>::from_buf(packet.encrypt_symmetric(&cipher).data());
+        let second = PacketData::<_, 1000>::from_buf(packet.encrypt_symmetric(&cipher).data());
+
+        assert_eq!(first.key_hint(), Some(cipher.hint()));
+        assert_eq!(second.key_hint(), Some(cipher.hint()));
+        assert_eq!(cipher.hint(), key_hint(&cipher));
+        assert_ne!(cipher.hint(), other.hint());
+        // the rest of the nonce is never repeated
+        assert_ne!(first.data()[..NONCE_SIZE], second.data()[..NONCE_SIZE]);
+        assert!(first.try_decrypt_sym(&cipher).is_ok());
+    }
+
     // Test detection of packet corruption
     #[test]
     fn test_encryption_decryption_corrupted() {
@@ -289,11 +378,8 @@ mod tests {
         let mut key = [0u8; 16];
         OsRng.fill_bytes(&mut key);
 
-        // Create a key object for AES-GCM
-        let key = GenericArray::from_slice(&key);
-
         // Create a new AES-128-GCM instance
-        let cipher = Aes128Gcm::new(key);
+        let cipher = SymmetricKey::new(key);
         let data = b"Hello, world!";
         let unencrypted_packet = PacketData::<_, 1000>::from_buf_plain(data);
         let mut encrypted_packet = unencrypted_packet.encrypt_symmetric(&cipher);
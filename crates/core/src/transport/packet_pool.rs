@@ -0,0 +1,134 @@
+//! Buffers for the packets going through the transport hot path.
+//!
+//! Outbound packets are carved out of a per-thread arena instead of being allocated one by
+//! one. Every packet keeps its chunk alive until it's dropped, once all the packets of a chunk
+//! are sent the chunk is reused for new packets. Packets kept around until acknowledged get a
+//! buffer of their own instead, otherwise a single unacknowledged packet would pin its chunk.
+//!
+//! Inbound packets are received in batches into a set of buffers owned by the listener.
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+
+use bytes::{Bytes, BytesMut};
+
+use super::packet_data::MAX_PACKET_SIZE;
+use super::relay::RELAY_HEADER_SIZE;
+
+/// Largest number of packets sent or received with a single syscall.
+pub(crate) const MAX_BATCH_SIZE: usize = 32;
+
+/// Room for this many packets of the largest size in each arena chunk.
+const PACKETS_PER_CHUNK: usize = 16;
+
+/// Size of the receive buffers, relayed packets carry a header on top of the largest packet.
+const RECV_BUFFER_SIZE: usize = MAX_PACKET_SIZE + RELAY_HEADER_SIZE;
+
+thread_local! {
+    static ARENA: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
+/// Copies an outbound packet into the arena of the current thread.
+pub(super) fn packet(data: &[u8]) -> Bytes {
+    ARENA.with(|arena| {
+        let mut arena = arena.borrow_mut();
+        if arena.capacity() < data.len() {
+            // reclaims the current chunk if all its packets were dropped, otherwise
+            // allocates a new one
+            arena.reserve(PACKETS_PER_CHUNK * MAX_PACKET_SIZE);
+        }
+        arena.extend_from_slice(data);
+        arena.split().freeze()
+    })
+}
+
+/// Copies an outbound packet which is kept until acknowledged into a buffer of its own.
+pub(super) fn retained_packet(data: &[u8]) -> Bytes {
+    Bytes::copy_from_slice(data)
+}
+
+/// Buffers for a batch of inbound packets, reused for every batch.
+pub(crate) struct RecvBatch {
+    pub(super) buffers: Vec<Box<[u8]>>,
+    /// Size and source of each packet received in the last batch.
+    pub(super) received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    pub(super) fn new(capacity: usize) -> Self {
+        RecvBatch {
+            buffers: (0..capacity)
+                .map(|_| vec![0; RECV_BUFFER_SIZE].into_boxed_slice())
+                .collect(),
+            received: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// The packets received in the last batch, along with their source.
+    pub(super) fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .zip(&self.buffers)
+            .map(|(&(size, addr), buffer)| (&buffer[..size], addr))
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.received.clear();
+    }
+}
+
+impl Default for RecvBatch {
+    fn default() -> Self {
+        Self::new(MAX_BATCH_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_share_chunks() {
+        let first = packet(&[1; 100]);
+        let second = packet(&[2; 100]);
+        assert_eq!(&first[..], &[1; 100]);
+        assert_eq!(&second[..], &[2; 100]);
+        // both were carved out of the same chunk
+        assert_eq!(first.as_ptr().wrapping_add(100), second.as_ptr());
+    }
+
+    #[test]
+    fn chunk_is_reused_once_packets_are_dropped() {
+        let chunk_size = PACKETS_PER_CHUNK * MAX_PACKET_SIZE;
+        let fill = |start: &Bytes| {
+            // fill the rest of the chunk so the next packet needs a new one
+            let mut packets = vec![];
+            let mut used = start.len();
+            while used + MAX_PACKET_SIZE <= chunk_size {
+                packets.push(packet(&[0; MAX_PACKET_SIZE]));
+                used += MAX_PACKET_SIZE;
+            }
+            packets
+        };
+        // start from a fresh chunk
+        let first = packet(&[0; MAX_PACKET_SIZE * PACKETS_PER_CHUNK]);
+        let first_ptr = first.as_ptr();
+        drop(first);
+        let first = packet(&[1; MAX_PACKET_SIZE]);
+        assert_eq!(first.as_ptr(), first_ptr);
+        let rest = fill(&first);
+        drop((first, rest));
+        let reused = packet(&[2; MAX_PACKET_SIZE]);
+        assert_eq!(reused.as_ptr(), first_ptr);
+    }
+
+    #[test]
+    fn retained_packets_outside_chunks() {
+        let first = packet(&[1; 100]);
+        let retained = retained_packet(&[2; 100]);
+        let second = packet(&[3; 100]);
+        assert_eq!(&retained[..], &[2; 100]);
+        // the retained packet took no room in the chunk
+        assert_eq!(first.as_ptr().wrapping_add(100), second.as_ptr());
+    }
+}
@@ -1,28 +1,30 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::pin::Pin;
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crate::transport::packet_data::UnknownEncryption;
-use aes_gcm::Aes128Gcm;
-use futures::stream::FuturesUnordered;
-use futures::{Future, StreamExt};
+use bytes::Bytes;
+use futures::future::{Fuse, FutureExt};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
+mod fec;
 mod inbound_stream;
 mod outbound_stream;
+mod path_mtu;
+mod rekey;
 
 use super::{
     connection_handler::SerializedMessage,
-    packet_data::{self, PacketData},
+    packet_data::{self, PacketData, SymmetricKey},
+    packet_pool,
     received_packet_tracker::ReceivedPacketTracker,
     received_packet_tracker::ReportResult,
-    sent_packet_tracker::{ResendAction, SentPacketTracker},
-    symmetric_message::{self, SymmetricMessage, SymmetricMessagePayload},
+    sent_packet_tracker::{ResendAction, SentPacketTracker, MAX_CONFIRMATION_DELAY},
+    symmetric_message::{self, ReceiptRange, SymmetricMessage, SymmetricMessagePayload},
     TransportError,
 };
 use crate::util::time_source::InstantTimeSrc;
@@ -30,21 +32,44 @@ use crate::util::time_source::InstantTimeSrc;
 type Result<T = (), E = TransportError> = std::result::Result<T, E>;
 
 // TODO: measure the space overhead of SymmetricMessage::ShortMessage since is likely less than 100
-/// The max payload we can send in a single fragment, this MUST be less than packet_data::MAX_DATA_SIZE
-/// since we need to account for the space overhead of SymmetricMessage::LongMessage metadata
-const MAX_DATA_SIZE: usize = packet_data::MAX_DATA_SIZE - 100;
+/// Space reserved for the SymmetricMessage::LongMessage metadata in each fragment
+const FRAGMENT_OVERHEAD: usize = 100;
+
+/// The max payload we can send in a single fragment of a packet of the given size
+const fn max_data_size(packet_size: usize) -> usize {
+    packet_data::data_size(packet_size) - FRAGMENT_OVERHEAD
+}
+
+/// Outbound key of a connection, shared with its outbound streams so they switch to the new
+/// key as soon as the connection is rekeyed.
+pub(super) type OutboundKey = Arc<parking_lot::RwLock<SymmetricKey>>;
+
+/// Inbound key of a connection, shared with the packets listener so it can recognize packets
+/// of this connection arriving from a new address of the remote.
+pub(super) type InboundKey = Arc<parking_lot::RwLock<SymmetricKey>>;
 
 #[must_use]
 pub(super) struct RemoteConnection {
-    pub outbound_packets: mpsc::Sender<(SocketAddr, Arc<[u8]>)>,
-    pub outbound_symmetric_key: Aes128Gcm,
+    pub outbound_packets: mpsc::Sender<(SocketAddr, Bytes)>,
+    pub outbound_symmetric_key: OutboundKey,
     pub remote_addr: SocketAddr,
     pub sent_tracker: Arc<parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>>,
     pub last_packet_id: Arc<AtomicU32>,
     pub inbound_packet_recv: mpsc::Receiver<PacketData<UnknownEncryption>>,
-    pub inbound_symmetric_key: Aes128Gcm,
+    pub inbound_symmetric_key: InboundKey,
     pub inbound_symmetric_key_bytes: [u8; 16],
     pub my_address: Option<SocketAddr>,
+    /// Whether the remote negotiated protocol version 2 or later, older peers can't decode
+    /// rekey or path validation messages
+    pub protoc_v2: bool,
+    /// Whether the remote can rebuild lost stream fragments from parity fragments
+    pub fec: bool,
+    /// Whether the remote answers keep-alive pings, older peers only get noops
+    pub pings: bool,
+    /// Time without receiving anything from the remote after which the connection is closed
+    pub idle_timeout: Duration,
+    /// New addresses of the remote, once the packets listener validated them
+    pub address_changes: mpsc::Receiver<SocketAddr>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -65,19 +90,45 @@ impl std::fmt::Display for StreamId {
     }
 }
 
-type InboundStreamResult = Result<(StreamId, SerializedMessage), StreamId>;
-type InboundStreamFut = Pin<Box<dyn Future<Output = InboundStreamResult> + Send>>;
+/// Priority of a message sent as a stream. Streams with a higher priority (declared first) are
+/// sent before the rest, streams of the same priority share the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum MessagePriority {
+    /// Messages driving operations, like connect or subscribe replies
+    Control,
+    /// Messages which may carry contract states
+    Transfer,
+}
+
+/// Fragments of a stream the receiver buffers ahead of the first one it is missing, the
+/// sender doesn't send further until the missing ones are acknowledged.
+const STREAM_WINDOW: u32 = 1024;
+
+/// Streams queued for sending which the scheduler didn't pick up yet.
+const MAX_QUEUED_STREAMS: usize = 100;
+
+/// Streams being received at once, fragments of further streams are dropped without
+/// acknowledging them so the remote sends them again later.
+const MAX_INBOUND_STREAMS: usize = 64;
+
+/// Time without receiving anything from the remote after which a connection is closed, unless
+/// configured otherwise.
+pub(crate) const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Max time without receiving anything before the remote is pinged, lower for connections
+/// with a short idle timeout so a few pings are sent before closing them.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
 
 /// The `PeerConnection` struct is responsible for managing the connection with a remote peer.
 /// It provides methods for sending and receiving messages to and from the remote peer.
 ///
 /// The `PeerConnection` struct maintains the state of the connection, including the remote
-/// connection details, trackers for received and sent packets, and futures for inbound and
-/// outbound streams.
+/// connection details, trackers for received and sent packets, the inbound streams being
+/// reassembled and the task sending the outbound streams.
 ///
 /// The `send` method is used to send serialized data to the remote peer. If the data size
 /// exceeds the maximum allowed size, it is sent as a stream; otherwise, it is sent as a
-/// short message.
+/// short message. Streams are sent by priority (see `send_with_priority`).
 ///
 /// The `recv` method is used to receive incoming packets from the remote peer. It listens for
 /// incoming packets or receipts, and resends packets if necessary.
@@ -92,32 +143,75 @@ type InboundStreamFut = Pin<Box<dyn Future<Output = InboundStreamResult> + Send>
 pub(crate) struct PeerConnection {
     remote_conn: RemoteConnection,
     received_tracker: ReceivedPacketTracker<InstantTimeSrc>,
-    inbound_streams: HashMap<StreamId, mpsc::Sender<(u32, Vec<u8>)>>,
-    inbound_stream_futures: FuturesUnordered<JoinHandle<InboundStreamResult>>,
-    outbound_stream_futures: FuturesUnordered<JoinHandle<Result>>,
+    inbound_streams: HashMap<StreamId, inbound_stream::InboundStream>,
+    finished_streams: inbound_stream::FinishedStreams,
+    early_parity: inbound_stream::EarlyParity,
+    outbound_streams: mpsc::Sender<(MessagePriority, outbound_stream::OutboundStream)>,
+    outbound_streams_task: Fuse<JoinHandle<Result>>,
+    rekey_tracker: rekey::RekeyTracker<InstantTimeSrc>,
+    path_mtu: path_mtu::PathMtu<InstantTimeSrc>,
+    /// When anything was last received from the remote, kept across calls to `recv`
+    last_received: std::time::Instant,
+    /// Sequence number of the last ping sent, and when
+    last_ping: Option<(u32, std::time::Instant)>,
 }
 
 impl PeerConnection {
     pub(super) fn new(remote_conn: RemoteConnection) -> Self {
+        let first_packet_id = remote_conn
+            .last_packet_id
+            .load(std::sync::atomic::Ordering::Acquire);
+        let path_mtu = path_mtu::PathMtu::new();
+        let (outbound_streams, queued_streams) = mpsc::channel(MAX_QUEUED_STREAMS);
+        let outbound_streams_task = tokio::spawn(outbound_stream::send_streams(
+            queued_streams,
+            outbound_stream::StreamSender {
+                last_packet_id: remote_conn.last_packet_id.clone(),
+                outbound_packets: remote_conn.outbound_packets.clone(),
+                outbound_symmetric_key: remote_conn.outbound_symmetric_key.clone(),
+                sent_tracker: remote_conn.sent_tracker.clone(),
+                packet_size: path_mtu.shared_packet_size(),
+                fec: remote_conn.fec,
+            },
+        ))
+        .fuse();
         Self {
+            rekey_tracker: rekey::RekeyTracker::new(first_packet_id),
+            path_mtu,
+            last_received: std::time::Instant::now(),
+            last_ping: None,
             remote_conn,
             received_tracker: ReceivedPacketTracker::new(),
             inbound_streams: HashMap::new(),
-            inbound_stream_futures: FuturesUnordered::new(),
-            outbound_stream_futures: FuturesUnordered::new(),
+            finished_streams: inbound_stream::FinishedStreams::default(),
+            early_parity: inbound_stream::EarlyParity::default(),
+            outbound_streams,
+            outbound_streams_task,
         }
     }
 
     pub async fn send<T>(&mut self, data: T) -> Result
+    where
+        T: Serialize + Send + 'static,
+    {
+        self.send_with_priority(data, MessagePriority::Control)
+            .await
+    }
+
+    /// Sends the data, if it has to be streamed it waits for the streams with a higher
+    /// priority to be sent first.
+    pub async fn send_with_priority<T>(&mut self, data: T, priority: MessagePriority) -> Result
     where
         T: Serialize + Send + 'static,
     {
         let data = tokio::task::spawn_blocking(move || bincode::serialize(&data).unwrap())
             .await
             .unwrap();
-        if data.len() + SymmetricMessage::short_message_overhead() > MAX_DATA_SIZE {
+        self.maybe_rekey().await?;
+        let max_data_size = max_data_size(self.path_mtu.packet_size());
+        if data.len() + SymmetricMessage::short_message_overhead() > max_data_size {
             tracing::debug!("sending as stream");
-            self.outbound_stream(data).await;
+            self.outbound_stream(data, priority).await?;
         } else {
             tracing::debug!("sending as short message");
             self.outbound_short_message(data).await?;
@@ -129,27 +223,29 @@ impl PeerConnection {
         // listen for incoming messages or receipts or wait until is time to do anything else again
         let mut resend_check = Some(tokio::time::sleep(tokio::time::Duration::from_secs(1)));
 
-        // #[cfg(debug_assertions)]
-        // const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
-        // #[cfg(not(debug_assertions))]
-        const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
-        // #[cfg(debug_assertions)]
-        // const KILL_CONNECTION_AFTER: Duration = Duration::from_secs(6);
-        // #[cfg(not(debug_assertions))]
-        const KILL_CONNECTION_AFTER: Duration = Duration::from_secs(60);
-
-        let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+        let keep_alive_interval = KEEP_ALIVE_INTERVAL.min(self.remote_conn.idle_timeout / 3);
+        let mut keep_alive = tokio::time::interval(keep_alive_interval);
         keep_alive.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
         keep_alive.tick().await;
-        let mut last_received = std::time::Instant::now();
+
+        let mut receipts_flush = tokio::time::interval(MAX_CONFIRMATION_DELAY);
+        receipts_flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
         loop {
             // tracing::trace!(remote = ?self.remote_conn.remote_addr, "waiting for inbound messages");
             tokio::select! {
                 inbound = self.remote_conn.inbound_packet_recv.recv() => {
                     let packet_data = inbound.ok_or(TransportError::ConnectionClosed(self.remote_addr()))?;
-                    last_received = std::time::Instant::now();
-                    let Ok(decrypted) = packet_data.try_decrypt_sym(&self.remote_conn.inbound_symmetric_key).map_err(|error| {
+                    let packet_size = packet_data.data().len();
+                    self.last_received = std::time::Instant::now();
+                    let decrypted = packet_data
+                        .try_decrypt_sym(&self.remote_conn.inbound_symmetric_key.read())
+                        .or_else(|error| match self.rekey_tracker.previous_inbound_key() {
+                            // sent by the remote before it switched to the current key
+                            Some(previous) => packet_data.try_decrypt_sym(previous),
+                            None => Err(error),
+                        });
+                    let Ok(decrypted) = decrypted.map_err(|error| {
                         tracing::debug!(%error, remote = ?self.remote_conn.remote_addr, "Failed to decrypt packet, might be an intro packet or a partial packet");
                     }) else {
                         // just ignore this message
@@ -173,10 +269,77 @@ impl PeerConnection {
                             "received inbound packet"
                         );
                     }
-                    self.remote_conn
-                        .sent_tracker
-                        .lock()
-                        .report_received_receipts(&confirm_receipt);
+                    let mut receipts = confirm_receipt;
+                    if let SymmetricMessagePayload::ReceiptRanges { ranges } = &payload {
+                        receipts.extend(self.remote_conn.sent_tracker.lock().pending_in(ranges));
+                    }
+                    if !receipts.is_empty() {
+                        let largest_acked = self.remote_conn
+                            .sent_tracker
+                            .lock()
+                            .report_received_receipts(&receipts);
+                        self.path_mtu.packet_acked(largest_acked);
+                        self.path_mtu.receipts_received(&receipts);
+                        // packets overtaken by the acknowledged ones may be resent right away
+                        resend_check = Some(tokio::time::sleep(Duration::ZERO));
+                        // and a new outbound key may be used if its announcement got acknowledged
+                        self.maybe_rekey().await?;
+                    }
+                    // path MTU probes from peers which didn't negotiate version 2 are noops
+                    // padded with receipts, acknowledged like older versions do
+                    let padded_noop = !self.remote_conn.protoc_v2
+                        && matches!(payload, SymmetricMessagePayload::NoOp)
+                        && packet_size > packet_data::MIN_PACKET_SIZE;
+                    if !padded_noop && matches!(
+                        payload,
+                        SymmetricMessagePayload::NoOp | SymmetricMessagePayload::ReceiptRanges { .. }
+                    ) {
+                        // noops only carry receipts or keep the connection alive, they are not
+                        // acknowledged to avoid an endless exchange of receipts
+                        continue;
+                    }
+                    // neither are keep-alive pings, a new ping is sent if the connection is still idle
+                    if let SymmetricMessagePayload::Ping { seq } = payload {
+                        self.send_unacknowledged(SymmetricMessagePayload::Pong { seq }).await?;
+                        continue;
+                    }
+                    if let SymmetricMessagePayload::Pong { seq } = payload {
+                        self.pong(seq);
+                        continue;
+                    }
+                    if let SymmetricMessagePayload::PathChallenge { token } = payload {
+                        // path validation packets are not acknowledged either, the challenge
+                        // is repeated if the response gets lost
+                        self.path_response(token).await?;
+                        continue;
+                    }
+                    // neither are path MTU probes, a lost probe means it was too large
+                    if let SymmetricMessagePayload::PmtuProbe { size, .. } = payload {
+                        self.pmtu_probe_ack(size).await?;
+                        continue;
+                    }
+                    if let SymmetricMessagePayload::PmtuProbeAck { size } = payload {
+                        self.path_mtu.probe_acked(size as usize);
+                        continue;
+                    }
+                    let mut of_finished_stream = false;
+                    if let SymmetricMessagePayload::StreamFragment { stream_id, fragment_number, .. }
+                        | SymmetricMessagePayload::StreamParity { stream_id, first_fragment: fragment_number, .. } = &payload
+                    {
+                        // resent because our receipt got lost, it is only acknowledged again
+                        of_finished_stream = self.finished_streams.contains(stream_id);
+                        let in_window = match self.inbound_streams.get(stream_id) {
+                            Some(stream) => stream.in_window(*fragment_number),
+                            None => of_finished_stream
+                                || (*fragment_number <= STREAM_WINDOW
+                                    && self.inbound_streams.len() < MAX_INBOUND_STREAMS),
+                        };
+                        if !in_window {
+                            // not acknowledged, so the remote sends it again once there is room
+                            tracing::trace!(%stream_id, %fragment_number, "fragment outside the receive window, dropping it");
+                            continue;
+                        }
+                    }
                     match self.received_tracker.report_received_packet(packet_id) {
                         ReportResult::Ok => {}
                         ReportResult::AlreadyReceived => {
@@ -189,6 +352,9 @@ impl PeerConnection {
                             self.noop(receipts).await?;
                         },
                     }
+                    if of_finished_stream {
+                        continue;
+                    }
                     if let Some(msg) = self.process_inbound(payload).await.map_err(|error| {
                         tracing::error!(%error, %packet_id, remote = %self.remote_conn.remote_addr, "error processing inbound packet");
                         error
@@ -197,34 +363,39 @@ impl PeerConnection {
                         return Ok(msg);
                     }
                 }
-                inbound_stream = self.inbound_stream_futures.next(), if !self.inbound_stream_futures.is_empty() => {
-                    let Some(res) = inbound_stream else {
-                        tracing::error!("unexpected no-stream from ongoing_inbound_streams");
-                        continue
-                    };
-                    let Ok((stream_id, msg)) = res.map_err(|e| TransportError::Other(e.into()))? else {
-                        tracing::error!("unexpected error from ongoing_inbound_streams");
-                        // TODO: may leave orphan stream recvs hanging around in this case
-                        continue;
-                    };
-                    self.inbound_streams.remove(&stream_id);
-                    tracing::trace!(%stream_id, "stream finished");
-                    return Ok(msg);
+                Some(remote_addr) = self.remote_conn.address_changes.recv() => {
+                    // ongoing outbound streams keep sending to the previous address, the fragments
+                    // lost because of that are resent to the new one
+                    tracing::info!(previous = %self.remote_conn.remote_addr, %remote_addr, "remote changed address");
+                    self.remote_conn.remote_addr = remote_addr;
                 }
-                outbound_stream = self.outbound_stream_futures.next(), if !self.outbound_stream_futures.is_empty() => {
-                    let Some(res) = outbound_stream else {
-                        tracing::error!("unexpected no-stream from ongoing_outbound_streams");
-                        continue
-                    };
-                    res.map_err(|e| TransportError::Other(e.into()))??
+                outbound_streams = &mut self.outbound_streams_task => {
+                    outbound_streams.map_err(|e| TransportError::Other(e.into()))??;
+                    // the task only stops early if the connection is closed
+                    return Err(TransportError::ConnectionClosed(self.remote_addr()));
+                }
+                _ = receipts_flush.tick() => {
+                    self.maybe_rekey().await?;
+                    self.maybe_probe_path_mtu().await?;
+                    let receipts = self.received_tracker.get_receipts();
+                    if !receipts.is_empty() {
+                        self.noop(receipts).await?;
+                    }
                 }
                 _ = keep_alive.tick() => {
-                    if last_received.elapsed() > KILL_CONNECTION_AFTER {
-                        tracing::warn!(remote = ?self.remote_conn.remote_addr, "connection timed out");
+                    let idle_for = self.last_received.elapsed();
+                    if idle_for > self.remote_conn.idle_timeout {
+                        tracing::warn!(remote = ?self.remote_conn.remote_addr, ?idle_for, "connection timed out");
                         return Err(TransportError::ConnectionClosed(self.remote_addr()));
                     }
-                    tracing::trace!(remote = ?self.remote_conn.remote_addr, "sending keep-alive");
-                    self.noop(vec![]).await?;
+                    if !self.remote_conn.pings {
+                        // older peers only know the connection is alive from the packets they
+                        // receive, so they get a noop on every tick
+                        tracing::trace!(remote = ?self.remote_conn.remote_addr, "sending keep-alive");
+                        self.noop(vec![]).await?;
+                    } else if idle_for >= keep_alive_interval {
+                        self.ping().await?;
+                    }
                 }
                 _ = resend_check.take().unwrap_or(tokio::time::sleep(Duration::from_secs(5))) => {
                     loop {
@@ -239,6 +410,10 @@ impl PeerConnection {
                                 break;
                             }
                             ResendAction::Resend(idx, packet) => {
+                                if !self.path_mtu.packet_lost(packet.len()) {
+                                    tracing::warn!(remote = ?self.remote_conn.remote_addr, size = packet.len(), "pending packet no longer fits the path MTU, closing connection");
+                                    return Err(TransportError::ConnectionClosed(self.remote_addr()));
+                                }
                                 self.remote_conn
                                     .outbound_packets
                                     .send((self.remote_conn.remote_addr, packet.clone()))
@@ -262,6 +437,11 @@ impl PeerConnection {
         self.remote_conn.remote_addr
     }
 
+    /// Smoothed round-trip time to the remote, measured from receipts and keep-alive pings.
+    pub fn rtt(&self) -> Duration {
+        self.remote_conn.sent_tracker.lock().smoothed_rtt()
+    }
+
     async fn process_inbound(
         &mut self,
         payload: SymmetricMessagePayload,
@@ -274,62 +454,285 @@ impl PeerConnection {
             }
             AckConnection { result: Ok(_) } => {
                 let packet = SymmetricMessage::ack_ok(
-                    &self.remote_conn.outbound_symmetric_key,
+                    &self.remote_conn.outbound_symmetric_key.read(),
                     self.remote_conn.inbound_symmetric_key_bytes,
                     self.remote_conn.remote_addr,
                 )?;
                 self.remote_conn
                     .outbound_packets
-                    .send((self.remote_conn.remote_addr, packet.data().into()))
+                    .send((
+                        self.remote_conn.remote_addr,
+                        packet_pool::packet(packet.data()),
+                    ))
                     .await
                     .map_err(|_| TransportError::ConnectionClosed(self.remote_addr()))?;
                 Ok(None)
             }
+            AckConnectionV2 { .. } | AckConnectionV3 { .. } => {
+                // these are encrypted with the keys exchanged during the handshake, not with the
+                // derived session keys, so they are not expected once the connection is established
+                Ok(None)
+            }
             StreamFragment {
                 stream_id,
                 total_length_bytes,
                 fragment_number,
                 payload,
             } => {
-                if let Some(sender) = self.inbound_streams.get(&stream_id) {
-                    sender
-                        .send((fragment_number, payload))
-                        .await
-                        .map_err(|_| TransportError::ConnectionClosed(self.remote_addr()))?;
-                    tracing::trace!(%stream_id, %fragment_number, "fragment pushed to existing stream");
-                } else {
-                    let (sender, receiver) = mpsc::channel(1);
+                let mut early_parity = vec![];
+                let stream = self.inbound_streams.entry(stream_id).or_insert_with(|| {
                     tracing::trace!(%stream_id, %fragment_number, "new stream");
-                    self.inbound_streams.insert(stream_id, sender);
-                    let mut stream = inbound_stream::InboundStream::new(total_length_bytes);
-                    if let Some(msg) = stream.push_fragment(fragment_number, payload) {
-                        self.inbound_streams.remove(&stream_id);
-                        tracing::trace!(%stream_id, %fragment_number, "stream finished");
+                    early_parity = self.early_parity.take(stream_id);
+                    inbound_stream::InboundStream::new(total_length_bytes)
+                });
+                if let Some(msg) = stream.push_fragment(fragment_number, payload) {
+                    self.inbound_streams.remove(&stream_id);
+                    self.finished_streams.insert(stream_id);
+                    tracing::trace!(%stream_id, %fragment_number, "stream finished");
+                    return Ok(Some(msg));
+                }
+                for parity in early_parity {
+                    if let Some(msg) = self.push_parity(stream_id, parity).await? {
                         return Ok(Some(msg));
                     }
-                    self.inbound_stream_futures
-                        .push(tokio::spawn(inbound_stream::recv_stream(
-                            stream_id, receiver, stream,
-                        )));
                 }
                 Ok(None)
             }
-            NoOp => Ok(None),
+            StreamParity {
+                stream_id,
+                first_fragment,
+                fragments,
+                parity_index,
+                payload,
+            } => {
+                let parity = inbound_stream::Parity {
+                    first_fragment,
+                    fragments,
+                    parity_index,
+                    payload,
+                };
+                if !self.inbound_streams.contains_key(&stream_id) {
+                    // parity arriving after the stream finished is of no use, but it may also
+                    // arrive before the first fragment of the stream
+                    if !self.finished_streams.contains(&stream_id) {
+                        self.early_parity.insert(stream_id, parity);
+                    }
+                    return Ok(None);
+                }
+                self.push_parity(stream_id, parity).await
+            }
+            NoOp | ReceiptRanges { .. } => Ok(None),
+            Rekey { key } => {
+                tracing::debug!(remote = %self.remote_conn.remote_addr, "remote replaced inbound key");
+                let previous = std::mem::replace(
+                    &mut *self.remote_conn.inbound_symmetric_key.write(),
+                    SymmetricKey::new(key),
+                );
+                self.remote_conn.inbound_symmetric_key_bytes = key;
+                self.rekey_tracker.inbound_key_replaced(previous);
+                Ok(None)
+            }
+            // challenges are answered before getting here, and responses are consumed by the
+            // packets listener when validating a new address
+            PathChallenge { .. } | PathResponse { .. } => Ok(None),
+            // handled before getting here since probes and pings are not acknowledged
+            PmtuProbe { .. } | PmtuProbeAck { .. } | Ping { .. } | Pong { .. } => Ok(None),
         }
     }
 
-    #[inline]
-    async fn noop(&mut self, receipts: Vec<u32>) -> Result<()> {
+    /// Adds a parity fragment to an inbound stream, returns the message if the stream could be
+    /// completed with the rebuilt fragments.
+    async fn push_parity(
+        &mut self,
+        stream_id: StreamId,
+        parity: inbound_stream::Parity,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(stream) = self.inbound_streams.get_mut(&stream_id) else {
+            return Ok(None);
+        };
+        let inbound_stream::Parity {
+            first_fragment,
+            fragments,
+            parity_index,
+            payload,
+        } = parity;
+        let (rebuilt, msg) = stream.push_parity(first_fragment, fragments, parity_index, payload);
+        if !rebuilt.is_empty() {
+            tracing::trace!(%stream_id, %first_fragment, ?rebuilt, "rebuilt lost fragments");
+        }
+        // the rebuilt fragments are acknowledged so the remote doesn't resend them
+        for packet_id in rebuilt {
+            if let ReportResult::QueueFull = self.received_tracker.report_received_packet(packet_id)
+            {
+                let receipts = self.received_tracker.get_receipts();
+                self.noop(receipts).await?;
+            }
+        }
+        if msg.is_some() {
+            self.inbound_streams.remove(&stream_id);
+            self.finished_streams.insert(stream_id);
+            tracing::trace!(%stream_id, "stream finished");
+        }
+        Ok(msg)
+    }
+
+    /// Sends the next path MTU probe, if the discovery is ongoing.
+    ///
+    /// Peers which didn't negotiate version 2 can't decode probes, they get a noop padded with
+    /// receipts instead, which they acknowledge like any other packet.
+    async fn maybe_probe_path_mtu(&mut self) -> Result<()> {
+        // the padding repeats a receipt for a packet of the remote, so wait for one
+        let last_received = self.received_tracker.last_received();
+        if !self.remote_conn.protoc_v2 && last_received.is_none() {
+            return Ok(());
+        }
+        let Some(size) = self.path_mtu.next_probe() else {
+            return Ok(());
+        };
+        tracing::trace!(remote = %self.remote_conn.remote_addr, %size, "sending path MTU probe");
+        let packet_id = self
+            .remote_conn
+            .last_packet_id
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+        let outbound_key = self.remote_conn.outbound_symmetric_key.read().clone();
+        let packet = match last_received {
+            Some(receipt) if !self.remote_conn.protoc_v2 => {
+                self.path_mtu.probe_sent(packet_id);
+                SymmetricMessage::padded_noop(packet_id, size, receipt, &outbound_key)?
+            }
+            _ => SymmetricMessage::pmtu_probe(packet_id, size, &outbound_key)?,
+        };
+        self.remote_conn
+            .outbound_packets
+            .send((self.remote_conn.remote_addr, packet.prepared_send()))
+            .await
+            .map_err(|_| TransportError::ConnectionClosed(self.remote_addr()))
+    }
+
+    async fn pmtu_probe_ack(&mut self, size: u16) -> Result<()> {
+        self.send_unacknowledged(SymmetricMessagePayload::PmtuProbeAck { size })
+            .await
+    }
+
+    /// Pings the remote of an idle connection, the answer keeps the connection open and
+    /// measures the round-trip time while there is no other traffic to measure it.
+    async fn ping(&mut self) -> Result<()> {
+        let seq = self.last_ping.map_or(0, |(seq, _)| seq.wrapping_add(1));
+        tracing::trace!(remote = %self.remote_conn.remote_addr, %seq, "sending keep-alive ping");
+        self.last_ping = Some((seq, std::time::Instant::now()));
+        self.send_unacknowledged(SymmetricMessagePayload::Ping { seq })
+            .await
+    }
+
+    fn pong(&mut self, seq: u32) {
+        // answers to older pings are ignored, they may have been delayed by a resend
+        let Some((last_seq, sent_at)) = self.last_ping else {
+            return;
+        };
+        if last_seq != seq {
+            return;
+        }
+        self.last_ping = None;
+        let rtt = sent_at.elapsed();
+        tracing::trace!(remote = %self.remote_conn.remote_addr, %seq, ?rtt, "keep-alive ping answered");
+        self.remote_conn.sent_tracker.lock().report_rtt_sample(rtt);
+    }
+
+    /// Sends a message which is not acknowledged nor resent.
+    async fn send_unacknowledged(&mut self, payload: SymmetricMessagePayload) -> Result<()> {
+        let packet_id = self
+            .remote_conn
+            .last_packet_id
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+        let outbound_key = self.remote_conn.outbound_symmetric_key.read().clone();
+        let packet = SymmetricMessage::serialize_msg_to_packet_data(
+            packet_id,
+            payload,
+            &outbound_key,
+            vec![],
+        )?;
+        self.remote_conn
+            .outbound_packets
+            .send((self.remote_conn.remote_addr, packet.prepared_send()))
+            .await
+            .map_err(|_| TransportError::ConnectionClosed(self.remote_addr()))
+    }
+
+    /// Echoes the token of a path challenge, which the remote sent to check that we are
+    /// reachable at the address our packets are coming from.
+    async fn path_response(&mut self, token: [u8; 8]) -> Result<()> {
+        self.send_unacknowledged(SymmetricMessagePayload::PathResponse { token })
+            .await
+    }
+
+    /// Replaces the outbound key if it has been used for too long or for too many packets.
+    ///
+    /// The new key is sent to the remote encrypted with the current one, and used for every
+    /// packet after the remote acknowledged it, otherwise packets encrypted with the new key
+    /// could overtake the one announcing it and be dropped by the remote.
+    async fn maybe_rekey(&mut self) -> Result<()> {
+        if !self.remote_conn.protoc_v2 {
+            return Ok(());
+        }
+        let next_packet_id = self
+            .remote_conn
+            .last_packet_id
+            .load(std::sync::atomic::Ordering::Acquire);
+        if let Some(announcement) = self.rekey_tracker.announcement() {
+            let acked = !self
+                .remote_conn
+                .sent_tracker
+                .lock()
+                .is_pending(announcement);
+            if let Some(key) = self.rekey_tracker.take_announced_key(acked, next_packet_id) {
+                tracing::debug!(remote = %self.remote_conn.remote_addr, acked, "switching to the new outbound key");
+                *self.remote_conn.outbound_symmetric_key.write() = SymmetricKey::new(key);
+            }
+            return Ok(());
+        }
+        if !self.rekey_tracker.should_rekey(next_packet_id) {
+            return Ok(());
+        }
+        tracing::debug!(remote = %self.remote_conn.remote_addr, "replacing outbound key");
+        let key = rand::random::<[u8; 16]>();
+        let receipts = self.received_tracker.get_receipts();
+        let packet_id = self
+            .remote_conn
+            .last_packet_id
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+        let current_key = self.remote_conn.outbound_symmetric_key.read().clone();
         packet_sending(
+            self.remote_conn.remote_addr,
+            &self.remote_conn.outbound_packets,
+            packet_id,
+            &current_key,
+            receipts,
+            SymmetricMessagePayload::Rekey { key },
+            &self.remote_conn.sent_tracker,
+            self.path_mtu.packet_size(),
+            self.remote_conn.protoc_v2,
+        )
+        .await?;
+        self.rekey_tracker.key_announced(key, packet_id);
+        Ok(())
+    }
+
+    /// Sends the given receipts, or just keeps the connection alive if there are none.
+    ///
+    /// Noop packets are not tracked for resending, if one gets lost the remote will resend
+    /// the packets it didn't get a receipt for and those will be acknowledged again.
+    async fn noop(&mut self, receipts: Vec<u32>) -> Result<()> {
+        let outbound_key = self.remote_conn.outbound_symmetric_key.read().clone();
+        send_receipts(
             self.remote_conn.remote_addr,
             &self.remote_conn.outbound_packets,
             self.remote_conn
                 .last_packet_id
                 .fetch_add(1, std::sync::atomic::Ordering::Release),
-            &self.remote_conn.outbound_symmetric_key,
-            receipts,
-            (),
-            &self.remote_conn.sent_tracker,
+            &outbound_key,
+            &receipts,
+            self.path_mtu.packet_size(),
+            self.remote_conn.protoc_v2,
         )
         .await
     }
@@ -341,137 +744,158 @@ impl PeerConnection {
             .remote_conn
             .last_packet_id
             .fetch_add(1, std::sync::atomic::Ordering::Release);
+        let outbound_key = self.remote_conn.outbound_symmetric_key.read().clone();
         packet_sending(
             self.remote_conn.remote_addr,
             &self.remote_conn.outbound_packets,
             packet_id,
-            &self.remote_conn.outbound_symmetric_key,
+            &outbound_key,
             receipts,
             symmetric_message::ShortMessage(data),
             &self.remote_conn.sent_tracker,
+            self.path_mtu.packet_size(),
+            self.remote_conn.protoc_v2,
         )
         .await?;
         Ok(())
     }
 
-    async fn outbound_stream(&mut self, data: SerializedMessage) {
-        let stream_id = StreamId::next();
-        let task = tokio::spawn(outbound_stream::send_stream(
-            stream_id,
-            self.remote_conn.last_packet_id.clone(),
-            self.remote_conn.outbound_packets.clone(),
+    async fn outbound_stream(
+        &mut self,
+        data: SerializedMessage,
+        priority: MessagePriority,
+    ) -> Result<()> {
+        let stream = outbound_stream::OutboundStream::new(
+            StreamId::next(),
             self.remote_conn.remote_addr,
             data,
-            self.remote_conn.outbound_symmetric_key.clone(),
-            self.remote_conn.sent_tracker.clone(),
-        ));
-        self.outbound_stream_futures.push(task);
+        );
+        self.outbound_streams
+            .send((priority, stream))
+            .await
+            .map_err(|_| TransportError::ConnectionClosed(self.remote_addr()))
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn packet_sending(
     remote_addr: SocketAddr,
-    outbound_packets: &mpsc::Sender<(SocketAddr, Arc<[u8]>)>,
+    outbound_packets: &mpsc::Sender<(SocketAddr, Bytes)>,
     packet_id: u32,
-    outbound_sym_key: &Aes128Gcm,
+    outbound_sym_key: &SymmetricKey,
     confirm_receipt: Vec<u32>,
     payload: impl Into<SymmetricMessagePayload>,
     sent_tracker: &parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>,
+    packet_size: usize,
+    receipt_ranges: bool,
 ) -> Result<()> {
     match SymmetricMessage::try_serialize_msg_to_packet_data(
         packet_id,
         payload,
         outbound_sym_key,
         confirm_receipt,
+        packet_size,
     )? {
         either::Either::Left(packet) => {
+            // the same buffer is sent and kept around for resending
+            let packet = packet.prepared_send_retained();
             outbound_packets
-                .send((remote_addr, packet.clone().prepared_send()))
+                .send((remote_addr, packet.clone()))
                 .await
                 .map_err(|_| TransportError::ConnectionClosed(remote_addr))?;
-            sent_tracker
-                .lock()
-                .report_sent_packet(packet_id, packet.prepared_send());
+            sent_tracker.lock().report_sent_packet(packet_id, packet);
             Ok(())
         }
-        either::Either::Right((payload, mut confirm_receipt)) => {
-            macro_rules! send {
-                ($packets:ident) => {{
-                    for packet in $packets {
-                        outbound_packets
-                            .send((remote_addr, packet.clone().prepared_send()))
-                            .await
-                            .map_err(|_| TransportError::ConnectionClosed(remote_addr))?;
-                        sent_tracker
-                            .lock()
-                            .report_sent_packet(packet_id, packet.prepared_send());
-                    }
-                }};
-            }
-
-            let max_num = SymmetricMessage::max_num_of_confirm_receipts_of_noop_message();
+        either::Either::Right((payload, confirm_receipt)) => {
+            // the receipts don't fit along the payload, so send them separately
             let packet = SymmetricMessage::serialize_msg_to_packet_data(
                 packet_id,
                 payload,
                 outbound_sym_key,
                 vec![],
-            )?;
-
-            if max_num > confirm_receipt.len() {
-                let packets = [
-                    packet,
-                    SymmetricMessage::serialize_msg_to_packet_data(
-                        packet_id,
-                        SymmetricMessagePayload::NoOp,
-                        outbound_sym_key,
-                        confirm_receipt,
-                    )?,
-                ];
-
-                send!(packets);
-                return Ok(());
-            }
-
-            let mut packets = Vec::with_capacity(8);
-            packets.push(packet);
-
-            while !confirm_receipt.is_empty() {
-                let len = confirm_receipt.len();
-
-                if len <= max_num {
-                    packets.push(SymmetricMessage::serialize_msg_to_packet_data(
-                        packet_id,
-                        SymmetricMessagePayload::NoOp,
-                        outbound_sym_key,
-                        confirm_receipt,
-                    )?);
-                    break;
-                }
-
-                let receipts = confirm_receipt.split_off(max_num);
-                packets.push(SymmetricMessage::serialize_msg_to_packet_data(
-                    packet_id,
-                    SymmetricMessagePayload::NoOp,
-                    outbound_sym_key,
-                    receipts,
-                )?);
-            }
+            )?
+            .prepared_send_retained();
+            outbound_packets
+                .send((remote_addr, packet.clone()))
+                .await
+                .map_err(|_| TransportError::ConnectionClosed(remote_addr))?;
+            sent_tracker.lock().report_sent_packet(packet_id, packet);
+            send_receipts(
+                remote_addr,
+                outbound_packets,
+                packet_id,
+                outbound_sym_key,
+                &confirm_receipt,
+                packet_size,
+                receipt_ranges,
+            )
+            .await
+        }
+    }
+}
 
-            send!(packets);
-            Ok(())
+/// Sends the receipts in as many noop packets as necessary (at least one). These packets are
+/// not reported to the sent packet tracker since noops are never acknowledged, so they can
+/// share the same packet id.
+///
+/// With `receipt_ranges` the receipts are encoded as ranges of consecutive packet ids, which
+/// only peers using protocol version 2 understand.
+async fn send_receipts(
+    remote_addr: SocketAddr,
+    outbound_packets: &mpsc::Sender<(SocketAddr, Bytes)>,
+    packet_id: u32,
+    outbound_sym_key: &SymmetricKey,
+    mut receipts: &[u32],
+    packet_size: usize,
+    receipt_ranges: bool,
+) -> Result<()> {
+    if receipt_ranges && !receipts.is_empty() {
+        let ranges = ReceiptRange::encode(receipts);
+        let max_num = SymmetricMessage::max_num_of_receipt_ranges(packet_size);
+        for batch in ranges.chunks(max_num) {
+            let packet = SymmetricMessage::serialize_msg_to_packet_data(
+                packet_id,
+                SymmetricMessagePayload::ReceiptRanges {
+                    ranges: batch.to_vec(),
+                },
+                outbound_sym_key,
+                vec![],
+            )?;
+            outbound_packets
+                .send((remote_addr, packet.prepared_send()))
+                .await
+                .map_err(|_| TransportError::ConnectionClosed(remote_addr))?;
         }
+        return Ok(());
+    }
+    let max_num = SymmetricMessage::max_num_of_confirm_receipts_of_noop_message(packet_size);
+    loop {
+        let (batch, rest) = receipts.split_at(receipts.len().min(max_num));
+        let packet = SymmetricMessage::serialize_msg_to_packet_data(
+            packet_id,
+            SymmetricMessagePayload::NoOp,
+            outbound_sym_key,
+            batch.to_vec(),
+        )?;
+        outbound_packets
+            .send((remote_addr, packet.prepared_send()))
+            .await
+            .map_err(|_| TransportError::ConnectionClosed(remote_addr))?;
+        if rest.is_empty() {
+            return Ok(());
+        }
+        receipts = rest;
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use aes_gcm::KeyInit;
     use futures::TryFutureExt;
     use std::net::Ipv4Addr;
 
     use super::{
-        inbound_stream::{recv_stream, InboundStream},
-        outbound_stream::send_stream,
+        inbound_stream::InboundStream,
+        outbound_stream::{send_streams, OutboundStream, StreamSender},
         *,
     };
     use crate::transport::packet_data::MAX_PACKET_SIZE;
@@ -486,27 +910,35 @@ mod tests {
             .map(|_| rand::random::<u8>())
             .collect();
         let key = rand::random::<[u8; 16]>();
-        let cipher = Aes128Gcm::new(&key.into());
+        let cipher = SymmetricKey::new(key);
         let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
 
-        let stream_id = StreamId::next();
         // Send a long message using the outbound stream
-        let outbound = tokio::task::spawn(send_stream(
-            stream_id,
-            Arc::new(AtomicU32::new(0)),
-            sender,
-            remote_addr,
-            message.clone(),
-            cipher.clone(),
-            sent_tracker,
+        let (streams_tx, streams_rx) = mpsc::channel(1);
+        streams_tx
+            .send((
+                MessagePriority::Transfer,
+                OutboundStream::new(StreamId::next(), remote_addr, message.clone()),
+            ))
+            .await?;
+        drop(streams_tx);
+        let outbound = tokio::task::spawn(send_streams(
+            streams_rx,
+            StreamSender {
+                last_packet_id: Arc::new(AtomicU32::new(0)),
+                outbound_packets: sender,
+                outbound_symmetric_key: Arc::new(parking_lot::RwLock::new(cipher.clone())),
+                sent_tracker,
+                packet_size: Arc::new(std::sync::atomic::AtomicUsize::new(MAX_PACKET_SIZE)),
+                fec: false,
+            },
         ))
         .map_err(|e| e.into());
 
         let inbound = async {
             // need to take care of decrypting and deserializing the inbound data before collecting into the message
-            let (tx, rx) = mpsc::channel(1);
-            let stream = InboundStream::new(MSG_LEN as u64);
-            let inbound_msg = tokio::task::spawn(recv_stream(stream_id, rx, stream));
+            let mut stream = InboundStream::new(MSG_LEN as u64);
+            let mut inbound_msg = None;
             while let Some((_, network_packet)) = receiver.recv().await {
                 let decrypted = PacketData::<_, MAX_PACKET_SIZE>::from_buf(&network_packet)
                     .try_decrypt_sym(&cipher)
@@ -523,11 +955,11 @@ mod tests {
                 else {
                     return Err("unexpected message".into());
                 };
-                tx.send((fragment_number, payload)).await?;
+                if let Some(msg) = stream.push_fragment(fragment_number, payload) {
+                    inbound_msg = Some(msg);
+                }
             }
-            let (_, msg) = inbound_msg
-                .await?
-                .map_err(|_| anyhow::anyhow!("stream failed"))?;
+            let msg = inbound_msg.ok_or_else(|| anyhow::anyhow!("stream failed"))?;
             Ok::<_, Box<dyn std::error::Error>>(msg)
         };
 
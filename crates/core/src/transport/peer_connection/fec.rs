@@ -0,0 +1,261 @@
+//! Forward error correction for stream fragments.
+//!
+//! Fragments are sent in groups of up to `GROUP_SIZE`, followed by a few parity fragments
+//! computed with a systematic Cauchy Reed-Solomon code over GF(256). Any `n` fragments of a
+//! group missing at the receiver can be rebuilt from the rest of the group and `n` of its
+//! parity fragments, without waiting for the sender to resend them.
+
+use crate::transport::PacketId;
+
+/// Max data fragments per parity group.
+pub(super) const GROUP_SIZE: usize = 16;
+
+/// Max parity fragments sent per group.
+pub(super) const MAX_PARITY: usize = 4;
+
+/// Space data fragments of a group leave free so the parity fragments, which carry the packet
+/// id and length of every fragment of the group, fit in a packet of the same size.
+pub(super) const PARITY_OVERHEAD: usize =
+    GROUP_SIZE * (std::mem::size_of::<PacketId>() + std::mem::size_of::<u16>());
+
+/// No parity is sent below this packet loss.
+const MIN_PACKET_LOSS: f64 = 0.01;
+
+/// Parity fragments to send per group for the given recent packet loss, enough to recover
+/// twice the expected losses of a group.
+pub(super) fn parity_count(packet_loss: f64) -> usize {
+    if packet_loss < MIN_PACKET_LOSS {
+        return 0;
+    }
+    ((2.0 * packet_loss * GROUP_SIZE as f64).ceil() as usize).clamp(1, MAX_PARITY)
+}
+
+/// Computes `parity_count` parity fragments for a group, as long as the longest fragment.
+/// Shorter fragments are zero padded.
+pub(super) fn encode(fragments: &[&[u8]], parity_count: usize) -> Vec<Vec<u8>> {
+    let len = fragments.iter().map(|f| f.len()).max().unwrap_or(0);
+    (0..parity_count)
+        .map(|parity_idx| {
+            let mut parity = vec![0; len];
+            for (idx, fragment) in fragments.iter().enumerate() {
+                mul_add(
+                    &mut parity,
+                    fragment,
+                    coefficient(fragments.len(), parity_idx, idx),
+                );
+            }
+            parity
+        })
+        .collect()
+}
+
+/// Rebuilds the missing fragments (`None`) of a group from the received ones and at least as
+/// many parity fragments, given as `(parity index, parity)`, as fragments are missing.
+///
+/// The rebuilt fragments are returned in order, zero padded to the length of the parity.
+pub(super) fn decode(fragments: &[Option<&[u8]>], parity: &[(usize, &[u8])]) -> Vec<Vec<u8>> {
+    let missing: Vec<_> = (0..fragments.len())
+        .filter(|&idx| fragments[idx].is_none())
+        .collect();
+    debug_assert!(missing.len() <= parity.len());
+    let parity = &parity[..missing.len()];
+    let len = parity.first().map_or(0, |(_, parity)| parity.len());
+
+    // remove the contribution of the received fragments, leaving a combination of the
+    // missing ones for each parity fragment
+    let combinations: Vec<Vec<u8>> = parity
+        .iter()
+        .map(|&(parity_idx, parity)| {
+            let mut combination = parity.to_vec();
+            combination.resize(len, 0);
+            for (idx, fragment) in fragments.iter().enumerate() {
+                if let Some(fragment) = fragment {
+                    let fragment = &fragment[..fragment.len().min(len)];
+                    mul_add(
+                        &mut combination,
+                        fragment,
+                        coefficient(fragments.len(), parity_idx, idx),
+                    );
+                }
+            }
+            combination
+        })
+        .collect();
+
+    // any square submatrix of a Cauchy matrix is invertible
+    let matrix: Vec<Vec<u8>> = parity
+        .iter()
+        .map(|&(parity_idx, _)| {
+            missing
+                .iter()
+                .map(|&idx| coefficient(fragments.len(), parity_idx, idx))
+                .collect()
+        })
+        .collect();
+    let inverse = invert(matrix);
+    inverse
+        .iter()
+        .map(|row| {
+            let mut fragment = vec![0; len];
+            for (&factor, combination) in row.iter().zip(&combinations) {
+                mul_add(&mut fragment, combination, factor);
+            }
+            fragment
+        })
+        .collect()
+}
+
+/// Coefficient of the data fragment `idx` in the parity fragment `parity_idx` of a group of
+/// `group_len` fragments: `1 / (x + y)` with distinct `x` for the parity fragments and `y`
+/// for the data fragments.
+fn coefficient(group_len: usize, parity_idx: usize, idx: usize) -> u8 {
+    let x = (group_len + parity_idx) as u8;
+    let y = idx as u8;
+    inv(x ^ y)
+}
+
+/// `dst += src * factor`, for every byte of `src`.
+fn mul_add(dst: &mut [u8], src: &[u8], factor: u8) {
+    if factor == 0 {
+        return;
+    }
+    let log_factor = LOG[factor as usize] as usize;
+    for (dst, &src) in dst.iter_mut().zip(src) {
+        if src != 0 {
+            *dst ^= EXP[LOG[src as usize] as usize + log_factor];
+        }
+    }
+}
+
+/// Inverts a square matrix with Gauss-Jordan elimination, the matrix must be invertible.
+fn invert(mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|row| (0..n).map(|col| u8::from(row == col)).collect())
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n)
+            .find(|&row| matrix[row][col] != 0)
+            .expect("the matrix is invertible");
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let factor = inv(matrix[col][col]);
+        for value in matrix[col].iter_mut().chain(inverse[col].iter_mut()) {
+            *value = mul(*value, factor);
+        }
+        let (pivot_row, pivot_inverse) = (matrix[col].clone(), inverse[col].clone());
+        for row in 0..n {
+            let factor = matrix[row][col];
+            if row == col || factor == 0 {
+                continue;
+            }
+            for (value, pivot) in matrix[row].iter_mut().zip(&pivot_row) {
+                *value ^= mul(*pivot, factor);
+            }
+            for (value, pivot) in inverse[row].iter_mut().zip(&pivot_inverse) {
+                *value ^= mul(*pivot, factor);
+            }
+        }
+    }
+    inverse
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+fn inv(a: u8) -> u8 {
+    debug_assert_ne!(a, 0);
+    EXP[255 - LOG[a as usize] as usize]
+}
+
+/// Powers of the generator of GF(256) with the polynomial `x^8 + x^4 + x^3 + x^2 + 1`, twice
+/// so the sum of two logarithms can be looked up without reducing it.
+static EXP: [u8; 512] = TABLES.0;
+
+/// Logarithms of the elements of GF(256), zero has none.
+static LOG: [u8; 256] = TABLES.1;
+
+const TABLES: ([u8; 512], [u8; 256]) = {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    (exp, log)
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_group(len: usize) -> Vec<Vec<u8>> {
+        (0..len)
+            .map(|idx| {
+                // the last fragment of a stream is usually shorter
+                let len = if idx == len - 1 { 300 } else { 1000 };
+                (0..len).map(|_| rand::random::<u8>()).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn field_arithmetic() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+            assert_eq!(mul(a, 1), a);
+            assert_eq!(mul(a, 0), 0);
+        }
+        assert_eq!(mul(2, 0x80), 0x1d);
+    }
+
+    #[test]
+    fn rebuild_missing_fragments() {
+        let group = random_group(GROUP_SIZE);
+        let fragments: Vec<&[u8]> = group.iter().map(Vec::as_slice).collect();
+        let parity = encode(&fragments, MAX_PARITY);
+        assert!(parity.iter().all(|parity| parity.len() == 1000));
+
+        for missing in [vec![0], vec![3, 15], vec![0, 1, 2, 3], vec![5, 9, 15]] {
+            let received: Vec<_> = fragments
+                .iter()
+                .enumerate()
+                .map(|(idx, fragment)| (!missing.contains(&idx)).then_some(*fragment))
+                .collect();
+            // any parity fragments will do
+            let parity: Vec<_> = parity
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(idx, parity)| (idx, parity.as_slice()))
+                .collect();
+            let rebuilt = decode(&received, &parity);
+            assert_eq!(rebuilt.len(), missing.len());
+            for (idx, rebuilt) in missing.into_iter().zip(rebuilt) {
+                assert_eq!(&rebuilt[..group[idx].len()], group[idx].as_slice());
+                assert!(rebuilt[group[idx].len()..].iter().all(|b| *b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn redundancy_follows_packet_loss() {
+        assert_eq!(parity_count(0.0), 0);
+        assert_eq!(parity_count(0.005), 0);
+        assert_eq!(parity_count(0.01), 1);
+        assert_eq!(parity_count(0.05), 2);
+        assert_eq!(parity_count(0.5), MAX_PARITY);
+    }
+}
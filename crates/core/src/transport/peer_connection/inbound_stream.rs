@@ -1,31 +1,46 @@
-use tokio::sync::mpsc;
-
 use crate::transport::peer_connection::outbound_stream::SerializedStream;
-use std::collections::BTreeMap;
+use crate::transport::PacketId;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 
-use super::StreamId;
+use super::{fec, StreamId, STREAM_WINDOW};
 
 type FragmentIdx = u32;
 
-pub(super) async fn recv_stream(
-    stream_id: StreamId,
-    mut receiver: mpsc::Receiver<(FragmentIdx, Vec<u8>)>,
-    mut stream: InboundStream,
-) -> Result<(StreamId, Vec<u8>), StreamId> {
-    while let Some((fragment_number, payload)) = receiver.recv().await {
-        if let Some(msg) = stream.push_fragment(fragment_number, payload) {
-            return Ok((stream_id, msg));
-        }
-    }
-    Err(stream_id)
-}
+/// Groups which fit in the receive window, parity of any other group is dropped.
+const MAX_PARITY_GROUPS: usize = STREAM_WINDOW as usize / fec::GROUP_SIZE + 1;
+
+/// Finished streams remembered per connection to recognize their resent fragments.
+const MAX_FINISHED_STREAMS: usize = 1024;
+
+/// Parity fragments buffered per connection until the first fragment of their stream arrives,
+/// enough for the groups of a full receive window.
+const MAX_EARLY_PARITY: usize = MAX_PARITY_GROUPS * fec::MAX_PARITY;
 
+/// Reassembles a stream from its fragments.
+///
+/// Only fragments within `STREAM_WINDOW` of the first missing one are buffered, so a remote
+/// can't make us hold an unbounded amount of out of order fragments.
+///
+/// Fragments lost on the way may be rebuilt from the parity fragments of their group, see
+/// [`fec`].
 pub(super) struct InboundStream {
     total_length_bytes: u64,
     /// Fragment numbers are 1-indexed
     last_contiguous_fragment_idx: FragmentIdx,
     non_contiguous_fragments: BTreeMap<FragmentIdx, Vec<u8>>,
     payload: Vec<u8>,
+    /// Where the last contiguous fragments start in the payload, oldest first, so they can
+    /// be used to rebuild the missing fragments of their group
+    recent_fragment_offsets: VecDeque<usize>,
+    /// Parity of the groups with missing fragments, by the first fragment of the group
+    parity_groups: BTreeMap<FragmentIdx, ParityGroup>,
+}
+
+struct ParityGroup {
+    /// Packet id and length of each fragment of the group
+    fragments: Vec<(PacketId, u16)>,
+    /// Parity fragments received, by parity index
+    parity: BTreeMap<u8, Vec<u8>>,
 }
 
 impl InboundStream {
@@ -35,9 +50,20 @@ impl InboundStream {
             last_contiguous_fragment_idx: 0,
             non_contiguous_fragments: BTreeMap::new(),
             payload: vec![],
+            recent_fragment_offsets: VecDeque::new(),
+            parity_groups: BTreeMap::new(),
         }
     }
 
+    /// Whether the fragment would be buffered, fragments too far ahead of the first missing one
+    /// must be dropped without acknowledging them so the remote sends them again later.
+    pub fn in_window(&self, fragment_number: FragmentIdx) -> bool {
+        fragment_number
+            <= self
+                .last_contiguous_fragment_idx
+                .saturating_add(STREAM_WINDOW)
+    }
+
     /// Returns some if the message has been completely streamed, none otherwise.
     pub fn push_fragment(
         &mut self,
@@ -50,25 +76,139 @@ impl InboundStream {
         //     non_contig = ?self.non_contiguous_fragments.keys().collect::<Vec<_>>(),
         //     "received stream fragment"
         // );
+        if fragment_number <= self.last_contiguous_fragment_idx || !self.in_window(fragment_number)
+        {
+            return None;
+        }
         if fragment_number == self.last_contiguous_fragment_idx + 1 {
-            self.last_contiguous_fragment_idx = fragment_number;
-            self.payload.append(&mut fragment);
+            self.append_contiguous(&mut fragment);
         } else {
             self.non_contiguous_fragments
                 .insert(fragment_number, fragment);
         }
         while let Some((idx, mut v)) = self.non_contiguous_fragments.pop_first() {
             if idx == self.last_contiguous_fragment_idx + 1 {
-                self.last_contiguous_fragment_idx += 1;
-                self.payload.append(&mut v);
+                self.append_contiguous(&mut v);
             } else {
                 self.non_contiguous_fragments.insert(idx, v);
                 break;
             }
         }
+        // groups are not needed anymore once all their fragments are in the payload
+        while let Some(entry) = self.parity_groups.first_entry() {
+            let last = *entry.key() + entry.get().fragments.len() as FragmentIdx - 1;
+            if last > self.last_contiguous_fragment_idx {
+                break;
+            }
+            entry.remove();
+        }
         self.get_and_clear()
     }
 
+    /// Adds a parity fragment of the group starting at `first_fragment`, and rebuilds the
+    /// missing fragments of the group once there are as many parity fragments as fragments
+    /// missing.
+    ///
+    /// Returns the packet ids of the rebuilt fragments, which should be acknowledged as if
+    /// they were received, and the message if it has been completely streamed.
+    pub fn push_parity(
+        &mut self,
+        first_fragment: FragmentIdx,
+        fragments: Vec<(PacketId, u16)>,
+        parity_index: u8,
+        parity: Vec<u8>,
+    ) -> (Vec<PacketId>, Option<Vec<u8>>) {
+        let valid = first_fragment > 0
+            && (1..=fec::GROUP_SIZE).contains(&fragments.len())
+            && (parity_index as usize) < fec::MAX_PARITY
+            && fragments
+                .iter()
+                .all(|&(_, len)| len as usize <= parity.len());
+        let last_fragment =
+            first_fragment.saturating_add((fragments.len() as FragmentIdx).saturating_sub(1));
+        if !valid
+            || last_fragment <= self.last_contiguous_fragment_idx
+            || !self.in_window(last_fragment)
+        {
+            return (vec![], None);
+        }
+
+        let mut group = match self.parity_groups.remove(&first_fragment) {
+            Some(group) => group,
+            None if self.parity_groups.len() >= MAX_PARITY_GROUPS => return (vec![], None),
+            None => ParityGroup {
+                fragments,
+                parity: BTreeMap::new(),
+            },
+        };
+        if group
+            .parity
+            .values()
+            .next()
+            .map_or(true, |other| other.len() == parity.len())
+        {
+            group.parity.entry(parity_index).or_insert(parity);
+        }
+        let missing: Vec<_> = (0..group.fragments.len())
+            .filter(|&idx| self.fragment(first_fragment + idx as FragmentIdx).is_none())
+            .collect();
+        if missing.is_empty() {
+            return (vec![], None);
+        }
+        if missing.len() > group.parity.len() {
+            self.parity_groups.insert(first_fragment, group);
+            return (vec![], None);
+        }
+
+        let received: Vec<_> = (0..group.fragments.len())
+            .map(|idx| self.fragment(first_fragment + idx as FragmentIdx))
+            .collect();
+        let parity: Vec<_> = group
+            .parity
+            .iter()
+            .map(|(&idx, parity)| (idx as usize, parity.as_slice()))
+            .collect();
+        let rebuilt = fec::decode(&received, &parity);
+
+        let mut packet_ids = Vec::with_capacity(missing.len());
+        let mut msg = None;
+        for (idx, mut fragment) in missing.into_iter().zip(rebuilt) {
+            let (packet_id, len) = group.fragments[idx];
+            fragment.truncate(len as usize);
+            packet_ids.push(packet_id);
+            msg = msg.or(self.push_fragment(first_fragment + idx as FragmentIdx, fragment));
+        }
+        (packet_ids, msg)
+    }
+
+    fn append_contiguous(&mut self, fragment: &mut Vec<u8>) {
+        self.last_contiguous_fragment_idx += 1;
+        if self.recent_fragment_offsets.len() == fec::GROUP_SIZE {
+            self.recent_fragment_offsets.pop_front();
+        }
+        self.recent_fragment_offsets.push_back(self.payload.len());
+        self.payload.append(fragment);
+    }
+
+    /// A received fragment, if it is still available.
+    fn fragment(&self, fragment_number: FragmentIdx) -> Option<&[u8]> {
+        if fragment_number > self.last_contiguous_fragment_idx {
+            return self
+                .non_contiguous_fragments
+                .get(&fragment_number)
+                .map(Vec::as_slice);
+        }
+        let age = (self.last_contiguous_fragment_idx - fragment_number) as usize;
+        let idx = self.recent_fragment_offsets.len().checked_sub(age + 1)?;
+        let start = self.recent_fragment_offsets[idx];
+        let end = self
+            .recent_fragment_offsets
+            .get(idx + 1)
+            .copied()
+            .unwrap_or(self.payload.len());
+        Some(&self.payload[start..end])
+    }
+
     fn get_and_clear(&mut self) -> Option<Vec<u8>> {
         if self.payload.len() as u64 == self.total_length_bytes {
             Some(std::mem::take(&mut self.payload))
@@ -78,9 +218,72 @@ impl InboundStream {
     }
 }
 
+/// Streams which were received completely lately. The remote resends their fragments whose
+/// receipts got lost, those must be acknowledged again instead of starting a new stream.
+#[derive(Default)]
+pub(super) struct FinishedStreams {
+    order: VecDeque<StreamId>,
+    ids: HashSet<StreamId>,
+}
+
+impl FinishedStreams {
+    pub fn insert(&mut self, stream_id: StreamId) {
+        if !self.ids.insert(stream_id) {
+            return;
+        }
+        self.order.push_back(stream_id);
+        if self.order.len() > MAX_FINISHED_STREAMS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, stream_id: &StreamId) -> bool {
+        self.ids.contains(stream_id)
+    }
+}
+
+/// A parity fragment as received, see [`InboundStream::push_parity`].
+pub(super) struct Parity {
+    pub first_fragment: FragmentIdx,
+    pub fragments: Vec<(PacketId, u16)>,
+    pub parity_index: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Parity which arrived before any fragment of its stream, e.g. when the first fragments
+/// were lost. The stream can only be started once a fragment tells its length, so the parity
+/// is kept until then, the oldest being dropped once `MAX_EARLY_PARITY` are buffered.
+#[derive(Default)]
+pub(super) struct EarlyParity {
+    parity: VecDeque<(StreamId, Parity)>,
+}
+
+impl EarlyParity {
+    pub fn insert(&mut self, stream_id: StreamId, parity: Parity) {
+        if self.parity.len() >= MAX_EARLY_PARITY {
+            self.parity.pop_front();
+        }
+        self.parity.push_back((stream_id, parity));
+    }
+
+    /// Removes the parity buffered for a stream which just started.
+    pub fn take(&mut self, stream_id: StreamId) -> Vec<Parity> {
+        let (taken, kept): (Vec<_>, VecDeque<_>) = std::mem::take(&mut self.parity)
+            .into_iter()
+            .partition(|(id, _)| *id == stream_id);
+        self.parity = kept;
+        taken.into_iter().map(|(_, parity)| parity).collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::InboundStream;
+    use super::{
+        fec, EarlyParity, FinishedStreams, InboundStream, Parity, StreamId, MAX_EARLY_PARITY,
+        MAX_FINISHED_STREAMS, STREAM_WINDOW,
+    };
 
     #[test]
     fn test_simple_sequence() {
@@ -119,4 +322,109 @@ mod tests {
         assert!(stream.non_contiguous_fragments.is_empty());
         assert!(stream.payload.is_empty());
     }
+
+    #[test]
+    fn test_receive_window() {
+        let mut stream = InboundStream::new(u64::MAX);
+        assert!(stream.in_window(STREAM_WINDOW));
+        assert!(!stream.in_window(STREAM_WINDOW + 1));
+        assert_eq!(stream.push_fragment(STREAM_WINDOW + 1, vec![1]), None);
+        assert!(stream.non_contiguous_fragments.is_empty());
+
+        // the window moves forward with the first missing fragment
+        assert_eq!(stream.push_fragment(1, vec![1]), None);
+        assert!(stream.in_window(STREAM_WINDOW + 1));
+        assert_eq!(stream.push_fragment(STREAM_WINDOW + 1, vec![1]), None);
+        assert_eq!(stream.non_contiguous_fragments.len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_fragment() {
+        let mut stream = InboundStream::new(6);
+        assert_eq!(stream.push_fragment(1, vec![1, 2]), None);
+        assert_eq!(stream.push_fragment(1, vec![1, 2]), None);
+        assert_eq!(stream.push_fragment(2, vec![3, 4]), None);
+        assert_eq!(
+            stream.push_fragment(3, vec![5, 6]),
+            Some(vec![1, 2, 3, 4, 5, 6])
+        );
+    }
+
+    #[test]
+    fn test_rebuild_lost_fragments() {
+        let fragments = [vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9], vec![10]];
+        let parity = fec::encode(&fragments.iter().map(Vec::as_slice).collect::<Vec<_>>(), 2);
+        let group: Vec<_> = fragments
+            .iter()
+            .enumerate()
+            .map(|(idx, fragment)| (100 + idx as u32, fragment.len() as u16))
+            .collect();
+
+        let mut stream = InboundStream::new(10);
+        assert_eq!(stream.push_fragment(1, fragments[0].clone()), None);
+        assert_eq!(stream.push_fragment(3, fragments[2].clone()), None);
+        // two fragments are missing, one parity fragment is not enough
+        assert_eq!(
+            stream.push_parity(1, group.clone(), 1, parity[1].clone()),
+            (vec![], None)
+        );
+        assert_eq!(
+            stream.push_parity(1, group, 0, parity[0].clone()),
+            (vec![101, 103], Some((1..=10).collect()))
+        );
+        assert!(stream.parity_groups.is_empty());
+    }
+
+    #[test]
+    fn test_parity_of_complete_group() {
+        let fragments = [vec![1, 2], vec![3, 4]];
+        let parity = fec::encode(&fragments.iter().map(Vec::as_slice).collect::<Vec<_>>(), 1);
+        let group = vec![(1, 2), (2, 2)];
+
+        let mut stream = InboundStream::new(6);
+        assert_eq!(stream.push_fragment(1, fragments[0].clone()), None);
+        assert_eq!(stream.push_fragment(2, fragments[1].clone()), None);
+        assert_eq!(
+            stream.push_parity(1, group, 0, parity[0].clone()),
+            (vec![], None)
+        );
+        assert!(stream.parity_groups.is_empty());
+    }
+
+    #[test]
+    fn finished_streams_bounded() {
+        let mut finished = FinishedStreams::default();
+        for id in 0..=MAX_FINISHED_STREAMS as u32 {
+            finished.insert(StreamId(id));
+            finished.insert(StreamId(id));
+        }
+        assert!(!finished.contains(&StreamId(0)));
+        assert!(finished.contains(&StreamId(1)));
+        assert!(finished.contains(&StreamId(MAX_FINISHED_STREAMS as u32)));
+        assert_eq!(finished.order.len(), MAX_FINISHED_STREAMS);
+    }
+
+    #[test]
+    fn early_parity_taken_by_stream() {
+        let parity = |first_fragment| Parity {
+            first_fragment,
+            fragments: vec![],
+            parity_index: 0,
+            payload: vec![],
+        };
+        let mut early = EarlyParity::default();
+        for n in 0..=MAX_EARLY_PARITY as u32 {
+            early.insert(StreamId(n % 2), parity(n));
+        }
+        // the oldest parity was dropped to make room
+        let taken: Vec<_> = early
+            .take(StreamId(0))
+            .iter()
+            .map(|parity| parity.first_fragment)
+            .collect();
+        assert_eq!(taken.len(), MAX_EARLY_PARITY / 2);
+        assert_eq!(taken.first(), Some(&2));
+        assert!(early.take(StreamId(0)).is_empty());
+        assert_eq!(early.take(StreamId(1)).len(), MAX_EARLY_PARITY / 2);
+    }
 }
@@ -1,127 +1,346 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicU32, AtomicUsize};
 use std::sync::Arc;
-use std::vec;
+use std::time::Duration;
 
-use aes_gcm::Aes128Gcm;
+use bytes::Bytes;
 use tokio::sync::mpsc;
 
 use crate::{
     transport::{
-        packet_data,
         sent_packet_tracker::SentPacketTracker,
-        symmetric_message::{self},
-        TransportError,
+        symmetric_message::{self, SymmetricMessage},
+        PacketId, TransportError,
     },
     util::time_source::InstantTimeSrc,
 };
 
-use super::StreamId;
+use super::{fec, max_data_size, MessagePriority, OutboundKey, StreamId, STREAM_WINDOW};
 
 pub(crate) type SerializedStream = Vec<u8>;
 
-// TODO: measure the space overhead of SymmetricMessage::LongMessage since is likely less than 100
-/// The max payload we can send in a single fragment, this MUST be less than packet_data::MAX_DATA_SIZE
-/// since we need to account for the space overhead of SymmetricMessage::LongMessage metadata
-const MAX_DATA_SIZE: usize = packet_data::MAX_DATA_SIZE - 100;
+/// Time to wait before checking again when every queued stream is waiting for receipts.
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-// TODO: unit test
-/// Handles sending a stream that is *not piped*. In the future this will be replaced by
-/// piped streams which start forwarding before the stream has been received.
-#[allow(clippy::too_many_arguments)]
-pub(super) async fn send_stream(
+/// A stream queued for sending. Handles streams that are *not piped*, in the future this will
+/// be replaced by piped streams which start forwarding before the stream has been received.
+pub(super) struct OutboundStream {
     stream_id: StreamId,
-    last_packet_id: Arc<AtomicU32>,
-    sender: mpsc::Sender<(SocketAddr, Arc<[u8]>)>,
     destination_addr: SocketAddr,
-    mut stream_to_send: SerializedStream,
-    outbound_symmetric_key: Aes128Gcm,
-    sent_packet_tracker: Arc<parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>>,
-) -> Result<(), TransportError> {
-    tracing::debug!(stream_id = %stream_id.0, length = stream_to_send.len(), "sending stream");
-    let total_length_bytes = stream_to_send.len() as u32;
-    let mut total_packets = stream_to_send.len() / MAX_DATA_SIZE;
-    total_packets += if stream_to_send.len() % MAX_DATA_SIZE == 0 {
-        0
-    } else {
-        1
-    };
-    let mut sent_so_far = 0;
-    let mut next_fragment_number = 1; // Fragment numbers are 1-indexed
+    total_length_bytes: u64,
+    /// Fragment numbers are 1-indexed
+    next_fragment_number: u32,
+    remaining: SerializedStream,
+    /// Fragments sent and not acknowledged yet, oldest first
+    unacked: VecDeque<(u32, PacketId)>,
+    /// Group of the fragments being sent, if parity is sent for it
+    parity_group: Option<ParityGroup>,
+}
+
+/// Fragments of a group sent so far, the parity is computed once the group is complete.
+struct ParityGroup {
+    first_fragment: u32,
+    parity_count: usize,
+    /// Packet id and payload of each fragment
+    fragments: Vec<(PacketId, Vec<u8>)>,
+}
+
+impl OutboundStream {
+    pub fn new(stream_id: StreamId, destination_addr: SocketAddr, data: SerializedStream) -> Self {
+        Self {
+            stream_id,
+            destination_addr,
+            total_length_bytes: data.len() as u64,
+            next_fragment_number: 1,
+            remaining: data,
+            unacked: VecDeque::new(),
+            parity_group: None,
+        }
+    }
 
+    /// Whether the next fragment fits in the receive window of the remote, which drops
+    /// fragments more than `STREAM_WINDOW` fragments ahead of the first one it is missing.
+    fn in_window(&mut self, sent_tracker: &SentPacketTracker<InstantTimeSrc>) -> bool {
+        while let Some(&(_, packet_id)) = self.unacked.front() {
+            if sent_tracker.is_pending(packet_id) {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+        self.unacked.front().map_or(true, |&(oldest, _)| {
+            self.next_fragment_number < oldest.saturating_add(STREAM_WINDOW)
+        })
+    }
+}
+
+/// Everything the outbound streams of a connection share to send their fragments.
+pub(super) struct StreamSender {
+    pub last_packet_id: Arc<AtomicU32>,
+    pub outbound_packets: mpsc::Sender<(SocketAddr, Bytes)>,
+    pub outbound_symmetric_key: OutboundKey,
+    pub sent_tracker: Arc<parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>>,
+    pub packet_size: Arc<AtomicUsize>,
+    /// Whether the remote can rebuild lost fragments from parity fragments
+    pub fec: bool,
+}
+
+/// Sends the streams of a connection one fragment at a time, until the connection stops
+/// queueing streams and the queued ones are sent.
+///
+/// Streams with a higher priority are sent first and streams of the same priority take turns,
+/// so a large transfer doesn't delay the streams queued after it. A stream whose remote
+/// window is full is skipped until receipts for its fragments arrive.
+pub(super) async fn send_streams(
+    mut new_streams: mpsc::Receiver<(MessagePriority, OutboundStream)>,
+    sender: StreamSender,
+) -> Result<(), TransportError> {
+    let mut queues: BTreeMap<MessagePriority, VecDeque<OutboundStream>> = BTreeMap::new();
     loop {
-        if sent_so_far == total_packets {
-            break;
-        }
-        let rest = {
-            if stream_to_send.len() > MAX_DATA_SIZE {
-                let mut rest = stream_to_send.split_off(MAX_DATA_SIZE);
-                std::mem::swap(&mut stream_to_send, &mut rest);
-                rest
-            } else {
-                std::mem::take(&mut stream_to_send)
+        if queues.is_empty() {
+            let Some((priority, stream)) = new_streams.recv().await else {
+                return Ok(());
+            };
+            queues.entry(priority).or_default().push_back(stream);
+        }
+
+        // the path MTU may have been discovered since the previous fragment
+        let packet_size = sender
+            .packet_size
+            .load(std::sync::atomic::Ordering::Acquire);
+        sender.congestion_wait(packet_size).await;
+
+        // streams queued while waiting may go first
+        while let Ok((priority, stream)) = new_streams.try_recv() {
+            queues.entry(priority).or_default().push_back(stream);
+        }
+        let Some((priority, mut stream)) = next_stream(&mut queues, &sender.sent_tracker) else {
+            tokio::time::sleep(WINDOW_POLL_INTERVAL).await;
+            continue;
+        };
+        sender.send_fragment(&mut stream, packet_size).await?;
+        if stream.remaining.is_empty() {
+            tracing::trace!(stream_id = %stream.stream_id, total_packets = %(stream.next_fragment_number - 1), "stream sent");
+        } else {
+            queues.entry(priority).or_default().push_back(stream);
+        }
+    }
+}
+
+/// Takes the first stream, in priority order, with room in the window of the remote.
+fn next_stream(
+    queues: &mut BTreeMap<MessagePriority, VecDeque<OutboundStream>>,
+    sent_tracker: &parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>,
+) -> Option<(MessagePriority, OutboundStream)> {
+    let sent_tracker = sent_tracker.lock();
+    let next = queues.iter_mut().find_map(|(&priority, queue)| {
+        let idx = queue
+            .iter_mut()
+            .position(|stream| stream.in_window(&sent_tracker))?;
+        Some((priority, queue.remove(idx)?))
+    });
+    queues.retain(|_, queue| !queue.is_empty());
+    next
+}
+
+impl StreamSender {
+    /// Waits until a packet of `packet_size` bytes fits in the congestion window.
+    async fn congestion_wait(&self, packet_size: usize) {
+        loop {
+            // the guard must be released before awaiting
+            let wait = self.sent_tracker.lock().congestion_wait(packet_size);
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => break,
             }
+        }
+    }
+
+    async fn send_fragment(
+        &self,
+        stream: &mut OutboundStream,
+        packet_size: usize,
+    ) -> Result<(), TransportError> {
+        if self.fec && stream.parity_group.is_none() {
+            // redundancy is adjusted at the start of every group
+            let packet_loss = self.sent_tracker.lock().get_recent_packet_loss();
+            let parity_count = fec::parity_count(packet_loss);
+            stream.parity_group = (parity_count > 0).then(|| ParityGroup {
+                first_fragment: stream.next_fragment_number,
+                parity_count,
+                fragments: Vec::with_capacity(fec::GROUP_SIZE),
+            });
+        }
+        let max_data_size = match stream.parity_group {
+            Some(_) => max_data_size(packet_size) - fec::PARITY_OVERHEAD,
+            None => max_data_size(packet_size),
+        };
+        let payload = if stream.remaining.len() > max_data_size {
+            let rest = stream.remaining.split_off(max_data_size);
+            std::mem::replace(&mut stream.remaining, rest)
+        } else {
+            std::mem::take(&mut stream.remaining)
         };
-        let packet_id = last_packet_id.fetch_add(1, std::sync::atomic::Ordering::Release);
+        let fragment_number = stream.next_fragment_number;
+        stream.next_fragment_number += 1;
+        let packet_id = self
+            .last_packet_id
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+        let parity_payload = stream.parity_group.is_some().then(|| payload.clone());
+        // the connection may have been rekeyed since the previous fragment
+        let outbound_key = self.outbound_symmetric_key.read().clone();
         super::packet_sending(
-            destination_addr,
-            &sender,
+            stream.destination_addr,
+            &self.outbound_packets,
             packet_id,
-            &outbound_symmetric_key,
+            &outbound_key,
             vec![],
             symmetric_message::StreamFragment {
-                stream_id,
-                total_length_bytes: total_length_bytes as u64,
-                fragment_number: next_fragment_number,
-                payload: rest,
+                stream_id: stream.stream_id,
+                total_length_bytes: stream.total_length_bytes,
+                fragment_number,
+                payload,
             },
-            &sent_packet_tracker,
+            &self.sent_tracker,
+            packet_size,
+            // fragments carry no receipts
+            false,
         )
         .await?;
-        next_fragment_number += 1;
-        sent_so_far += 1;
-    }
+        stream.unacked.push_back((fragment_number, packet_id));
 
-    // tracing::trace!(stream_id = %stream_id.0, total_packets = %sent_so_far, "stream sent");
+        if let Some(mut group) = stream.parity_group.take() {
+            group
+                .fragments
+                .push((packet_id, parity_payload.unwrap_or_default()));
+            if group.fragments.len() == fec::GROUP_SIZE || stream.remaining.is_empty() {
+                self.send_parity(stream, group, packet_size).await?;
+            } else {
+                stream.parity_group = Some(group);
+            }
+        }
+        Ok(())
+    }
 
-    Ok(())
+    /// Sends the parity fragments of a complete group. They count against the congestion
+    /// window like the fragments, but lost parity is never resent since the fragments
+    /// themselves will be.
+    async fn send_parity(
+        &self,
+        stream: &OutboundStream,
+        group: ParityGroup,
+        packet_size: usize,
+    ) -> Result<(), TransportError> {
+        let data: Vec<_> = group
+            .fragments
+            .iter()
+            .map(|(_, payload)| payload.as_slice())
+            .collect();
+        let parity = fec::encode(&data, group.parity_count.min(data.len()));
+        let fragments: Vec<_> = group
+            .fragments
+            .iter()
+            .map(|(packet_id, payload)| (*packet_id, payload.len() as u16))
+            .collect();
+        let outbound_key = self.outbound_symmetric_key.read().clone();
+        for (parity_index, payload) in parity.into_iter().enumerate() {
+            self.congestion_wait(packet_size).await;
+            let packet_id = self
+                .last_packet_id
+                .fetch_add(1, std::sync::atomic::Ordering::Release);
+            let packet = SymmetricMessage::serialize_msg_to_packet_data(
+                packet_id,
+                symmetric_message::StreamParity {
+                    stream_id: stream.stream_id,
+                    first_fragment: group.first_fragment,
+                    fragments: fragments.clone(),
+                    parity_index: parity_index as u8,
+                    payload,
+                },
+                &outbound_key,
+                vec![],
+            )?
+            .prepared_send_retained();
+            self.sent_tracker
+                .lock()
+                .report_sent_unreliable_packet(packet_id, packet.clone());
+            self.outbound_packets
+                .send((stream.destination_addr, packet))
+                .await
+                .map_err(|_| TransportError::ConnectionClosed(stream.destination_addr))?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use aes_gcm::KeyInit;
     use std::net::Ipv4Addr;
-    use tests::packet_data::MAX_PACKET_SIZE;
 
     use super::{
         symmetric_message::{SymmetricMessage, SymmetricMessagePayload},
         *,
     };
-    use crate::transport::packet_data::PacketData;
+    use crate::transport::packet_data::{PacketData, SymmetricKey, MAX_PACKET_SIZE};
+
+    fn stream_sender(
+        outbound_packets: mpsc::Sender<(SocketAddr, Bytes)>,
+        outbound_symmetric_key: OutboundKey,
+        sent_tracker: Arc<parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>>,
+    ) -> StreamSender {
+        StreamSender {
+            last_packet_id: Arc::new(AtomicU32::new(0)),
+            outbound_packets,
+            outbound_symmetric_key,
+            sent_tracker,
+            packet_size: Arc::new(AtomicUsize::new(MAX_PACKET_SIZE)),
+            fec: false,
+        }
+    }
+
+    /// Spawns the scheduler with the given streams already queued.
+    fn spawn_send_streams(
+        streams: Vec<(MessagePriority, Vec<u8>)>,
+        sender: StreamSender,
+    ) -> tokio::task::JoinHandle<Result<(), TransportError>> {
+        let remote_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080);
+        let (streams_tx, streams_rx) = mpsc::channel(streams.len());
+        for (priority, data) in streams {
+            streams_tx
+                .try_send((
+                    priority,
+                    OutboundStream::new(StreamId::next(), remote_addr, data),
+                ))
+                .unwrap();
+        }
+        tokio::spawn(send_streams(streams_rx, sender))
+    }
+
+    fn random_message(len: usize) -> Vec<u8> {
+        std::iter::repeat(())
+            .take(len)
+            .map(|_| rand::random::<u8>())
+            .collect()
+    }
 
     #[tokio::test]
     async fn test_send_stream_success() -> Result<(), Box<dyn std::error::Error>> {
         let (outbound_sender, mut outbound_receiver) = mpsc::channel(1);
-        let remote_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080);
-        let message: Vec<_> = std::iter::repeat(())
-            .take(100_000)
-            .map(|_| rand::random::<u8>())
-            .collect();
+        let message = random_message(100_000);
         let cipher = {
             let key = rand::random::<[u8; 16]>();
-            Aes128Gcm::new(&key.into())
+            SymmetricKey::new(key)
         };
         let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
 
-        let background_task = tokio::spawn(send_stream(
-            StreamId::next(),
-            Arc::new(AtomicU32::new(0)),
-            outbound_sender,
-            remote_addr,
-            message.clone(),
-            cipher.clone(),
-            sent_tracker,
-        ));
+        let background_task = spawn_send_streams(
+            vec![(MessagePriority::Transfer, message.clone())],
+            stream_sender(
+                outbound_sender,
+                Arc::new(parking_lot::RwLock::new(cipher.clone())),
+                sent_tracker.clone(),
+            ),
+        );
 
         let mut inbound_bytes = Vec::new();
         while let Some((_, packet)) = outbound_receiver.recv().await {
@@ -129,6 +348,10 @@ mod tests {
                 .try_decrypt_sym(&cipher)
                 .map_err(TransportError::PrivateKeyDecryptionError)?;
             let deserialized = SymmetricMessage::deser(decrypted_packet.data())?;
+            // acknowledge the packet so the congestion window keeps opening
+            sent_tracker
+                .lock()
+                .report_received_receipts(&[deserialized.packet_id]);
             let SymmetricMessagePayload::StreamFragment { payload, .. } = deserialized.payload
             else {
                 panic!("Expected a StreamFragment, got {:?}", deserialized.payload);
@@ -143,4 +366,257 @@ mod tests {
         assert_eq!(&message[99_990..], &inbound_bytes[99_990..]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_send_stream_while_rekeying() -> Result<(), Box<dyn std::error::Error>> {
+        let (outbound_sender, mut outbound_receiver) = mpsc::channel(1);
+        let message = random_message(100_000);
+        let old_cipher = SymmetricKey::new(rand::random::<[u8; 16]>());
+        let new_cipher = SymmetricKey::new(rand::random::<[u8; 16]>());
+        let outbound_key = Arc::new(parking_lot::RwLock::new(old_cipher.clone()));
+        let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
+
+        let background_task = spawn_send_streams(
+            vec![(MessagePriority::Transfer, message.clone())],
+            stream_sender(outbound_sender, outbound_key.clone(), sent_tracker.clone()),
+        );
+
+        let mut inbound_bytes = Vec::new();
+        let mut fragments_with_new_key = 0;
+        while let Some((_, packet)) = outbound_receiver.recv().await {
+            let packet = PacketData::<_, MAX_PACKET_SIZE>::from_buf(packet.as_ref());
+            let decrypted_packet = match packet.try_decrypt_sym(&old_cipher) {
+                Ok(decrypted) => {
+                    assert_eq!(fragments_with_new_key, 0, "old key used after rekeying");
+                    decrypted
+                }
+                Err(_) => {
+                    fragments_with_new_key += 1;
+                    packet
+                        .try_decrypt_sym(&new_cipher)
+                        .map_err(TransportError::PrivateKeyDecryptionError)?
+                }
+            };
+            let deserialized = SymmetricMessage::deser(decrypted_packet.data())?;
+            sent_tracker
+                .lock()
+                .report_received_receipts(&[deserialized.packet_id]);
+            let SymmetricMessagePayload::StreamFragment {
+                payload,
+                fragment_number,
+                ..
+            } = deserialized.payload
+            else {
+                panic!("Expected a StreamFragment, got {:?}", deserialized.payload);
+            };
+            if fragment_number == 10 {
+                // the connection is rekeyed in the middle of the stream
+                *outbound_key.write() = new_cipher.clone();
+            }
+            inbound_bytes.extend_from_slice(payload.as_ref());
+        }
+
+        let result = background_task.await?;
+        assert!(result.is_ok());
+        assert!(fragments_with_new_key > 0);
+        assert_eq!(inbound_bytes, message);
+        Ok(())
+    }
+
+    /// Returns the stream id of every fragment sent, acknowledging them as they arrive.
+    async fn sent_fragments(
+        mut outbound_receiver: mpsc::Receiver<(SocketAddr, Bytes)>,
+        cipher: &SymmetricKey,
+        sent_tracker: &parking_lot::Mutex<SentPacketTracker<InstantTimeSrc>>,
+    ) -> Result<Vec<StreamId>, Box<dyn std::error::Error>> {
+        let mut fragments = Vec::new();
+        while let Some((_, packet)) = outbound_receiver.recv().await {
+            let decrypted_packet = PacketData::<_, MAX_PACKET_SIZE>::from_buf(packet.as_ref())
+                .try_decrypt_sym(cipher)
+                .map_err(TransportError::PrivateKeyDecryptionError)?;
+            let deserialized = SymmetricMessage::deser(decrypted_packet.data())?;
+            sent_tracker
+                .lock()
+                .report_received_receipts(&[deserialized.packet_id]);
+            let SymmetricMessagePayload::StreamFragment { stream_id, .. } = deserialized.payload
+            else {
+                panic!("Expected a StreamFragment, got {:?}", deserialized.payload);
+            };
+            fragments.push(stream_id);
+        }
+        Ok(fragments)
+    }
+
+    #[tokio::test]
+    async fn test_control_streams_first() -> Result<(), Box<dyn std::error::Error>> {
+        let (outbound_sender, outbound_receiver) = mpsc::channel(1);
+        let cipher = SymmetricKey::new(rand::random::<[u8; 16]>());
+        let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
+
+        let background_task = spawn_send_streams(
+            vec![
+                (MessagePriority::Transfer, random_message(50_000)),
+                (MessagePriority::Control, random_message(5_000)),
+            ],
+            stream_sender(
+                outbound_sender,
+                Arc::new(parking_lot::RwLock::new(cipher.clone())),
+                sent_tracker.clone(),
+            ),
+        );
+        let fragments = sent_fragments(outbound_receiver, &cipher, &sent_tracker).await?;
+        background_task.await??;
+
+        let control = fragments[0];
+        let control_fragments = fragments.iter().filter(|id| **id == control).count();
+        assert!(control_fragments > 1);
+        assert!(fragments[..control_fragments]
+            .iter()
+            .all(|id| *id == control));
+        assert!(fragments[control_fragments..]
+            .iter()
+            .all(|id| *id != control));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_fragments() -> Result<(), Box<dyn std::error::Error>> {
+        let (outbound_sender, outbound_receiver) = mpsc::channel(1);
+        let cipher = SymmetricKey::new(rand::random::<[u8; 16]>());
+        let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
+
+        let background_task = spawn_send_streams(
+            vec![
+                (MessagePriority::Transfer, random_message(20_000)),
+                (MessagePriority::Transfer, random_message(20_000)),
+            ],
+            stream_sender(
+                outbound_sender,
+                Arc::new(parking_lot::RwLock::new(cipher.clone())),
+                sent_tracker.clone(),
+            ),
+        );
+        let fragments = sent_fragments(outbound_receiver, &cipher, &sent_tracker).await?;
+        background_task.await??;
+
+        assert!(fragments.len() > 2);
+        for pair in fragments.chunks(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_waits_for_remote_window() -> Result<(), Box<dyn std::error::Error>> {
+        let (outbound_sender, mut outbound_receiver) = mpsc::channel(1);
+        let cipher = SymmetricKey::new(rand::random::<[u8; 16]>());
+        let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
+        let message = random_message(max_data_size(MAX_PACKET_SIZE) * (STREAM_WINDOW as usize + 1));
+
+        let background_task = spawn_send_streams(
+            vec![(MessagePriority::Transfer, message)],
+            stream_sender(
+                outbound_sender,
+                Arc::new(parking_lot::RwLock::new(cipher.clone())),
+                sent_tracker.clone(),
+            ),
+        );
+
+        // acknowledge every fragment but the first one
+        let mut first_packet = None;
+        for _ in 0..STREAM_WINDOW {
+            let (_, packet) = outbound_receiver.recv().await.unwrap();
+            let decrypted_packet = PacketData::<_, MAX_PACKET_SIZE>::from_buf(packet.as_ref())
+                .try_decrypt_sym(&cipher)
+                .map_err(TransportError::PrivateKeyDecryptionError)?;
+            let packet_id = SymmetricMessage::deser(decrypted_packet.data())?.packet_id;
+            match first_packet {
+                None => first_packet = Some(packet_id),
+                Some(_) => sent_tracker.lock().report_received_receipts(&[packet_id]),
+            }
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), outbound_receiver.recv())
+                .await
+                .is_err()
+        );
+
+        sent_tracker
+            .lock()
+            .report_received_receipts(&[first_packet.unwrap()]);
+        assert!(outbound_receiver.recv().await.is_some());
+        assert!(outbound_receiver.recv().await.is_none());
+        background_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lost_fragments_rebuilt_from_parity() -> Result<(), Box<dyn std::error::Error>> {
+        let (outbound_sender, mut outbound_receiver) = mpsc::channel(1);
+        let cipher = SymmetricKey::new(rand::random::<[u8; 16]>());
+        let sent_tracker = Arc::new(parking_lot::Mutex::new(SentPacketTracker::new()));
+        crate::transport::sent_packet_tracker::tests::set_packet_loss(
+            &mut sent_tracker.lock(),
+            0.05,
+        );
+        let message = random_message(30_000);
+
+        let mut sender = stream_sender(
+            outbound_sender,
+            Arc::new(parking_lot::RwLock::new(cipher.clone())),
+            sent_tracker.clone(),
+        );
+        sender.fec = true;
+        let background_task =
+            spawn_send_streams(vec![(MessagePriority::Transfer, message.clone())], sender);
+
+        let mut inbound = super::super::inbound_stream::InboundStream::new(message.len() as u64);
+        let mut parity_fragments = 0;
+        let mut rebuilt = None;
+        while let Some((_, packet)) = outbound_receiver.recv().await {
+            assert!(packet.len() <= MAX_PACKET_SIZE);
+            let decrypted_packet = PacketData::<_, MAX_PACKET_SIZE>::from_buf(packet.as_ref())
+                .try_decrypt_sym(&cipher)
+                .map_err(TransportError::PrivateKeyDecryptionError)?;
+            let deserialized = SymmetricMessage::deser(decrypted_packet.data())?;
+            let pending = sent_tracker.lock().is_pending(deserialized.packet_id);
+            sent_tracker
+                .lock()
+                .report_received_receipts(&[deserialized.packet_id]);
+            match deserialized.payload {
+                // one fragment of each group gets lost
+                SymmetricMessagePayload::StreamFragment {
+                    fragment_number: 2 | 20,
+                    ..
+                } => {}
+                SymmetricMessagePayload::StreamFragment {
+                    fragment_number,
+                    payload,
+                    ..
+                } => {
+                    assert_eq!(inbound.push_fragment(fragment_number, payload), None);
+                }
+                SymmetricMessagePayload::StreamParity {
+                    first_fragment,
+                    fragments,
+                    parity_index,
+                    payload,
+                    ..
+                } => {
+                    // parity is in flight until acknowledged, like the fragments
+                    assert!(pending);
+                    parity_fragments += 1;
+                    let (_, msg) =
+                        inbound.push_parity(first_fragment, fragments, parity_index, payload);
+                    rebuilt = rebuilt.or(msg);
+                }
+                other => panic!("Expected a stream fragment, got {other:?}"),
+            }
+        }
+
+        background_task.await??;
+        assert_eq!(parity_fragments, 4);
+        assert_eq!(rebuilt, Some(message));
+        Ok(())
+    }
 }
@@ -0,0 +1,370 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    transport::{
+        packet_data::{MAX_PACKET_SIZE, MIN_PACKET_SIZE},
+        PacketId,
+    },
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+/// Time to wait for the acknowledgement of a probe before sending it again.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Times a probe is sent before assuming it exceeds the path MTU.
+const MAX_PROBE_ATTEMPTS: u8 = 3;
+
+/// The search stops once the largest confirmed size is this close to the smallest size which
+/// didn't reach the remote.
+const SEARCH_PRECISION: usize = 16;
+
+/// Time after which a finished search starts again, in case the path MTU increased.
+const RAISE_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
+/// Packets larger than `MIN_PACKET_SIZE` lost in a row, without any of them being acknowledged
+/// in between, after which the path is assumed to no longer carry them.
+const BLACK_HOLE_LOSSES: u8 = 5;
+
+/// Discovers the largest packet size which reaches the remote of a connection.
+///
+/// Connections start with `MIN_PACKET_SIZE` packets. Padded probes are sent first with the
+/// largest size supported and then following a binary search between the largest confirmed size
+/// and the smallest one which didn't get through. Probes are never fragmented nor resent by
+/// the connection, so like a packet with the don't fragment bit set, a probe which is not
+/// acknowledged after a few attempts is assumed to exceed the path MTU. Probe sizes are
+/// multiples of 4 so probes padded with receipts can have the exact size.
+///
+/// If the path stops carrying packets of the discovered size, e.g. because the route to the
+/// remote changed, large packets are lost in a row and the size goes back to
+/// `MIN_PACKET_SIZE` before searching again. Packets already sent can't be split to the new
+/// size, since stream fragments are numbered when they are first sent, so once those keep
+/// being lost as well the connection can't make progress and has to be closed.
+pub(super) struct PathMtu<T: TimeSource> {
+    /// Largest packet size known to reach the remote, shared with the outbound streams
+    packet_size: Arc<AtomicUsize>,
+    /// Smallest packet size known to not reach the remote
+    too_large: usize,
+    probe: Option<Probe>,
+    /// When the last search finished
+    search_done_at: Option<Instant>,
+    /// Packets larger than `MIN_PACKET_SIZE` lost since one of them was last acknowledged
+    large_losses: u8,
+    /// Packets larger than the current size lost since one of them was last acknowledged
+    oversized_losses: u8,
+    time_source: T,
+}
+
+struct Probe {
+    size: usize,
+    attempts: u8,
+    sent_at: Instant,
+    /// Set when the probe is acknowledged by a receipt rather than by a probe ack
+    packet_id: Option<PacketId>,
+}
+
+impl PathMtu<InstantTimeSrc> {
+    pub(super) fn new() -> Self {
+        PathMtu {
+            packet_size: Arc::new(AtomicUsize::new(MIN_PACKET_SIZE)),
+            too_large: MAX_PACKET_SIZE + 1,
+            probe: None,
+            search_done_at: None,
+            large_losses: 0,
+            oversized_losses: 0,
+            time_source: InstantTimeSrc::new(),
+        }
+    }
+}
+
+impl<T: TimeSource> PathMtu<T> {
+    pub(super) fn packet_size(&self) -> usize {
+        self.packet_size.load(Ordering::Acquire)
+    }
+
+    pub(super) fn shared_packet_size(&self) -> Arc<AtomicUsize> {
+        self.packet_size.clone()
+    }
+
+    /// Returns the size of the probe to send next, if it is time to send one.
+    pub(super) fn next_probe(&mut self) -> Option<usize> {
+        let now = self.time_source.now();
+        if let Some(probe) = &mut self.probe {
+            if now - probe.sent_at < PROBE_TIMEOUT {
+                return None;
+            }
+            if probe.attempts < MAX_PROBE_ATTEMPTS {
+                probe.attempts += 1;
+                probe.sent_at = now;
+                probe.packet_id = None;
+                return Some(probe.size);
+            }
+            tracing::debug!(
+                size = probe.size,
+                "path MTU probe lost, assuming it is too large"
+            );
+            self.too_large = probe.size;
+            self.probe = None;
+        }
+
+        if let Some(done_at) = self.search_done_at {
+            if now - done_at < RAISE_INTERVAL {
+                return None;
+            }
+            self.search_done_at = None;
+            self.too_large = MAX_PACKET_SIZE + 1;
+        }
+
+        let confirmed = self.packet_size();
+        if self.too_large - confirmed <= SEARCH_PRECISION {
+            tracing::debug!(packet_size = confirmed, "path MTU discovered");
+            self.search_done_at = Some(now);
+            return None;
+        }
+        let size = if self.too_large > MAX_PACKET_SIZE {
+            // most paths support the largest size, so try that first
+            MAX_PACKET_SIZE
+        } else {
+            (confirmed + (self.too_large - confirmed) / 2) & !3
+        };
+        self.probe = Some(Probe {
+            size,
+            attempts: 1,
+            sent_at: now,
+            packet_id: None,
+        });
+        Some(size)
+    }
+
+    /// Reports that the remote received a probe of the given size.
+    pub(super) fn probe_acked(&mut self, size: usize) {
+        if size > self.packet_size() && size <= MAX_PACKET_SIZE {
+            self.packet_size.store(size, Ordering::Release);
+            self.large_losses = 0;
+            self.oversized_losses = 0;
+        }
+        if self.probe.as_ref().is_some_and(|probe| probe.size == size) {
+            self.probe = None;
+        }
+    }
+
+    /// Reports the id of the packet carrying the last probe, for probes acknowledged by a
+    /// receipt like any other packet.
+    pub(super) fn probe_sent(&mut self, packet_id: PacketId) {
+        if let Some(probe) = &mut self.probe {
+            probe.packet_id = Some(packet_id);
+        }
+    }
+
+    /// Checks whether the receipts acknowledge the last probe.
+    pub(super) fn receipts_received(&mut self, receipts: &[PacketId]) {
+        let Some(size) = self.probe.as_ref().and_then(|probe| {
+            let packet_id = probe.packet_id?;
+            receipts.contains(&packet_id).then_some(probe.size)
+        }) else {
+            return;
+        };
+        self.probe_acked(size);
+    }
+
+    /// Reports that the remote received a packet of the given size.
+    pub(super) fn packet_acked(&mut self, size: usize) {
+        if size > MIN_PACKET_SIZE {
+            self.large_losses = 0;
+            self.oversized_losses = 0;
+        }
+    }
+
+    /// Reports that a packet of the given size was lost, after too many large packets are lost
+    /// in a row the packet size goes back to `MIN_PACKET_SIZE` and the search starts again.
+    ///
+    /// Returns false once packets larger than the current size, sent before falling back,
+    /// have been lost too many times in a row as well, since they will never reach the remote.
+    pub(super) fn packet_lost(&mut self, size: usize) -> bool {
+        if size <= MIN_PACKET_SIZE {
+            return true;
+        }
+        if size > self.packet_size() {
+            self.oversized_losses += 1;
+            return self.oversized_losses < BLACK_HOLE_LOSSES;
+        }
+        self.large_losses += 1;
+        if self.large_losses < BLACK_HOLE_LOSSES {
+            return true;
+        }
+        let packet_size = self.packet_size();
+        tracing::debug!(
+            packet_size,
+            "packets of the discovered size are being lost, falling back to the minimum size"
+        );
+        self.packet_size.store(MIN_PACKET_SIZE, Ordering::Release);
+        self.too_large = packet_size.max(size).min(MAX_PACKET_SIZE + 1);
+        self.probe = None;
+        self.search_done_at = None;
+        self.large_losses = 0;
+        self.oversized_losses = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::time_source::MockTimeSource;
+
+    fn mock_path_mtu() -> PathMtu<MockTimeSource> {
+        PathMtu {
+            packet_size: Arc::new(AtomicUsize::new(MIN_PACKET_SIZE)),
+            too_large: MAX_PACKET_SIZE + 1,
+            probe: None,
+            search_done_at: None,
+            large_losses: 0,
+            oversized_losses: 0,
+            time_source: MockTimeSource::new(Instant::now()),
+        }
+    }
+
+    /// Probes until the search finishes, with probes larger than `mtu` being lost.
+    fn discover(path_mtu: &mut PathMtu<MockTimeSource>, mtu: usize) {
+        loop {
+            match path_mtu.next_probe() {
+                Some(size) if size <= mtu => path_mtu.probe_acked(size),
+                Some(_) => path_mtu.time_source.advance_time(PROBE_TIMEOUT),
+                None if path_mtu.search_done_at.is_some() => break,
+                None => path_mtu.time_source.advance_time(PROBE_TIMEOUT),
+            }
+        }
+    }
+
+    #[test]
+    fn largest_size_first() {
+        let mut path_mtu = mock_path_mtu();
+        assert_eq!(path_mtu.packet_size(), MIN_PACKET_SIZE);
+        assert_eq!(path_mtu.next_probe(), Some(MAX_PACKET_SIZE));
+        path_mtu.probe_acked(MAX_PACKET_SIZE);
+        assert_eq!(path_mtu.packet_size(), MAX_PACKET_SIZE);
+        assert_eq!(path_mtu.next_probe(), None);
+        assert!(path_mtu.search_done_at.is_some());
+    }
+
+    #[test]
+    fn lost_probes_are_retried() {
+        let mut path_mtu = mock_path_mtu();
+        assert_eq!(path_mtu.next_probe(), Some(MAX_PACKET_SIZE));
+        assert_eq!(path_mtu.next_probe(), None);
+        for _ in 1..MAX_PROBE_ATTEMPTS {
+            path_mtu.time_source.advance_time(PROBE_TIMEOUT);
+            assert_eq!(path_mtu.next_probe(), Some(MAX_PACKET_SIZE));
+        }
+        path_mtu.time_source.advance_time(PROBE_TIMEOUT);
+        let next = path_mtu.next_probe().unwrap();
+        assert!(next < MAX_PACKET_SIZE && next > MIN_PACKET_SIZE);
+        assert_eq!(path_mtu.packet_size(), MIN_PACKET_SIZE);
+    }
+
+    #[test]
+    fn discover_smaller_mtu() {
+        let mut path_mtu = mock_path_mtu();
+        // e.g. PPPoE with some tunneling overhead
+        let mtu = 1400;
+        discover(&mut path_mtu, mtu);
+        assert!(path_mtu.packet_size() <= mtu);
+        assert!(mtu - path_mtu.packet_size() <= SEARCH_PRECISION);
+    }
+
+    #[test]
+    fn never_below_floor() {
+        let mut path_mtu = mock_path_mtu();
+        discover(&mut path_mtu, MIN_PACKET_SIZE - 100);
+        assert_eq!(path_mtu.packet_size(), MIN_PACKET_SIZE);
+    }
+
+    #[test]
+    fn probe_acknowledged_by_receipt() {
+        let mut path_mtu = mock_path_mtu();
+        assert_eq!(path_mtu.next_probe(), Some(MAX_PACKET_SIZE));
+        path_mtu.probe_sent(7);
+        path_mtu.receipts_received(&[5, 6]);
+        assert_eq!(path_mtu.packet_size(), MIN_PACKET_SIZE);
+        path_mtu.receipts_received(&[6, 7]);
+        assert_eq!(path_mtu.packet_size(), MAX_PACKET_SIZE);
+    }
+
+    #[test]
+    fn probe_sizes_multiple_of_four() {
+        let mut path_mtu = mock_path_mtu();
+        while path_mtu.search_done_at.is_none() {
+            match path_mtu.next_probe() {
+                Some(size) => {
+                    assert_eq!(size % 4, 0);
+                    if size <= 1401 {
+                        path_mtu.probe_acked(size);
+                    }
+                }
+                None => path_mtu.time_source.advance_time(PROBE_TIMEOUT),
+            }
+        }
+    }
+
+    #[test]
+    fn black_hole_falls_back() {
+        let mut path_mtu = mock_path_mtu();
+        discover(&mut path_mtu, MAX_PACKET_SIZE);
+        assert_eq!(path_mtu.packet_size(), MAX_PACKET_SIZE);
+
+        // losses of small packets or interleaved with acknowledged large ones don't count
+        for _ in 0..BLACK_HOLE_LOSSES {
+            path_mtu.packet_lost(MIN_PACKET_SIZE);
+        }
+        for _ in 1..BLACK_HOLE_LOSSES {
+            path_mtu.packet_lost(MAX_PACKET_SIZE);
+        }
+        path_mtu.packet_acked(MAX_PACKET_SIZE);
+        path_mtu.packet_lost(MAX_PACKET_SIZE);
+        assert_eq!(path_mtu.packet_size(), MAX_PACKET_SIZE);
+
+        for _ in 1..BLACK_HOLE_LOSSES {
+            path_mtu.packet_lost(MAX_PACKET_SIZE);
+        }
+        assert_eq!(path_mtu.packet_size(), MIN_PACKET_SIZE);
+        // the search starts again below the size which stopped getting through
+        let probe = path_mtu.next_probe().unwrap();
+        assert!(probe > MIN_PACKET_SIZE && probe < MAX_PACKET_SIZE);
+    }
+
+    #[test]
+    fn oversized_packets_lost_after_fall_back() {
+        let mut path_mtu = mock_path_mtu();
+        discover(&mut path_mtu, MAX_PACKET_SIZE);
+        for _ in 0..BLACK_HOLE_LOSSES {
+            assert!(path_mtu.packet_lost(MAX_PACKET_SIZE));
+        }
+        assert_eq!(path_mtu.packet_size(), MIN_PACKET_SIZE);
+
+        // packets sent before falling back get a chance while the search starts again
+        for _ in 1..BLACK_HOLE_LOSSES {
+            assert!(path_mtu.packet_lost(MAX_PACKET_SIZE));
+        }
+        path_mtu.packet_acked(MAX_PACKET_SIZE);
+        for _ in 1..BLACK_HOLE_LOSSES {
+            assert!(path_mtu.packet_lost(MAX_PACKET_SIZE));
+        }
+        assert!(!path_mtu.packet_lost(MAX_PACKET_SIZE));
+
+        // unless the search finds out the path carries them again
+        path_mtu.probe_acked(MAX_PACKET_SIZE);
+        assert!(path_mtu.packet_lost(MAX_PACKET_SIZE));
+    }
+
+    #[test]
+    fn search_again_after_interval() {
+        let mut path_mtu = mock_path_mtu();
+        discover(&mut path_mtu, 1300);
+        assert_eq!(path_mtu.next_probe(), None);
+        path_mtu.time_source.advance_time(RAISE_INTERVAL);
+        assert_eq!(path_mtu.next_probe(), Some(MAX_PACKET_SIZE));
+        path_mtu.probe_acked(MAX_PACKET_SIZE);
+        assert_eq!(path_mtu.packet_size(), MAX_PACKET_SIZE);
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    transport::{packet_data::SymmetricKey, PacketId},
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+/// Amount of packets sent with the same outbound key before it is replaced. Nonces come from a
+/// counter so they never repeat, this keeps the data encrypted with each key well within the
+/// usage limits of AES-GCM, and since it is below `u32::MAX` a packet id is never reused with
+/// the same key after the counter wraps around.
+const REKEY_AFTER_PACKETS: u32 = 1 << 30;
+
+/// Maximum time the same outbound key is used.
+const REKEY_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
+/// Maximum time a new outbound key waits for the receipt of the `Rekey` message announcing it
+/// before being used anyway.
+const REKEY_GRACE: Duration = Duration::from_secs(5);
+
+/// Decides when the outbound key of a connection must be replaced, and keeps the inbound key
+/// replaced by the last rekey of the remote.
+///
+/// Each side of a connection rekeys its own outbound direction: it sends the new key in a
+/// `Rekey` message encrypted with the current key and keeps using the current key until the
+/// remote acknowledges it, or `REKEY_GRACE` elapses, so packets encrypted with the new key
+/// don't arrive before the remote knows it. Packets which were in flight, or are resent, with
+/// the old key can still be decrypted by the remote until the next rekey, so ongoing streams
+/// are not affected.
+pub(super) struct RekeyTracker<T: TimeSource> {
+    /// Id of the first packet sent with the current outbound key
+    first_packet_id: PacketId,
+    /// When the current outbound key started being used
+    key_since: Instant,
+    rekey_after_packets: u32,
+    rekey_interval: Duration,
+    /// New outbound key sent to the remote but not used yet, along with the id of the packet
+    /// announcing it and when it was sent
+    announced_key: Option<([u8; 16], PacketId, Instant)>,
+    previous_inbound_key: Option<SymmetricKey>,
+    time_source: T,
+}
+
+impl RekeyTracker<InstantTimeSrc> {
+    pub(super) fn new(first_packet_id: PacketId) -> Self {
+        let time_source = InstantTimeSrc::new();
+        RekeyTracker {
+            first_packet_id,
+            key_since: time_source.now(),
+            rekey_after_packets: REKEY_AFTER_PACKETS,
+            rekey_interval: REKEY_INTERVAL,
+            announced_key: None,
+            previous_inbound_key: None,
+            time_source,
+        }
+    }
+}
+
+impl<T: TimeSource> RekeyTracker<T> {
+    /// Whether the outbound key must be replaced before sending the packet with the given id.
+    pub(super) fn should_rekey(&self, next_packet_id: PacketId) -> bool {
+        if self.announced_key.is_some() {
+            return false;
+        }
+        // the packet id counter wraps around, so count the packets sent modulo 2^32
+        next_packet_id.wrapping_sub(self.first_packet_id) >= self.rekey_after_packets
+            || self.time_source.now() - self.key_since >= self.rekey_interval
+    }
+
+    /// Reports that a new outbound key is used starting with the packet with the given id.
+    pub(super) fn rekeyed(&mut self, next_packet_id: PacketId) {
+        self.first_packet_id = next_packet_id;
+        self.key_since = self.time_source.now();
+    }
+
+    /// Reports that the new outbound key was sent to the remote in the packet with the given id.
+    pub(super) fn key_announced(&mut self, key: [u8; 16], packet_id: PacketId) {
+        self.announced_key = Some((key, packet_id, self.time_source.now()));
+    }
+
+    /// Id of the packet announcing a new outbound key which is not used yet.
+    pub(super) fn announcement(&self) -> Option<PacketId> {
+        self.announced_key.map(|(_, packet_id, _)| packet_id)
+    }
+
+    /// Returns the announced outbound key once it can be used, that is, once the remote
+    /// acknowledged the packet announcing it or the grace period elapsed, the key is then
+    /// considered used starting with the packet with the given id.
+    pub(super) fn take_announced_key(
+        &mut self,
+        acked: bool,
+        next_packet_id: PacketId,
+    ) -> Option<[u8; 16]> {
+        let (key, _, announced_at) = self.announced_key?;
+        if !acked && self.time_source.now() - announced_at < REKEY_GRACE {
+            return None;
+        }
+        self.announced_key = None;
+        self.rekeyed(next_packet_id);
+        Some(key)
+    }
+
+    /// Reports that the remote replaced our inbound key, the old one is kept since packets
+    /// encrypted with it may still be in flight.
+    pub(super) fn inbound_key_replaced(&mut self, previous: SymmetricKey) {
+        self.previous_inbound_key = Some(previous);
+    }
+
+    pub(super) fn previous_inbound_key(&self) -> Option<&SymmetricKey> {
+        self.previous_inbound_key.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::time_source::MockTimeSource;
+
+    fn mock_rekey_tracker(first_packet_id: PacketId) -> RekeyTracker<MockTimeSource> {
+        let time_source = MockTimeSource::new(Instant::now());
+        RekeyTracker {
+            first_packet_id,
+            key_since: time_source.now(),
+            rekey_after_packets: 100,
+            rekey_interval: REKEY_INTERVAL,
+            announced_key: None,
+            previous_inbound_key: None,
+            time_source,
+        }
+    }
+
+    #[test]
+    fn rekey_after_packets() {
+        let mut tracker = mock_rekey_tracker(0);
+        assert!(!tracker.should_rekey(99));
+        assert!(tracker.should_rekey(100));
+
+        tracker.rekeyed(101);
+        assert!(!tracker.should_rekey(101));
+        assert!(!tracker.should_rekey(200));
+        assert!(tracker.should_rekey(201));
+    }
+
+    #[test]
+    fn rekey_on_packet_id_wrap_around() {
+        let first_packet_id = u32::MAX - 49;
+        let mut tracker = mock_rekey_tracker(first_packet_id);
+        // 50 packets before the counter wraps around and 49 after it
+        assert!(!tracker.should_rekey(first_packet_id.wrapping_add(99)));
+        assert_eq!(first_packet_id.wrapping_add(99), 49);
+        assert!(tracker.should_rekey(50));
+
+        tracker.rekeyed(50);
+        assert!(!tracker.should_rekey(51));
+        assert!(tracker.should_rekey(150));
+    }
+
+    #[test]
+    fn rekey_after_interval() {
+        let mut tracker = mock_rekey_tracker(0);
+        tracker
+            .time_source
+            .advance_time(REKEY_INTERVAL - Duration::from_secs(1));
+        assert!(!tracker.should_rekey(1));
+        tracker.time_source.advance_time(Duration::from_secs(1));
+        assert!(tracker.should_rekey(1));
+
+        tracker.rekeyed(2);
+        assert!(!tracker.should_rekey(2));
+        tracker.time_source.advance_time(REKEY_INTERVAL);
+        assert!(tracker.should_rekey(2));
+    }
+
+    #[test]
+    fn announced_key_used_once_acknowledged() {
+        let mut tracker = mock_rekey_tracker(0);
+        let key = rand::random::<[u8; 16]>();
+        tracker.key_announced(key, 100);
+        assert_eq!(tracker.announcement(), Some(100));
+        assert!(!tracker.should_rekey(101));
+        assert_eq!(tracker.take_announced_key(false, 101), None);
+
+        assert_eq!(tracker.take_announced_key(true, 102), Some(key));
+        assert_eq!(tracker.announcement(), None);
+        assert!(!tracker.should_rekey(201));
+        assert!(tracker.should_rekey(202));
+    }
+
+    #[test]
+    fn announced_key_used_after_grace_period() {
+        let mut tracker = mock_rekey_tracker(0);
+        let key = rand::random::<[u8; 16]>();
+        tracker.key_announced(key, 100);
+        tracker
+            .time_source
+            .advance_time(REKEY_GRACE - Duration::from_millis(1));
+        assert_eq!(tracker.take_announced_key(false, 101), None);
+        tracker.time_source.advance_time(Duration::from_millis(1));
+        assert_eq!(tracker.take_announced_key(false, 101), Some(key));
+        assert_eq!(tracker.announcement(), None);
+    }
+
+    #[test]
+    fn keeps_previous_inbound_key() {
+        let mut tracker = mock_rekey_tracker(0);
+        assert!(tracker.previous_inbound_key().is_none());
+        let key = SymmetricKey::new(rand::random::<[u8; 16]>());
+        tracker.inbound_key_replaced(key);
+        assert!(tracker.previous_inbound_key().is_some());
+    }
+}
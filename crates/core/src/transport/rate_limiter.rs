@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use tokio::sync::mpsc;
 
 use std::collections::VecDeque;
@@ -5,23 +6,26 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use super::Socket;
+use super::{packet_pool::MAX_BATCH_SIZE, Socket};
 use crate::util::time_source::{InstantTimeSrc, TimeSource};
 
 /// Keeps track of the bandwidth used in the last window_size. Recommend a `window_size` of
 /// 10 seconds.
+///
+/// This is a node-wide ceiling shared by all connections, how fast each connection can send
+/// is governed by its own [`CongestionController`](super::congestion_control::CongestionController).
 pub(super) struct PacketRateLimiter<T: TimeSource> {
     packets: VecDeque<(usize, Instant)>,
     window_size: Duration,
     current_bandwidth: usize,
-    outbound_packets: mpsc::Receiver<(SocketAddr, Arc<[u8]>)>,
+    outbound_packets: mpsc::Receiver<(SocketAddr, Bytes)>,
     time_source: T,
 }
 
 impl PacketRateLimiter<InstantTimeSrc> {
     pub(super) fn new(
         window_size: Duration,
-        outbound_packets: mpsc::Receiver<(SocketAddr, Arc<[u8]>)>,
+        outbound_packets: mpsc::Receiver<(SocketAddr, Bytes)>,
     ) -> Self {
         PacketRateLimiter {
             packets: VecDeque::new(),
@@ -36,18 +40,22 @@ impl PacketRateLimiter<InstantTimeSrc> {
 impl<T: TimeSource> PacketRateLimiter<T> {
     pub(super) async fn rate_limiter<S: Socket>(mut self, bandwidth_limit: usize, socket: Arc<S>) {
         tracing::info!(bandwidth_limit, "Rate limiter task started");
-        while let Some((socket_addr, packet)) = self.outbound_packets.recv().await {
-            if let Some(wait_time) = self.can_send_packet(bandwidth_limit, packet.len()) {
-                tokio::time::sleep(wait_time).await;
-                if let Err(error) = socket.send_to(&packet, socket_addr).await {
-                    tracing::debug!("Error sending packet: {:?}", error);
-                    continue;
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        while let Some(packet) = self.outbound_packets.recv().await {
+            // queue whatever else is ready so it goes out with a single syscall
+            let mut next = Some(packet);
+            while let Some((socket_addr, packet)) = next.take() {
+                if let Some(wait_time) = self.can_send_packet(bandwidth_limit, packet.len()) {
+                    send_batch(&*socket, &mut batch).await;
+                    tokio::time::sleep(wait_time).await;
+                }
+                self.add_packet(packet.len());
+                batch.push((socket_addr, packet));
+                if batch.len() < MAX_BATCH_SIZE {
+                    next = self.outbound_packets.try_recv().ok();
                 }
-            } else if let Err(error) = socket.send_to(&packet, socket_addr).await {
-                tracing::debug!(%socket_addr, "Error sending packet: {:?}", error);
-                continue;
             }
-            self.add_packet(packet.len());
+            send_batch(&*socket, &mut batch).await;
         }
         tracing::debug!("Rate limiter task ended unexpectedly");
     }
@@ -107,6 +115,25 @@ impl<T: TimeSource> PacketRateLimiter<T> {
     }
 }
 
+/// Sends all the packets in `batch`, skipping the ones which fail.
+async fn send_batch<S: Socket>(socket: &S, batch: &mut Vec<(SocketAddr, Bytes)>) {
+    let mut pending = &batch[..];
+    while let Some((socket_addr, _)) = pending.first() {
+        match socket.send_batch(pending).await {
+            Ok(0) => {
+                tracing::debug!(%socket_addr, "Packet not sent");
+                pending = &pending[1..];
+            }
+            Ok(sent) => pending = &pending[sent..],
+            Err(error) => {
+                tracing::debug!(%socket_addr, "Error sending packet: {:?}", error);
+                pending = &pending[1..];
+            }
+        }
+    }
+    batch.clear();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,7 +56,13 @@ impl<T: TimeSource> ReceivedPacketTracker<T> {
         let current_time = self.time_source.now();
 
         match self.time_by_packet_id.entry(packet_id) {
-            std::collections::hash_map::Entry::Occupied(_) => ReportResult::AlreadyReceived,
+            std::collections::hash_map::Entry::Occupied(_) => {
+                // the remote probably didn't get our receipt, so acknowledge it again
+                if !self.pending_receipts.contains(&packet_id) {
+                    self.pending_receipts.push(packet_id);
+                }
+                ReportResult::AlreadyReceived
+            }
             std::collections::hash_map::Entry::Vacant(e) => {
                 e.insert(current_time);
                 self.packet_id_time.push_back((packet_id, current_time));
@@ -71,6 +77,11 @@ impl<T: TimeSource> ReceivedPacketTracker<T> {
         }
     }
 
+    /// Id of the last new packet received, if it was received recently.
+    pub(super) fn last_received(&self) -> Option<PacketId> {
+        self.packet_id_time.back().map(|(packet_id, _)| *packet_id)
+    }
+
     /// Returns a list of packets that have been received since the last call to this function.
     /// This should be called every time a packet is sent to ensure that receipts are sent
     /// promptly. Every `MAX_CONFIRMATION_DELAY` (50ms) this should be called and if the returned
@@ -0,0 +1,128 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+/// Marks a packet forwarded through a relay, followed by the address of the other end.
+const RELAY_PREFIX: [u8; 4] = *b"FNRL";
+
+/// `prefix | address family | ip (padded to 16 bytes) | port`
+pub(super) const RELAY_HEADER_SIZE: usize = RELAY_PREFIX.len() + 1 + 16 + 2;
+
+const IPV4: u8 = 4;
+const IPV6: u8 = 6;
+
+/// Prefixes a packet with the relay header.
+///
+/// Packets sent to a relay carry the address of the final destination, the relay replaces it
+/// with the address the packet came from before forwarding it, so the receiver learns the
+/// actual sender.
+pub(super) fn wrap(addr: SocketAddr, packet: &[u8]) -> Bytes {
+    let mut wrapped = Vec::with_capacity(RELAY_HEADER_SIZE + packet.len());
+    wrapped.extend_from_slice(&RELAY_PREFIX);
+    let mut ip = [0; 16];
+    match addr.ip() {
+        IpAddr::V4(v4) => {
+            wrapped.push(IPV4);
+            ip[..4].copy_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            wrapped.push(IPV6);
+            ip.copy_from_slice(&v6.octets());
+        }
+    }
+    wrapped.extend_from_slice(&ip);
+    wrapped.extend_from_slice(&addr.port().to_le_bytes());
+    wrapped.extend_from_slice(packet);
+    wrapped.into()
+}
+
+/// Splits a relayed packet into the address in its header and the inner packet.
+pub(super) fn unwrap(packet: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if packet.len() <= RELAY_HEADER_SIZE || packet[..RELAY_PREFIX.len()] != RELAY_PREFIX {
+        return None;
+    }
+    let (header, inner) = packet.split_at(RELAY_HEADER_SIZE);
+    let ip_bytes = &header[RELAY_PREFIX.len() + 1..RELAY_HEADER_SIZE - 2];
+    let ip = match header[RELAY_PREFIX.len()] {
+        IPV4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&ip_bytes[..4]).ok()?)),
+        IPV6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip_bytes).ok()?)),
+        _ => return None,
+    };
+    let port = u16::from_le_bytes(header[RELAY_HEADER_SIZE - 2..].try_into().ok()?);
+    Some((SocketAddr::new(ip, port), inner))
+}
+
+/// Packets relayed for other peers waiting for the rate limiter.
+const RELAY_QUEUE_SIZE: usize = 64;
+
+/// Returns a sender for the packets relayed for other peers, which a separate task hands to
+/// the rate limiter so the sender never waits for it. Packets are meant to be dropped when the
+/// queue is full, as a congested link would.
+pub(super) fn relay_queue(
+    outbound_packets: mpsc::Sender<(SocketAddr, Bytes)>,
+) -> mpsc::Sender<(SocketAddr, Bytes)> {
+    let (sender, mut packets) = mpsc::channel(RELAY_QUEUE_SIZE);
+    tokio::spawn(async move {
+        while let Some(packet) = packets.recv().await {
+            if outbound_packets.send(packet).await.is_err() {
+                break;
+            }
+        }
+    });
+    sender
+}
+
+/// Returns a sender for the packets of a connection going through `relay_addr`, which wraps
+/// them before handing them to the rate limiter. The forwarding task ends when the connection
+/// drops the sender.
+pub(super) fn relayed_sender(
+    relay_addr: SocketAddr,
+    outbound_packets: mpsc::Sender<(SocketAddr, Bytes)>,
+) -> mpsc::Sender<(SocketAddr, Bytes)> {
+    let (sender, mut packets) = mpsc::channel::<(SocketAddr, Bytes)>(1);
+    tokio::spawn(async move {
+        while let Some((remote_addr, packet)) = packets.recv().await {
+            if outbound_packets
+                .send((relay_addr, wrap(remote_addr, &packet)))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_unwrap() {
+        for addr in [
+            SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 1234)),
+            SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 4321)),
+        ] {
+            let wrapped = wrap(addr, &[1, 2, 3]);
+            assert_eq!(wrapped.len(), RELAY_HEADER_SIZE + 3);
+            assert_eq!(unwrap(&wrapped), Some((addr, &[1u8, 2, 3][..])));
+        }
+        assert_eq!(unwrap(&[0; RELAY_HEADER_SIZE + 3]), None);
+    }
+
+    #[tokio::test]
+    async fn relayed_sender_wraps_packets() {
+        let relay: SocketAddr = (Ipv4Addr::LOCALHOST, 1).into();
+        let remote: SocketAddr = (Ipv4Addr::LOCALHOST, 2).into();
+        let (outbound, mut sent) = mpsc::channel(1);
+        let sender = relayed_sender(relay, outbound);
+        sender.send((remote, vec![7].into())).await.unwrap();
+        let (addr, packet) = sent.recv().await.unwrap();
+        assert_eq!(addr, relay);
+        assert_eq!(unwrap(&packet), Some((remote, &[7u8][..])));
+        drop(sender);
+        assert!(sent.recv().await.is_none());
+    }
+}
@@ -1,14 +1,18 @@
-use super::PacketId;
+use super::{
+    congestion_control::CongestionController, packet_data::MAX_PACKET_SIZE,
+    symmetric_message::ReceiptRange, PacketId,
+};
 use crate::util::time_source::{InstantTimeSrc, TimeSource};
-use std::collections::{HashMap, VecDeque};
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+
 const NETWORK_DELAY_ALLOWANCE: Duration = Duration::from_millis(500);
 
 /// We can wait up to 100ms to confirm a message was received, this allows us to batch
 /// receipts together and send them in a single message.
-const MAX_CONFIRMATION_DELAY: Duration = Duration::from_millis(100);
+pub(super) const MAX_CONFIRMATION_DELAY: Duration = Duration::from_millis(100);
 
 /// If we don't get a receipt for a message within 500ms, we assume the message was lost and
 /// resend it. This must be significantly higher than MAX_CONFIRMATION_DELAY (100ms) to
@@ -29,12 +33,23 @@ pub(super) const MESSAGE_CONFIRMATION_TIMEOUT: Duration = {
 /// in a more accurate estimate, but it will take longer to converge to the true value.
 const PACKET_LOSS_DECAY_FACTOR: f64 = 1.0 / 1000.0;
 
+/// A packet is assumed lost, without waiting for `MESSAGE_CONFIRMATION_TIMEOUT`, once receipts
+/// arrived for this many packets sent after it (fast retransmit, as in RFC 5681).
+const FAST_RETRANSMIT_THRESHOLD: usize = 3;
+
 /// This struct is responsible for tracking packets that have been sent but not yet acknowledged.
 /// It is also responsible for deciding when to resend packets that have not been acknowledged.
 ///
+/// Receipt timing and losses are fed into a per-connection [`CongestionController`] which
+/// limits how many bytes can be in flight, callers sending bulk data should check
+/// `congestion_wait` before sending a new packet.
+///
 /// The caller must report when packets are sent and when receipts are received using the
 /// `report_sent_packet` and `report_received_receipts` functions. The caller must also call
-/// `get_resend` periodically to check if any packets need to be resent.
+/// `get_resend` periodically to check if any packets need to be resent. Packets are resent
+/// once `MESSAGE_CONFIRMATION_TIMEOUT` elapses or earlier, when receipts for
+/// `FAST_RETRANSMIT_THRESHOLD` packets sent after them arrive first, so callers should also call
+/// `get_resend` after reporting receipts.
 ///
 /// The expectation is that get_resend will be called as part of a loop that looks something like
 /// this:
@@ -53,13 +68,31 @@ const PACKET_LOSS_DECAY_FACTOR: f64 = 1.0 / 1000.0;
 /// }
 /// ```
 pub(super) struct SentPacketTracker<T: TimeSource> {
-    /// The list of packets that have been sent but not yet acknowledged
-    pending_receipts: HashMap<PacketId, Arc<[u8]>>,
+    /// The list of packets that have been sent but not yet acknowledged, along with when and
+    /// in which order they were sent
+    pending_receipts: HashMap<PacketId, (Bytes, Instant, u64)>,
 
     resend_queue: VecDeque<ResendQueueEntry>,
 
     packet_loss_proportion: f64,
 
+    /// Packets which have been resent at least once, receipts for those can't be
+    /// used to sample the RTT since it's ambiguous which transmission they refer to
+    retransmitted: HashSet<PacketId>,
+
+    /// Pending packets which are not resent if lost
+    unreliable: HashSet<PacketId>,
+
+    bytes_in_flight: usize,
+
+    /// Send order of the next packet, unlike packet ids it increases on every retransmission
+    next_send_order: u64,
+
+    /// Send order of the acknowledged packets which were sent after the oldest pending one
+    acked_send_order: BTreeSet<u64>,
+
+    congestion: CongestionController,
+
     pub(super) time_source: T,
 }
 
@@ -69,6 +102,12 @@ impl SentPacketTracker<InstantTimeSrc> {
             pending_receipts: HashMap::new(),
             resend_queue: VecDeque::new(),
             packet_loss_proportion: 0.0,
+            retransmitted: HashSet::new(),
+            unreliable: HashSet::new(),
+            bytes_in_flight: 0,
+            next_send_order: 0,
+            acked_send_order: BTreeSet::new(),
+            congestion: CongestionController::new(MAX_PACKET_SIZE),
             time_source: InstantTimeSrc::new(),
         }
     }
@@ -84,22 +123,114 @@ impl<T: TimeSource> SentPacketTracker<T> {
         self.packet_loss_proportion
     }
 
-    pub(super) fn report_sent_packet(&mut self, packet_id: PacketId, payload: Arc<[u8]>) {
-        self.pending_receipts.insert(packet_id, payload);
+    /// Smoothed round-trip time to the remote as measured from receipts.
+    pub(super) fn smoothed_rtt(&self) -> Duration {
+        self.congestion.rtt().smoothed()
+    }
+
+    /// Report a round-trip time measured outside of receipts, e.g. by a keep-alive ping.
+    pub(super) fn report_rtt_sample(&mut self, rtt: Duration) {
+        self.congestion.on_rtt_sample(rtt);
+    }
+
+    /// Current congestion window in bytes.
+    pub(super) fn congestion_window(&self) -> usize {
+        self.congestion.window()
+    }
+
+    pub(super) fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    /// Whether the packet was sent and its receipt didn't arrive yet.
+    pub(super) fn is_pending(&self, packet_id: PacketId) -> bool {
+        self.pending_receipts.contains_key(&packet_id)
+    }
+
+    /// Returns none if a packet of `packet_size` bytes can be sent right away without exceeding
+    /// the congestion window, otherwise returns how long to wait before checking again.
+    pub(super) fn congestion_wait(&self, packet_size: usize) -> Option<Duration> {
+        if self.congestion.can_send(self.bytes_in_flight, packet_size) {
+            None
+        } else {
+            Some(self.congestion.blocked_poll_interval())
+        }
+    }
+
+    pub(super) fn report_sent_packet(&mut self, packet_id: PacketId, payload: Bytes) {
+        let now = self.time_source.now();
+        let send_order = self.next_send_order;
+        self.next_send_order += 1;
+        self.bytes_in_flight += payload.len();
+        if let Some((previous, ..)) = self
+            .pending_receipts
+            .insert(packet_id, (payload, now, send_order))
+        {
+            self.bytes_in_flight -= previous.len();
+        }
         self.resend_queue.push_back(ResendQueueEntry {
-            timeout_at: self.time_source.now() + MESSAGE_CONFIRMATION_TIMEOUT,
+            timeout_at: now + MESSAGE_CONFIRMATION_TIMEOUT,
             packet_id,
+            send_order,
         });
     }
 
-    pub(super) fn report_received_receipts(&mut self, packet_ids: &[PacketId]) {
+    /// Reports a packet which is not resent if lost, e.g. stream parity. It still counts
+    /// against the congestion window until acknowledged or lost.
+    pub(super) fn report_sent_unreliable_packet(&mut self, packet_id: PacketId, payload: Bytes) {
+        self.unreliable.insert(packet_id);
+        self.report_sent_packet(packet_id, payload);
+    }
+
+    /// Returns the size of the largest packet acknowledged, 0 if none was pending.
+    pub(super) fn report_received_receipts(&mut self, packet_ids: &[PacketId]) -> usize {
+        let now = self.time_source.now();
+        let mut largest_acked = 0;
         for packet_id in packet_ids {
             // This can be simplified but I'm leaving it like this for readability.
             self.packet_loss_proportion = self.packet_loss_proportion
                 * (1.0 - PACKET_LOSS_DECAY_FACTOR)
                 + (PACKET_LOSS_DECAY_FACTOR * 0.0);
-            self.pending_receipts.remove(packet_id);
+            if let Some((payload, sent_at, send_order)) = self.pending_receipts.remove(packet_id) {
+                self.unreliable.remove(packet_id);
+                self.bytes_in_flight -= payload.len();
+                self.acked_send_order.insert(send_order);
+                let rtt_sample =
+                    (!self.retransmitted.remove(packet_id)).then(|| now.duration_since(sent_at));
+                self.congestion.on_ack(now, payload.len(), rtt_sample);
+                largest_acked = largest_acked.max(payload.len());
+            }
         }
+        largest_acked
+    }
+
+    /// Returns the pending packets acknowledged by the given ranges. Ranges are matched against
+    /// the pending packets instead of being expanded, since each of them can cover up to 65535
+    /// packet ids.
+    pub(super) fn pending_in(&self, ranges: &[ReceiptRange]) -> Vec<PacketId> {
+        let mut pending: Vec<PacketId> = self.pending_receipts.keys().copied().collect();
+        pending.sort_unstable();
+        let mut acked = Vec::new();
+        let mut add_between = |from: PacketId, to: PacketId| {
+            let start = pending.partition_point(|packet_id| *packet_id < from);
+            acked.extend(
+                pending[start..]
+                    .iter()
+                    .take_while(|packet_id| **packet_id <= to),
+            );
+        };
+        for range in ranges {
+            let Some(last) = range.last() else {
+                continue;
+            };
+            if range.first <= last {
+                add_between(range.first, last);
+            } else {
+                add_between(range.first, PacketId::MAX);
+                add_between(0, last);
+            }
+        }
+        acked
     }
 
     /// Either get a packet that needs to be resent, or how long the caller should wait until
@@ -109,26 +240,46 @@ impl<T: TimeSource> SentPacketTracker<T> {
         let now = self.time_source.now();
 
         while let Some(entry) = self.resend_queue.pop_front() {
-            if entry.timeout_at > now {
-                if !self.pending_receipts.contains_key(&entry.packet_id) {
-                    continue;
-                }
+            let Some(&(_, _, send_order)) = self.pending_receipts.get(&entry.packet_id) else {
+                // If the packet is no longer in pending_receipts, it means its receipt has been
+                // received. No action needed, continue to check the next entry in the queue.
+                continue;
+            };
+            if send_order != entry.send_order {
+                // the packet was sent again since, a later entry tracks it
+                continue;
+            }
+            // this is the oldest pending packet, receipts of packets sent before it don't matter
+            self.acked_send_order = self.acked_send_order.split_off(&send_order);
+            let acked_after = self
+                .acked_send_order
+                .range(send_order + 1..)
+                .take(FAST_RETRANSMIT_THRESHOLD)
+                .count();
+            if entry.timeout_at > now && acked_after < FAST_RETRANSMIT_THRESHOLD {
                 let wait_until = entry.timeout_at;
                 self.resend_queue.push_front(entry);
                 return ResendAction::WaitUntil(wait_until);
-            } else if let Some(packet) = self.pending_receipts.remove(&entry.packet_id) {
-                // Update packet loss proportion for a lost packet
-                // Resend logic
-                self.packet_loss_proportion = self.packet_loss_proportion
-                    * (1.0 - PACKET_LOSS_DECAY_FACTOR)
-                    + PACKET_LOSS_DECAY_FACTOR;
-
-                return ResendAction::Resend(entry.packet_id, packet);
             }
-            // If the packet is no longer in pending_receipts, it means its receipt has been received.
-            // No action needed, continue to check the next entry in the queue.
+            let Some((packet, sent_at, _)) = self.pending_receipts.remove(&entry.packet_id) else {
+                unreachable!("checked above");
+            };
+            // Update packet loss proportion for a lost packet
+            // Resend logic
+            self.packet_loss_proportion = self.packet_loss_proportion
+                * (1.0 - PACKET_LOSS_DECAY_FACTOR)
+                + PACKET_LOSS_DECAY_FACTOR;
+            self.bytes_in_flight -= packet.len();
+            self.congestion.on_loss(now, sent_at);
+            if self.unreliable.remove(&entry.packet_id) {
+                continue;
+            }
+            self.retransmitted.insert(entry.packet_id);
+
+            return ResendAction::Resend(entry.packet_id, packet);
         }
 
+        self.acked_send_order.clear();
         ResendAction::WaitUntil(now + MESSAGE_CONFIRMATION_TIMEOUT)
     }
 }
@@ -136,12 +287,13 @@ impl<T: TimeSource> SentPacketTracker<T> {
 #[derive(Debug, PartialEq)]
 pub enum ResendAction {
     WaitUntil(Instant),
-    Resend(u32, Arc<[u8]>),
+    Resend(u32, Bytes),
 }
 
 struct ResendQueueEntry {
     timeout_at: Instant,
     packet_id: u32,
+    send_order: u64,
 }
 
 // Unit tests
@@ -158,10 +310,23 @@ pub(in crate::transport) mod tests {
             pending_receipts: HashMap::new(),
             resend_queue: VecDeque::new(),
             packet_loss_proportion: 0.0,
+            retransmitted: HashSet::new(),
+            unreliable: HashSet::new(),
+            bytes_in_flight: 0,
+            next_send_order: 0,
+            acked_send_order: BTreeSet::new(),
+            congestion: CongestionController::new(MAX_PACKET_SIZE),
             time_source,
         }
     }
 
+    pub(in crate::transport) fn set_packet_loss<T: TimeSource>(
+        tracker: &mut SentPacketTracker<T>,
+        packet_loss: f64,
+    ) {
+        tracker.packet_loss_proportion = packet_loss;
+    }
+
     #[test]
     fn test_report_sent_packet() {
         let mut tracker = mock_sent_packet_tracker();
@@ -171,6 +336,23 @@ pub(in crate::transport) mod tests {
         assert_eq!(tracker.packet_loss_proportion, 0.0);
     }
 
+    #[test]
+    fn pending_acknowledged_by_ranges() {
+        let mut tracker = mock_sent_packet_tracker();
+        for packet_id in [1, 2, 5, 100_000, u32::MAX - 1, u32::MAX] {
+            tracker.report_sent_packet(packet_id, vec![1, 2, 3].into());
+        }
+        let range = |first, len| ReceiptRange { first, len };
+        let mut acked = tracker.pending_in(&[range(0, u16::MAX), range(3, 2), range(0, 0)]);
+        acked.sort_unstable();
+        assert_eq!(acked, vec![1, 2, 5]);
+
+        // ranges can wrap around
+        let mut acked = tracker.pending_in(&[range(u32::MAX, 3)]);
+        acked.sort_unstable();
+        assert_eq!(acked, vec![1, u32::MAX]);
+    }
+
     #[test]
     fn test_report_received_receipts() {
         let mut tracker = mock_sent_packet_tracker();
@@ -253,4 +435,128 @@ pub(in crate::transport) mod tests {
             _ => panic!("Expected ResendAction::WaitUntil"),
         }
     }
+
+    #[test]
+    fn test_bytes_in_flight_and_rtt() {
+        let mut tracker = mock_sent_packet_tracker();
+        tracker.report_sent_packet(1, vec![0; 100].into());
+        tracker.report_sent_packet(2, vec![0; 200].into());
+        assert_eq!(tracker.bytes_in_flight(), 300);
+
+        tracker.time_source.advance_time(Duration::from_millis(80));
+        tracker.report_received_receipts(&[1]);
+        assert_eq!(tracker.bytes_in_flight(), 200);
+        assert_eq!(tracker.smoothed_rtt(), Duration::from_millis(80));
+
+        // receipts for retransmitted packets are not used as RTT samples
+        tracker
+            .time_source
+            .advance_time(MESSAGE_CONFIRMATION_TIMEOUT);
+        let ResendAction::Resend(id, packet) = tracker.get_resend() else {
+            panic!("expected resend");
+        };
+        assert_eq!(tracker.bytes_in_flight(), 0);
+        tracker.report_sent_packet(id, packet);
+        assert_eq!(tracker.bytes_in_flight(), 200);
+        tracker.time_source.advance_time(Duration::from_millis(10));
+        tracker.report_received_receipts(&[2]);
+        assert_eq!(tracker.bytes_in_flight(), 0);
+        assert_eq!(tracker.smoothed_rtt(), Duration::from_millis(80));
+    }
+
+    #[test]
+    fn test_congestion_window_blocks_sending() {
+        let mut tracker = mock_sent_packet_tracker();
+        let window = tracker.congestion_window();
+        let mut packet_id = 0;
+        while tracker.congestion_wait(MAX_PACKET_SIZE).is_none() {
+            tracker.report_sent_packet(packet_id, vec![0; MAX_PACKET_SIZE].into());
+            packet_id += 1;
+        }
+        assert_eq!(tracker.bytes_in_flight(), window);
+
+        // losing a packet shrinks the window
+        tracker
+            .time_source
+            .advance_time(MESSAGE_CONFIRMATION_TIMEOUT);
+        assert!(matches!(tracker.get_resend(), ResendAction::Resend(..)));
+        assert!(tracker.congestion_window() < window);
+
+        // receipts free up space in the window again
+        tracker.report_received_receipts(&(1..packet_id).collect::<Vec<_>>());
+        assert_eq!(tracker.bytes_in_flight(), 0);
+        assert!(tracker.congestion_wait(MAX_PACKET_SIZE).is_none());
+    }
+
+    #[test]
+    fn unreliable_packets_not_resent() {
+        let mut tracker = mock_sent_packet_tracker();
+        tracker.report_sent_unreliable_packet(1, vec![1, 2, 3].into());
+        tracker.report_sent_packet(2, vec![4, 5].into());
+        assert_eq!(tracker.bytes_in_flight(), 5);
+
+        tracker
+            .time_source
+            .advance_time(MESSAGE_CONFIRMATION_TIMEOUT);
+        // the unreliable packet is counted as lost and only the other one is resent
+        assert_eq!(
+            tracker.get_resend(),
+            ResendAction::Resend(2, vec![4, 5].into())
+        );
+        assert_eq!(tracker.bytes_in_flight(), 0);
+        assert!(tracker.get_recent_packet_loss() > 0.0);
+        assert!(tracker.unreliable.is_empty());
+    }
+
+    #[test]
+    fn test_fast_retransmit() {
+        let mut tracker = mock_sent_packet_tracker();
+        for id in 0..5 {
+            tracker.report_sent_packet(id, vec![id as u8].into());
+        }
+
+        // two out-of-order receipts are not enough to assume packet 0 was lost
+        tracker.report_received_receipts(&[1, 2]);
+        assert!(matches!(tracker.get_resend(), ResendAction::WaitUntil(_)));
+
+        tracker.report_received_receipts(&[3]);
+        assert_eq!(
+            tracker.get_resend(),
+            ResendAction::Resend(0, vec![0].into())
+        );
+        assert_eq!(tracker.packet_loss_proportion, PACKET_LOSS_DECAY_FACTOR);
+        tracker.report_sent_packet(0, vec![0].into());
+
+        // no receipts arrived for packets sent after packet 4, so it waits for the timeout
+        match tracker.get_resend() {
+            ResendAction::WaitUntil(wait_until) => assert_eq!(
+                wait_until,
+                tracker.time_source.now() + MESSAGE_CONFIRMATION_TIMEOUT
+            ),
+            ResendAction::Resend(..) => panic!("unexpected fast retransmit"),
+        }
+    }
+
+    #[test]
+    fn test_fast_retransmit_only_counts_later_transmissions() {
+        let mut tracker = mock_sent_packet_tracker();
+        for id in 0..4 {
+            tracker.report_sent_packet(id, vec![id as u8].into());
+        }
+        tracker.report_received_receipts(&[1, 2, 3]);
+        let ResendAction::Resend(0, packet) = tracker.get_resend() else {
+            panic!("expected fast retransmit of packet 0");
+        };
+        tracker.report_sent_packet(0, packet);
+
+        // the receipts which triggered the retransmission predate it
+        assert!(matches!(tracker.get_resend(), ResendAction::WaitUntil(_)));
+        for id in 4..7 {
+            tracker.report_sent_packet(id, vec![id as u8].into());
+        }
+        tracker.report_received_receipts(&[4, 5]);
+        assert!(matches!(tracker.get_resend(), ResendAction::WaitUntil(_)));
+        tracker.report_received_receipts(&[6]);
+        assert!(matches!(tracker.get_resend(), ResendAction::Resend(0, _)));
+    }
 }
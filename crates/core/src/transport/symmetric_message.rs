@@ -1,13 +1,14 @@
 use std::{borrow::Cow, net::SocketAddr, sync::OnceLock};
 
 use crate::transport::packet_data::SymmetricAES;
-use aes_gcm::Aes128Gcm;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
 use super::{
-    packet_data::MAX_DATA_SIZE, peer_connection::StreamId, MessagePayload, PacketData, PacketId,
+    packet_data::{self, SymmetricKey, MAX_DATA_SIZE, MAX_PACKET_SIZE},
+    peer_connection::StreamId,
+    MessagePayload, PacketData, PacketId,
 };
 
 #[serde_as]
@@ -64,18 +65,99 @@ impl SymmetricMessage {
         *OVERHEAD
     }
 
-    pub(crate) fn max_num_of_confirm_receipts_of_noop_message() -> usize {
+    /// Amount of receipts which fit in a noop packet of the given size.
+    pub(crate) fn max_num_of_confirm_receipts_of_noop_message(packet_size: usize) -> usize {
         static MAX_NUM_CONFIRM_RECEIPTS: Lazy<usize> = Lazy::new(|| {
             let overhead = SymmetricMessage::noop_message_overhead() as u64;
             let max_elems = (MAX_DATA_SIZE as u64 - overhead) / core::mem::size_of::<u32>() as u64;
             max_elems as usize
         });
 
-        *MAX_NUM_CONFIRM_RECEIPTS
+        // smaller packets fit as many receipts less as it takes to fill the missing bytes
+        let missing = MAX_PACKET_SIZE - packet_size;
+        let receipt_size = core::mem::size_of::<u32>();
+        *MAX_NUM_CONFIRM_RECEIPTS - (missing + receipt_size - 1) / receipt_size
+    }
+
+    /// Builds a noop padded with copies of a receipt so the encrypted packet is `packet_size`
+    /// long, exactly if the size is a multiple of 4. Used as a path MTU probe for peers which
+    /// can't decode `PmtuProbe`, since those acknowledge every packet.
+    pub fn padded_noop(
+        packet_id: PacketId,
+        packet_size: usize,
+        receipt: PacketId,
+        outbound_sym_key: &SymmetricKey,
+    ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
+        let receipts = Self::max_num_of_confirm_receipts_of_noop_message(packet_size);
+        let message = Self {
+            packet_id,
+            confirm_receipt: vec![receipt; receipts],
+            payload: SymmetricMessagePayload::NoOp,
+        };
+        message.to_packet_data(outbound_sym_key)
+    }
+
+    fn receipt_ranges_overhead() -> usize {
+        static OVERHEAD: Lazy<usize> = Lazy::new(|| {
+            let blank = SymmetricMessage {
+                packet_id: u32::MAX,
+                confirm_receipt: vec![],
+                payload: SymmetricMessagePayload::ReceiptRanges { ranges: vec![] },
+            };
+            bincode::serialized_size(&blank).unwrap() as usize
+        });
+
+        *OVERHEAD
+    }
+
+    /// Amount of receipt ranges which fit in a packet of the given size.
+    pub(crate) fn max_num_of_receipt_ranges(packet_size: usize) -> usize {
+        static RANGE_SIZE: Lazy<usize> = Lazy::new(|| {
+            bincode::serialized_size(&ReceiptRange {
+                first: u32::MAX,
+                len: u16::MAX,
+            })
+            .unwrap() as usize
+        });
+        (packet_data::data_size(packet_size) - Self::receipt_ranges_overhead()) / *RANGE_SIZE
+    }
+
+    fn pmtu_probe_overhead() -> usize {
+        static OVERHEAD: Lazy<usize> = Lazy::new(|| {
+            let blank = SymmetricMessage {
+                packet_id: u32::MAX,
+                confirm_receipt: vec![],
+                payload: SymmetricMessagePayload::PmtuProbe {
+                    size: u16::MAX,
+                    padding: vec![],
+                },
+            };
+            bincode::serialized_size(&blank).unwrap() as usize
+        });
+
+        *OVERHEAD
+    }
+
+    /// Builds a path MTU probe padded so the encrypted packet is exactly `packet_size` long.
+    pub fn pmtu_probe(
+        packet_id: PacketId,
+        packet_size: usize,
+        outbound_sym_key: &SymmetricKey,
+    ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
+        let padding = packet_data::data_size(packet_size) - Self::pmtu_probe_overhead();
+        let message = Self {
+            packet_id,
+            confirm_receipt: vec![],
+            payload: SymmetricMessagePayload::PmtuProbe {
+                size: packet_size as u16,
+                padding: vec![0; padding],
+            },
+        };
+        message.to_packet_data(outbound_sym_key)
     }
 
     pub fn ack_error(
-        outbound_sym_key: &Aes128Gcm,
+        outbound_sym_key: &SymmetricKey,
     ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
         static SERIALIZED: OnceLock<Box<[u8]>> = OnceLock::new();
         let bytes = SERIALIZED.get_or_init(|| {
@@ -89,7 +171,7 @@ impl SymmetricMessage {
     }
 
     pub fn ack_ok(
-        outbound_sym_key: &Aes128Gcm,
+        outbound_sym_key: &SymmetricKey,
         our_inbound_key: [u8; 16],
         remote_addr: SocketAddr,
     ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
@@ -103,22 +185,51 @@ impl SymmetricMessage {
                 }),
             },
         };
-        let mut packet = [0u8; MAX_DATA_SIZE];
-        let size = bincode::serialized_size(&message)?;
-        debug_assert!(size <= MAX_DATA_SIZE as u64);
-        bincode::serialize_into(packet.as_mut_slice(), &message)?;
-        let bytes = &packet[..size as usize];
+        message.to_packet_data(outbound_sym_key)
+    }
 
-        let packet = PacketData::from_buf_plain(bytes);
-        Ok(packet.encrypt_symmetric(outbound_sym_key))
+    /// Acknowledges a connection using protocol version 2, which includes this peer ephemeral key
+    /// so both sides can derive the session keys.
+    ///
+    /// The features supported by this peer are included when the remote announced its own,
+    /// peers which didn't can't read them.
+    pub fn ack_ok_v2(
+        outbound_sym_key: &SymmetricKey,
+        our_inbound_key: [u8; 16],
+        ephemeral_key: [u8; 32],
+        remote_addr: SocketAddr,
+        features: Option<u8>,
+    ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
+        let connection = OutboundConnection {
+            key: our_inbound_key,
+            remote_addr,
+        };
+        let payload = match features {
+            Some(features) => SymmetricMessagePayload::AckConnectionV3 {
+                connection,
+                ephemeral_key,
+                features,
+            },
+            None => SymmetricMessagePayload::AckConnectionV2 {
+                connection,
+                ephemeral_key,
+            },
+        };
+        let message = Self {
+            packet_id: Self::FIRST_PACKET_ID,
+            confirm_receipt: vec![],
+            payload,
+        };
+        message.to_packet_data(outbound_sym_key)
     }
 
     #[allow(clippy::type_complexity)]
     pub fn try_serialize_msg_to_packet_data(
         packet_id: PacketId,
         payload: impl Into<SymmetricMessagePayload>,
-        outbound_sym_key: &Aes128Gcm,
+        outbound_sym_key: &SymmetricKey,
         confirm_receipt: Vec<u32>,
+        packet_size: usize,
     ) -> Result<
         either::Either<PacketData<SymmetricAES>, (SymmetricMessagePayload, Vec<u32>)>,
         bincode::Error,
@@ -130,7 +241,7 @@ impl SymmetricMessage {
         };
 
         let size = bincode::serialized_size(&msg)?;
-        if size <= MAX_DATA_SIZE as u64 {
+        if size <= packet_data::data_size(packet_size) as u64 {
             let mut packet = [0u8; MAX_DATA_SIZE];
             bincode::serialize_into(packet.as_mut_slice(), &msg)?;
             let bytes = &packet[..size as usize];
@@ -144,7 +255,7 @@ impl SymmetricMessage {
     pub fn serialize_msg_to_packet_data(
         packet_id: PacketId,
         payload: impl Into<SymmetricMessagePayload>,
-        outbound_sym_key: &Aes128Gcm,
+        outbound_sym_key: &SymmetricKey,
         confirm_receipt: Vec<u32>,
     ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
         let message = Self {
@@ -158,7 +269,7 @@ impl SymmetricMessage {
 
     pub(crate) fn to_packet_data(
         &self,
-        outbound_sym_key: &Aes128Gcm,
+        outbound_sym_key: &SymmetricKey,
     ) -> Result<PacketData<SymmetricAES>, bincode::Error> {
         let mut packet = [0u8; MAX_DATA_SIZE];
         let size = bincode::serialized_size(self)?;
@@ -204,6 +315,69 @@ impl From<StreamFragment> for SymmetricMessagePayload {
     }
 }
 
+pub(super) struct StreamParity {
+    pub stream_id: StreamId,
+    pub first_fragment: u32,
+    pub fragments: Vec<(PacketId, u16)>,
+    pub parity_index: u8,
+    pub payload: MessagePayload,
+}
+
+impl From<StreamParity> for SymmetricMessagePayload {
+    fn from(stream_parity: StreamParity) -> Self {
+        Self::StreamParity {
+            stream_id: stream_parity.stream_id,
+            first_fragment: stream_parity.first_fragment,
+            fragments: stream_parity.fragments,
+            parity_index: stream_parity.parity_index,
+            payload: stream_parity.payload,
+        }
+    }
+}
+
+/// Receipts for `len` consecutive packet ids starting at `first`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[cfg_attr(test, derive(PartialEq, Debug))]
+pub(super) struct ReceiptRange {
+    pub first: PacketId,
+    pub len: u16,
+}
+
+impl ReceiptRange {
+    /// Encodes receipts as ranges of consecutive packet ids, duplicates are ignored.
+    pub fn encode(receipts: &[PacketId]) -> Vec<ReceiptRange> {
+        let mut receipts = receipts.to_vec();
+        receipts.sort_unstable();
+        receipts.dedup();
+        let mut ranges: Vec<ReceiptRange> = Vec::new();
+        for packet_id in receipts {
+            match ranges.last_mut() {
+                Some(range)
+                    if range.len < u16::MAX
+                        && range.first.checked_add(range.len as u32) == Some(packet_id) =>
+                {
+                    range.len += 1;
+                }
+                _ => ranges.push(ReceiptRange {
+                    first: packet_id,
+                    len: 1,
+                }),
+            }
+        }
+        ranges
+    }
+
+    /// Last packet id in the range, the range may wrap around `u32::MAX`.
+    pub fn last(self) -> Option<PacketId> {
+        (self.len > 0).then(|| self.first.wrapping_add(self.len as u32 - 1))
+    }
+
+    #[cfg(test)]
+    pub fn packet_ids(self) -> impl Iterator<Item = PacketId> {
+        (0..self.len as u32).map(move |offset| self.first.wrapping_add(offset))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq, Debug, Clone))]
 pub(super) struct OutboundConnection {
@@ -229,6 +403,59 @@ pub(super) enum SymmetricMessagePayload {
         payload: MessagePayload,
     },
     NoOp,
+    AckConnectionV2 {
+        // same as `AckConnection` but also returns the ephemeral key of the remote,
+        // session keys are derived from it after the handshake
+        connection: OutboundConnection,
+        ephemeral_key: [u8; 32],
+    },
+    Rekey {
+        // new key the sender will use for the packets after this one
+        key: [u8; 16],
+    },
+    PathChallenge {
+        // sent to the new address of a remote, which must echo the token back
+        // before the connection is moved to that address
+        token: [u8; 8],
+    },
+    PathResponse {
+        token: [u8; 8],
+    },
+    PmtuProbe {
+        // size of the whole packet, the padding fills it up to that size
+        size: u16,
+        padding: Vec<u8>,
+    },
+    PmtuProbeAck {
+        size: u16,
+    },
+    ReceiptRanges {
+        // like `NoOp` with the receipts encoded as ranges, so many more fit in a packet
+        ranges: Vec<ReceiptRange>,
+    },
+    AckConnectionV3 {
+        // same as `AckConnectionV2` but also returns the features supported by the remote,
+        // only sent to peers which announced their features in the intro packet
+        connection: OutboundConnection,
+        ephemeral_key: [u8; 32],
+        features: u8,
+    },
+    StreamParity {
+        // parity of the fragments `first_fragment..first_fragment + fragments.len()` of a
+        // stream, along with the packet id and length of each of those fragments
+        stream_id: StreamId,
+        first_fragment: u32,
+        fragments: Vec<(PacketId, u16)>,
+        parity_index: u8,
+        payload: MessagePayload,
+    },
+    Ping {
+        // sent to idle connections, only to peers which announced they answer them
+        seq: u32,
+    },
+    Pong {
+        seq: u32,
+    },
 }
 
 #[cfg(test)]
@@ -255,6 +482,29 @@ impl std::fmt::Display for SymmetricMessagePayload {
                 stream_id, fragment_number
             ),
             SymmetricMessagePayload::NoOp => write!(f, "NoOp"),
+            SymmetricMessagePayload::AckConnectionV2 { .. } => write!(f, "AckConnectionV2"),
+            SymmetricMessagePayload::Rekey { .. } => write!(f, "Rekey"),
+            SymmetricMessagePayload::PathChallenge { .. } => write!(f, "PathChallenge"),
+            SymmetricMessagePayload::PathResponse { .. } => write!(f, "PathResponse"),
+            SymmetricMessagePayload::PmtuProbe { size, .. } => write!(f, "PmtuProbe: {size}"),
+            SymmetricMessagePayload::PmtuProbeAck { size } => write!(f, "PmtuProbeAck: {size}"),
+            SymmetricMessagePayload::ReceiptRanges { ranges } => {
+                write!(f, "ReceiptRanges: {}", ranges.len())
+            }
+            SymmetricMessagePayload::AckConnectionV3 { features, .. } => {
+                write!(f, "AckConnectionV3: {features:#04x}")
+            }
+            SymmetricMessagePayload::StreamParity {
+                stream_id,
+                first_fragment,
+                parity_index,
+                ..
+            } => write!(
+                f,
+                "StreamParity: (stream id: {stream_id}, first fragment: {first_fragment}, parity no: {parity_index})"
+            ),
+            SymmetricMessagePayload::Ping { seq } => write!(f, "Ping: {seq}"),
+            SymmetricMessagePayload::Pong { seq } => write!(f, "Pong: {seq}"),
         }
     }
 }
@@ -263,18 +513,16 @@ impl std::fmt::Display for SymmetricMessagePayload {
 mod test {
     use std::net::Ipv4Addr;
 
-    use aes_gcm::KeyInit;
-
     use super::*;
 
-    fn gen_key() -> Aes128Gcm {
+    fn gen_key() -> SymmetricKey {
         let key = rand::random::<[u8; 16]>();
-        Aes128Gcm::new(&key.into())
+        SymmetricKey::new(key)
     }
 
     fn serialization_round_trip(
         payload: impl Into<SymmetricMessagePayload>,
-        key: &Aes128Gcm,
+        key: &SymmetricKey,
     ) -> SymmetricMessagePayload {
         let enc_sym_packet =
             SymmetricMessage::serialize_msg_to_packet_data(1, payload, key, vec![]).unwrap();
@@ -312,6 +560,41 @@ mod test {
                     .collect(),
             },
             SymmetricMessagePayload::NoOp,
+            SymmetricMessagePayload::AckConnectionV2 {
+                connection: OutboundConnection {
+                    key: [0; 16],
+                    remote_addr: (Ipv4Addr::LOCALHOST, 1234).into(),
+                },
+                ephemeral_key: [1; 32],
+            },
+            SymmetricMessagePayload::Rekey { key: [2; 16] },
+            SymmetricMessagePayload::PathChallenge { token: [3; 8] },
+            SymmetricMessagePayload::PathResponse { token: [3; 8] },
+            SymmetricMessagePayload::PmtuProbe {
+                size: 1300,
+                padding: vec![0; 100],
+            },
+            SymmetricMessagePayload::PmtuProbeAck { size: 1300 },
+            SymmetricMessagePayload::ReceiptRanges {
+                ranges: ReceiptRange::encode(&[1, 2, 3, 7, 9, 10]),
+            },
+            SymmetricMessagePayload::AckConnectionV3 {
+                connection: OutboundConnection {
+                    key: [0; 16],
+                    remote_addr: (Ipv4Addr::LOCALHOST, 1234).into(),
+                },
+                ephemeral_key: [1; 32],
+                features: 1,
+            },
+            SymmetricMessagePayload::StreamParity {
+                stream_id: StreamId::next(),
+                first_fragment: 1,
+                fragments: vec![(4, 60), (5, 40)],
+                parity_index: 0,
+                payload: vec![7; 60],
+            },
+            SymmetricMessagePayload::Ping { seq: 3 },
+            SymmetricMessagePayload::Pong { seq: 3 },
         ];
         let key = gen_key();
 
@@ -359,9 +642,31 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn pmtu_probe_size() -> Result<(), Box<dyn std::error::Error>> {
+        let key = gen_key();
+        for size in [
+            packet_data::MIN_PACKET_SIZE,
+            1400,
+            packet_data::MAX_PACKET_SIZE,
+        ] {
+            let packet = SymmetricMessage::pmtu_probe(1, size, &key)?;
+            assert_eq!(packet.data().len(), size);
+            let data = packet.decrypt(&key).unwrap();
+            let deser = SymmetricMessage::deser(data.data())?;
+            assert!(matches!(
+                deser.payload,
+                SymmetricMessagePayload::PmtuProbe { size: probe_size, .. } if probe_size as usize == size
+            ));
+        }
+        Ok(())
+    }
+
     #[test]
     fn max_confirm_receipts_of_noop_message() {
-        let num = SymmetricMessage::max_num_of_confirm_receipts_of_noop_message();
+        let num = SymmetricMessage::max_num_of_confirm_receipts_of_noop_message(
+            packet_data::MAX_PACKET_SIZE,
+        );
 
         let msg = SymmetricMessage {
             packet_id: u32::MAX,
@@ -372,6 +677,86 @@ mod test {
         assert_eq!(size, MAX_DATA_SIZE as u64);
     }
 
+    #[test]
+    fn max_confirm_receipts_of_smaller_noop_message() {
+        for packet_size in [packet_data::MIN_PACKET_SIZE, 1399, 1401] {
+            let num = SymmetricMessage::max_num_of_confirm_receipts_of_noop_message(packet_size);
+            let msg = SymmetricMessage {
+                packet_id: u32::MAX,
+                confirm_receipt: vec![u32::MAX; num],
+                payload: SymmetricMessagePayload::NoOp,
+            };
+            let size = bincode::serialized_size(&msg).unwrap() as usize;
+            assert!(size <= packet_data::data_size(packet_size));
+            assert!(size + core::mem::size_of::<u32>() > packet_data::data_size(packet_size));
+        }
+    }
+
+    #[test]
+    fn padded_noop_size() -> Result<(), Box<dyn std::error::Error>> {
+        let key = gen_key();
+        for size in [
+            packet_data::MIN_PACKET_SIZE,
+            1400,
+            packet_data::MAX_PACKET_SIZE,
+        ] {
+            let packet = SymmetricMessage::padded_noop(1, size, 7, &key)?;
+            assert_eq!(packet.data().len(), size);
+            let data = packet.decrypt(&key).unwrap();
+            let deser = SymmetricMessage::deser(data.data())?;
+            assert!(matches!(deser.payload, SymmetricMessagePayload::NoOp));
+            assert!(deser.confirm_receipt.iter().all(|receipt| *receipt == 7));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_receipt_ranges() {
+        let num = SymmetricMessage::max_num_of_receipt_ranges(packet_data::MAX_PACKET_SIZE);
+        let range = ReceiptRange {
+            first: u32::MAX,
+            len: u16::MAX,
+        };
+        let mut msg = SymmetricMessage {
+            packet_id: u32::MAX,
+            confirm_receipt: vec![],
+            payload: SymmetricMessagePayload::ReceiptRanges {
+                ranges: vec![range; num],
+            },
+        };
+        let size = bincode::serialized_size(&msg).unwrap();
+        assert!(size <= MAX_DATA_SIZE as u64);
+        let SymmetricMessagePayload::ReceiptRanges { ranges } = &mut msg.payload else {
+            unreachable!()
+        };
+        ranges.push(range);
+        let size = bincode::serialized_size(&msg).unwrap();
+        assert!(size > MAX_DATA_SIZE as u64);
+    }
+
+    #[test]
+    fn receipt_ranges_encoding() {
+        let receipts = [9, 1, 2, 3, 7, 3, 10, u32::MAX];
+        let ranges = ReceiptRange::encode(&receipts);
+        assert_eq!(
+            ranges,
+            vec![
+                ReceiptRange { first: 1, len: 3 },
+                ReceiptRange { first: 7, len: 1 },
+                ReceiptRange { first: 9, len: 2 },
+                ReceiptRange {
+                    first: u32::MAX,
+                    len: 1
+                },
+            ]
+        );
+        let decoded: Vec<_> = ranges
+            .into_iter()
+            .flat_map(ReceiptRange::packet_ids)
+            .collect();
+        assert_eq!(decoded, vec![1, 2, 3, 7, 9, 10, u32::MAX]);
+    }
+
     #[test]
     fn max_short_message() {
         let overhead = SymmetricMessage::short_message_overhead();
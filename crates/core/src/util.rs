@@ -3,6 +3,7 @@ pub(crate) mod time_source;
 use std::{
     collections::{BTreeMap, HashSet},
     net::{Ipv4Addr, SocketAddr, TcpListener},
+    path::Path,
     sync::Arc,
     time::Duration,
 };
@@ -100,6 +101,15 @@ impl ExponentialBackoff {
     }
 }
 
+/// Writes to a temporary file first so a crash while writing doesn't leave a truncated
+/// file behind.
+pub(crate) async fn persist_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.to_path_buf();
+    tmp.set_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
 #[allow(clippy::result_unit_err)]
 pub fn get_free_port() -> Result<u16, ()> {
     let mut port;
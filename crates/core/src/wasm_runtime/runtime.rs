@@ -1,4 +1,11 @@
-use std::{collections::HashMap, sync::atomic::AtomicI64};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use freenet_stdlib::{
     memory::{
@@ -19,11 +26,60 @@ static INSTANCE_ID: AtomicI64 = AtomicI64::new(0);
 pub(super) struct RunningInstance {
     pub id: i64,
     pub instance: Instance,
+    cpu_timer: CpuTimer,
+    /// Nanoseconds of CPU time spent by the instances of the runtime, updated when dropped.
+    cpu_time: Arc<AtomicU64>,
 }
 
 impl Drop for RunningInstance {
     fn drop(&mut self) {
         let _ = native_api::MEM_ADDR.remove(&self.id);
+        let elapsed = self.cpu_timer.elapsed().as_nanos() as u64;
+        self.cpu_time.fetch_add(elapsed, Ordering::SeqCst);
+    }
+}
+
+/// Measures the CPU time spent by the current thread.
+///
+/// Only Linux exposes a per thread CPU clock here; on other targets this falls back
+/// to wall time, so time the thread spends descheduled is charged as well.
+///
+/// Instances are executed synchronously, so the thread which prepared an instance
+/// is the one which runs it until it is dropped.
+struct CpuTimer {
+    cpu_start: Option<Duration>,
+    wall_start: Instant,
+}
+
+impl CpuTimer {
+    fn start() -> Self {
+        Self {
+            cpu_start: Self::thread_cpu_time(),
+            wall_start: Instant::now(),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        match (self.cpu_start, Self::thread_cpu_time()) {
+            (Some(start), Some(now)) => now.saturating_sub(start),
+            _ => self.wall_start.elapsed(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn thread_cpu_time() -> Option<Duration> {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: the timespec is valid for writes for the duration of the call
+        let res = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+        (res == 0).then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn thread_cpu_time() -> Option<Duration> {
+        None
     }
 }
 
@@ -47,7 +103,13 @@ enum Key {
 }
 
 impl RunningInstance {
-    fn new(rt: &mut Runtime, instance: Instance, key: Key) -> RuntimeResult<Self> {
+    /// `mem_baseline` is the size of the memory used by the instance before it was prepared.
+    fn new(
+        rt: &mut Runtime,
+        instance: Instance,
+        key: Key,
+        mem_baseline: usize,
+    ) -> RuntimeResult<Self> {
         let memory = rt
             .host_memory
             .as_ref()
@@ -67,7 +129,14 @@ impl RunningInstance {
                 key,
             },
         );
-        Ok(Self { instance, id })
+        rt.executed_instances_mem
+            .push((memory.clone(), mem_baseline));
+        Ok(Self {
+            instance,
+            id,
+            cpu_timer: CpuTimer::start(),
+            cpu_time: rt.cpu_time.clone(),
+        })
     }
 }
 
@@ -106,6 +175,11 @@ pub struct Runtime {
     pub(crate) contract_store: ContractStore,
     /// loaded contract modules
     pub(super) contract_modules: HashMap<ContractKey, Module>,
+    /// Linear memory of the instances run since the usage was last taken, along with
+    /// its size before each instance was prepared.
+    executed_instances_mem: Vec<(Memory, usize)>,
+    /// Nanoseconds of CPU time spent by the instances run since the usage was last taken.
+    cpu_time: Arc<AtomicU64>,
 }
 
 impl Runtime {
@@ -142,9 +216,34 @@ impl Runtime {
 
             contract_store,
             delegate_modules: HashMap::new(),
+            executed_instances_mem: Vec::new(),
+            cpu_time: Arc::new(AtomicU64::new(0)),
         })
     }
 
+    /// Returns the CPU time spent and the bytes of linear memory used by the instances
+    /// run since the last call.
+    ///
+    /// Linear memory never shrinks, so what it grew by since an instance was prepared
+    /// is the peak memory that instance added.
+    pub(crate) fn take_execution_usage(&mut self) -> (Duration, usize) {
+        let cpu_time = Duration::from_nanos(self.cpu_time.swap(0, Ordering::SeqCst));
+        let grown = self
+            .executed_instances_mem
+            .iter()
+            .map(|(memory, baseline)| {
+                (memory.view(&self.wasm_store).data_size() as usize).saturating_sub(*baseline)
+            });
+        let memory_usage = if self.host_memory.is_some() {
+            // the host memory is shared by all the instances, so their growth overlaps
+            grown.max().unwrap_or(0)
+        } else {
+            grown.sum()
+        };
+        self.executed_instances_mem.clear();
+        (cpu_time, memory_usage)
+    }
+
     pub(super) fn init_buf<T>(&mut self, instance: &Instance, data: T) -> RuntimeResult<BufferMut>
     where
         T: AsRef<[u8]>,
@@ -196,9 +295,10 @@ impl Runtime {
             self.contract_modules.get(key).unwrap()
         }
         .clone();
+        let mem_baseline = self.host_mem_size();
         let instance = self.prepare_instance(&module)?;
         self.set_instance_mem(req_bytes, &instance)?;
-        RunningInstance::new(self, instance, Key::Contract(*key.id()))
+        RunningInstance::new(self, instance, Key::Contract(*key.id()), mem_baseline)
     }
 
     pub(super) fn prepare_delegate_call(
@@ -219,9 +319,17 @@ impl Runtime {
             self.delegate_modules.get(key).unwrap()
         }
         .clone();
+        let mem_baseline = self.host_mem_size();
         let instance = self.prepare_instance(&module)?;
         self.set_instance_mem(req_bytes, &instance)?;
-        RunningInstance::new(self, instance, Key::Delegate(key.clone()))
+        RunningInstance::new(self, instance, Key::Delegate(key.clone()), mem_baseline)
+    }
+
+    /// Size of the host memory, instances which own their memory start from zero.
+    fn host_mem_size(&self) -> usize {
+        self.host_memory.as_ref().map_or(0, |memory| {
+            memory.view(&self.wasm_store).data_size() as usize
+        })
     }
 
     fn set_instance_mem(&mut self, req_bytes: usize, instance: &Instance) -> RuntimeResult<()> {
@@ -277,3 +385,23 @@ impl Runtime {
     //     // Store::new(&Dylib::headless().engine())
     // }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cpu_timer_excludes_idle_time() {
+        let timer = CpuTimer::start();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(timer.elapsed() < Duration::from_millis(100));
+
+        let timer = CpuTimer::start();
+        let busy_until = Instant::now() + Duration::from_millis(200);
+        while Instant::now() < busy_until {
+            std::hint::spin_loop();
+        }
+        assert!(timer.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,60 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use freenet::dev_tool::{read_capture, CaptureDecoder};
+
+use crate::Error;
+
+/// Decode a transport packet capture into a timeline of packet ids, receipts, stream
+/// fragments and resends.
+#[derive(clap::Parser, Clone)]
+pub struct DecodeCaptureConfig {
+    /// Capture files, oldest first, e.g. `capture.2 capture.1 capture`.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Keylog with the session keys, by default the `<file>.keylog` of every capture file.
+    /// Without it only the packet sizes and addresses are shown.
+    #[arg(long)]
+    keylog: Option<PathBuf>,
+    /// Only show packets sent to or received from this address.
+    #[arg(long)]
+    remote: Option<SocketAddr>,
+}
+
+pub fn decode_capture(config: DecodeCaptureConfig) -> anyhow::Result<()> {
+    let mut decoder = CaptureDecoder::default();
+    let keylogs: Vec<_> = match config.keylog {
+        Some(keylog) => vec![keylog],
+        None => config
+            .files
+            .iter()
+            .map(|file| {
+                let mut path = file.as_os_str().to_owned();
+                path.push(".keylog");
+                PathBuf::from(path)
+            })
+            .filter(|path| path.exists())
+            .collect(),
+    };
+    if keylogs.is_empty() {
+        eprintln!("no keylog found, packets won't be decrypted");
+    }
+    for keylog in &keylogs {
+        decoder.load_keylog(keylog)?;
+    }
+
+    for file in &config.files {
+        if !file.exists() {
+            return Err(Error::CommandFailed("couldn't find capture file").into());
+        }
+        for packet in read_capture(file)? {
+            let entry = decoder.decode(&packet);
+            if config
+                .remote
+                .map_or(true, |remote| remote == entry.remote_addr)
+            {
+                println!("{entry}");
+            }
+        }
+    }
+    Ok(())
+}
@@ -5,13 +5,13 @@ use std::{
     path::PathBuf,
 };
 
-use freenet::dev_tool::OperationMode;
+use freenet::dev_tool::{OperationMode, RingHealth, RoutingExplanation};
 use freenet_stdlib::{
     client_api::{ClientRequest, ContractRequest, DelegateRequest, WebApi},
     prelude::*,
 };
 
-use crate::config::{BaseConfig, PutConfig, UpdateConfig};
+use crate::config::{BaseConfig, HealthConfig, PutConfig, RoutingConfig, UpdateConfig};
 
 mod v1;
 
@@ -145,6 +145,123 @@ pub async fn update(config: UpdateConfig, other: BaseConfig) -> anyhow::Result<(
     execute_command(request, other, config.address, config.port).await
 }
 
+pub async fn routing(config: RoutingConfig) -> anyhow::Result<()> {
+    let key = ContractInstanceId::try_from(config.key)?;
+    let url = format!(
+        "http://{}/v1/node/routing/{key}",
+        SocketAddr::new(config.address, config.port)
+    );
+    let response = reqwest::get(&url).await?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "failed to get the routing explanation ({}): {}",
+            response.status(),
+            response.text().await?
+        );
+    }
+    let explanation: RoutingExplanation = response.json().await?;
+
+    println!("Routing contract {key} (location {})", explanation.target);
+    let estimators = &explanation.estimators;
+    println!(
+        "Estimator events: response start time {}, transfer rate {}, failure {} (required {})",
+        estimators.response_start_time,
+        estimators.transfer_rate,
+        estimators.failure,
+        estimators.required
+    );
+    if !explanation.sufficient_data {
+        println!("Not enough historical data, routing to the closest reputable peer");
+    }
+    for (rank, candidate) in explanation.candidates.iter().enumerate() {
+        let selected = if explanation.selected.as_ref() == Some(&candidate.peer) {
+            "*"
+        } else {
+            " "
+        };
+        let prediction = match (&candidate.prediction, &candidate.error) {
+            (Some(prediction), _) => format!(
+                "failure probability {:.3}, response start {:.3}s, expected total {:.3}s",
+                prediction.failure_probability,
+                prediction.time_to_response_start,
+                prediction.expected_total_time
+            ),
+            (None, Some(error)) => format!("no prediction: {error}"),
+            (None, None) => "no prediction".to_string(),
+        };
+        println!(
+            "{selected} {}. {} distance {:.5}, reputation {:.2}: {prediction}",
+            rank + 1,
+            candidate.peer,
+            candidate.distance,
+            candidate.reputation
+        );
+    }
+    if explanation.selected.is_none() {
+        println!("No peer available to route to");
+    }
+    Ok(())
+}
+
+pub async fn health(config: HealthConfig) -> anyhow::Result<()> {
+    let url = format!(
+        "http://{}/v1/node/health",
+        SocketAddr::new(config.address, config.port)
+    );
+    let response = reqwest::get(&url).await?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "failed to get the ring health ({}): {}",
+            response.status(),
+            response.text().await?
+        );
+    }
+    let health: RingHealth = response.json().await?;
+    if config.json {
+        println!("{}", serde_json::to_string_pretty(&health)?);
+        return Ok(());
+    }
+
+    match (&health.peer, &health.location) {
+        (Some(peer), Some(location)) => println!("Peer {peer} at location {location}"),
+        (Some(peer), None) => println!("Peer {peer}, no location assigned yet"),
+        _ => println!("Peer not joined the network yet"),
+    }
+    match health.network_size {
+        Some(size) => println!("Estimated network size: {size:.0} peers"),
+        None => println!("Estimated network size: unknown"),
+    }
+    println!("Connections: {}", health.connections);
+    for bucket in &health.distance_buckets {
+        let fraction = if health.connections > 0 {
+            bucket.connections as f64 / health.connections as f64
+        } else {
+            0.0
+        };
+        println!(
+            "  distance {:.4}-{:.4}: {:>3} ({:.2}, ideal {:.2})",
+            bucket.from, bucket.to, bucket.connections, fraction, bucket.ideal_fraction
+        );
+    }
+    if let Some(deviation) = health.small_world_deviation {
+        println!("Deviation from the ideal small world distribution: {deviation:.3}");
+    }
+    println!("Seeded contracts: {}", health.seeding.len());
+    for contract in &health.seeding {
+        println!("  {} score {:.3}", contract.key, contract.score);
+    }
+    println!("Contracts with subscribers: {}", health.subscribers.len());
+    for contract in &health.subscribers {
+        println!("  {} subscribers {}", contract.key, contract.subscribers);
+    }
+    println!("Live transactions: {}", health.live_transactions.len());
+    for tx in &health.live_transactions {
+        let peers: Vec<_> = tx.peers.iter().map(ToString::to_string).collect();
+        println!("  {} waiting on {}", tx.transaction, peers.join(", "));
+    }
+    Ok(())
+}
+
 async fn execute_command(
     request: ClientRequest<'static>,
     other: BaseConfig,
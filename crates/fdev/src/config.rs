@@ -34,6 +34,7 @@ pub enum SubCommand {
     New(NewPackageConfig),
     Build(BuildToolConfig),
     Inspect(crate::inspect::InspectConfig),
+    DecodeCapture(crate::capture::DecodeCaptureConfig),
     Publish(PutConfig),
     WasmRuntime(ExecutorConfig),
     Execute(RunCliConfig),
@@ -67,6 +68,36 @@ pub struct RunCliConfig {
 pub enum NodeCommand {
     Put(PutConfig),
     Update(UpdateConfig),
+    Routing(RoutingConfig),
+    Health(HealthConfig),
+}
+
+/// Explains how the node would route a request for a contract, ranking the peers it considers.
+#[derive(clap::Parser, Clone)]
+pub struct RoutingConfig {
+    /// Contract id of the contract being routed to in Base58 format.
+    pub(crate) key: String,
+    /// The ip address of the freenet node.
+    #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub(crate) address: IpAddr,
+    /// The port of the running local freenet node.
+    #[arg(short, long, default_value = "50509")]
+    pub(crate) port: u16,
+}
+
+/// Reports the position of the node in the ring, its connections, seeded contracts,
+/// subscriptions and transactions in progress.
+#[derive(clap::Parser, Clone)]
+pub struct HealthConfig {
+    /// Print the raw report in JSON format.
+    #[arg(long)]
+    pub(crate) json: bool,
+    /// The ip address of the freenet node.
+    #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub(crate) address: IpAddr,
+    /// The port of the running local freenet node.
+    #[arg(short, long, default_value = "50509")]
+    pub(crate) port: u16,
 }
 
 /// Updates a contract in the network.
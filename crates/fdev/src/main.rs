@@ -4,6 +4,7 @@ use clap::Parser;
 use freenet_stdlib::client_api::ClientRequest;
 
 mod build;
+mod capture;
 mod commands;
 mod config;
 mod inspect;
@@ -15,7 +16,8 @@ mod wasm_runtime;
 
 use crate::{
     build::build_package,
-    commands::{put, update},
+    capture::decode_capture,
+    commands::{health, put, routing, update},
     config::{Config, SubCommand},
     inspect::inspect,
     new_package::create_new_package,
@@ -49,6 +51,7 @@ fn main() -> anyhow::Result<()> {
             }
             SubCommand::Build(build_tool_config) => build_package(build_tool_config, &cwd),
             SubCommand::Inspect(inspect_config) => inspect(inspect_config),
+            SubCommand::DecodeCapture(capture_config) => decode_capture(capture_config),
             SubCommand::New(new_pckg_config) => create_new_package(new_pckg_config),
             SubCommand::Publish(publish_config) => put(publish_config, config.additional).await,
             SubCommand::Execute(cmd_config) => match cmd_config.command {
@@ -56,6 +59,8 @@ fn main() -> anyhow::Result<()> {
                 config::NodeCommand::Update(update_config) => {
                     update(update_config, config.additional).await
                 }
+                config::NodeCommand::Routing(routing_config) => routing(routing_config).await,
+                config::NodeCommand::Health(health_config) => health(health_config).await,
             },
             SubCommand::Test(test_config) => testing::test_framework(test_config).await,
             SubCommand::NetworkMetricsServer(server_config) => {